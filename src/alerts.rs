@@ -0,0 +1,337 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use bincode::{Decode, Encode};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{header, Method, Request};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::mpsc;
+
+pub const DEFAULT_ALERT_STATUS: &str = "5xx";
+pub const DEFAULT_ALERT_COUNT: u64 = 50;
+pub const DEFAULT_ALERT_WINDOW: &str = "60s";
+pub const DEFAULT_ALERT_COOLDOWN: &str = "10m";
+
+// Responses waiting to be counted. The new ones are dropped when it is full,
+// the threshold is reached anyway.
+const ALERT_QUEUE_SIZE: usize = 4096;
+// URLs of the last matching responses sent with an alert.
+const SAMPLE_URLS: usize = 5;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+type WebhookClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+// Statuses counted by the alerts, e.g. 500 to 599 for "5xx".
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub struct StatusRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl StatusRange {
+    // "4xx", "5xx" or a status code like "502".
+    pub fn parse(status: &str) -> Result<StatusRange, String> {
+        let invalid = || format!("invalid status \"{status}\" (e.g. \"5xx\" or \"502\")");
+        if let Some(class) = status.strip_suffix("xx") {
+            let class: u16 = class.parse().map_err(|_| invalid())?;
+            if !(1..=5).contains(&class) {
+                return Err(invalid());
+            }
+            return Ok(StatusRange {
+                first: class * 100,
+                last: class * 100 + 99,
+            });
+        }
+        match status.parse::<u16>() {
+            Ok(code) if (100..=599).contains(&code) => Ok(StatusRange {
+                first: code,
+                last: code,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn contains(&self, status: u16) -> bool {
+        (self.first..=self.last).contains(&status)
+    }
+}
+
+impl std::fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}xx", self.first / 100)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct AlertsConfig {
+    pub webhook: String,
+    pub status: StatusRange,
+    pub count: u64,
+    pub window: u64,   // Seconds.
+    pub cooldown: u64, // Seconds between two alerts of a service.
+}
+
+// Duration like "60s", "10m", "1h" or a number of seconds.
+pub fn parse_duration(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("invalid duration \"{duration}\" (e.g. \"60s\")")),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid duration \"{duration}\" (e.g. \"60s\")"))
+}
+
+// Scheme and host of the webhook, the path often contains a token.
+pub fn redact_webhook(webhook: &str) -> String {
+    match webhook.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split('/').next().unwrap_or_default();
+            format!("{scheme}://{host}/<redacted>")
+        }
+        None => "<redacted>".to_string(),
+    }
+}
+
+struct AlertEvent {
+    service: String,
+    url: String,
+}
+
+// Set once at startup, the alerts are disabled if it is not set.
+static ALERTS: OnceLock<(StatusRange, mpsc::Sender<AlertEvent>)> = OnceLock::new();
+
+// Count the responses of the services in a background task. Delivery
+// failures are only logged.
+pub fn start_alerts(config: AlertsConfig) {
+    let (tx, rx) = mpsc::channel(ALERT_QUEUE_SIZE);
+    if ALERTS.set((config.status, tx)).is_err() {
+        return;
+    }
+    tracing::info!(
+        "Alerts enabled: {} {} responses in {}s for a service",
+        config.count,
+        config.status,
+        config.window
+    );
+    tokio::spawn(watch_responses(config, rx));
+}
+
+// Called for each response, with the name of its service. The url is only
+// formatted for the matching responses.
+pub fn record(status: u16, service: &str, url: &impl std::fmt::Display) {
+    let Some((range, tx)) = ALERTS.get() else {
+        return;
+    };
+    if !range.contains(status) {
+        return;
+    }
+    // Without the query string, which may contain secrets.
+    let mut url = url.to_string();
+    if let Some(query) = url.find('?') {
+        url.truncate(query);
+    }
+    let _ = tx.try_send(AlertEvent {
+        service: service.to_string(),
+        url,
+    });
+}
+
+// Matching responses of a service in the window.
+#[derive(Default)]
+struct ServiceResponses {
+    times: VecDeque<Instant>, // At most count.
+    urls: VecDeque<String>,
+    last_alert: Option<Instant>,
+}
+
+impl ServiceResponses {
+    // Return true when the alert must be sent.
+    fn add(&mut self, config: &AlertsConfig, url: String, now: Instant) -> bool {
+        self.times.push_back(now);
+        if self.times.len() as u64 > config.count {
+            self.times.pop_front();
+        }
+        self.urls.push_back(url);
+        if self.urls.len() > SAMPLE_URLS {
+            self.urls.pop_front();
+        }
+        let window = Duration::from_secs(config.window);
+        let reached = self.times.len() as u64 == config.count
+            && self
+                .times
+                .front()
+                .is_some_and(|first| now.duration_since(*first) <= window);
+        let cooled = self
+            .last_alert
+            .is_none_or(|last| now.duration_since(last) >= Duration::from_secs(config.cooldown));
+        if reached && cooled {
+            self.last_alert = Some(now);
+            return true;
+        }
+        false
+    }
+}
+
+async fn watch_responses(config: AlertsConfig, mut rx: mpsc::Receiver<AlertEvent>) {
+    let tls_config = match rustls::ClientConfig::builder().with_native_roots() {
+        Ok(builder) => builder.with_no_client_auth(),
+        Err(e) => {
+            tracing::error!("Alerts disabled, can't load the root certificates: {e}");
+            return;
+        }
+    };
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: WebhookClient = Client::builder(TokioExecutor::new()).build(connector);
+
+    let mut services: HashMap<String, ServiceResponses> = HashMap::new();
+    while let Some(event) = rx.recv().await {
+        let responses = services.entry(event.service.clone()).or_default();
+        if !responses.add(&config, event.url, Instant::now()) {
+            continue;
+        }
+        tracing::warn!(
+            "{} {} responses in {}s for the service {}, sending an alert",
+            config.count,
+            config.status,
+            config.window,
+            event.service
+        );
+        let payload = payload(&config, &event.service, responses.urls.iter());
+        let client = client.clone();
+        let webhook = config.webhook.clone();
+        // Never slow down the counting.
+        tokio::spawn(async move {
+            if let Err(e) = send(&client, &webhook, payload).await {
+                tracing::error!(
+                    "Failed to send the alert to {}: {e}",
+                    redact_webhook(&webhook)
+                );
+            }
+        });
+    }
+}
+
+fn payload<'a>(
+    config: &AlertsConfig,
+    service: &str,
+    urls: impl Iterator<Item = &'a String>,
+) -> String {
+    let summary = format!(
+        "Quark: {} {} responses in {}s for the service {service}",
+        config.count, config.status, config.window
+    );
+    json!({
+        // Shown by Slack, Mattermost and Discord compatible webhooks.
+        "text": summary,
+        "service": service,
+        "status": config.status.to_string(),
+        "count": config.count,
+        "window": config.window,
+        "sample_urls": urls.collect::<Vec<_>>(),
+        "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+    })
+    .to_string()
+}
+
+async fn send(client: &WebhookClient, webhook: &str, payload: String) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(payload)))
+        .map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| "timeout".to_string())?
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("the webhook answered {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alert_thresholds() {
+        assert_eq!(
+            StatusRange::parse("5xx"),
+            Ok(StatusRange {
+                first: 500,
+                last: 599
+            })
+        );
+        assert_eq!(StatusRange::parse("502").unwrap().to_string(), "502");
+        assert_eq!(StatusRange::parse("4xx").unwrap().to_string(), "4xx");
+        assert!(StatusRange::parse("6xx").is_err());
+        assert!(StatusRange::parse("99").is_err());
+        assert_eq!(parse_duration("60s"), Ok(60));
+        assert_eq!(parse_duration("10m"), Ok(600));
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert_eq!(
+            redact_webhook("https://hooks.example.com/services/T0/B0/secret"),
+            "https://hooks.example.com/<redacted>"
+        );
+
+        let config = AlertsConfig {
+            webhook: "http://127.0.0.1/hook".to_string(),
+            status: StatusRange::parse("5xx").unwrap(),
+            count: 3,
+            window: 60,
+            cooldown: 600,
+        };
+        let mut responses = ServiceResponses::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(!responses.add(&config, "/a".to_string(), at(0)));
+        assert!(!responses.add(&config, "/b".to_string(), at(10)));
+        // Outside of the window of the first one.
+        assert!(!responses.add(&config, "/c".to_string(), at(70)));
+        assert!(!responses.add(&config, "/d".to_string(), at(80)));
+        assert!(responses.add(&config, "/e".to_string(), at(90)));
+        // Cooldown.
+        assert!(!responses.add(&config, "/f".to_string(), at(100)));
+        assert!(!responses.add(&config, "/g".to_string(), at(700)));
+        assert!(!responses.add(&config, "/h".to_string(), at(705)));
+        assert!(responses.add(&config, "/i".to_string(), at(710)));
+
+        let payload: serde_json::Value =
+            serde_json::from_str(&payload(&config, "app", responses.urls.iter())).unwrap();
+        assert_eq!(payload["service"], "app");
+        assert_eq!(payload["status"], "5xx");
+        assert_eq!(payload["count"], 3);
+        assert_eq!(
+            payload["sample_urls"],
+            json!(["/e", "/f", "/g", "/h", "/i"])
+        );
+    }
+}
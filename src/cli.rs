@@ -0,0 +1,327 @@
+use std::error::Error;
+use std::time::Duration;
+
+use argh::FromArgs;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::utils;
+
+// A reload is applied within seconds, it failed if it takes longer.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Subcommands using the admin socket of a running server.
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Status(StatusCommand),
+    Targets(TargetsCommand),
+    Certs(CertsCommand),
+    Reload(ReloadCommand),
+}
+
+#[derive(FromArgs)]
+/// show the servers, backends and listeners of the running server
+#[argh(subcommand, name = "status")]
+pub struct StatusCommand {}
+
+#[derive(FromArgs)]
+/// show the routes of a domain, or the route serving a path
+#[argh(subcommand, name = "targets")]
+pub struct TargetsCommand {
+    /// domain, optionally followed by a path, e.g. example.com/api/users
+    #[argh(positional)]
+    target: String,
+}
+
+#[derive(FromArgs)]
+/// show the loaded certificates and their expiry date
+#[argh(subcommand, name = "certs")]
+pub struct CertsCommand {}
+
+#[derive(FromArgs)]
+/// reload the configuration of the running server
+#[argh(subcommand, name = "reload")]
+pub struct ReloadCommand {}
+
+// Run the command and print the result. Any failure exits with 1.
+pub async fn run(command: Command, admin_socket_path: &str) -> Result<(), Box<dyn Error>> {
+    let output = match command {
+        Command::Status(_) => request(admin_socket_path, json!({ "command": "status" }))
+            .await
+            .map(|status| format_status(&status)),
+        Command::Targets(targets) => {
+            let (domain, path) = split_target(&targets.target);
+            let request_body = json!({ "command": "targets", "domain": domain, "path": path });
+            request(admin_socket_path, request_body)
+                .await
+                .map(|targets| format_targets(&targets))
+        }
+        Command::Certs(_) => request(admin_socket_path, json!({ "command": "certs" }))
+            .await
+            .map(|certs| format_certs(&certs)),
+        Command::Reload(_) => reload(admin_socket_path).await,
+    };
+    match output {
+        Ok(output) => {
+            print!("{output}");
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Send one request and return the result of the response.
+async fn request(admin_socket_path: &str, request: Value) -> Result<Value, String> {
+    let stream = UnixStream::connect(admin_socket_path).await.map_err(|e| {
+        format!("Can't connect to the admin socket at {admin_socket_path}, is quark running? {e}")
+    })?;
+    let (read, mut write) = stream.into_split();
+    let mut line = request.to_string();
+    line.push('\n');
+    write
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| format!("Can't send the request: {e}"))?;
+
+    let mut response = String::new();
+    BufReader::new(read)
+        .read_line(&mut response)
+        .await
+        .map_err(|e| format!("Can't read the response: {e}"))?;
+    let response: Value = serde_json::from_str(&response)
+        .map_err(|e| format!("Invalid response from the server: {e}"))?;
+    if response["ok"] != true {
+        return Err(format!(
+            "Error: {}",
+            response["error"].as_str().unwrap_or("unknown error")
+        ));
+    }
+    Ok(response["result"].clone())
+}
+
+// The configuration is reloaded by the main process on SIGHUP. The server
+// counts the reloads it applied, an invalid configuration is never applied.
+async fn reload(admin_socket_path: &str) -> Result<String, String> {
+    let status = request(admin_socket_path, json!({ "command": "status" })).await?;
+    let reloads = status["reloads"].as_u64().unwrap_or(0);
+    let main_pid = status["main_pid"].as_i64().unwrap_or(0) as i32;
+    // The server is orphaned, never signal init.
+    if main_pid <= 1 {
+        return Err("Error: the main process isn't running".to_string());
+    }
+    kill(Pid::from_raw(main_pid), Signal::SIGHUP)
+        .map_err(|e| format!("Can't send SIGHUP to the main process {main_pid}: {e}"))?;
+
+    let start = tokio::time::Instant::now();
+    while start.elapsed() < RELOAD_TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let status = request(admin_socket_path, json!({ "command": "status" })).await?;
+        if status["reloads"].as_u64().unwrap_or(0) > reloads {
+            return Ok("Configuration reloaded\n".to_string());
+        }
+    }
+    Err(
+        "Error: the configuration wasn't reloaded, the reason is in the logs of the main process"
+            .to_string(),
+    )
+}
+
+// example.com/api/users -> (example.com, Some(/api/users))
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    match target.find('/') {
+        Some(pos) => (&target[..pos], Some(&target[pos..])),
+        None => (target, None),
+    }
+}
+
+fn format_status(status: &Value) -> String {
+    let mut out = format!(
+        "pid {} (main process {}), up {}, {} reload(s)\n",
+        status["pid"],
+        status["main_pid"],
+        format_uptime(status["uptime"].as_u64().unwrap_or(0)),
+        status["reloads"],
+    );
+    for server in status["servers"].as_array().into_iter().flatten() {
+        let https = match server["https_port"].as_u64() {
+            Some(port) => format!(", https port {port}"),
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            "\n[servers.{}] port {}{https}, http2 {}, {} domain(s), {} route(s)\n",
+            text(&server["name"]),
+            server["port"],
+            server["http2"],
+            server["domains"],
+            server["targets"],
+        ));
+        for upstream in server["upstreams"].as_array().into_iter().flatten() {
+            // Only with an outlier detection.
+            let ejections = match upstream["ejections"].as_u64() {
+                Some(0) | None => String::new(),
+                Some(count) if upstream["ejected"] == true => {
+                    format!(", ejected ({count} ejection(s))")
+                }
+                Some(count) => format!(", {count} ejection(s)"),
+            };
+            out.push_str(&format!(
+                "  {} {} -> {}: {} request(s), {} error(s), max {} ms{ejections}\n",
+                text(&upstream["domain"]),
+                text(&upstream["source"]),
+                text(&upstream["backend"]),
+                upstream["requests"],
+                upstream["errors"],
+                upstream["duration_ms"]["max"],
+            ));
+        }
+        for limit in server["limits"].as_array().into_iter().flatten() {
+            out.push_str(&format!(
+                "  {} {}: {}/{} max_concurrent, {} rejected\n",
+                text(&limit["domain"]),
+                text(&limit["source"]),
+                limit["active"],
+                limit["max"],
+                limit["rejected"],
+            ));
+        }
+    }
+    out.push('\n');
+    for listener in status["listeners"].as_array().into_iter().flatten() {
+        let accept_loop = match listener["accept_loop"].as_u64() {
+            Some(accept_loop) => format!(", accept loop {accept_loop}"),
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            "port {} ({}{accept_loop}): {} active, {} accepted, {} rejected by max_connections, {} rejected by max_conn_per_ip, {} closed idle, {} closed at max lifetime, {} sent, {} responses aborted",
+            listener["port"],
+            text(&listener["protocol"]),
+            listener["active"],
+            listener["accepted_total"],
+            listener["rejected_limit"],
+            listener["rejected_ip_limit"],
+            listener["reaped_idle"],
+            listener["reaped_lifetime"],
+            utils::format_size(listener["bytes_sent"].as_u64().unwrap_or(0)),
+            listener["responses_aborted"],
+        ));
+        if listener["protocol"] == "https" {
+            out.push_str(&format!(
+                ", {} TLS handshake(s) failed",
+                listener["tls_handshake_failed"]
+            ));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn format_targets(targets: &Value) -> String {
+    let mut out = String::new();
+    for target in targets.as_array().into_iter().flatten() {
+        let destination = match target["type"].as_str() {
+            Some("location") => {
+                let backends: Vec<&str> = target["backends"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(text)
+                    .collect();
+                match target["algo"].as_str() {
+                    Some(algo) => format!("{} ({algo})", backends.join(", ")),
+                    None => backends.join(", "),
+                }
+            }
+            Some("redirection") => format!("{} ({})", text(&target["location"]), target["code"]),
+            _ => text(&target["location"]).to_string(),
+        };
+        out.push_str(&format!(
+            "[servers.{}] {} -> {} {destination}\n",
+            text(&target["server"]),
+            text(&target["source"]),
+            text(&target["type"]),
+        ));
+    }
+    out
+}
+
+fn format_certs(certs: &Value) -> String {
+    let mut out = String::new();
+    for cert in certs.as_array().into_iter().flatten() {
+        let domains: Vec<&str> = cert["domains"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(text)
+            .collect();
+        out.push_str(&format!(
+            "{}: {}, expires {} ({} day(s))\n",
+            text(&cert["path"]),
+            domains.join(", "),
+            cert["not_after"].as_str().unwrap_or("unknown"),
+            cert["days_remaining"],
+        ));
+    }
+    if out.is_empty() {
+        out.push_str("No certificate loaded\n");
+    }
+    out
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or("-")
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours) = (seconds / 86400, seconds / 3600 % 24);
+    let (minutes, seconds) = (seconds / 60 % 60, seconds % 60);
+    match days {
+        0 => format!("{hours}h {minutes}m {seconds}s"),
+        days => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_responses() {
+        assert_eq!(split_target("example.com"), ("example.com", None));
+        assert_eq!(
+            split_target("example.com/api/users"),
+            ("example.com", Some("/api/users"))
+        );
+        assert_eq!(format_uptime(3725), "1h 2m 5s");
+        assert_eq!(format_uptime(90061), "1d 1h 1m");
+
+        let targets = json!([
+            {
+                "type": "location",
+                "backends": ["http://10.0.0.1:3001", "http://10.0.0.2:3001"],
+                "algo": "round_robin",
+                "server": "main",
+                "source": "/api/*",
+            },
+            {
+                "type": "redirection",
+                "location": "https://example.com",
+                "code": 301,
+                "server": "main",
+                "source": "/*",
+            },
+        ]);
+        assert_eq!(
+            format_targets(&targets),
+            "[servers.main] /api/* -> location http://10.0.0.1:3001, http://10.0.0.2:3001 (round_robin)\n\
+             [servers.main] /* -> redirection https://example.com (301)\n"
+        );
+        assert_eq!(format_certs(&json!([])), "No certificate loaded\n");
+    }
+}
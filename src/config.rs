@@ -1,29 +1,65 @@
+mod error;
+mod format;
+mod print;
 pub mod tls;
 mod toml_model;
+mod vars;
 use argh::FromArgs;
 use bincode::{Decode, Encode};
+pub use error::ConfigError;
+use format::ConfigFormat;
 use hyper::StatusCode;
+pub use print::redact_url;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use toml_model::{ConfigToml, SubConfigToml};
 
 use crate::{
-    config::toml_model::{FileServers, Headers},
+    alerts::{self, AlertsConfig, StatusRange},
+    config::toml_model::{AuthorizedDir, FileServers, Headers},
+    load_balancing::ALGO_LEAST_TIME,
+    logs::{
+        self,
+        access::{AccessLogFormat, AccessLogSampling},
+        rotation::{self, LogRotation, SizeRotation},
+        syslog, LogDestination,
+    },
     utils::{self, extract_vars_from_string, generate_u32_id, get_path_and_file},
 };
 
-const MAIN_SERVER_NAME: &str = "main";
+pub const MAIN_SERVER_NAME: &str = "main";
 const DEFAULT_PORT: u16 = 80;
 const DEFAULT_PORT_HTTPS: u16 = 443;
 const DEFAULT_PROXY_TIMEOUT: u64 = 60;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+const DEFAULT_UPSTREAM_IDLE_TIMEOUT: u64 = 60;
 const DEFAULT_TLS_REDIRECTION: bool = true;
+const DEFAULT_TLS_REDIRECTION_CODE: u16 = 308; // Permanent, keeps the method.
+const DEFAULT_ENABLED: bool = true;
+const DEFAULT_TLS_OPTIONAL: bool = false;
+const DEFAULT_TIMING_HEADER: bool = false;
+const DEFAULT_UPSTREAM_HEADER: bool = false;
+const DEFAULT_WWW_REDIRECT: bool = true;
+const DEFAULT_DENY_TRACE: bool = true;
 const DEFAULT_REDIRECTION_CODE: u16 = 301; // Permanent.
+const DEFAULT_PRESERVE_QUERY: bool = true;
+const DEFAULT_DNS_REFRESH: u64 = 30;
+const DEFAULT_EWMA_DECAY: f64 = 0.2; // About the last 10 responses.
+const DEFAULT_OUTLIER_INTERVAL: u64 = 10; // Seconds.
+const DEFAULT_OUTLIER_ERROR_PERCENT: u32 = 10; // Over the average of the pool.
+const DEFAULT_OUTLIER_MIN_REQUESTS: u64 = 10; // In an interval.
+const DEFAULT_EJECTION_TIME: u64 = 30; // Seconds, times the ejections in a row.
+const DEFAULT_MAX_EJECTION_TIME: u64 = 300; // Seconds.
+const DEFAULT_MAX_EJECTION_PERCENT: u32 = 50;
 const DEFAULT_BACKLOG: i32 = 4096;
 const DEFAULT_MAX_CONNECTIONS: usize = 1024;
 const DEFAULT_MAX_REQUESTS: usize = 100;
+const DEFAULT_QUEUE_TIMEOUT: u64 = 0; // Milliseconds.
+const DEFAULT_MAX_CONNECTIONS_RETRY_AFTER: u64 = 1; // Seconds.
 const DEFAULT_KEEPALIVE: bool = true;
 const DEFAULT_KEEPALIVE_TIMEOUT: u64 = 60;
 const DEFAULT_KEEPALIVE_INTERVAL: u64 = 20;
@@ -31,24 +67,83 @@ const DEFAULT_TLS_HANDSHAKE_TIMEOUT: u64 = 10;
 const DEFAULT_HTTP_HEADER_TIMEOUT: u64 = 30;
 const DEFAULT_IDLE_TIMEOUT: u64 = 300;
 const DEFAULT_IDLE_CHECK_INTERVAL: u64 = 20;
-const DEFAULT_FORBIDDEN_DIR: bool = true;
+const DEFAULT_MAX_CONNECTION_LIFETIME: u64 = 0; // Unlimited.
+const DEFAULT_AUTOINDEX: bool = false;
+const DEFAULT_CANONICAL_INDEX_REDIRECT: bool = false;
 const DEFAULT_TLS_PROXY_VERIFY: bool = true;
+const DEFAULT_CLIENT_CERT_HEADER: &str = "X-Client-Cert-Subject";
+const DEFAULT_STRICT_SNI: bool = false;
+const DEFAULT_CERT_EXPIRY_WARNING: u64 = 21; // Days.
+const DEFAULT_CONNECTION_STATS_INTERVAL: u64 = 300; // Seconds.
+const DEFAULT_ORPHAN_SHUTDOWN: bool = false;
+const DEFAULT_ACCEPT_LOOPS: usize = 1;
+const DEFAULT_HTTP2: bool = true;
+const DEFAULT_CACHE_MAX_SIZE: &str = "64MB";
+const DEFAULT_CACHE_MAX_ENTRY_SIZE: &str = "1MB";
+const DEFAULT_CACHE_METHOD: &str = "GET";
+const DEFAULT_MAX_BUFFERED_BODY: &str = "10MB";
+const DEFAULT_BUFFERING_TIMEOUT: u64 = 60;
+// The other methods may change the state of the backend.
+const CACHEABLE_METHODS: [&str; 2] = ["GET", "HEAD"];
+const DEFAULT_SESSION_TICKETS: bool = true;
+const DEFAULT_TICKET_ROTATION: u32 = 6 * 60 * 60; // Seconds, also the maximum.
+const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+const SUPPORTED_ALPN: [&str; 3] = ["h2", "http/1.1", "http/1.0"];
 
 const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/quark/config.toml";
-const DEFAULT_LOG_PATH: &str = "/var/log/quark";
+pub const DEFAULT_LOG_PATH: &str = "/var/log/quark";
+const DEFAULT_LOG_STDOUT: bool = cfg!(debug_assertions);
+const DEFAULT_ACCESS_LOG: bool = true;
+const DEFAULT_ACCESS_LOG_FORMAT: AccessLogFormat = AccessLogFormat::Combined;
+const DEFAULT_LOG_KEEP: usize = 10;
+const DEFAULT_LOG_COMPRESS: bool = false;
+const DEFAULT_LOG_OUTPUT: &str = "file";
+
+// Sent to the child process when the configuration is reloaded.
+#[derive(Debug, Encode, Decode)]
+pub struct ConfigReload {
+    pub config: InternalConfig,
+    pub client_cas: HashMap<u16, Vec<u8>>, // https port -> client CA
+}
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct InternalConfig {
     pub servers: HashMap<String, Server>, // name -> Server
     pub global: Global,
+    pub logs: LogsConfig,
+    pub alerts: Option<AlertsConfig>, // Read at startup.
     pub empty: bool,
 }
 
+// The command line options take precedence over these values.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct LogsConfig {
+    pub path: Option<String>,
+    pub level: Option<String>,
+    pub stdout: bool,
+    pub access_log: bool,
+    pub access_log_path: Option<String>, // Relative to the logs directory.
+    pub access_log_format: AccessLogFormat,
+    pub access_log_sampling: AccessLogSampling,
+    pub file: Option<String>, // Name of the diagnostic log file.
+    pub rotation: LogRotation,
+    pub outputs: Vec<LogDestination>, // Besides the terminal.
+    pub error_log: Option<String>,    // Relative to the logs directory.
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct Global {
     pub backlog: i32,
     pub max_conn: usize,
     pub max_req: usize,
+    // Milliseconds to wait for a free connection or request, 0 to reject
+    // at once.
+    pub queue_timeout: u64,
+    // Retry-After of the 503 sent over max_connections, 0 for none.
+    pub max_conn_retry_after: u64,
+    // Close the connections over max_connections without a response,
+    // before the TLS handshake.
+    pub close_over_max_conn: bool,
     pub keepalive: bool,
     pub keepalive_timeout: u64,
     pub keepalive_interval: u64,
@@ -56,8 +151,20 @@ pub struct Global {
     pub http_header_timeout: u64,
     pub idle_timeout: u64,
     pub idle_check_interval: u64,
+    // Seconds before a connection is closed once its requests are done,
+    // 0 for no limit.
+    pub max_connection_lifetime: u64,
     pub max_conn_per_ip: Option<usize>,
     pub tls_proxy_verify: bool,
+    pub cert_expiry_warning: u64,
+    pub connection_stats_interval: u64, // 0 when disabled.
+    // Of the server process, when started as root.
+    pub user: String,
+    pub group: String,
+    // Stop the server when the main process is lost.
+    pub orphan_shutdown: bool,
+    // Listening sockets of each port, at most the number of cores.
+    pub accept_loops: usize,
 }
 
 #[derive(Debug, Clone, Encode, Decode, Default)]
@@ -66,9 +173,27 @@ pub struct Server {
     pub port: u16,
     pub https_port: u16,
     pub tls: Option<Vec<TlsCertificate>>,
+    pub tls_options: TlsOptions,
+    pub client_auth: Option<ClientAuth>,
+    // Certificate used when the client sends no SNI or an unknown name.
+    pub default_cert: Option<TlsCertificate>,
+    pub http2: bool,                        // Allow h2c on the plain http listener.
+    pub tls_handshake_timeout: Option<u64>, // Override of the global value.
+    pub tcp_options: TcpOptions,
+    // Aliases of the TLS services, served with the certificates of the server.
+    pub tls_aliases: Vec<TlsAlias>,
+    // Certificate path -> services using it, for the errors.
+    pub cert_services: HashMap<String, Vec<String>>,
 }
 
-#[derive(Debug, Clone, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct TlsAlias {
+    pub domain: String,
+    pub service: String,
+}
+
+// Strict routes come first when a path has both kinds.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode)]
 pub enum RouteKind {
     Strict,
     Path,
@@ -76,11 +201,22 @@ pub enum RouteKind {
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum TargetType {
-    Location(Locations),
+    Location(Box<Locations>), // Boxed, much larger than the others.
     FileServer(FileServer),
     Redirection(Redirection),
 }
 
+impl TargetType {
+    // Name of the kind of target, in the logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TargetType::Location(_) => "location",
+            TargetType::FileServer(_) => "file_server",
+            TargetType::Redirection(_) => "redirection",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct ServerRoute {
     pub path: String,
@@ -88,19 +224,258 @@ pub struct ServerRoute {
     pub kind: RouteKind,
 }
 
+impl ServerRoute {
+    // Whether the route serves the path of a request, query string included.
+    pub fn matches(&self, path: &str) -> bool {
+        match self.kind {
+            RouteKind::Strict => {
+                let path = path.split_once('?').map_or(path, |(path, _)| path);
+                utils::remove_last_slash(path) == self.path
+            }
+            // "/api/*" serves "/api", "/api/..." and "/api?...", not "/apiv2".
+            RouteKind::Path => path
+                .strip_prefix(&self.path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?'])),
+        }
+    }
+}
+
+impl ServerParams {
+    // Domain whose routes serve the host, the service domain of an alias.
+    pub fn canonical_domain<'a>(&'a self, domain: &'a str) -> &'a str {
+        self.aliases
+            .get(domain)
+            .map_or(domain, |domain| domain.as_str())
+    }
+
+    // Longest route of the domain serving the path.
+    pub fn find_route(&self, domain: &str, path: &str) -> Option<&ServerRoute> {
+        self.routes
+            .get(self.canonical_domain(domain))?
+            .iter()
+            .find(|route| route.matches(path))
+    }
+}
+
 // Domain -> Location
 type ServerParamsRoutes = HashMap<String, Vec<ServerRoute>>;
 
+// (Domain, path, kind) -> Service which defined the route.
+type RouteOwners = HashMap<(String, String, RouteKind), RouteOwner>;
+
+struct RouteOwner {
+    service: String,
+    overrides: bool,
+}
+
 #[derive(Debug, Clone, Encode, Decode, Default)]
 pub struct ServerParams {
     pub routes: ServerParamsRoutes,
-    pub auto_tls: Option<Vec<String>>,
-    pub proxy_timeout: u64,
+    // Domain -> redirection of its plain http requests to https.
+    pub auto_tls: HashMap<String, TlsRedirection>,
+    pub proxy_timeout: u64,   // Whole exchange with the backend, in seconds.
+    pub connect_timeout: u64, // Connection to the backend, in seconds.
+    // Time without data from the backend while its response is streamed,
+    // in seconds. 0 disables it.
+    pub upstream_idle_timeout: u64,
+    pub client_cert_header: Option<String>,
+    // Domains of the services with timing_header, answered with X-Response-Time.
+    pub timing_domains: HashSet<String>,
+    // Domains of the services with upstream_header, answered with the
+    // X-Upstream header naming the backend.
+    pub upstream_domains: HashSet<String>,
+    // Domain -> name of its service, for the alerts.
+    pub services: HashMap<String, String>,
+    // Alias -> domain of its service, whose routes serve it.
+    pub aliases: HashMap<String, String>,
+    pub unknown_host: UnknownHost,
+    pub normalize_path: NormalizePath,
+    // Domain -> methods allowed by its service, the others get a 405. The
+    // domains without a list allow all the methods but CONNECT.
+    pub allowed_methods: HashMap<String, Vec<String>>,
+    // Domains of the services with deny_trace = false, whose TRACE and
+    // TRACK requests reach the backends.
+    pub trace_domains: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct TlsRedirection {
+    pub authority: String, // With the https port when it's not 443.
+    pub code: u16,
+    pub exclude_paths: Vec<String>, // Prefixes still served over http.
+}
+
+// Answer to the requests without a Host, or with a Host which matches no
+// service of the server.
+#[derive(Debug, Clone, PartialEq, Default, Encode, Decode)]
+pub enum UnknownHost {
+    Reject, // The connection is closed without a response.
+    #[default]
+    BadRequest,
+    Service(String), // Domain of the service routing them.
 }
+
+impl UnknownHost {
+    // "reject", "400" or the name of a service of the server.
+    fn parse(value: &str, services: &HashMap<String, String>) -> Result<UnknownHost, String> {
+        match value {
+            "reject" => Ok(UnknownHost::Reject),
+            "400" => Ok(UnknownHost::BadRequest),
+            name => services
+                .iter()
+                .find(|(_, service)| *service == name)
+                .map(|(domain, _)| UnknownHost::Service(domain.clone()))
+                .ok_or_else(|| {
+                    format!(
+                        "unknown_host must be \"reject\", \"400\" or the name of a service of the server, got \"{name}\""
+                    )
+                }),
+        }
+    }
+}
+
+impl std::fmt::Display for UnknownHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownHost::Reject => write!(f, "reject"),
+            UnknownHost::BadRequest => write!(f, "400"),
+            UnknownHost::Service(domain) => write!(f, "service of {domain}"),
+        }
+    }
+}
+
+// Handling of the request paths with duplicate slashes, e.g. "/app//x".
+#[derive(Debug, Clone, Copy, PartialEq, Default, Encode, Decode)]
+pub enum NormalizePath {
+    #[default]
+    Rewrite, // Routed and forwarded as "/app/x".
+    Redirect, // Redirected to "/app/x" with a 301.
+    Off,
+}
+
+impl std::fmt::Display for NormalizePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalizePath::Rewrite => write!(f, "rewrite"),
+            NormalizePath::Redirect => write!(f, "redirect"),
+            NormalizePath::Off => write!(f, "off"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Encode, Decode)]
 pub struct TlsCertificate {
     pub cert: String,
     pub key: String,
+    pub key_passphrase_file: Option<String>, // For encrypted keys.
+}
+
+// Settings of the rustls server config of a https port.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct TlsOptions {
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    pub cipher_suites: Vec<String>, // Empty to use the provider defaults.
+    pub kx_groups: Vec<String>,     // Empty to use the provider defaults.
+    pub alpn: Vec<String>,
+    pub session_tickets: bool,
+    pub ticket_rotation: u32,
+    pub session_cache_size: usize, // 0 to disable stateful resumption.
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        TlsOptions {
+            min_version: TlsVersion::Tls12,
+            max_version: TlsVersion::Tls13,
+            cipher_suites: Vec::new(),
+            kx_groups: Vec::new(),
+            alpn: SUPPORTED_ALPN.iter().map(|p| p.to_string()).collect(),
+            session_tickets: DEFAULT_SESSION_TICKETS,
+            ticket_rotation: DEFAULT_TICKET_ROTATION,
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+        }
+    }
+}
+
+// Options of the TCP sockets of a server, the OS defaults are kept when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Encode, Decode)]
+pub struct TcpOptions {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<TcpKeepalive>,
+    pub defer_accept: Option<u64>, // Seconds, Linux only.
+}
+
+// Kernel keepalive probes of the accepted connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Encode, Decode)]
+pub struct TcpKeepalive {
+    pub time: Option<u64>,     // Seconds of idle before the first probe.
+    pub interval: Option<u64>, // Seconds between the probes.
+    pub retries: Option<u32>,  // Probes before the connection is dropped.
+}
+
+fn or_default<T: std::fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "default".to_string(),
+    }
+}
+
+impl std::fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tcp_nodelay {}, tcp_keepalive {}, tcp_defer_accept {}",
+            or_default(self.nodelay),
+            or_default(self.keepalive.map(|keepalive| format!("({keepalive})"))),
+            or_default(self.defer_accept)
+        )
+    }
+}
+
+impl std::fmt::Display for TcpKeepalive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "time {}, interval {}, retries {}",
+            or_default(self.time),
+            or_default(self.interval),
+            or_default(self.retries)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn parse(version: &str) -> Result<TlsVersion, String> {
+        match version {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!(
+                "unsupported TLS version \"{version}\" (supported: \"1.2\", \"1.3\")"
+            )),
+        }
+    }
+}
+
+// Client certificate authentication (mTLS).
+// Rustls configures it per listener, so it applies to the whole https port.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct ClientAuth {
+    pub ca: String,
+    pub mode: ClientAuthMode,
+    pub header: String, // Forward the client certificate subject to the backend.
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum ClientAuthMode {
+    Require,
+    Optional,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -109,20 +484,174 @@ pub struct Locations {
     pub params: TargetParams<Vec<String>>,
     pub algo: Option<String>,
     pub weights: Option<Vec<u32>>,
+    pub ewma_decay: f64, // Weight of a new response time, for least_time.
+    pub outlier_detection: Option<OutlierDetection>,
+    pub proxy_timeout: Option<u64>, // Overrides the timeout of the server.
+    pub connect_timeout: Option<u64>, // Same.
+    pub upstream_idle_timeout: Option<u64>, // Same.
+    pub dns: Option<DnsBackends>,   // The backends are resolved at runtime.
+    pub backend_options: Vec<BackendOptions>, // Empty if the backends are plain urls.
+    pub cache: Option<CacheConfig>,
+    pub upstream_http2: UpstreamHttp2,
+    pub request_buffering: Option<RequestBuffering>,
+    pub max_concurrent: Option<usize>, // Requests in progress.
+    pub forwarded_headers: ForwardedHeaders, // Set by the service.
+}
+
+// X-Forwarded-* headers added to the requests sent to the backends.
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub struct ForwardedHeaders {
+    pub r#for: bool,
+    pub host: bool,
+    pub proto: bool,
+    pub port: bool,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> Self {
+        ForwardedHeaders {
+            r#for: true,
+            host: true,
+            proto: true,
+            port: true,
+        }
+    }
+}
+
+// Request bodies read before the backend is requested.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct RequestBuffering {
+    pub max_body: u64, // Bytes.
+    // The larger bodies are streamed, otherwise answered with a 413.
+    pub stream_over_max: bool,
+    pub timeout: u64, // To read the body, in seconds.
+}
+
+// HTTP version spoken to the backends of a location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Encode, Decode)]
+pub enum UpstreamHttp2 {
+    Auto,   // h2 when a TLS backend selects it with ALPN, HTTP/1.1 otherwise.
+    Always, // h2 only, with prior knowledge on cleartext.
+    #[default]
+    Never,
+}
+
+impl std::fmt::Display for UpstreamHttp2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamHttp2::Auto => write!(f, "auto"),
+            UpstreamHttp2::Always => write!(f, "always"),
+            UpstreamHttp2::Never => write!(f, "never"),
+        }
+    }
+}
+
+// Micro-cache of the complete responses of a location.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct CacheConfig {
+    pub ttl: u64,             // Seconds.
+    pub max_size: u64,        // Bytes, of all the responses of the location.
+    pub max_entry_size: u64,  // Bytes, the larger responses aren't stored.
+    pub methods: Vec<String>, // GET and HEAD only.
+}
+
+// Options of a backend defined as a table, in the order of the backends.
+#[derive(Debug, Clone, Default, PartialEq, Encode, Decode)]
+pub struct BackendOptions {
+    pub max_conns: Option<usize>, // Simultaneous requests.
+    pub backup: bool,
+}
+
+// Error ratios of the backends of a load balancer, compared every interval.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct OutlierDetection {
+    pub interval: u64, // Seconds.
+    // Ejected over the error percentage of the pool plus this.
+    pub error_percent: u32,
+    pub min_requests: u64,  // Of a backend in an interval to be judged.
+    pub ejection_time: u64, // Seconds, multiplied by the ejections in a row.
+    pub max_ejection_time: u64,
+    pub max_ejection_percent: u32, // Of the backends, ejected at once.
+}
+
+// Backends of a location resolved from a DNS name, refreshed in the background.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct DnsBackends {
+    pub name: String,
+    pub port: Option<u16>,
+    pub refresh: u64, // In seconds.
+    pub target: String,
+    pub var: String, // Replaced by each address in the target.
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct FileServer {
+    pub id: u32, // Shared by the routes of its authorized_dirs.
     pub params: TargetParams<String>,
     pub fallback_file: Option<String>, // for 404 or spa page.
     pub is_fallback_404: bool,         // for 404 http status.
     pub forbidden_dir: bool,
+    pub canonical_index_redirect: bool,
+    pub max_concurrent: Option<usize>, // Requests in progress.
+    // Globs of the entry names hidden from the directory listings, still
+    // served when requested.
+    pub listing_hide: GlobSet,
+}
+
+// Globs compiled with the config, and again when the server process
+// receives it: a listing only matches the names against them.
+#[derive(Debug, Clone, Default)]
+pub struct GlobSet(Arc<[glob::Pattern]>);
+
+impl GlobSet {
+    pub fn new(patterns: &[String]) -> Result<GlobSet, (&str, glob::PatternError)> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).map_err(|e| (pattern.as_str(), e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(GlobSet(patterns.into()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.iter().any(|pattern| pattern.matches(name))
+    }
+
+    pub fn patterns(&self) -> Vec<&str> {
+        self.0.iter().map(glob::Pattern::as_str).collect()
+    }
+}
+
+impl Encode for GlobSet {
+    fn encode<E: bincode::enc::Encoder>(
+        &self,
+        encoder: &mut E,
+    ) -> Result<(), bincode::error::EncodeError> {
+        self.patterns().encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for GlobSet {
+    fn decode<D: bincode::de::Decoder<Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let patterns: Vec<String> = Decode::decode(decoder)?;
+        GlobSet::new(&patterns).map_err(|(pattern, e)| {
+            bincode::error::DecodeError::OtherString(format!("{pattern:?}, {e}"))
+        })
+    }
 }
 
+bincode::impl_borrow_decode!(GlobSet);
+
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct Redirection {
     pub params: TargetParams<String>,
     pub code: u16,
+    pub preserve_query: bool, // Append the query string of the request to the target.
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -150,17 +679,65 @@ pub struct Options {
     #[argh(option, short = 'c', default = "DEFAULT_CONFIG_FILE_PATH.to_string()")]
     pub config: String,
     /// logs directory path
-    #[argh(option, short = 'l', default = "DEFAULT_LOG_PATH.to_string()")]
-    pub logs: String,
+    #[argh(option, short = 'l')]
+    pub logs: Option<String>,
+
+    /// log level: error, warn, info, debug or trace
+    #[argh(option)]
+    pub log_level: Option<String>,
+
+    /// validate the configuration and exit
+    #[argh(switch)]
+    pub check: bool,
+
+    /// print the resolved configuration and exit
+    #[argh(switch)]
+    pub print_config: bool,
+
+    /// path of the socket shared by the main and child processes
+    #[argh(option)]
+    pub socket_path: Option<String>,
+
+    /// maximum size of a message between the main and child processes, in MB
+    /// (default: 4, 16 times more for the certificates)
+    #[argh(option)]
+    pub ipc_max_message_size: Option<usize>,
+
+    /// user of the server process when started as root, overrides [global]
+    /// user (default: quark)
+    #[argh(option)]
+    pub user: Option<String>,
+
+    /// group of the server process when started as root, overrides [global]
+    /// group (default: quark)
+    #[argh(option)]
+    pub group: Option<String>,
 
     /// run as child process
     #[argh(switch)]
     _child_process: bool,
+
+    #[argh(subcommand)]
+    pub command: Option<crate::cli::Command>,
+}
+
+impl Options {
+    // Build the config file, the command line options take precedence.
+    pub fn build_config(&self) -> Result<InternalConfig, ConfigError> {
+        let mut internal_config = InternalConfig::build_from(self.config.clone())?;
+        if let Some(user) = &self.user {
+            internal_config.global.user = user.clone();
+        }
+        if let Some(group) = &self.group {
+            internal_config.global.group = group.clone();
+        }
+        Ok(internal_config)
+    }
 }
 
 impl InternalConfig {
-    pub fn build_from(path: String) -> InternalConfig {
-        let config = get_toml_config(path);
+    pub fn build_from(path: String) -> Result<InternalConfig, ConfigError> {
+        let config = get_toml_config(&path)?;
 
         // Check if the toml config has services.
         // If not, define the InternalConfig as empty
@@ -169,20 +746,101 @@ impl InternalConfig {
 
         let mut servers: HashMap<String, Server> = HashMap::new();
 
+        let global_tls = config.global.as_ref().and_then(|g| g.tls.as_ref());
+        let defaults = config.defaults.as_ref();
+        let default_proxy_timeout = defaults
+            .and_then(|d| d.proxy_timeout)
+            .unwrap_or(DEFAULT_PROXY_TIMEOUT);
+        let default_upstream_idle_timeout = defaults
+            .and_then(|d| d.upstream_idle_timeout)
+            .unwrap_or(DEFAULT_UPSTREAM_IDLE_TIMEOUT);
+        let default_connect_timeout = defaults
+            .and_then(|d| d.connect_timeout)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        if default_connect_timeout == 0 {
+            return Err(ConfigError::invalid(
+                &path,
+                "Invalid [defaults]: connect_timeout must be at least 1",
+            ));
+        }
+        let default_tls_options = build_tls_options(global_tls, None).map_err(|e| {
+            ConfigError::invalid(
+                &path,
+                format!("Invalid TLS configuration in [global.tls]: {e}"),
+            )
+        })?;
+        let default_tcp_options = build_tcp_options(config.global.as_ref(), None)
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [global]: {e}")))?;
+        let default_deny_trace = config
+            .global
+            .as_ref()
+            .and_then(|g| g.deny_trace)
+            .unwrap_or(DEFAULT_DENY_TRACE);
+
         // Declare all servers defined in the config.
         if let Some(server_map) = &config.servers {
             for (name, server) in server_map {
+                if server.connect_timeout == Some(0) {
+                    return Err(ConfigError::invalid(
+                        &path,
+                        format!("Invalid [servers.{name}]: connect_timeout must be at least 1"),
+                    ));
+                }
                 let port = server.port.unwrap_or(DEFAULT_PORT);
                 let https_port = server.https_port.unwrap_or(DEFAULT_PORT_HTTPS);
+                let mut tls_options =
+                    build_tls_options(global_tls, server.tls.as_ref()).map_err(|e| {
+                        ConfigError::invalid(
+                            &path,
+                            format!("Invalid TLS configuration in [servers.{name}.tls]: {e}"),
+                        )
+                    })?;
+                if let Some(alpn) = &server.alpn {
+                    tls_options.alpn = check_alpn(alpn).map_err(|e| {
+                        ConfigError::invalid(
+                            &path,
+                            format!("Invalid alpn in [servers.{name}]: {e}"),
+                        )
+                    })?;
+                }
+                let tcp_options =
+                    build_tcp_options(config.global.as_ref(), Some(server)).map_err(|e| {
+                        ConfigError::invalid(&path, format!("Invalid [servers.{name}]: {e}"))
+                    })?;
                 let server = Server {
                     params: ServerParams {
                         routes: HashMap::new(),
-                        auto_tls: None,
-                        proxy_timeout: server.proxy_timeout.unwrap_or(DEFAULT_PROXY_TIMEOUT),
+                        auto_tls: HashMap::new(),
+                        proxy_timeout: server.proxy_timeout.unwrap_or(default_proxy_timeout),
+                        upstream_idle_timeout: server
+                            .upstream_idle_timeout
+                            .unwrap_or(default_upstream_idle_timeout),
+                        connect_timeout: server.connect_timeout.unwrap_or(default_connect_timeout),
+                        client_cert_header: None,
+                        timing_domains: HashSet::new(),
+                        upstream_domains: HashSet::new(),
+                        services: HashMap::new(),
+                        aliases: HashMap::new(),
+                        unknown_host: UnknownHost::default(),
+                        normalize_path: match server.normalize_path {
+                            Some(toml_model::NormalizePath::Redirect) => NormalizePath::Redirect,
+                            Some(toml_model::NormalizePath::Off) => NormalizePath::Off,
+                            _ => NormalizePath::Rewrite,
+                        },
+                        allowed_methods: HashMap::new(),
+                        trace_domains: HashSet::new(),
                     },
                     port,
                     https_port,
                     tls: None,
+                    tls_options,
+                    client_auth: None,
+                    default_cert: None,
+                    http2: server.http2.unwrap_or(DEFAULT_HTTP2),
+                    tls_handshake_timeout: server.tls_handshake_timeout,
+                    tcp_options,
+                    tls_aliases: Vec::new(),
+                    cert_services: HashMap::new(),
                 };
                 servers.insert(name.clone(), server);
             }
@@ -193,41 +851,145 @@ impl InternalConfig {
             let server = Server {
                 params: ServerParams {
                     routes: HashMap::new(),
-                    auto_tls: None,
-                    proxy_timeout: DEFAULT_PROXY_TIMEOUT,
+                    auto_tls: HashMap::new(),
+                    proxy_timeout: default_proxy_timeout,
+                    upstream_idle_timeout: default_upstream_idle_timeout,
+                    connect_timeout: default_connect_timeout,
+                    client_cert_header: None,
+                    timing_domains: HashSet::new(),
+                    upstream_domains: HashSet::new(),
+                    services: HashMap::new(),
+                    aliases: HashMap::new(),
+                    unknown_host: UnknownHost::default(),
+                    normalize_path: NormalizePath::default(),
+                    allowed_methods: HashMap::new(),
+                    trace_domains: HashSet::new(),
                 },
                 port: DEFAULT_PORT,
                 https_port: DEFAULT_PORT_HTTPS,
                 tls: None,
+                tls_options: default_tls_options,
+                client_auth: None,
+                default_cert: None,
+                http2: DEFAULT_HTTP2,
+                tls_handshake_timeout: None,
+                tcp_options: default_tcp_options,
+                tls_aliases: Vec::new(),
+                cert_services: HashMap::new(),
             };
             servers.insert(MAIN_SERVER_NAME.to_string(), server);
         }
 
+        // Client auth defined by the first TLS service of each server.
+        let mut servers_client_auth: HashMap<String, Option<ClientAuth>> = HashMap::new();
+        let mut servers_route_owners: HashMap<String, RouteOwners> = HashMap::new();
+        // (Server, domain, port, tls) of the www redirections.
+        let mut www_redirections = Vec::new();
+
         let services = config.services.unwrap_or_default();
-        for service in services.values() {
+        for (service_name, service) in services.iter() {
             // if service has TLS configuration, create a server for https.
 
-            let mut tls_redirection = false;
+            // (Code, excluded paths) of the redirection to https.
+            let mut tls_redirection = None;
             let server_name = service.server.as_deref().unwrap_or(MAIN_SERVER_NAME);
+            if service.connect_timeout == Some(0) {
+                return Err(ConfigError::invalid(
+                    &path,
+                    format!(
+                        "Invalid [services.{service_name}]: connect_timeout must be at least 1"
+                    ),
+                ));
+            }
 
-            let server = servers.get_mut(server_name).unwrap();
+            // A disabled service is built against a copy of its server, so the
+            // block is still validated but nothing is registered.
+            let enabled = service.enabled.unwrap_or(DEFAULT_ENABLED);
+            let mut disabled_server;
+            let server = if enabled {
+                servers.get_mut(server_name).unwrap()
+            } else {
+                println!("Service {service_name} disabled by config");
+                disabled_server = servers[server_name].clone();
+                &mut disabled_server
+            };
 
             let port = server.port;
             let https_port = server.https_port;
 
-            if let Some(tls) = &service.tls {
-                let tls_cert = TlsCertificate {
-                    cert: tls.certificate.clone(),
-                    key: tls.key.clone(),
-                };
-                server.tls.get_or_insert_with(Vec::new);
-                if let Some(tls) = &mut server.tls {
-                    if !tls.contains(&tls_cert) {
+            // With tls_optional, a service whose certificates can't be used
+            // is served over HTTP only instead of stopping the startup.
+            let mut service_tls = service.tls.as_ref();
+            if enabled && service.tls_optional.unwrap_or(DEFAULT_TLS_OPTIONAL) {
+                if let Some(e) = service_tls.and_then(unusable_certificate) {
+                    eprintln!(
+                        "Warning: TLS of [services.{service_name}] skipped, the service is served over HTTP only. {e}"
+                    );
+                    service_tls = None;
+                }
+            }
+
+            if let Some(tls) = service_tls {
+                let tls_certs = get_service_certificates(tls).map_err(|e| {
+                    ConfigError::invalid(
+                        &path,
+                        format!("Invalid TLS configuration in [services.{service_name}.tls]: {e}"),
+                    )
+                })?;
+                let server_tls = server.tls.get_or_insert_with(Vec::new);
+                for tls_cert in tls_certs {
+                    let services = server
+                        .cert_services
+                        .entry(tls_cert.cert.clone())
+                        .or_default();
+                    if !services.contains(service_name) {
+                        services.push(service_name.clone());
+                    }
+                    if !server_tls.contains(&tls_cert) {
                         // Add the certificate to the list.
-                        tls.push(tls_cert);
+                        server_tls.push(tls_cert);
+                    }
+                }
+                tls_redirection = build_tls_redirection(tls.redirection.as_ref()).map_err(|e| {
+                    ConfigError::invalid(
+                        &path,
+                        format!("Invalid tls.redirection in [services.{service_name}]: {e}"),
+                    )
+                })?;
+
+                // All the TLS services of a server share the same listener,
+                // so they must agree on the client authentication.
+                let client_auth = tls
+                    .client_auth
+                    .as_ref()
+                    .map(build_client_auth)
+                    .transpose()
+                    .map_err(|e| {
+                        ConfigError::invalid(
+                            &path,
+                            format!("Invalid tls.client_auth in [services.{service_name}]: {e}"),
+                        )
+                    })?;
+                match servers_client_auth.get(server_name) {
+                    _ if !enabled => (),
+                    Some(defined) if defined != &client_auth => {
+                        return Err(ConfigError::invalid(
+                            &path,
+                            format!(
+                                "Conflicting tls.client_auth for the services of the server \"{server_name}\".\n\
+                                Client authentication applies to the whole https port, \
+                                all TLS services of a server must use the same configuration."
+                            ),
+                        ));
+                    }
+                    Some(_) => (),
+                    None => {
+                        server.params.client_cert_header =
+                            client_auth.as_ref().map(|ca| ca.header.clone());
+                        server.client_auth = client_auth.clone();
+                        servers_client_auth.insert(server_name.to_string(), client_auth);
                     }
                 }
-                tls_redirection = tls.redirection.unwrap_or(DEFAULT_TLS_REDIRECTION);
             }
 
             let server_headers = config
@@ -236,42 +998,302 @@ impl InternalConfig {
                 .and_then(|servers| servers.get(server_name))
                 .and_then(|server| server.headers.as_ref());
 
-            manage_server_targets(server, service, &config.loadbalancers, server_headers);
-            www_auto_redirection(
-                &mut server.params.routes,
-                &service.domain,
-                if service.tls.is_some() {
-                    https_port
-                } else {
-                    port
-                },
-                service.tls.is_some() && tls_redirection,
-            );
+            let mut disabled_owners;
+            let route_owners = if enabled {
+                servers_route_owners
+                    .entry(server_name.to_string())
+                    .or_default()
+            } else {
+                disabled_owners = RouteOwners::new();
+                &mut disabled_owners
+            };
+            manage_server_targets(
+                server,
+                (service_name, service),
+                route_owners,
+                &config.loadbalancers,
+                [defaults.and_then(|d| d.headers.as_ref()), server_headers],
+            )
+            .map_err(|e| ConfigError::invalid(&path, e))?;
+            if service.timing_header.unwrap_or(DEFAULT_TIMING_HEADER) {
+                server.params.timing_domains.insert(service.domain.clone());
+            }
+            if service.upstream_header.unwrap_or(DEFAULT_UPSTREAM_HEADER) {
+                server
+                    .params
+                    .upstream_domains
+                    .insert(service.domain.clone());
+            }
+            let deny_trace = service.deny_trace.unwrap_or(default_deny_trace);
+            let allowed_methods = service
+                .allowed_methods
+                .as_deref()
+                .map(|names| build_allowed_methods(names, deny_trace))
+                .transpose()
+                .map_err(|e| {
+                    ConfigError::invalid(
+                        &path,
+                        format!("Invalid allowed_methods in [services.{service_name}]: {e}"),
+                    )
+                })?;
+            // Checked before routing, so the services sharing a domain must
+            // agree on them.
+            let shared_domain = server.params.services.contains_key(&service.domain);
+            if shared_domain
+                && server.params.allowed_methods.get(&service.domain) != allowed_methods.as_ref()
+            {
+                return Err(ConfigError::invalid(
+                    &path,
+                    format!(
+                        "Conflicting allowed_methods for {} in [services.{service_name}]",
+                        service.domain
+                    ),
+                ));
+            }
+            if shared_domain && server.params.trace_domains.contains(&service.domain) == deny_trace
+            {
+                return Err(ConfigError::invalid(
+                    &path,
+                    format!(
+                        "Conflicting deny_trace for {} in [services.{service_name}]",
+                        service.domain
+                    ),
+                ));
+            }
+            if let Some(methods) = allowed_methods {
+                server
+                    .params
+                    .allowed_methods
+                    .insert(service.domain.clone(), methods);
+            }
+            if !deny_trace {
+                server.params.trace_domains.insert(service.domain.clone());
+            }
+            server
+                .params
+                .services
+                .insert(service.domain.clone(), service_name.clone());
+            // The aliases share the routes of the domain, the targets and
+            // their ids are the same.
+            let aliases = service.aliases.as_deref().unwrap_or_default();
+            for alias in aliases {
+                match server.params.aliases.get(alias) {
+                    _ if *alias == service.domain => {
+                        return Err(ConfigError::invalid(
+                            &path,
+                            format!("Invalid aliases in [services.{service_name}]: {alias} is the domain of the service"),
+                        ));
+                    }
+                    Some(domain) if *domain != service.domain => {
+                        return Err(ConfigError::invalid(
+                            &path,
+                            format!("Conflicting alias {alias} in [services.{service_name}]: already an alias of {domain}"),
+                        ));
+                    }
+                    _ => {
+                        server
+                            .params
+                            .aliases
+                            .insert(alias.clone(), service.domain.clone());
+                    }
+                }
+                if service_tls.is_some() && enabled {
+                    server.tls_aliases.push(TlsAlias {
+                        domain: alias.clone(),
+                        service: service_name.clone(),
+                    });
+                }
+            }
+            let domains = std::iter::once(&service.domain).chain(aliases);
+            if enabled && service.www_redirect.unwrap_or(DEFAULT_WWW_REDIRECT) {
+                for domain in domains.clone() {
+                    www_redirections.push((
+                        server_name,
+                        domain.as_str(),
+                        if service_tls.is_some() {
+                            https_port
+                        } else {
+                            port
+                        },
+                        tls_redirection.is_some(),
+                    ));
+                }
+            }
 
             // Define if a tls redirection should be done.
-            if tls_redirection {
-                let domain = service.domain.clone();
-                let tls_port = https_port;
-                let tls_domain = if tls_port != DEFAULT_PORT_HTTPS {
-                    format!("{domain}:{tls_port}")
-                } else {
-                    domain
-                };
+            if let Some((code, exclude_paths)) = tls_redirection {
+                for domain in domains {
+                    let redirection = TlsRedirection {
+                        authority: if https_port != DEFAULT_PORT_HTTPS {
+                            format!("{domain}:{https_port}")
+                        } else {
+                            domain.clone()
+                        },
+                        code,
+                        exclude_paths: exclude_paths.clone(),
+                    };
+                    // The services sharing a domain must agree on it.
+                    match server.params.auto_tls.get(domain) {
+                        Some(existing) if *existing != redirection => {
+                            return Err(ConfigError::invalid(
+                                &path,
+                                format!("Conflicting tls.redirection for {domain} in [services.{service_name}]"),
+                            ));
+                        }
+                        Some(_) => (),
+                        None => {
+                            server.params.auto_tls.insert(domain.clone(), redirection);
+                        }
+                    }
+                }
+            }
 
-                server
-                    .params
-                    .auto_tls
-                    .get_or_insert_with(Vec::new)
-                    .push(tls_domain);
+            // Sort the routes of each domain by path length, so that the first
+            // matching one is the longest.
+            for routes in server.params.routes.values_mut() {
+                routes.sort_by(|a, b| {
+                    b.path
+                        .len()
+                        .cmp(&a.path.len())
+                        .then_with(|| a.kind.cmp(&b.kind))
+                });
+            }
+        }
+
+        // An alias can't be the domain of another service of its server.
+        for server in servers.values() {
+            let mut aliases: Vec<(&String, &String)> = server.params.aliases.iter().collect();
+            aliases.sort();
+            for (alias, domain) in aliases {
+                if let Some(other) = server.params.services.get(alias) {
+                    return Err(ConfigError::invalid(
+                        &path,
+                        format!(
+                            "Conflicting alias {alias} in [services.{}]: it is the domain of [services.{other}]",
+                            server.params.services[domain]
+                        ),
+                    ));
+                }
             }
+        }
 
-            // Sort the routes by path length.
-            for route in server.params.routes.values_mut() {
-                route.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        // Added once the routes of all the services are known, since the
+        // www counterpart of a domain can be a service too.
+        for (server_name, domain, port, tls) in www_redirections {
+            let server = servers.get_mut(server_name).unwrap();
+            www_auto_redirection(&mut server.params, domain, port, tls);
+        }
+
+        // Define the answer to the unknown hosts, and the certificate to use
+        // when the SNI doesn't match.
+        for (name, server) in servers.iter_mut() {
+            let server_config = config.servers.as_ref().and_then(|s| s.get(name));
+            if let Some(unknown_host) = server_config.and_then(|s| s.unknown_host.as_deref()) {
+                server.params.unknown_host =
+                    UnknownHost::parse(unknown_host, &server.params.services).map_err(|e| {
+                        ConfigError::invalid(&path, format!("Invalid [servers.{name}]: {e}"))
+                    })?;
+            }
+            let strict_sni = server_config
+                .and_then(|s| s.strict_sni)
+                .unwrap_or(DEFAULT_STRICT_SNI);
+            // Without services, the welcome page is served over HTTPS too
+            // with the default certificate of the main server.
+            if let (true, MAIN_SERVER_NAME, Some(default)) = (
+                empty,
+                name.as_str(),
+                server_config.and_then(|s| s.default_certificate.as_ref()),
+            ) {
+                server.tls = Some(vec![TlsCertificate {
+                    cert: default.cert.clone(),
+                    key: default.key.clone(),
+                    key_passphrase_file: default.key_passphrase_file.clone(),
+                }]);
             }
+            if let (Some(tls), false) = (&mut server.tls, strict_sni) {
+                server.default_cert =
+                    match server_config.and_then(|s| s.default_certificate.as_ref()) {
+                        Some(default) => match tls
+                            .iter()
+                            .find(|c| c.cert == default.cert && c.key == default.key)
+                        {
+                            Some(cert) => Some(cert.clone()),
+                            None => {
+                                let default = TlsCertificate {
+                                    cert: default.cert.clone(),
+                                    key: default.key.clone(),
+                                    key_passphrase_file: default.key_passphrase_file.clone(),
+                                };
+                                tls.push(default.clone());
+                                Some(default)
+                            }
+                        },
+                        // Fallback to the first configured certificate.
+                        None => tls.first().cloned(),
+                    };
+            }
+        }
+
+        // The welcome server only listens on the ports of the main server.
+        if !empty {
+            check_listener_ports(&servers).map_err(|e| ConfigError::invalid(&path, e))?;
+        }
+
+        let logs_config = config.logs.as_ref();
+        let level = logs_config.and_then(|l| l.level.clone());
+        if let Some(level) = &level {
+            logs::check_level(level)
+                .map_err(|e| ConfigError::invalid(&path, format!("Invalid [logs]: {e}")))?;
         }
+        let access_log_format = logs_config
+            .and_then(|l| l.access_log_format.as_deref())
+            .map(AccessLogFormat::parse)
+            .transpose()
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [logs]: {e}")))?
+            .unwrap_or(DEFAULT_ACCESS_LOG_FORMAT);
+        let access_log_sampling = match logs_config {
+            Some(logs) => AccessLogSampling::new(
+                logs.access_log_sample
+                    .unwrap_or(AccessLogSampling::ALL.rate),
+                logs.access_log_sample_not_found
+                    .unwrap_or(AccessLogSampling::ALL.sample_not_found),
+            )
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [logs]: {e}")))?,
+            None => AccessLogSampling::ALL,
+        };
+        let rotation = build_log_rotation(logs_config)
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [logs]: {e}")))?;
+        let outputs = build_log_outputs(logs_config)
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [logs]: {e}")))?;
+        let logs = LogsConfig {
+            path: logs_config.and_then(|l| l.path.clone()),
+            level,
+            stdout: logs_config
+                .and_then(|l| l.stdout)
+                .unwrap_or(DEFAULT_LOG_STDOUT),
+            access_log: logs_config
+                .and_then(|l| l.access_log)
+                .unwrap_or(DEFAULT_ACCESS_LOG),
+            access_log_path: logs_config.and_then(|l| l.access_log_path.clone()),
+            access_log_format,
+            access_log_sampling,
+            file: logs_config.and_then(|l| l.file.clone()),
+            rotation,
+            outputs,
+            error_log: logs_config.and_then(|l| l.error_log.clone()),
+        };
 
         let global_config = config.global.as_ref();
+        let accept_loops = match global_config.and_then(|g| g.accept_loops) {
+            Some(0) => {
+                return Err(ConfigError::invalid(
+                    &path,
+                    "Invalid [global]: accept_loops must be at least 1",
+                ))
+            }
+            Some(accept_loops) => accept_loops.min(utils::available_cores()),
+            None => DEFAULT_ACCEPT_LOOPS,
+        };
         let global = Global {
             backlog: global_config
                 .and_then(|g| g.backlog)
@@ -282,6 +1304,16 @@ impl InternalConfig {
             max_req: global_config
                 .and_then(|g| g.max_requests)
                 .unwrap_or(DEFAULT_MAX_REQUESTS),
+            queue_timeout: global_config
+                .and_then(|g| g.queue_timeout)
+                .unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+            max_conn_retry_after: global_config
+                .and_then(|g| g.max_connections_retry_after)
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS_RETRY_AFTER),
+            close_over_max_conn: matches!(
+                global_config.and_then(|g| g.over_max_connections.as_ref()),
+                Some(toml_model::OverMaxConnections::Close)
+            ),
             keepalive: global_config
                 .and_then(|g| g.keepalive)
                 .unwrap_or(DEFAULT_KEEPALIVE),
@@ -303,180 +1335,914 @@ impl InternalConfig {
             idle_check_interval: global_config
                 .and_then(|g| g.idle_check_interval)
                 .unwrap_or(DEFAULT_IDLE_CHECK_INTERVAL),
+            max_connection_lifetime: global_config
+                .and_then(|g| g.max_connection_lifetime)
+                .unwrap_or(DEFAULT_MAX_CONNECTION_LIFETIME),
             tls_proxy_verify: global_config
                 .and_then(|g| g.tls_proxy_verify)
                 .unwrap_or(DEFAULT_TLS_PROXY_VERIFY),
             max_conn_per_ip: global_config.and_then(|g| g.max_conn_per_ip),
+            cert_expiry_warning: global_config
+                .and_then(|g| g.cert_expiry_warning)
+                .unwrap_or(DEFAULT_CERT_EXPIRY_WARNING),
+            connection_stats_interval: global_config
+                .and_then(|g| g.connection_stats_interval)
+                .unwrap_or(DEFAULT_CONNECTION_STATS_INTERVAL),
+            user: global_config
+                .and_then(|g| g.user.clone())
+                .unwrap_or(utils::QUARK_USER_AND_GROUP.to_string()),
+            group: global_config
+                .and_then(|g| g.group.clone())
+                .unwrap_or(utils::QUARK_USER_AND_GROUP.to_string()),
+            orphan_shutdown: global_config
+                .and_then(|g| g.orphan_shutdown)
+                .unwrap_or(DEFAULT_ORPHAN_SHUTDOWN),
+            accept_loops,
         };
 
-        InternalConfig {
+        let alerts = config
+            .alerts
+            .as_ref()
+            .map(build_alerts)
+            .transpose()
+            .map_err(|e| ConfigError::invalid(&path, format!("Invalid [alerts]: {e}")))?;
+
+        Ok(InternalConfig {
             servers,
             global,
+            logs,
+            alerts,
             empty,
+        })
+    }
+
+    // Settings that can't be changed by a reload, one line per server.
+    pub fn restart_settings(&self) -> Vec<String> {
+        // The privileges of the server are dropped once, and the listening
+        // sockets are only bound at startup.
+        let global = format!(
+            "[global] user {}, group {}, accept_loops {}",
+            self.global.user, self.global.group, self.global.accept_loops
+        );
+        if self.empty {
+            // The welcome server listens on the ports of the main server.
+            let main = &self.servers[MAIN_SERVER_NAME];
+            let https = match &main.tls {
+                Some(_) => format!("https_port {}", main.https_port),
+                None => "no tls".to_string(),
+            };
+            let welcome = format!("no services (welcome server), port {}, {https}", main.port);
+            return vec![welcome, global];
         }
+        let mut settings: Vec<String> = self
+            .servers
+            .iter()
+            .map(|(name, server)| {
+                let https = match &server.tls {
+                    Some(_) => format!(
+                        "https_port {}, h2 {}",
+                        server.https_port,
+                        server.tls_options.alpn.iter().any(|p| p == "h2")
+                    ),
+                    None => "no tls".to_string(),
+                };
+                // The TCP options are set on the sockets when the server starts.
+                format!(
+                    "[servers.{name}] port {}, http2 {}, {https}, {}",
+                    server.port, server.http2, server.tcp_options
+                )
+            })
+            .collect();
+        settings.sort();
+        settings.push(global);
+        settings
     }
-}
 
-fn get_toml_config(path: String) -> ConfigToml {
-    println!("Loading config from {path}");
-    let toml_str = fs::read_to_string(&path).unwrap_or_else(|e| {
-        eprintln!("Failed to open toml file. {path} \n{e}");
-        std::process::exit(1);
-    });
-    let mut config: ConfigToml = toml::from_str(&toml_str).unwrap_or_else(|e| {
-        eprintln!("Failed to parse toml file.\nInvalid configuration file.\n{e}");
-        std::process::exit(1);
-    });
-    // import subconfiguration.
-    if let Some(subconf) = &config.import {
-        let mut conf_path = PathBuf::from(path);
-        conf_path.pop();
-        for file in subconf.iter() {
-            let sub_config = import_sub_toml_config(file, conf_path.to_str().unwrap());
-            // insert the subconfig into the main config.
-            if let Some(services) = sub_config.services {
-                config
-                    .services
-                    .get_or_insert_with(HashMap::new)
-                    .extend(services);
+    // Cross-validation of the built config, used by --check.
+    // Return the problems found.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for (name, server) in self.servers.iter() {
+            for (domain, routes) in server.params.routes.iter() {
+                for route in routes {
+                    let TargetType::Location(location) = &route.target else {
+                        continue;
+                    };
+                    let source = format!("{domain}{} (server {name})", route.path);
+                    if location.params.location.is_empty() && location.dns.is_none() {
+                        errors.push(format!(
+                            "{source}: the target has no backend, check the loadbalancer name"
+                        ));
+                    }
+                    for backend in location.params.location.iter() {
+                        if let Err(e) = check_backend_url(backend) {
+                            errors.push(format!("{source}: invalid backend \"{backend}\": {e}"));
+                        }
+                    }
+                }
             }
-            if let Some(loadbalancers) = sub_config.loadbalancer {
-                config
-                    .loadbalancers
-                    .get_or_insert_with(HashMap::new)
-                    .extend(loadbalancers);
+        }
+
+        errors
+    }
+}
+
+// Every listener binds all the addresses, so a port can only be claimed by a
+// single listener of a single server.
+fn check_listener_ports(servers: &HashMap<String, Server>) -> Result<(), String> {
+    let mut ports: HashMap<u16, String> = HashMap::new();
+    let mut names: Vec<&String> = servers.keys().collect();
+    names.sort();
+    for name in names {
+        let server = &servers[name];
+        let mut listeners = vec![(server.port, "port")];
+        if server.tls.is_some() {
+            listeners.push((server.https_port, "https_port"));
+        }
+        for (port, field) in listeners {
+            let listener = format!("{field} of [servers.{name}]");
+            if let Some(other) = ports.insert(port, listener.clone()) {
+                return Err(format!(
+                    "Port {port} is used by the {other} and the {listener}"
+                ));
             }
         }
     }
-    config
+    Ok(())
 }
 
-fn import_sub_toml_config(path: &str, dir: &str) -> SubConfigToml {
-    let file_path = Path::new(path);
-    let real_path = if file_path.is_relative() {
-        Path::new(dir).join(file_path)
-    } else {
-        PathBuf::from(path)
-    };
-    let real_path = real_path.to_str().unwrap();
-    let toml_str = fs::read_to_string(real_path).unwrap_or_else(|e| {
-        eprintln!("Failed to open toml file. {real_path} \n{e}");
-        std::process::exit(1);
-    });
-    let config: SubConfigToml = toml::from_str(&toml_str).unwrap_or_else(|e| {
-        eprintln!("Failed to parse toml file.\nInvalid configuration file.\n{e}");
-        std::process::exit(1);
-    });
-    config
+fn check_backend_url(url: &str) -> Result<(), String> {
+    let uri = url.parse::<hyper::Uri>().map_err(|e| e.to_string())?;
+    match uri.scheme_str() {
+        Some("http" | "https") => (),
+        _ => return Err("the scheme must be http or https".to_string()),
+    }
+    if uri.authority().is_none() {
+        return Err("missing host".to_string());
+    }
+    Ok(())
 }
 
-fn manage_server_targets(
-    server: &mut Server,
-    service: &toml_model::Service,
-    loadbalancers: &Option<HashMap<String, toml_model::Loadbalancer>>,
-    server_headers: Option<&Headers>,
-) {
-    // Manage headers
-    let (l_headers, fs_headers) = headers::get_config_headers_from(server_headers);
-    // Locations
-    if let Some(locations) = &service.locations {
-        // Manage locations.
-        for location in locations {
-            // Custom headers for this specific location.
-            let mut headers = l_headers.clone();
+// Get the certificates of a service. A service can have several
+// certificates for the same domains (ECDSA and RSA for example).
+fn get_service_certificates(tls: &toml_model::Tls) -> Result<Vec<TlsCertificate>, String> {
+    let mut certs = Vec::new();
+    match (&tls.certificate, &tls.key) {
+        (Some(cert), Some(key)) => certs.push(TlsCertificate {
+            cert: cert.clone(),
+            key: key.clone(),
+            key_passphrase_file: tls.key_passphrase_file.clone(),
+        }),
+        (None, None) => (),
+        _ => return Err("certificate and key must be defined together".to_string()),
+    }
+    for cert in tls.certificates.iter().flatten() {
+        certs.push(TlsCertificate {
+            cert: cert.certificate.clone(),
+            key: cert.key.clone(),
+            key_passphrase_file: cert
+                .key_passphrase_file
+                .clone()
+                .or(tls.key_passphrase_file.clone()),
+        });
+    }
+    if certs.is_empty() {
+        return Err("no certificate defined".to_string());
+    }
+    Ok(certs)
+}
 
-            headers::apply_header_actions(
-                service.headers.as_ref().and_then(|h| h.locations.as_ref()),
-                &mut headers,
-            );
-            headers::apply_header_actions(location.headers.as_ref(), &mut headers);
+// First error of the certificates of a service, e.g. a missing file.
+fn unusable_certificate(tls: &toml_model::Tls) -> Option<String> {
+    get_service_certificates(tls)
+        .ok()?
+        .iter()
+        .find_map(|cert| tls::IpcCerts::build_blocking(cert).err())
+}
 
-            // Remove last slash.
-            let (source, route_kind) = source_and_route_kind(&location.source);
-            // Get all backends info required for load balancing.
-            let (backends, algo, weight) = get_backends_config(&location.target, loadbalancers);
+fn check_alpn(alpn: &[String]) -> Result<Vec<String>, String> {
+    if alpn.is_empty() {
+        return Err("at least one protocol is required".to_string());
+    }
+    if let Some(protocol) = alpn.iter().find(|p| !SUPPORTED_ALPN.contains(&p.as_str())) {
+        return Err(format!(
+            "unsupported protocol \"{protocol}\" (supported: {})",
+            SUPPORTED_ALPN.join(", ")
+        ));
+    }
+    Ok(alpn.to_vec())
+}
+
+// Merge the server TLS options over the global ones.
+fn build_tls_options(
+    global: Option<&toml_model::TlsOptions>,
+    server: Option<&toml_model::TlsOptions>,
+) -> Result<TlsOptions, String> {
+    let mut options = TlsOptions::default();
+
+    let min_version = server
+        .and_then(|s| s.min_version.as_deref())
+        .or(global.and_then(|g| g.min_version.as_deref()));
+    if let Some(version) = min_version {
+        options.min_version = TlsVersion::parse(version)?;
+    }
+
+    let max_version = server
+        .and_then(|s| s.max_version.as_deref())
+        .or(global.and_then(|g| g.max_version.as_deref()));
+    if let Some(version) = max_version {
+        options.max_version = TlsVersion::parse(version)?;
+    }
+
+    if options.min_version > options.max_version {
+        return Err(format!(
+            "min_version \"{}\" is greater than max_version \"{}\"",
+            min_version.unwrap_or_default(),
+            max_version.unwrap_or_default()
+        ));
+    }
+
+    if let Some(cipher_suites) = server
+        .and_then(|s| s.cipher_suites.as_ref())
+        .or(global.and_then(|g| g.cipher_suites.as_ref()))
+    {
+        options.cipher_suites = cipher_suites.clone();
+    }
+
+    if let Some(kx_groups) = server
+        .and_then(|s| s.kx_groups.as_ref())
+        .or(global.and_then(|g| g.kx_groups.as_ref()))
+    {
+        options.kx_groups = kx_groups.clone();
+    }
+
+    if let Some(session_tickets) = server
+        .and_then(|s| s.session_tickets)
+        .or(global.and_then(|g| g.session_tickets))
+    {
+        options.session_tickets = session_tickets;
+    }
+
+    if let Some(ticket_rotation) = server
+        .and_then(|s| s.ticket_rotation)
+        .or(global.and_then(|g| g.ticket_rotation))
+    {
+        if ticket_rotation == 0 || ticket_rotation > DEFAULT_TICKET_ROTATION {
+            return Err(format!(
+                "ticket_rotation must be between 1 and {DEFAULT_TICKET_ROTATION} seconds"
+            ));
+        }
+        options.ticket_rotation = ticket_rotation;
+    }
+
+    if let Some(session_cache_size) = server
+        .and_then(|s| s.session_cache_size)
+        .or(global.and_then(|g| g.session_cache_size))
+    {
+        options.session_cache_size = session_cache_size;
+    }
+
+    // Check the names and that the suites can be used with the versions.
+    tls::server_config_builder(&options)?;
+
+    Ok(options)
+}
+
+// Merge the server TCP options over the global ones.
+fn build_tcp_options(
+    global: Option<&toml_model::Global>,
+    server: Option<&toml_model::Server>,
+) -> Result<TcpOptions, String> {
+    let keepalive = server
+        .and_then(|s| s.tcp_keepalive.as_ref())
+        .or(global.and_then(|g| g.tcp_keepalive.as_ref()));
+    let keepalive = keepalive.map(|keepalive| TcpKeepalive {
+        time: keepalive.time,
+        interval: keepalive.interval,
+        retries: keepalive.retries,
+    });
+    if let Some(keepalive) = &keepalive {
+        if keepalive.time == Some(0)
+            || keepalive.interval == Some(0)
+            || keepalive.retries == Some(0)
+        {
+            return Err(
+                "the time, interval and retries of tcp_keepalive must be at least 1".to_string(),
+            );
+        }
+    }
+    Ok(TcpOptions {
+        nodelay: server
+            .and_then(|s| s.tcp_nodelay)
+            .or(global.and_then(|g| g.tcp_nodelay)),
+        keepalive,
+        defer_accept: server
+            .and_then(|s| s.tcp_defer_accept)
+            .or(global.and_then(|g| g.tcp_defer_accept)),
+    })
+}
+
+fn build_client_auth(client_auth: &toml_model::ClientAuth) -> Result<ClientAuth, String> {
+    let header = client_auth
+        .header
+        .clone()
+        .unwrap_or(DEFAULT_CLIENT_CERT_HEADER.to_string());
+    if hyper::header::HeaderName::try_from(&header).is_err() {
+        return Err(format!("{header:?} is not a valid header name"));
+    }
+    Ok(ClientAuth {
+        ca: client_auth.ca.clone(),
+        mode: match client_auth.mode {
+            Some(toml_model::ClientAuthMode::Optional) => ClientAuthMode::Optional,
+            _ => ClientAuthMode::Require,
+        },
+        header,
+    })
+}
+
+// The files are rotated by size when only rotate_size is set.
+fn build_log_rotation(logs: Option<&toml_model::Logs>) -> Result<LogRotation, String> {
+    let Some(logs) = logs else {
+        return Ok(LogRotation::Never);
+    };
+    let mode = match (&logs.rotate, &logs.rotate_size) {
+        (Some(mode), _) => mode.as_str(),
+        (None, Some(_)) => "size",
+        (None, None) => "never",
+    };
+    if mode != "size" && logs.rotate_size.is_some() {
+        return Err(format!(
+            "rotate_size requires rotate = \"size\", not \"{mode}\""
+        ));
+    }
+    if mode != "size" && logs.compress.is_some() {
+        return Err("compress is only supported with rotate = \"size\"".to_string());
+    }
+    if mode == "never" && logs.keep.is_some() {
+        return Err("keep requires the files to be rotated".to_string());
+    }
+    let keep = logs.keep.unwrap_or(DEFAULT_LOG_KEEP);
+    match mode {
+        "never" => Ok(LogRotation::Never),
+        "size" => {
+            let size = logs
+                .rotate_size
+                .as_deref()
+                .ok_or("rotate = \"size\" requires rotate_size")?;
+            Ok(LogRotation::Size(SizeRotation {
+                size: rotation::parse_size(size)?,
+                keep,
+                compress: logs.compress.unwrap_or(DEFAULT_LOG_COMPRESS),
+            }))
+        }
+        "daily" => Ok(LogRotation::Daily { keep }),
+        "hourly" => Ok(LogRotation::Hourly { keep }),
+        _ => Err(format!(
+            "unknown rotate mode \"{mode}\" (allowed: {})",
+            LogRotation::MODES.join(", ")
+        )),
+    }
+}
+
+// Method names are case-sensitive, HEAD is allowed with GET.
+fn build_allowed_methods(names: &[String], deny_trace: bool) -> Result<Vec<String>, String> {
+    if names.is_empty() {
+        return Err("the list is empty".to_string());
+    }
+    let mut methods: Vec<String> = Vec::new();
+    for name in names {
+        if hyper::Method::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!("invalid method {name:?}"));
+        }
+        if name.bytes().any(|b| b.is_ascii_lowercase()) {
+            return Err(format!(
+                "method names are case-sensitive, {name:?} must be written {:?}",
+                name.to_ascii_uppercase()
+            ));
+        }
+        if name == "CONNECT" {
+            return Err("CONNECT is always refused, Quark is not a forward proxy".to_string());
+        }
+        if deny_trace && (name == "TRACE" || name == "TRACK") {
+            return Err(format!(
+                "{name} is refused, set deny_trace = false to allow it"
+            ));
+        }
+        if !methods.contains(name) {
+            methods.push(name.clone());
+        }
+    }
+    if methods.iter().any(|m| m == "GET") && !methods.iter().any(|m| m == "HEAD") {
+        methods.push("HEAD".to_string());
+    }
+    Ok(methods)
+}
+
+// All the headers without a list.
+fn build_forwarded_headers(names: Option<&[String]>) -> Result<ForwardedHeaders, String> {
+    let Some(names) = names else {
+        return Ok(ForwardedHeaders::default());
+    };
+    let mut headers = ForwardedHeaders {
+        r#for: false,
+        host: false,
+        proto: false,
+        port: false,
+    };
+    for name in names {
+        match name.to_ascii_lowercase().as_str() {
+            "for" => headers.r#for = true,
+            "host" => headers.host = true,
+            "proto" => headers.proto = true,
+            "port" => headers.port = true,
+            _ => {
+                return Err(format!(
+                    "unknown header {name}, expected for, host, proto or port"
+                ))
+            }
+        }
+    }
+    Ok(headers)
+}
+
+fn build_request_buffering(
+    location: &toml_model::Locations,
+) -> Result<Option<RequestBuffering>, String> {
+    let max_body = rotation::parse_size(
+        location
+            .max_buffered_body
+            .as_deref()
+            .unwrap_or(DEFAULT_MAX_BUFFERED_BODY),
+    )?;
+    Ok(location
+        .request_buffering
+        .unwrap_or(false)
+        .then_some(RequestBuffering {
+            max_body,
+            stream_over_max: matches!(
+                location.over_max_buffered_body,
+                Some(toml_model::OverMaxBufferedBody::Stream)
+            ),
+            timeout: location
+                .buffering_timeout
+                .unwrap_or(DEFAULT_BUFFERING_TIMEOUT),
+        }))
+}
+
+fn build_cache(cache: &toml_model::Cache) -> Result<CacheConfig, String> {
+    let size = |size: &Option<String>, default: &str| {
+        rotation::parse_size(size.as_deref().unwrap_or(default))
+    };
+    let max_size = size(&cache.max_size, DEFAULT_CACHE_MAX_SIZE)?;
+    let max_entry_size = size(&cache.max_entry_size, DEFAULT_CACHE_MAX_ENTRY_SIZE)?;
+    if max_entry_size > max_size {
+        return Err("max_entry_size is greater than max_size".to_string());
+    }
+    let methods: Vec<String> = match &cache.methods {
+        Some(methods) => methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+        None => vec![DEFAULT_CACHE_METHOD.to_string()],
+    };
+    if methods.is_empty() {
+        return Err("at least one method is required".to_string());
+    }
+    if let Some(method) = methods
+        .iter()
+        .find(|m| !CACHEABLE_METHODS.contains(&m.as_str()))
+    {
+        return Err(format!(
+            "the {method} responses can't be cached (allowed: {})",
+            CACHEABLE_METHODS.join(", ")
+        ));
+    }
+    Ok(CacheConfig {
+        ttl: alerts::parse_duration(&cache.ttl)?,
+        max_size,
+        max_entry_size,
+        methods,
+    })
+}
+
+fn build_alerts(alerts: &toml_model::Alerts) -> Result<AlertsConfig, String> {
+    let uri: hyper::Uri = alerts
+        .webhook
+        .parse()
+        .map_err(|e| format!("invalid webhook: {e}"))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+        return Err("the webhook must be an http or https url".to_string());
+    }
+    let threshold = alerts.threshold.as_ref();
+    let count = threshold
+        .and_then(|t| t.count)
+        .unwrap_or(alerts::DEFAULT_ALERT_COUNT);
+    if count == 0 {
+        return Err("threshold.count must be at least 1".to_string());
+    }
+    Ok(AlertsConfig {
+        webhook: alerts.webhook.clone(),
+        status: StatusRange::parse(
+            threshold
+                .and_then(|t| t.status.as_deref())
+                .unwrap_or(alerts::DEFAULT_ALERT_STATUS),
+        )?,
+        count,
+        window: alerts::parse_duration(
+            threshold
+                .and_then(|t| t.window.as_deref())
+                .unwrap_or(alerts::DEFAULT_ALERT_WINDOW),
+        )?,
+        cooldown: alerts::parse_duration(
+            alerts
+                .cooldown
+                .as_deref()
+                .unwrap_or(alerts::DEFAULT_ALERT_COOLDOWN),
+        )?,
+    })
+}
+
+// The logs are written to the file when no output is set.
+fn build_log_outputs(logs: Option<&toml_model::Logs>) -> Result<Vec<LogDestination>, String> {
+    let names = match logs.and_then(|l| l.output.as_ref()) {
+        None => vec![DEFAULT_LOG_OUTPUT.to_string()],
+        Some(toml_model::LogOutputs::One(name)) => vec![name.clone()],
+        Some(toml_model::LogOutputs::Many(names)) => names.clone(),
+    };
+    let facility = logs.and_then(|l| l.syslog_facility.as_deref());
+    if facility.is_some() && !names.iter().any(|name| name == "syslog") {
+        return Err("syslog_facility requires the \"syslog\" output".to_string());
+    }
+    let mut outputs = Vec::new();
+    for name in names {
+        let output = match name.as_str() {
+            "file" => LogDestination::File,
+            "journald" => LogDestination::Journald,
+            "syslog" => LogDestination::Syslog {
+                facility: syslog::parse_facility(
+                    facility.unwrap_or(syslog::DEFAULT_SYSLOG_FACILITY),
+                )?,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown log output \"{name}\" (allowed: {})",
+                    LogDestination::NAMES.join(", ")
+                ))
+            }
+        };
+        if !outputs.contains(&output) {
+            outputs.push(output);
+        }
+    }
+    Ok(outputs)
+}
+
+fn get_toml_config(path: &str) -> Result<ConfigToml, ConfigError> {
+    println!("Loading config from {path}");
+    let source = fs::read_to_string(path).map_err(|e| ConfigError::io(path, e))?;
+    let (mut config, vars): (ConfigToml, _) = parse_config(path, &source, &HashMap::new())?;
+    // import subconfiguration.
+    if let Some(imports) = config.import.take() {
+        let mut conf_path = PathBuf::from(path);
+        conf_path.pop();
+        import_sub_toml_configs(&mut config, path, &imports, &conf_path, &vars)?;
+    }
+    Ok(config)
+}
+
+// Parse a config file (TOML, YAML or JSON) and expand its variables.
+// Returns the variables for the imported files.
+fn parse_config<T: serde::de::DeserializeOwned>(
+    path: &str,
+    source: &str,
+    inherited_vars: &HashMap<String, String>,
+) -> Result<(T, HashMap<String, String>), ConfigError> {
+    let format = ConfigFormat::from_path(path);
+    // Variables are only used in strings, so the file can be checked before
+    // the expansion, the errors keep their position in the file.
+    format.parse::<T>(path, source)?;
+
+    let mut table = format.parse_table(path, source)?;
+    let vars =
+        vars::expand_vars(&mut table, inherited_vars).map_err(|e| ConfigError::invalid(path, e))?;
+    let config = table
+        .try_into()
+        .map_err(|e| ConfigError::parse(path, source, e))?;
+    Ok((config, vars))
+}
+
+// Load the imported files into the main config. A service or a loadbalancer
+// can only be defined once across all the files.
+fn import_sub_toml_configs(
+    config: &mut ConfigToml,
+    main_path: &str,
+    imports: &[String],
+    dir: &Path,
+    vars: &HashMap<String, String>,
+) -> Result<(), ConfigError> {
+    let mut services_origin: HashMap<String, String> = HashMap::new();
+    let mut loadbalancers_origin: HashMap<String, String> = HashMap::new();
+    for name in config.services.iter().flat_map(|s| s.keys()) {
+        services_origin.insert(name.clone(), main_path.to_string());
+    }
+    for name in config.loadbalancers.iter().flat_map(|l| l.keys()) {
+        loadbalancers_origin.insert(name.clone(), main_path.to_string());
+    }
+
+    for import in imports {
+        let files = expand_import(import, dir).map_err(|e| ConfigError::invalid(main_path, e))?;
+        for file in files {
+            let file = file.to_string_lossy().to_string();
+            let sub_config =
+                import_sub_toml_config(&file, vars).map_err(|e| e.imported_from(main_path))?;
+            // insert the subconfig into the main config.
+            if let Some(services) = sub_config.services {
+                for (name, service) in services {
+                    check_duplicate(&mut services_origin, "Service", &name, &file)
+                        .map_err(|e| ConfigError::invalid(&file, e).imported_from(main_path))?;
+                    config
+                        .services
+                        .get_or_insert_with(HashMap::new)
+                        .insert(name, service);
+                }
+            }
+            if let Some(loadbalancers) = sub_config.loadbalancer {
+                for (name, loadbalancer) in loadbalancers {
+                    check_duplicate(&mut loadbalancers_origin, "Loadbalancer", &name, &file)
+                        .map_err(|e| ConfigError::invalid(&file, e).imported_from(main_path))?;
+                    config
+                        .loadbalancers
+                        .get_or_insert_with(HashMap::new)
+                        .insert(name, loadbalancer);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_duplicate(
+    origins: &mut HashMap<String, String>,
+    kind: &str,
+    name: &str,
+    file: &str,
+) -> Result<(), String> {
+    match origins.insert(name.to_string(), file.to_string()) {
+        Some(other) => Err(format!(
+            "{kind} \"{name}\" is defined in both {other} and {file}"
+        )),
+        None => Ok(()),
+    }
+}
+
+// Return the files of an import entry, relative to the main config directory:
+// a single file, a directory (all its .toml, .yaml, .yml and .json files) or a
+// glob pattern.
+// The files are sorted by name.
+fn expand_import(import: &str, dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let path = dir.join(import);
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        let entries =
+            fs::read_dir(&path).map_err(|e| format!("Can't read the directory {import}. {e}"))?;
+        entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.is_file() && ConfigFormat::is_config_file(file))
+            .collect()
+    } else if import.contains(['*', '?', '[']) {
+        let pattern = path.to_string_lossy();
+        glob::glob(&pattern)
+            .map_err(|e| format!("Invalid import pattern {import}. {e}"))?
+            .filter_map(Result::ok)
+            .filter(|file| file.is_file())
+            .collect()
+    } else {
+        return Ok(vec![path]);
+    };
+
+    if files.is_empty() {
+        eprintln!("Warning: no configuration file found for the import {import}");
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn import_sub_toml_config(
+    real_path: &str,
+    vars: &HashMap<String, String>,
+) -> Result<SubConfigToml, ConfigError> {
+    let source = fs::read_to_string(real_path).map_err(|e| ConfigError::io(real_path, e))?;
+    let (config, _) = parse_config(real_path, &source, vars)?;
+    Ok(config)
+}
+
+fn manage_server_targets(
+    server: &mut Server,
+    (service_name, service): (&str, &toml_model::Service),
+    route_owners: &mut RouteOwners,
+    loadbalancers: &Option<HashMap<String, toml_model::Loadbalancer>>,
+    base_headers: [Option<&Headers>; 2], // Defaults and server headers.
+) -> Result<(), String> {
+    // Manage headers
+    let (l_headers, fs_headers, red_headers) = headers::get_config_headers_from(&base_headers);
+    let forwarded_headers = build_forwarded_headers(service.forwarded_headers.as_deref())
+        .map_err(|e| format!("Invalid forwarded_headers in [services.{service_name}]: {e}"))?;
+    // Locations
+    if let Some(locations) = &service.locations {
+        // Manage locations.
+        for location in locations
+            .iter()
+            .filter(|l| l.enabled.unwrap_or(DEFAULT_ENABLED))
+        {
+            // Custom headers for this specific location.
+            let mut headers = l_headers.clone();
+
+            headers::apply_header_actions(
+                service.headers.as_ref().and_then(|h| h.locations.as_ref()),
+                &mut headers,
+            );
+            headers::apply_header_actions(location.headers.as_ref(), &mut headers);
+
+            // Remove last slash.
+            let (source, route_kind) = source_and_route_kind(&location.source);
+            // Get all backends info required for load balancing.
+            let backends = get_backends_config(&location.target, loadbalancers)?;
+            let cache = location
+                .cache
+                .as_ref()
+                .map(build_cache)
+                .transpose()
+                .map_err(|e| format!("Invalid cache of the location {}: {e}", location.source))?;
+            let request_buffering = build_request_buffering(location).map_err(|e| {
+                format!(
+                    "Invalid max_buffered_body of the location {}: {e}",
+                    location.source
+                )
+            })?;
+            if location.buffering_timeout == Some(0) {
+                return Err(format!(
+                    "Invalid buffering_timeout of the location {}: must be at least 1",
+                    location.source
+                ));
+            }
+            if location.connect_timeout == Some(0) {
+                return Err(format!(
+                    "Invalid connect_timeout of the location {}: must be at least 1",
+                    location.source
+                ));
+            }
+            if location.max_concurrent == Some(0) {
+                return Err(format!(
+                    "Invalid max_concurrent of the location {}: must be at least 1",
+                    location.source
+                ));
+            }
 
-            let target = TargetType::Location(Locations {
+            let target = TargetType::Location(Box::new(Locations {
                 id: generate_u32_id(),
                 params: TargetParams {
-                    location: backends,
+                    location: backends.backends,
                     headers,
                 },
-                algo,
-                weights: weight,
-            });
+                algo: backends.algo,
+                weights: backends.weights,
+                ewma_decay: backends.ewma_decay,
+                outlier_detection: backends.outlier_detection,
+                proxy_timeout: location.proxy_timeout.or(service.proxy_timeout),
+                connect_timeout: location.connect_timeout.or(service.connect_timeout),
+                upstream_idle_timeout: location
+                    .upstream_idle_timeout
+                    .or(service.upstream_idle_timeout),
+                dns: backends.dns,
+                backend_options: backends.options,
+                cache,
+                upstream_http2: match location.upstream_http2 {
+                    Some(toml_model::UpstreamHttp2::Auto) => UpstreamHttp2::Auto,
+                    Some(toml_model::UpstreamHttp2::Always) => UpstreamHttp2::Always,
+                    _ => UpstreamHttp2::Never,
+                },
+                request_buffering,
+                max_concurrent: location.max_concurrent,
+                forwarded_headers,
+            }));
 
             let route = ServerRoute {
-                path: source.to_string(),
+                path: source,
                 kind: route_kind,
                 target,
             };
 
-            let routes = server
-                .params
-                .routes
-                .entry(service.domain.clone())
-                .or_default();
-            routes.push(route);
+            insert_route(
+                &mut server.params.routes,
+                route_owners,
+                (service_name, &service.domain),
+                route,
+                location.r#override.unwrap_or(false),
+            )?;
         }
     }
     if let Some(file_server) = &service.file_servers {
-        for fs in file_server {
+        for fs in file_server
+            .iter()
+            .filter(|fs| fs.enabled.unwrap_or(DEFAULT_ENABLED))
+        {
             manage_file_servers(
                 fs,
-                service.domain.clone(),
+                (service_name, &service.domain),
                 &mut server.params.routes,
+                route_owners,
                 &fs_headers,
                 service.headers.as_ref(),
-            );
+            )?;
         }
     }
     // Redirections.
     if let Some(redirections) = &service.redirections {
         // Manage redirections.
-        for red in redirections {
+        for red in redirections
+            .iter()
+            .filter(|r| r.enabled.unwrap_or(DEFAULT_ENABLED))
+        {
             // Remove last slash.
             let (source, route_kind) = source_and_route_kind(&red.source);
 
+            // Custom headers for this specific redirection.
+            let mut headers = red_headers.clone();
+            if let Some(rh) = service
+                .headers
+                .as_ref()
+                .and_then(|h| h.redirections.as_ref())
+            {
+                headers::merge_headers_actions(rh, &mut headers.response);
+            }
+            if let Some(ha) = &red.headers {
+                headers::merge_headers_actions(ha, &mut headers.response);
+            }
+
             let target = TargetType::Redirection(Redirection {
                 params: TargetParams {
                     location: red.target.clone(),
-                    headers: ConfigHeaders::default(),
+                    headers,
                 },
                 code: match red.code {
                     // Available redirection codes.
-                    Some(code @ (301 | 302 | 307 | 308)) => code,
-                    _ => DEFAULT_REDIRECTION_CODE,
+                    Some(code @ (301 | 302 | 303 | 307 | 308)) => code,
+                    Some(code) => {
+                        return Err(format!(
+                        "Invalid redirection code {code} for \"{}\" in [services.{service_name}] \
+                            (allowed: 301, 302, 303, 307, 308)",
+                        red.source
+                    ))
+                    }
+                    None => DEFAULT_REDIRECTION_CODE,
                 },
+                preserve_query: red.preserve_query.unwrap_or(DEFAULT_PRESERVE_QUERY),
             });
 
             let route = ServerRoute {
-                path: source.to_string(),
+                path: source,
                 kind: route_kind,
                 target,
             };
 
-            let routes = server
-                .params
-                .routes
-                .entry(service.domain.clone())
-                .or_default();
-            routes.push(route);
+            insert_route(
+                &mut server.params.routes,
+                route_owners,
+                (service_name, &service.domain),
+                route,
+                red.r#override.unwrap_or(false),
+            )?;
         }
     }
+    Ok(())
 }
 
 fn manage_file_servers(
     fs: &FileServers,
-    domain: String,
+    (service_name, domain): (&str, &str),
     targets: &mut ServerParamsRoutes,
+    route_owners: &mut RouteOwners,
     headers: &ConfigHeaders,
     service_headers: Option<&Headers>,
-) {
+) -> Result<(), String> {
     let (source, route_kind) = source_and_route_kind(&fs.source);
+    if fs.max_concurrent == Some(0) {
+        return Err(format!(
+            "Invalid max_concurrent of the file server {}: must be at least 1",
+            fs.source
+        ));
+    }
+    let listing_hide =
+        GlobSet::new(fs.listing_hide.as_deref().unwrap_or_default()).map_err(|(pattern, e)| {
+            format!(
+                "Invalid listing_hide of the file server {}: {pattern:?}, {e}",
+                fs.source
+            )
+        })?;
+    let id = generate_u32_id();
     let (target, file_name) = get_path_and_file(&fs.target);
     let target_str = target.to_string_lossy().to_string();
     let mut is_fallback_404 = false;
+    let canonical_index_redirect = fs
+        .canonical_index_redirect
+        .unwrap_or(DEFAULT_CANONICAL_INDEX_REDIRECT);
 
     let file_path = if file_name.is_some() {
         Some(fs.target.clone())
@@ -501,36 +2267,53 @@ fn manage_file_servers(
     }
 
     let target = TargetType::FileServer(FileServer {
+        id,
         params: TargetParams {
             location: target_str.clone(),
             headers: headers.clone(),
         },
         fallback_file: file_path.clone(),
         is_fallback_404,
-        forbidden_dir: DEFAULT_FORBIDDEN_DIR,
+        forbidden_dir: !fs.autoindex.unwrap_or(DEFAULT_AUTOINDEX),
+        canonical_index_redirect,
+        max_concurrent: fs.max_concurrent,
+        listing_hide: listing_hide.clone(),
     });
 
     let route = ServerRoute {
-        path: source.to_string(),
+        path: source.clone(),
         kind: route_kind,
         target,
     };
 
-    let routes = targets.entry(domain.clone()).or_default();
-    routes.push(route);
+    let inserted = insert_route(
+        targets,
+        route_owners,
+        (service_name, domain),
+        route,
+        fs.r#override.unwrap_or(false),
+    )?;
+    if !inserted {
+        // Shadowed by another file server.
+        return Ok(());
+    }
 
     if let Some(ads) = &fs.authorized_dirs {
         for ad in ads {
-            let (dir, route_kind, access) = dir_strict_mode_and_access(ad);
+            let (dir, route_kind, forbidden_dir) = dir_strict_mode_and_access(ad, service_name);
             let key = format!("{source}{dir}");
             let target = TargetType::FileServer(FileServer {
+                id,
                 params: TargetParams {
                     location: format!("{target_str}{dir}"),
                     headers: headers.clone(),
                 },
                 fallback_file: file_path.clone(),
                 is_fallback_404,
-                forbidden_dir: access,
+                forbidden_dir,
+                canonical_index_redirect,
+                max_concurrent: fs.max_concurrent,
+                listing_hide: listing_hide.clone(),
             });
 
             let route = ServerRoute {
@@ -539,49 +2322,225 @@ fn manage_file_servers(
                 target,
             };
 
-            let routes = targets.entry(domain.clone()).or_default();
+            let routes = targets.entry(domain.to_string()).or_default();
             match routes.iter().position(|r| r.path == route.path) {
                 Some(pos) => routes[pos] = route,
                 None => routes.push(route),
             }
         }
     }
+    Ok(())
+}
+
+// Add a route defined by a service. The same source can't be defined twice
+// for a domain, unless one of the definitions sets `override = true`.
+// Returns false if the route is shadowed by an existing override.
+fn insert_route(
+    targets: &mut ServerParamsRoutes,
+    route_owners: &mut RouteOwners,
+    (service_name, domain): (&str, &str),
+    route: ServerRoute,
+    overrides: bool,
+) -> Result<bool, String> {
+    let key = (domain.to_string(), route.path.clone(), route.kind.clone());
+    let routes = targets.entry(domain.to_string()).or_default();
+
+    match route_owners.get(&key) {
+        None => routes.push(route),
+        Some(owner) if owner.overrides && !overrides => return Ok(false),
+        Some(owner) if overrides && !owner.overrides => {
+            let pos = routes
+                .iter()
+                .position(|r| r.path == route.path && r.kind == route.kind)
+                .unwrap();
+            routes[pos] = route;
+        }
+        Some(owner) => {
+            let source = match key.2 {
+                RouteKind::Path => format!("{domain}{}/*", key.1),
+                RouteKind::Strict if key.1.is_empty() => format!("{domain}/"),
+                RouteKind::Strict => format!("{domain}{}", key.1),
+            };
+            let mut services = [owner.service.as_str(), service_name];
+            services.sort();
+            let defined_in = if services[0] == services[1] {
+                format!("defined twice in [services.{}]", services[0])
+            } else {
+                format!(
+                    "defined in both [services.{}] and [services.{}]",
+                    services[0], services[1]
+                )
+            };
+            let hint = if overrides {
+                "Only one of them can set override = true."
+            } else {
+                "Set override = true on the one that should win."
+            };
+            return Err(format!(
+                "Conflicting targets for \"{source}\": {defined_in}. {hint}"
+            ));
+        }
+    }
+
+    route_owners.insert(
+        key,
+        RouteOwner {
+            service: service_name.to_string(),
+            overrides,
+        },
+    );
+    Ok(true)
+}
+
+// Backends of a location, with their load balancing settings.
+#[derive(Default)]
+struct BackendsConfig {
+    backends: Vec<String>,
+    algo: Option<String>,
+    weights: Option<Vec<u32>>,
+    ewma_decay: f64,
+    outlier_detection: Option<OutlierDetection>,
+    options: Vec<BackendOptions>,
+    dns: Option<DnsBackends>,
 }
 
 fn get_backends_config(
     target: &str,
     loadbalancers: &Option<HashMap<String, toml_model::Loadbalancer>>,
-) -> (Vec<String>, Option<String>, Option<Vec<u32>>) {
+) -> Result<BackendsConfig, String> {
     let keys = extract_vars_from_string(target);
-    let mut server_list: Vec<String> = Vec::new();
-    let mut algo: Option<String> = None;
-    let mut weight: Option<Vec<u32>> = None;
+    let mut config = BackendsConfig {
+        ewma_decay: DEFAULT_EWMA_DECAY,
+        ..Default::default()
+    };
 
     // Only get the first key since you can only have one loadbalancer list.
     if let Some(key) = keys.first() {
-        if let Some(loadbalancer) = loadbalancers.as_ref().unwrap().get(key) {
-            let srv_nbr = loadbalancer.backends.len();
-            for (i, lb_server) in loadbalancer.backends.iter().enumerate() {
-                let server = if let Some(server) = server_list.get(i) {
-                    server
-                } else {
-                    target
-                };
+        if let Some(loadbalancer) = loadbalancers.as_ref().and_then(|l| l.get(key)) {
+            let var = format!("${{{key}}}");
+            config.algo = Some(loadbalancer.algo.clone());
+            if let Some(ewma_decay) = loadbalancer.ewma_decay {
+                if loadbalancer.algo != ALGO_LEAST_TIME {
+                    return Err(format!(
+                        "[loadbalancers.{key}]: ewma_decay is only used by the least_time algo"
+                    ));
+                }
+                if !(ewma_decay > 0.0 && ewma_decay <= 1.0) {
+                    return Err(format!(
+                        "[loadbalancers.{key}]: ewma_decay must be over 0 and at most 1"
+                    ));
+                }
+                config.ewma_decay = ewma_decay;
+            }
+            if let Some(outliers) = &loadbalancer.outlier_detection {
+                config.outlier_detection = Some(
+                    build_outlier_detection(outliers)
+                        .map_err(|e| format!("[loadbalancers.{key}]: outlier_detection {e}"))?,
+                );
+            }
 
-                let server_url = server.to_string();
-                let var = format!("${{{key}}}");
-                let server = server_url.replace(&var, lb_server);
+            if let Some(dns) = &loadbalancer.backends_dns {
+                if !loadbalancer.backends.is_empty() || loadbalancer.weights.is_some() {
+                    return Err(format!(
+                        "[loadbalancers.{key}]: backends_dns can't be used with backends or weights"
+                    ));
+                }
+                config.dns = Some(DnsBackends {
+                    name: dns.name.clone(),
+                    port: dns.port,
+                    refresh: dns.refresh.unwrap_or(DEFAULT_DNS_REFRESH).max(1),
+                    target: target.to_string(),
+                    var,
+                });
+                return Ok(config);
+            }
+
+            // The backends are either all urls or all tables.
+            let tables = loadbalancer
+                .backends
+                .iter()
+                .filter(|b| matches!(b, toml_model::Backend::Table(_)))
+                .count();
+            if tables > 0 && tables < loadbalancer.backends.len() {
+                return Err(format!(
+                    "[loadbalancers.{key}]: backends can't mix urls and tables"
+                ));
+            }
+            if tables > 0 && loadbalancer.weights.is_some() {
+                return Err(format!(
+                    "[loadbalancers.{key}]: weights can't be used with backend tables, \
+                    set the weight of each backend instead"
+                ));
+            }
 
-                server_list.push(server.to_string());
-                algo = Some(loadbalancer.algo.clone());
-                weight = manage_weights(srv_nbr, &loadbalancer.weights);
+            for backend in loadbalancer.backends.iter() {
+                config.backends.push(target.replace(&var, backend.url()));
+            }
+            if tables > 0 {
+                config.weights = Some(loadbalancer.backends.iter().map(backend_weight).collect());
+                config.options = loadbalancer
+                    .backends
+                    .iter()
+                    .map(|backend| match backend {
+                        toml_model::Backend::Table(b) => BackendOptions {
+                            max_conns: b.max_conns,
+                            backup: b.backup.unwrap_or(false),
+                        },
+                        toml_model::Backend::Url(_) => BackendOptions::default(),
+                    })
+                    .collect();
+            } else {
+                config.weights = manage_weights(loadbalancer.backends.len(), &loadbalancer.weights);
             }
         }
     } else {
-        server_list.push(target.to_string());
+        config.backends.push(target.to_string());
+    }
+
+    Ok(config)
+}
+
+fn build_outlier_detection(
+    outliers: &toml_model::OutlierDetection,
+) -> Result<OutlierDetection, String> {
+    let detection = OutlierDetection {
+        interval: outliers.interval.unwrap_or(DEFAULT_OUTLIER_INTERVAL),
+        error_percent: outliers
+            .error_percent
+            .unwrap_or(DEFAULT_OUTLIER_ERROR_PERCENT),
+        min_requests: outliers
+            .min_requests
+            .unwrap_or(DEFAULT_OUTLIER_MIN_REQUESTS),
+        ejection_time: outliers.ejection_time.unwrap_or(DEFAULT_EJECTION_TIME),
+        max_ejection_time: outliers
+            .max_ejection_time
+            .unwrap_or(DEFAULT_MAX_EJECTION_TIME),
+        max_ejection_percent: outliers
+            .max_ejection_percent
+            .unwrap_or(DEFAULT_MAX_EJECTION_PERCENT),
+    };
+    if detection.interval == 0 || detection.ejection_time == 0 || detection.min_requests == 0 {
+        return Err("interval, ejection_time and min_requests must be at least 1".to_string());
+    }
+    if detection.max_ejection_time < detection.ejection_time {
+        return Err("max_ejection_time can't be shorter than ejection_time".to_string());
     }
+    if !(1..100).contains(&detection.error_percent) {
+        return Err("error_percent must be between 1 and 99".to_string());
+    }
+    if detection.max_ejection_percent > 100 {
+        return Err("max_ejection_percent can't be over 100".to_string());
+    }
+    Ok(detection)
+}
 
-    (server_list, algo, weight)
+// Backups only receive the requests the other backends can't take.
+fn backend_weight(backend: &toml_model::Backend) -> u32 {
+    match backend {
+        toml_model::Backend::Table(b) if b.backup == Some(true) => 0,
+        toml_model::Backend::Table(b) => b.weight.unwrap_or(1),
+        toml_model::Backend::Url(_) => 1,
+    }
 }
 
 // Add or remmove weights if necessary.
@@ -598,12 +2557,33 @@ fn manage_weights(srv_nbr: usize, weights: &Option<Vec<u32>>) -> Option<Vec<u32>
     }
 }
 
-fn www_auto_redirection(
-    server_targets: &mut ServerParamsRoutes,
-    service_domain: &str,
-    port: u16,
-    tls: bool,
-) {
+// (Code, excluded paths), or None when the plain http requests are served.
+fn build_tls_redirection(
+    redirection: Option<&toml_model::TlsRedirection>,
+) -> Result<Option<(u16, Vec<String>)>, String> {
+    let table = match redirection {
+        None => {
+            return Ok(DEFAULT_TLS_REDIRECTION.then(|| (DEFAULT_TLS_REDIRECTION_CODE, Vec::new())));
+        }
+        Some(toml_model::TlsRedirection::Enabled(false)) => return Ok(None),
+        Some(toml_model::TlsRedirection::Enabled(true)) => {
+            return Ok(Some((DEFAULT_TLS_REDIRECTION_CODE, Vec::new())));
+        }
+        Some(toml_model::TlsRedirection::Table(table)) => table,
+    };
+    let code = match table.code {
+        Some(code @ (301 | 302 | 307 | 308)) => code,
+        Some(code) => return Err(format!("code {code} (allowed: 301, 302, 307, 308)")),
+        None => DEFAULT_TLS_REDIRECTION_CODE,
+    };
+    let exclude_paths = table.exclude_paths.clone().unwrap_or_default();
+    if let Some(path) = exclude_paths.iter().find(|p| !p.starts_with('/')) {
+        return Err(format!("exclude_paths must start with /, got \"{path}\""));
+    }
+    Ok(Some((code, exclude_paths)))
+}
+
+fn www_auto_redirection(params: &mut ServerParams, service_domain: &str, port: u16, tls: bool) {
     let domain: String;
     let target_domain: String;
     let default_port = if tls {
@@ -621,6 +2601,11 @@ fn www_auto_redirection(
         domain = service_domain.strip_prefix("www.").unwrap().to_string();
         target_domain = service_domain.to_string();
     }
+    // The counterpart is served by another service or alias, or already
+    // redirected.
+    if params.routes.contains_key(&domain) || params.aliases.contains_key(&domain) {
+        return;
+    }
     let location_target = format!(
         "http{}://{}{}",
         if tls { "s" } else { "" },
@@ -638,6 +2623,7 @@ fn www_auto_redirection(
             headers: ConfigHeaders::default(),
         },
         code: StatusCode::MOVED_PERMANENTLY.as_u16(),
+        preserve_query: true,
     });
 
     let route = ServerRoute {
@@ -646,26 +2632,45 @@ fn www_auto_redirection(
         target,
     };
 
-    let routes = server_targets.entry(domain).or_default();
-    routes.push(route);
+    params.routes.insert(domain, vec![route]);
 }
 
-fn dir_strict_mode_and_access(path: &str) -> (&str, RouteKind, bool) {
-    if let Some(p) = path.strip_prefix("!") {
-        // forbidden directory.
-        let (source, mode) = source_and_route_kind(p);
-        (source, mode, true)
-    } else {
-        let (source, mode) = source_and_route_kind(path);
-        (source, mode, false)
-    }
+// The authorized dirs are listed, unless they set autoindex = false or use
+// the deprecated "!" prefix. An explicit autoindex takes precedence over the
+// prefix. Returns the source, its route kind and forbidden_dir.
+fn dir_strict_mode_and_access(
+    dir: &AuthorizedDir,
+    service_name: &str,
+) -> (String, RouteKind, bool) {
+    let (path, autoindex) = match dir {
+        AuthorizedDir::Path(path) => (path.as_str(), None),
+        AuthorizedDir::Table(table) => (table.path.as_str(), table.autoindex),
+    };
+    let (path, autoindex) = match path.strip_prefix('!') {
+        Some(path) => {
+            eprintln!(
+                "Warning: the \"!\" prefix of authorized_dirs is deprecated in [services.{service_name}], \
+                use {{ path = \"{path}\", autoindex = false }}"
+            );
+            (path, autoindex.unwrap_or(false))
+        }
+        None => (path, autoindex.unwrap_or(true)),
+    };
+    let (source, mode) = source_and_route_kind(path);
+    (source, mode, !autoindex)
 }
 
-fn source_and_route_kind(source: &str) -> (&str, RouteKind) {
+// Sources are normalized like the request paths: "/app//*" and "/app/*",
+// or "/app/" and "/app", define the same route.
+fn source_and_route_kind(source: &str) -> (String, RouteKind) {
+    let source = utils::merge_slashes(source);
     if let Some(s) = source.strip_suffix("/*") {
-        (s, RouteKind::Path)
+        (utils::remove_last_slash(s).to_string(), RouteKind::Path)
     } else {
-        (utils::remove_last_slash(source), RouteKind::Strict)
+        (
+            utils::remove_last_slash(&source).to_string(),
+            RouteKind::Strict,
+        )
     }
 }
 
@@ -675,29 +2680,28 @@ mod headers {
         ConfigHeaders, ConfigHeadersActions,
     };
 
+    // Merge the headers of each level, the last one wins.
     pub fn get_config_headers_from(
-        headers: Option<&Headers>,
+        levels: &[Option<&Headers>],
     ) -> (
         ConfigHeaders, // Location
         ConfigHeaders, // FileServer
+        ConfigHeaders, // Redirection
     ) {
         let mut l_headers = ConfigHeaders::default();
         let mut fs_headers = ConfigHeaders::default();
-        if let Some(h) = headers {
-            if let Some(locations) = &h.locations {
-                if let Some(request) = &locations.request {
-                    l_headers.request = Some(process_headers_set_del(request));
-                }
-                if let Some(response) = &locations.response {
-                    l_headers.response = Some(process_headers_set_del(response));
-                }
-            }
+        let mut red_headers = ConfigHeaders::default();
+        for h in levels.iter().flatten() {
+            apply_header_actions(h.locations.as_ref(), &mut l_headers);
             if let Some(response) = &h.file_servers {
-                fs_headers.response = Some(process_headers_set_del(response));
+                merge_headers_actions(response, &mut fs_headers.response);
+            }
+            if let Some(response) = &h.redirections {
+                merge_headers_actions(response, &mut red_headers.response);
             }
         }
 
-        (l_headers, fs_headers)
+        (l_headers, fs_headers, red_headers)
     }
 
     fn process_headers_set_del(action: &HeaderAction) -> ConfigHeadersActions {
@@ -749,6 +2753,15 @@ mod headers {
     }
 }
 
+// Build a configuration from the content of its file, for the tests.
+#[cfg(test)]
+pub fn build_config(toml: impl AsRef<str>) -> Result<InternalConfig, ConfigError> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, toml.as_ref()).unwrap();
+    InternalConfig::build_from(path.to_string_lossy().to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::toml_model::HeaderAction;
@@ -769,12 +2782,31 @@ mod tests {
         Server {
             params: ServerParams {
                 routes: HashMap::new(),
-                auto_tls: None,
+                auto_tls: HashMap::new(),
                 proxy_timeout: DEFAULT_PROXY_TIMEOUT,
+                upstream_idle_timeout: DEFAULT_UPSTREAM_IDLE_TIMEOUT,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                client_cert_header: None,
+                timing_domains: HashSet::new(),
+                upstream_domains: HashSet::new(),
+                services: HashMap::new(),
+                aliases: HashMap::new(),
+                unknown_host: UnknownHost::default(),
+                normalize_path: NormalizePath::default(),
+                allowed_methods: HashMap::new(),
+                trace_domains: HashSet::new(),
             },
             port: DEFAULT_PORT,
             https_port: DEFAULT_PORT_HTTPS,
             tls: None,
+            tls_options: TlsOptions::default(),
+            client_auth: None,
+            default_cert: None,
+            http2: DEFAULT_HTTP2,
+            tls_handshake_timeout: None,
+            tcp_options: TcpOptions::default(),
+            tls_aliases: Vec::new(),
+            cert_services: HashMap::new(),
         }
     }
 
@@ -786,7 +2818,7 @@ mod tests {
         tls: bool,
     ) {
         let mut server = server_mock();
-        www_auto_redirection(&mut server.params.routes, target_domain, port, tls);
+        www_auto_redirection(&mut server.params, target_domain, port, tls);
         let routes = server.params.routes.get(source_domain).unwrap();
         let target = &routes[0].target;
 
@@ -800,6 +2832,1550 @@ mod tests {
         }
     }
 
+    fn tls_options_mock(min: Option<&str>, max: Option<&str>) -> toml_model::TlsOptions {
+        toml_model::TlsOptions {
+            min_version: min.map(String::from),
+            max_version: max.map(String::from),
+            cipher_suites: None,
+            kx_groups: None,
+            session_tickets: None,
+            ticket_rotation: None,
+            session_cache_size: None,
+        }
+    }
+
+    #[test]
+    fn tls_options_server_overrides_global() {
+        let global = tls_options_mock(Some("1.3"), None);
+        let server = tls_options_mock(Some("1.2"), Some("1.2"));
+        let options = build_tls_options(Some(&global), Some(&server)).unwrap();
+        assert_eq!(options.min_version, TlsVersion::Tls12);
+        assert_eq!(options.max_version, TlsVersion::Tls12);
+
+        let options = build_tls_options(Some(&global), None).unwrap();
+        assert_eq!(options.min_version, TlsVersion::Tls13);
+        assert_eq!(options.max_version, TlsVersion::Tls13);
+    }
+
+    #[test]
+    fn tls_options_invalid() {
+        let unsupported = tls_options_mock(Some("1.1"), None);
+        assert!(build_tls_options(Some(&unsupported), None).is_err());
+
+        let inverted = tls_options_mock(Some("1.3"), Some("1.2"));
+        assert!(build_tls_options(None, Some(&inverted)).is_err());
+
+        let mut rotation = tls_options_mock(None, None);
+        rotation.ticket_rotation = Some(0);
+        assert!(build_tls_options(Some(&rotation), None).is_err());
+        rotation.ticket_rotation = Some(DEFAULT_TICKET_ROTATION + 1);
+        assert!(build_tls_options(Some(&rotation), None).is_err());
+    }
+
+    #[test]
+    fn cache_config() {
+        let cache = |max_entry_size: &str, methods: &[&str]| toml_model::Cache {
+            ttl: "2s".to_string(),
+            max_size: Some("1MB".to_string()),
+            max_entry_size: Some(max_entry_size.to_string()),
+            methods: Some(methods.iter().map(|m| m.to_string()).collect()),
+        };
+        assert_eq!(
+            build_cache(&cache("64KB", &["get", "HEAD"])),
+            Ok(CacheConfig {
+                ttl: 2,
+                max_size: 1024 * 1024,
+                max_entry_size: 64 * 1024,
+                methods: vec!["GET".to_string(), "HEAD".to_string()],
+            })
+        );
+        assert!(build_cache(&cache("2MB", &["GET"])).is_err());
+        assert!(build_cache(&cache("64KB", &["POST"])).is_err());
+        assert!(build_cache(&cache("64KB", &[])).is_err());
+    }
+
+    #[test]
+    fn request_buffering_config() {
+        let buffering = |options: &str| {
+            let config = build_config(format!(
+                r#"
+                [services.app]
+                domain = "example.com"
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000", {options} }}]
+                "#
+            ))?;
+            let route = &config.servers[MAIN_SERVER_NAME].params.routes["example.com"][0];
+            let TargetType::Location(location) = &route.target else {
+                panic!("not a location");
+            };
+            Ok::<_, ConfigError>(location.request_buffering.clone())
+        };
+        assert_eq!(
+            buffering("request_buffering = true").unwrap(),
+            Some(RequestBuffering {
+                max_body: 10 * 1024 * 1024,
+                stream_over_max: false,
+                timeout: 60,
+            })
+        );
+        assert_eq!(
+            buffering("request_buffering = true, buffering_timeout = 5")
+                .unwrap()
+                .unwrap()
+                .timeout,
+            5
+        );
+        assert!(buffering("request_buffering = true, buffering_timeout = 0").is_err());
+    }
+
+    #[test]
+    fn tcp_options_server_overrides_global() {
+        let global: toml_model::Global = toml::from_str(
+            "tcp_nodelay = true\ntcp_defer_accept = 5\ntcp_keepalive = { time = 60 }",
+        )
+        .unwrap();
+        let server: toml_model::Server =
+            toml::from_str("tcp_nodelay = false\ntcp_keepalive = { interval = 10, retries = 3 }")
+                .unwrap();
+        let options = build_tcp_options(Some(&global), Some(&server)).unwrap();
+        assert_eq!(options.nodelay, Some(false));
+        assert_eq!(options.defer_accept, Some(5));
+        assert_eq!(
+            options.keepalive,
+            Some(TcpKeepalive {
+                time: None,
+                interval: Some(10),
+                retries: Some(3),
+            })
+        );
+        assert_eq!(
+            build_tcp_options(Some(&global), None).unwrap().to_string(),
+            "tcp_nodelay true, tcp_keepalive (time 60, interval default, retries default), tcp_defer_accept 5"
+        );
+        assert_eq!(
+            build_tcp_options(None, None).unwrap(),
+            TcpOptions::default()
+        );
+
+        let zero: toml_model::Server = toml::from_str("tcp_keepalive = { retries = 0 }").unwrap();
+        assert!(build_tcp_options(None, Some(&zero)).is_err());
+    }
+
+    #[test]
+    fn alpn_validation() {
+        let alpn = vec!["http/1.1".to_string()];
+        assert_eq!(check_alpn(&alpn).unwrap(), alpn);
+        assert!(check_alpn(&[]).is_err());
+        assert!(check_alpn(&["h3".to_string()]).is_err());
+    }
+
+    #[test]
+    fn tls_options_cipher_suites() {
+        let mut options = tls_options_mock(None, None);
+        options.cipher_suites = Some(vec!["TLS13_AES_256_GCM_SHA384".to_string()]);
+        options.kx_groups = Some(vec!["X25519".to_string(), "secp256r1".to_string()]);
+        assert!(build_tls_options(Some(&options), None).is_ok());
+
+        // Unknown suite.
+        options.cipher_suites = Some(vec!["TLS_RSA_WITH_RC4_128_MD5".to_string()]);
+        let err = build_tls_options(Some(&options), None).unwrap_err();
+        assert!(err.contains("TLS13_AES_256_GCM_SHA384"), "{err}");
+
+        // TLS 1.2 suite only while TLS 1.2 is disabled.
+        let mut options = tls_options_mock(Some("1.3"), None);
+        options.cipher_suites = Some(vec!["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string()]);
+        assert!(build_tls_options(Some(&options), None).is_err());
+    }
+
+    #[test]
+    fn check_cross_validation() {
+        let config = build_config(
+            r#"
+            [loadbalancers.pool]
+            backends = ["127.0.0.1:3000"]
+            algo = "round_robin"
+
+            [services.app]
+            domain = "example.com"
+            locations = [
+              { source = "/", target = "http://${pool}" },
+              { source = "/unknown", target = "http://${missing}" },
+              { source = "/bad", target = "ftp://127.0.0.1" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let errors = config.check();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors
+            .iter()
+            .any(|e| e.contains("/unknown") && e.contains("no backend")));
+        assert!(errors.iter().any(|e| e.contains("ftp://127.0.0.1")));
+    }
+
+    #[test]
+    fn conflicting_ports() {
+        let build = |servers: &str| {
+            build_config(format!(
+                r#"{servers}
+                [services.app]
+                domain = "example.com"
+                tls.certificate = "tests/certs/ecdsa.pem"
+                tls.key = "tests/certs/ecdsa.key"
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+                "#
+            ))
+            .map(|_| ())
+        };
+
+        let err = build("[servers.main]\nport = 8080\n[servers.other]\nport = 8080\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "Port 8080 is used by the port of [servers.main] and the port of [servers.other]"
+            ),
+            "{err}"
+        );
+        let err = build("[servers.main]\nport = 8080\n[servers.other]\nport = 443\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Port 443 is used by the https_port of [servers.main] and the port of [servers.other]"),
+            "{err}"
+        );
+        let err = build("[servers.main]\nport = 8443\nhttps_port = 8443\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Port 8443 is used by the port of [servers.main] and the https_port of [servers.main]"),
+            "{err}"
+        );
+        // Without TLS, the https port of a server isn't bound.
+        build("[servers.main]\nport = 8080\n[servers.other]\nport = 8443\nhttps_port = 8080\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn conflicting_targets() {
+        let route = |path: &str, kind: RouteKind| ServerRoute {
+            path: path.to_string(),
+            kind,
+            target: TargetType::Redirection(Redirection {
+                params: TargetParams {
+                    location: "https://example.org".to_string(),
+                    headers: ConfigHeaders::default(),
+                },
+                code: DEFAULT_REDIRECTION_CODE,
+                preserve_query: DEFAULT_PRESERVE_QUERY,
+            }),
+        };
+        let mut targets = ServerParamsRoutes::new();
+        let mut owners = RouteOwners::new();
+        let b = ("b", "example.com");
+        let a = ("a", "example.com");
+
+        insert_route(
+            &mut targets,
+            &mut owners,
+            b,
+            route("/api", RouteKind::Path),
+            false,
+        )
+        .unwrap();
+        // Same path with another kind, or on another domain.
+        insert_route(
+            &mut targets,
+            &mut owners,
+            a,
+            route("/api", RouteKind::Strict),
+            false,
+        )
+        .unwrap();
+        insert_route(
+            &mut targets,
+            &mut owners,
+            ("a", "example.org"),
+            route("/api", RouteKind::Path),
+            false,
+        )
+        .unwrap();
+
+        let err = insert_route(
+            &mut targets,
+            &mut owners,
+            a,
+            route("/api", RouteKind::Path),
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Conflicting targets for \"example.com/api/*\": defined in both [services.a] \
+            and [services.b]. Set override = true on the one that should win."
+        );
+
+        // An override replaces the route, whatever the order.
+        assert!(insert_route(
+            &mut targets,
+            &mut owners,
+            a,
+            route("/api", RouteKind::Path),
+            true
+        )
+        .unwrap());
+        assert!(!insert_route(
+            &mut targets,
+            &mut owners,
+            ("c", "example.com"),
+            route("/api", RouteKind::Path),
+            false
+        )
+        .unwrap());
+        assert_eq!(targets["example.com"].len(), 2);
+        assert!(insert_route(
+            &mut targets,
+            &mut owners,
+            b,
+            route("/api", RouteKind::Path),
+            true
+        )
+        .unwrap_err()
+        .contains("Only one of them"));
+    }
+
+    #[test]
+    fn config_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("config.toml");
+        let sub = dir.path().join("sub.toml");
+        let main_str = main.to_string_lossy().to_string();
+        let sub_str = sub.to_string_lossy().to_string();
+
+        let missing = dir.path().join("missing.toml");
+        let err = InternalConfig::build_from(missing.to_string_lossy().to_string()).unwrap_err();
+        assert!(matches!(err.kind, error::ConfigErrorKind::Io(_)));
+
+        fs::write(&main, "import = [\"sub.toml\"]\n").unwrap();
+        fs::write(
+            &sub,
+            "[services.app]\ndomain = \"example.com\"\nsource = \n",
+        )
+        .unwrap();
+        let err = InternalConfig::build_from(main_str.clone()).unwrap_err();
+        assert_eq!(err.path, sub_str);
+        assert_eq!(err.context, std::slice::from_ref(&main_str));
+        assert!(matches!(
+            err.kind,
+            error::ConfigErrorKind::Parse {
+                position: Some((3, 10)),
+                ..
+            }
+        ));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Failed to parse toml file {sub_str}:3:10.")));
+        assert!(err
+            .to_string()
+            .ends_with(&format!("\n  imported from {main_str}")));
+
+        fs::write(&main, "[global.tls]\nmin_version = \"1.1\"\n").unwrap();
+        let err = InternalConfig::build_from(main_str.clone()).unwrap_err();
+        assert_eq!(err.path, main_str);
+        assert!(err.context.is_empty());
+        assert!(
+            matches!(&err.kind, error::ConfigErrorKind::Invalid(e) if e.contains("[global.tls]"))
+        );
+    }
+
+    #[test]
+    fn logs_config() {
+        let config = build_config("[logs]\npath = \"/tmp/quark\"\nlevel = \"debug\"\n").unwrap();
+        assert_eq!(config.logs.path.as_deref(), Some("/tmp/quark"));
+        assert_eq!(config.logs.level.as_deref(), Some("debug"));
+        assert_eq!(config.logs.stdout, DEFAULT_LOG_STDOUT);
+        assert_eq!(config.logs.rotation, LogRotation::Never);
+        assert_eq!(config.logs.outputs, [LogDestination::File]);
+        assert_eq!(config.logs.error_log, None);
+        assert_eq!(config.logs.access_log_sampling, AccessLogSampling::ALL);
+
+        let config =
+            build_config("[logs]\naccess_log_sample = 0.1\naccess_log_sample_not_found = true\n")
+                .unwrap();
+        assert_eq!(
+            config.logs.access_log_sampling,
+            AccessLogSampling {
+                rate: 0.1,
+                sample_not_found: true
+            }
+        );
+
+        let config = build_config("[logs]\nrotate_size = \"100MB\"\ncompress = true\n").unwrap();
+        assert_eq!(
+            config.logs.rotation,
+            LogRotation::Size(SizeRotation {
+                size: 100 * 1024 * 1024,
+                keep: DEFAULT_LOG_KEEP,
+                compress: true,
+            })
+        );
+
+        let config = build_config("[logs]\nrotate = \"daily\"\nkeep = 7\n").unwrap();
+        assert_eq!(config.logs.rotation, LogRotation::Daily { keep: 7 });
+
+        for invalid in [
+            "rotate = \"weekly\"",
+            "rotate = \"size\"",
+            "rotate = \"hourly\"\nrotate_size = \"1MB\"",
+            "rotate = \"daily\"\ncompress = true",
+            "access_log_sample = 2.0",
+        ] {
+            assert!(
+                build_config(format!("[logs]\n{invalid}\n")).is_err(),
+                "{invalid}"
+            );
+        }
+
+        let err = build_config("[logs]\nlevel = \"verbose\"\n").unwrap_err();
+        assert!(
+            matches!(&err.kind, error::ConfigErrorKind::Invalid(e) if e.contains("\"verbose\""))
+        );
+
+        let config = build_config(
+            "[logs]\noutput = [\"file\", \"syslog\"]\nsyslog_facility = \"local3\"\nerror_log = \"error.log\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.logs.outputs,
+            [
+                LogDestination::File,
+                LogDestination::Syslog { facility: 19 }
+            ]
+        );
+        assert_eq!(config.logs.error_log.as_deref(), Some("error.log"));
+
+        for invalid in [
+            "output = \"kafka\"",
+            "output = \"journald\"\nsyslog_facility = \"local3\"",
+            "output = \"syslog\"\nsyslog_facility = \"local9\"",
+        ] {
+            assert!(
+                build_config(format!("[logs]\n{invalid}\n")).is_err(),
+                "{invalid}"
+            );
+        }
+
+        let config = build_config(
+            "[alerts]\nwebhook = \"https://hooks.example.com/T0/secret\"\nthreshold = { count = 20, window = \"2m\" }\n",
+        )
+        .unwrap();
+        let alerts = config.alerts.as_ref().unwrap();
+        assert_eq!(alerts.status, StatusRange::parse("5xx").unwrap());
+        assert_eq!(
+            (alerts.count, alerts.window, alerts.cooldown),
+            (20, 120, 600)
+        );
+        assert!(config
+            .dump()
+            .contains("webhook = https://hooks.example.com/<redacted>"));
+        for invalid in [
+            "webhook = \"hooks.example.com\"",
+            "webhook = \"ftp://hooks.example.com\"",
+            "webhook = \"https://hooks.example.com\"\nthreshold = { status = \"2xxx\" }",
+            "webhook = \"https://hooks.example.com\"\nthreshold = { count = 0 }",
+            "webhook = \"https://hooks.example.com\"\ncooldown = \"1 week\"",
+        ] {
+            assert!(
+                build_config(format!("[alerts]\n{invalid}\n")).is_err(),
+                "{invalid}"
+            );
+        }
+
+        let err = build_config("[logs]\nkeep = 5\n").unwrap_err();
+        assert!(
+            matches!(&err.kind, error::ConfigErrorKind::Invalid(e) if e.contains("keep requires"))
+        );
+    }
+
+    #[test]
+    fn disabled_services() {
+        let config = build_config(
+            r#"
+            [services.on]
+            domain = "on.example.com"
+            locations = [
+              { source = "/*", target = "http://127.0.0.1:3000" },
+              { source = "/old/*", target = "http://127.0.0.1:3001", enabled = false },
+            ]
+
+            [services.off]
+            domain = "off.example.com"
+            enabled = false
+            tls.certificate = "tests/certs/ecdsa.pem"
+            tls.key = "tests/certs/ecdsa.key"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3002" }]
+            "#,
+        )
+        .unwrap();
+        let main = &config.servers[MAIN_SERVER_NAME];
+        assert!(main.tls.is_none());
+        assert!(main.params.auto_tls.is_empty());
+        let mut domains: Vec<&String> = main.params.routes.keys().collect();
+        domains.sort();
+        assert_eq!(domains, ["on.example.com", "www.on.example.com"]);
+        assert_eq!(main.params.routes["on.example.com"].len(), 1);
+
+        // A disabled service is still validated.
+        assert!(build_config(
+            r#"
+            [services.off]
+            domain = "off.example.com"
+            enabled = false
+            redirections = [
+              { source = "/a", target = "https://example.com/b" },
+              { source = "/a", target = "https://example.com/c" },
+            ]
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn route_matching() {
+        let config = build_config(
+            r#"
+            [services.app]
+            domain = "example.com"
+            locations = [
+              { source = "/*", target = "http://127.0.0.1:3000" },
+              { source = "/api/*", target = "http://127.0.0.1:3001" },
+              { source = "/api/v2/*", target = "http://127.0.0.1:3002" },
+              { source = "/api/v2", target = "http://127.0.0.1:3003" },
+            ]
+
+            [services.api]
+            domain = "api.example.com"
+            locations = [{ source = "/api/v2/x/*", target = "http://127.0.0.1:3004" }]
+            "#,
+        )
+        .unwrap();
+        let params = &config.servers[MAIN_SERVER_NAME].params;
+        let target = |domain: &str, path: &str| {
+            params
+                .find_route(domain, path)
+                .map(|route| match &route.target {
+                    TargetType::Location(location) => location.params.location[0].as_str(),
+                    _ => unreachable!(),
+                })
+        };
+        let cases = [
+            ("example.com", "/", Some("http://127.0.0.1:3000")),
+            ("example.com", "/apiv2", Some("http://127.0.0.1:3000")),
+            ("example.com", "/api", Some("http://127.0.0.1:3001")),
+            ("example.com", "/api?page=2", Some("http://127.0.0.1:3001")),
+            ("example.com", "/api/users", Some("http://127.0.0.1:3001")),
+            ("example.com", "/api/v2x", Some("http://127.0.0.1:3001")),
+            // The strict route wins over the prefix of the same path.
+            ("example.com", "/api/v2", Some("http://127.0.0.1:3003")),
+            ("example.com", "/api/v2/", Some("http://127.0.0.1:3003")),
+            (
+                "example.com",
+                "/api/v2?page=2",
+                Some("http://127.0.0.1:3003"),
+            ),
+            (
+                "example.com",
+                "/api/v2/users",
+                Some("http://127.0.0.1:3002"),
+            ),
+            // The longer route of a sibling domain doesn't capture the request.
+            ("example.com", "/api/v2/x/1", Some("http://127.0.0.1:3002")),
+            (
+                "api.example.com",
+                "/api/v2/x/1",
+                Some("http://127.0.0.1:3004"),
+            ),
+            ("api.example.com", "/api/v2", None),
+            ("other.com", "/", None),
+        ];
+        for (domain, path, expected) in cases {
+            assert_eq!(target(domain, path), expected, "{domain}{path}");
+        }
+    }
+
+    #[test]
+    fn tls_redirection_options() {
+        let build = |redirections: [&str; 2]| {
+            build_config(format!(
+                r#"
+                [servers.main]
+                https_port = 8443
+
+                [services.a]
+                domain = "a.example.com"
+                tls.certificate = "tests/certs/ecdsa.pem"
+                tls.key = "tests/certs/ecdsa.key"
+                {}
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+
+                [services.b]
+                domain = "b.example.com"
+                tls.certificate = "tests/certs/ecdsa.pem"
+                tls.key = "tests/certs/ecdsa.key"
+                {}
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3001" }}]
+                "#,
+                redirections[0], redirections[1]
+            ))
+            .map(|config| config.servers[MAIN_SERVER_NAME].params.auto_tls.clone())
+        };
+
+        let auto_tls = build([
+            "",
+            r#"tls.redirection = { code = 301, exclude_paths = ["/.well-known/acme-challenge/"] }"#,
+        ])
+        .unwrap();
+        assert_eq!(
+            auto_tls["a.example.com"],
+            TlsRedirection {
+                authority: "a.example.com:8443".to_string(),
+                code: 308,
+                exclude_paths: Vec::new(),
+            }
+        );
+        assert_eq!(auto_tls["b.example.com"].code, 301);
+        assert_eq!(
+            auto_tls["b.example.com"].exclude_paths,
+            ["/.well-known/acme-challenge/"]
+        );
+
+        let auto_tls = build(["tls.redirection = false", "tls.redirection = true"]).unwrap();
+        assert_eq!(auto_tls.keys().collect::<Vec<_>>(), ["b.example.com"]);
+
+        for invalid in [
+            "tls.redirection = { code = 303 }",
+            r#"tls.redirection = { exclude_paths = ["acme"] }"#,
+        ] {
+            let err = build(["", invalid]).unwrap_err().to_string();
+            assert!(
+                err.contains("Invalid tls.redirection in [services.b]"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn optional_tls() {
+        let build = |cert: &str, optional: bool| {
+            build_config(format!(
+                r#"
+                [services.a]
+                domain = "a.example.com"
+                tls.certificate = "{cert}"
+                tls.key = "tests/certs/ecdsa.key"
+                tls_optional = {optional}
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+
+                [services.b]
+                domain = "b.example.com"
+                tls.certificate = "{cert}"
+                tls.key = "tests/certs/ecdsa.key"
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3001" }}]
+                "#
+            ))
+            .unwrap()
+        };
+
+        let config = build("tests/certs/ecdsa.pem", true);
+        let server = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(server.tls.as_ref().unwrap().len(), 1);
+        let mut services = server.cert_services["tests/certs/ecdsa.pem"].clone();
+        services.sort();
+        assert_eq!(services, ["a", "b"]);
+        assert!(server.params.auto_tls.contains_key("a.example.com"));
+
+        // The missing certificate of b is reported when the files are read.
+        let config = build("tests/certs/missing.pem", true);
+        let server = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(
+            server.cert_services["tests/certs/missing.pem"],
+            ["b".to_string()]
+        );
+        assert!(!server.params.auto_tls.contains_key("a.example.com"));
+        assert!(server.params.auto_tls.contains_key("b.example.com"));
+        let TargetType::Redirection(www) = &server.params.routes["www.a.example.com"][0].target
+        else {
+            panic!("www.a.example.com isn't redirected");
+        };
+        assert_eq!(www.params.location, "http://a.example.com");
+
+        let config = build("tests/certs/missing.pem", false);
+        let server = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(server.cert_services["tests/certs/missing.pem"].len(), 2);
+    }
+
+    #[test]
+    fn forwarded_headers_list() {
+        let build = |forwarded_headers: &str| {
+            build_config(format!(
+                r#"
+                [services.a]
+                domain = "example.com"
+                {forwarded_headers}
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+                "#
+            ))
+            .map(|config| {
+                let TargetType::Location(location) =
+                    &config.servers[MAIN_SERVER_NAME].params.routes["example.com"][0].target
+                else {
+                    panic!("not a location");
+                };
+                location.forwarded_headers
+            })
+        };
+
+        assert_eq!(build("").unwrap(), ForwardedHeaders::default());
+        assert_eq!(
+            build(r#"forwarded_headers = ["for", "Proto"]"#).unwrap(),
+            ForwardedHeaders {
+                r#for: true,
+                host: false,
+                proto: true,
+                port: false,
+            }
+        );
+        let err = build(r#"forwarded_headers = ["for", "server"]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Invalid forwarded_headers in [services.a]: unknown header server"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn allowed_methods_list() {
+        let build = |services: &str| {
+            build_config(services).map(|config| {
+                config.servers[MAIN_SERVER_NAME]
+                    .params
+                    .allowed_methods
+                    .clone()
+            })
+        };
+        let service = |name: &str, allowed_methods: &str| {
+            format!(
+                r#"
+                [services.{name}]
+                domain = "example.com"
+                {allowed_methods}
+                locations = [{{ source = "/{name}/*", target = "http://127.0.0.1:3000" }}]
+                "#
+            )
+        };
+
+        assert!(build(&service("a", "")).unwrap().is_empty());
+        let methods = build(&service("a", r#"allowed_methods = ["GET", "POST", "GET"]"#)).unwrap();
+        assert_eq!(methods["example.com"], ["GET", "POST", "HEAD"]);
+        let methods = build(&service("a", r#"allowed_methods = ["PROPFIND"]"#)).unwrap();
+        assert_eq!(methods["example.com"], ["PROPFIND"]);
+
+        for (list, error) in [
+            ("[]", "the list is empty"),
+            (r#"["get"]"#, "must be written \"GET\""),
+            (r#"["GET", "CONNECT"]"#, "CONNECT is always refused"),
+            (r#"["GE T"]"#, "invalid method"),
+            (
+                r#"["GET", "TRACE"]"#,
+                "TRACE is refused, set deny_trace = false",
+            ),
+        ] {
+            let err = build(&service("a", &format!("allowed_methods = {list}")))
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.contains("Invalid allowed_methods in [services.a]") && err.contains(error),
+                "{err}"
+            );
+        }
+
+        // The services of a domain share the list.
+        let shared = format!(
+            "{}{}",
+            service("a", r#"allowed_methods = ["GET"]"#),
+            service("b", r#"allowed_methods = ["GET"]"#)
+        );
+        assert!(build(&shared).is_ok());
+        let conflicting = format!(
+            "{}{}",
+            service("a", r#"allowed_methods = ["GET"]"#),
+            service("b", "")
+        );
+        let err = build(&conflicting).unwrap_err().to_string();
+        assert!(
+            err.contains("Conflicting allowed_methods for example.com"),
+            "{err}"
+        );
+
+        let trace = format!(
+            "{}{}",
+            service("a", r#"deny_trace = false"#),
+            service("b", "")
+        );
+        let err = build(&trace).unwrap_err().to_string();
+        assert!(
+            err.contains("Conflicting deny_trace for example.com"),
+            "{err}"
+        );
+        let trace = format!(
+            "[global]\ndeny_trace = false\n{}",
+            service("a", r#"allowed_methods = ["TRACE"]"#)
+        );
+        assert!(build(&trace).is_ok());
+    }
+
+    #[test]
+    fn client_auth_header() {
+        let build = |client_auth: &str| {
+            build_config(format!(
+                r#"
+                [services.a]
+                domain = "example.com"
+                tls.certificate = "tests/certs/ecdsa.pem"
+                tls.key = "tests/certs/ecdsa.key"
+                tls.client_auth = {{ ca = "tests/certs/chain_ca.pem"{client_auth} }}
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+                "#
+            ))
+        };
+
+        let config = build("").unwrap();
+        let main = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(
+            main.params.client_cert_header.as_deref(),
+            Some(DEFAULT_CLIENT_CERT_HEADER)
+        );
+        assert_eq!(
+            main.client_auth.as_ref().unwrap().mode,
+            ClientAuthMode::Require
+        );
+
+        let config = build(r#", mode = "optional", header = "X-Client-DN""#).unwrap();
+        let main = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(
+            main.params.client_cert_header.as_deref(),
+            Some("X-Client-DN")
+        );
+        assert_eq!(
+            main.client_auth.as_ref().unwrap().mode,
+            ClientAuthMode::Optional
+        );
+
+        let err = build(r#", header = "X Client DN""#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Invalid tls.client_auth in [services.a]: \"X Client DN\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn listing_hide_patterns() {
+        let build = |listing_hide: &str| {
+            build_config(format!(
+                    r#"
+                    [services.a]
+                    domain = "example.com"
+                    file_servers = [
+                      {{ source = "/files/*", target = "/var/www/", authorized_dirs = ["/pub"], listing_hide = {listing_hide} }},
+                    ]
+                    "#
+                )).map(|config| {
+                config.servers[MAIN_SERVER_NAME].params.routes["example.com"]
+                    .iter()
+                    .map(|route| match &route.target {
+                        TargetType::FileServer(file_server) => {
+                            file_server.listing_hide.patterns().join(" ")
+                        }
+                        _ => panic!("not a file server"),
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Shared with the authorized dirs.
+        let hidden = build(r#"["*.tmp", ".snapshot"]"#).unwrap();
+        assert_eq!(hidden.len(), 2);
+        assert!(hidden.iter().all(|hide| hide == "*.tmp .snapshot"));
+
+        // Compiled again by the server process.
+        let globs = GlobSet::new(&["*.tmp".to_string()]).unwrap();
+        let encoded = bincode::encode_to_vec(&globs, bincode::config::standard()).unwrap();
+        let (decoded, _): (GlobSet, _) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+        assert!(decoded.matches("upload.tmp") && !decoded.matches("report.pdf"));
+
+        let err = build(r#"["[abc"]"#).unwrap_err().to_string();
+        assert!(
+            err.contains("Invalid listing_hide of the file server /files/*: \"[abc\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn autoindex_options() {
+        let listed = |file_server: &str| {
+            let config = build_config(format!(
+                r#"
+                [services.a]
+                domain = "example.com"
+                file_servers = [{{ source = "/files/*", target = "/var/www/", {file_server} }}]
+                "#
+            ))
+            .unwrap();
+            let mut listed: Vec<(String, bool)> = config.servers[MAIN_SERVER_NAME].params.routes
+                ["example.com"]
+                .iter()
+                .map(|route| match &route.target {
+                    TargetType::FileServer(file_server) => {
+                        (route.path.clone(), !file_server.forbidden_dir)
+                    }
+                    _ => panic!("not a file server"),
+                })
+                .collect();
+            listed.sort();
+            listed
+        };
+        let routes = |routes: &[(&str, bool)]| {
+            routes
+                .iter()
+                .map(|(path, listed)| (path.to_string(), *listed))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(listed("autoindex = false"), routes(&[("/files", false)]));
+        assert_eq!(listed("autoindex = true"), routes(&[("/files", true)]));
+        // The authorized dirs are listed unless they opt out, with the table
+        // form or the "!" prefix, whose autoindex takes precedence.
+        assert_eq!(
+            listed(
+                r#"authorized_dirs = [
+                  "/a/*",
+                  "!/b/*",
+                  { path = "/c/*", autoindex = false },
+                  { path = "/d/*" },
+                  { path = "!/e/*", autoindex = true },
+                  { path = "!/f/*" },
+                ]"#
+            ),
+            routes(&[
+                ("/files", false),
+                ("/files/a", true),
+                ("/files/b", false),
+                ("/files/c", false),
+                ("/files/d", true),
+                ("/files/e", true),
+                ("/files/f", false),
+            ])
+        );
+    }
+
+    #[test]
+    fn www_redirection_services() {
+        let toml = r#"
+            [services.apex]
+            domain = "example.com"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3000" }]
+
+            [services.www]
+            domain = "www.example.com"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3001" }]
+
+            [services.api]
+            domain = "api.example.com"
+            www_redirect = false
+            locations = [{ source = "/*", target = "http://127.0.0.1:3002" }]
+
+            [services.blog]
+            domain = "blog.example.com"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3003" }]
+            "#;
+        // Whatever the order of the services.
+        for _ in 0..8 {
+            let config = build_config(toml).unwrap();
+            let routes = &config.servers[MAIN_SERVER_NAME].params.routes;
+            let mut domains: Vec<&String> = routes.keys().collect();
+            domains.sort();
+            assert_eq!(
+                domains,
+                [
+                    "api.example.com",
+                    "blog.example.com",
+                    "example.com",
+                    "www.blog.example.com",
+                    "www.example.com",
+                ]
+            );
+            for (domain, backend) in [
+                ("example.com", "http://127.0.0.1:3000"),
+                ("www.example.com", "http://127.0.0.1:3001"),
+            ] {
+                assert_eq!(routes[domain].len(), 1);
+                let TargetType::Location(location) = &routes[domain][0].target else {
+                    panic!("{domain} is redirected");
+                };
+                assert_eq!(location.params.location, [backend]);
+            }
+            assert!(matches!(
+                routes["www.blog.example.com"][0].target,
+                TargetType::Redirection(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn service_aliases() {
+        let build = |other: &str| {
+            build_config(format!(
+                r#"
+                [servers.main]
+                https_port = 8443
+
+                [services.app]
+                domain = "example.com"
+                aliases = ["example.net", "www.example.com"]
+                tls.certificate = "tests/certs/ecdsa.pem"
+                tls.key = "tests/certs/ecdsa.key"
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3000" }}]
+
+                [services.other]
+                domain = "{other}"
+                locations = [{{ source = "/*", target = "http://127.0.0.1:3001" }}]
+                "#
+            ))
+        };
+
+        let config = build("other.com").unwrap();
+        let server = &config.servers[MAIN_SERVER_NAME];
+        let params = &server.params;
+        let mut domains: Vec<&String> = params.routes.keys().collect();
+        domains.sort();
+        // The www counterpart of the domain is an alias, not a redirection.
+        assert_eq!(
+            domains,
+            [
+                "example.com",
+                "other.com",
+                "www.example.net",
+                "www.other.com"
+            ]
+        );
+        assert_eq!(params.canonical_domain("example.net"), "example.com");
+        assert_eq!(params.canonical_domain("other.com"), "other.com");
+        // The same target, with the same id.
+        let id = |domain| match &params.find_route(domain, "/").unwrap().target {
+            TargetType::Location(location) => location.id,
+            _ => panic!("{domain} is redirected"),
+        };
+        assert_eq!(id("example.net"), id("example.com"));
+        assert_eq!(params.auto_tls["example.net"].authority, "example.net:8443");
+        let TargetType::Redirection(redirection) = &params.routes["www.example.net"][0].target
+        else {
+            panic!("www.example.net isn't redirected");
+        };
+        assert_eq!(redirection.params.location, "https://example.net:8443");
+        let mut tls_aliases: Vec<&str> = server
+            .tls_aliases
+            .iter()
+            .map(|alias| alias.domain.as_str())
+            .collect();
+        tls_aliases.sort();
+        assert_eq!(tls_aliases, ["example.net", "www.example.com"]);
+
+        for domain in ["example.net", "www.example.com"] {
+            let err = build(domain).unwrap_err().to_string();
+            assert!(
+                err.contains(&format!(
+                    "Conflicting alias {domain} in [services.app]: it is the domain of [services.other]"
+                )),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn slash_normalization() {
+        // Sources written with extra slashes.
+        let config = build_config(
+            r#"
+            [services.app]
+            domain = "example.com"
+            locations = [
+              { source = "/app//*", target = "http://127.0.0.1:3001" },
+              { source = "//exact/", target = "http://127.0.0.1:3002" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let params = &config.servers[MAIN_SERVER_NAME].params;
+        let sources: Vec<_> = params.routes["example.com"]
+            .iter()
+            .map(ServerRoute::source)
+            .collect();
+        assert_eq!(sources, ["/exact", "/app/*"]);
+
+        let target = |path: &str| {
+            let path = utils::merge_slashes(path);
+            params
+                .find_route("example.com", &path)
+                .map(|route| match &route.target {
+                    TargetType::Location(location) => location.params.location[0].as_str(),
+                    _ => unreachable!(),
+                })
+        };
+        let app = Some("http://127.0.0.1:3001");
+        let exact = Some("http://127.0.0.1:3002");
+        let cases = [
+            ("/app", app),
+            ("/app/", app),
+            ("//app", app),
+            ("/app//", app),
+            ("//app//x", app),
+            ("/app///x/", app),
+            ("/app%2F%2Fx", None),
+            ("/exact", exact),
+            ("/exact/", exact),
+            ("//exact", exact),
+            ("/exact//", exact),
+            ("/exact//?a=//b", exact),
+            ("/exact/x", None),
+            ("/exact%2F", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(target(path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn defaults_precedence() {
+        let config = build_config(
+            r#"
+            [defaults]
+            proxy_timeout = 10
+            connect_timeout = 1
+            headers.locations.request.set = { A = "defaults", B = "defaults", C = "defaults", D = "defaults" }
+
+            [servers.main]
+            headers.locations.request.set = { B = "server", C = "server", D = "server" }
+            [servers.other]
+            port = 8080
+            proxy_timeout = 20
+            connect_timeout = 2
+
+            [services.a]
+            domain = "a.example.com"
+            proxy_timeout = 30
+            connect_timeout = 3
+            headers.locations.request.set = { C = "service", D = "service" }
+            locations = [
+              { source = "/location/*", target = "http://127.0.0.1:3000", proxy_timeout = 40, connect_timeout = 4, headers.request.set = { D = "location" } },
+              { source = "/*", target = "http://127.0.0.1:3000" },
+            ]
+
+            [services.b]
+            domain = "b.example.com"
+            server = "other"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3000" }]
+            "#,
+        )
+        .unwrap();
+
+        // The timeouts and the request headers of the route at `path`.
+        let resolve = |server: &str, domain: &str, path: &str| {
+            let server = &config.servers[server];
+            let route = server.params.routes[domain]
+                .iter()
+                .find(|r| r.path == path)
+                .unwrap();
+            let TargetType::Location(location) = &route.target else {
+                panic!("not a location");
+            };
+            let headers = location.params.headers.request.clone().unwrap_or_default();
+            (
+                location
+                    .proxy_timeout
+                    .unwrap_or(server.params.proxy_timeout),
+                location
+                    .connect_timeout
+                    .unwrap_or(server.params.connect_timeout),
+                headers.set.unwrap_or_default(),
+            )
+        };
+
+        let (timeout, connect_timeout, headers) = resolve("main", "a.example.com", "/location");
+        assert_eq!(timeout, 40);
+        assert_eq!(connect_timeout, 4);
+        assert_eq!(headers["A"], "defaults");
+        assert_eq!(headers["B"], "server");
+        assert_eq!(headers["C"], "service");
+        assert_eq!(headers["D"], "location");
+
+        let (timeout, connect_timeout, headers) = resolve("main", "a.example.com", "");
+        assert_eq!(timeout, 30);
+        assert_eq!(connect_timeout, 3);
+        assert_eq!(headers["D"], "service");
+
+        // No server headers, and the server timeout wins over the defaults.
+        let (timeout, connect_timeout, headers) = resolve("other", "b.example.com", "");
+        assert_eq!(timeout, 20);
+        assert_eq!(connect_timeout, 2);
+        assert_eq!(headers["B"], "defaults");
+        assert_eq!(config.servers["main"].params.proxy_timeout, 10);
+        assert_eq!(config.servers["main"].params.connect_timeout, 1);
+
+        // Built-in constant.
+        let config = build_config("").unwrap();
+        assert_eq!(
+            config.servers["main"].params.proxy_timeout,
+            DEFAULT_PROXY_TIMEOUT
+        );
+        assert_eq!(
+            config.servers["main"].params.connect_timeout,
+            DEFAULT_CONNECT_TIMEOUT
+        );
+
+        // A connect timeout of 0 would fail every connection.
+        let service = r#"
+            [services.a]
+            domain = "a.example.com"
+            locations = [{ source = "/*", target = "http://127.0.0.1:3000" }]
+        "#;
+        let invalid = [
+            "[defaults]\nconnect_timeout = 0",
+            "[servers.main]\nconnect_timeout = 0",
+        ];
+        for block in invalid {
+            assert!(
+                build_config(format!("{block}\n{service}")).is_err(),
+                "{block}"
+            );
+        }
+        assert!(
+            build_config(service.replace("locations", "connect_timeout = 0\nlocations")).is_err()
+        );
+        assert!(build_config(service.replace("3000\"", "3000\", connect_timeout = 0")).is_err());
+    }
+
+    #[test]
+    fn vars_in_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            import = ["${vars.sites}/*.toml"]
+            [vars]
+            sites = "sites"
+            backend = "10.0.0.5"
+            port = "3000"
+            "#,
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("sites")).unwrap();
+        fs::write(
+            dir.path().join("sites/app.toml"),
+            r#"
+            vars.port = "4000"
+            [services.app]
+            domain = "example.com"
+            locations = [{ source = "/*", target = "http://${vars.backend}:${vars.port}" }]
+            "#,
+        )
+        .unwrap();
+        let config = InternalConfig::build_from(path.to_string_lossy().to_string()).unwrap();
+        let route = &config.servers[MAIN_SERVER_NAME].params.routes["example.com"][0];
+        let TargetType::Location(location) = &route.target else {
+            panic!("not a location");
+        };
+        assert_eq!(location.params.location, ["http://10.0.0.5:4000"]);
+    }
+
+    #[test]
+    fn yaml_and_json_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let path_str = path.to_string_lossy().to_string();
+        fs::write(
+            &path,
+            r#"{
+              "import": ["sites"],
+              "vars": { "backend": "10.0.0.5" },
+              "global": { "max_conn_per_ip": null },
+              "services": {
+                "api": {
+                  "domain": "api.example.com",
+                  "locations": [{ "source": "/*", "target": "http://${vars.backend}:3000" }]
+                }
+              }
+            }"#,
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("sites")).unwrap();
+        fs::write(
+            dir.path().join("sites/app.yaml"),
+            "services:\n  app:\n    domain: example.com\n    locations:\n      - source: /*\n        target: http://${vars.backend}:4000\n",
+        )
+        .unwrap();
+        let config = InternalConfig::build_from(path_str.clone()).unwrap();
+        let routes = &config.servers[MAIN_SERVER_NAME].params.routes;
+        for (domain, target) in [
+            ("api.example.com", "http://10.0.0.5:3000"),
+            ("example.com", "http://10.0.0.5:4000"),
+        ] {
+            let TargetType::Location(location) = &routes[domain][0].target else {
+                panic!("not a location");
+            };
+            assert_eq!(location.params.location, [target]);
+        }
+
+        fs::write(&path, "{\n  \"services\": {\n    \"api\": 1\n  }\n}").unwrap();
+        let err = InternalConfig::build_from(path_str).unwrap_err();
+        assert!(
+            matches!(
+                err.kind,
+                error::ConfigErrorKind::Parse {
+                    position: Some((3, _)),
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert!(err.to_string().starts_with("Failed to parse json file"));
+
+        let path = dir.path().join("config.yml");
+        fs::write(&path, "services:\n  api:\n    domain: [\n").unwrap();
+        let err = InternalConfig::build_from(path.to_string_lossy().to_string()).unwrap_err();
+        assert!(
+            matches!(
+                err.kind,
+                error::ConfigErrorKind::Parse {
+                    position: Some(_),
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn redirection_headers() {
+        let config = build_config(
+            r#"
+            [servers.main]
+            headers.redirections.set = { Cache-Control = "no-store", X-Level = "server" }
+
+            [services.app]
+            domain = "example.com"
+            headers.redirections = { set = { X-Level = "service" }, del = ["Server"] }
+            redirections = [
+              { source = "/old", target = "https://example.org/new", headers.set = { X-Tracking = "1" } },
+            ]
+            "#,
+        )
+        .unwrap();
+        let route = config.servers[MAIN_SERVER_NAME].params.routes["example.com"]
+            .iter()
+            .find(|r| r.path == "/old")
+            .unwrap();
+        let TargetType::Redirection(redirection) = &route.target else {
+            panic!("not a redirection");
+        };
+        assert!(redirection.params.headers.request.is_none());
+        let response = redirection.params.headers.response.as_ref().unwrap();
+        let set = response.set.as_ref().unwrap();
+        assert_eq!(set["Cache-Control"], "no-store");
+        assert_eq!(set["X-Level"], "service");
+        assert_eq!(set["X-Tracking"], "1");
+        assert_eq!(response.del.as_deref(), Some(&["Server".to_string()][..]));
+
+        // Unknown codes are rejected instead of replaced.
+        let err = build_config(
+            r#"
+            [services.app]
+            domain = "example.com"
+            redirections = [
+              { source = "/form", target = "https://example.org/done", code = 303 },
+              { source = "/old", target = "https://example.org/new", code = 304 },
+            ]
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid redirection code 304"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn backend_tables() {
+        let loadbalancers = |backends: &str| {
+            let toml = format!("[pool]\nalgo = \"round_robin\"\nbackends = {backends}");
+            Some(toml::from_str::<HashMap<String, toml_model::Loadbalancer>>(&toml).unwrap())
+        };
+
+        let config = get_backends_config(
+            "http://${pool}",
+            &loadbalancers(
+                r#"[
+                  { url = "10.0.0.1", weight = 3 },
+                  { url = "10.0.0.2", max_conns = 100 },
+                  { url = "10.0.0.3", weight = 5, backup = true },
+                ]"#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            config.backends,
+            ["http://10.0.0.1", "http://10.0.0.2", "http://10.0.0.3"]
+        );
+        assert_eq!(config.weights, Some(vec![3, 1, 0]));
+        assert_eq!(config.options[1].max_conns, Some(100));
+        assert!(config.options[2].backup);
+
+        // Plain urls.
+        let config = get_backends_config(
+            "http://${pool}",
+            &loadbalancers(r#"["10.0.0.1", "10.0.0.2"]"#),
+        )
+        .unwrap();
+        assert_eq!(config.backends, ["http://10.0.0.1", "http://10.0.0.2"]);
+        assert_eq!(config.weights, None);
+        assert!(config.options.is_empty());
+
+        let err = get_backends_config(
+            "http://${pool}",
+            &loadbalancers(r#"["10.0.0.1", { url = "10.0.0.2", weight = 2 }]"#),
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("can't mix urls and tables"), "{err}");
+
+        let pool = |algo: &str, decay: &str| {
+            let toml = format!("[pool]\nalgo = \"{algo}\"\nbackends = [\"a\"]\n{decay}");
+            Some(toml::from_str::<HashMap<String, toml_model::Loadbalancer>>(&toml).unwrap())
+        };
+        let config = get_backends_config("http://${pool}", &pool("least_time", "")).unwrap();
+        assert_eq!(config.ewma_decay, DEFAULT_EWMA_DECAY);
+        let config =
+            get_backends_config("http://${pool}", &pool("least_time", "ewma_decay = 0.5")).unwrap();
+        assert_eq!(config.ewma_decay, 0.5);
+        for (algo, decay, expected) in [
+            ("least_time", "ewma_decay = 0.0", "must be over 0"),
+            ("least_time", "ewma_decay = 1.5", "must be over 0"),
+            (
+                "round_robin",
+                "ewma_decay = 0.5",
+                "only used by the least_time algo",
+            ),
+        ] {
+            let err = get_backends_config("http://${pool}", &pool(algo, decay))
+                .err()
+                .unwrap();
+            assert!(err.contains(expected), "{err}");
+        }
+
+        let outliers = "[pool.outlier_detection]\nerror_percent = 20\nejection_time = 60";
+        let config = get_backends_config("http://${pool}", &pool("round_robin", outliers)).unwrap();
+        let outliers = config.outlier_detection.unwrap();
+        assert_eq!((outliers.error_percent, outliers.ejection_time), (20, 60));
+        assert_eq!(outliers.max_ejection_time, DEFAULT_MAX_EJECTION_TIME);
+        for (outliers, expected) in [
+            ("interval = 0", "must be at least 1"),
+            (
+                "max_ejection_time = 10",
+                "can't be shorter than ejection_time",
+            ),
+            ("error_percent = 100", "must be between 1 and 99"),
+            ("max_ejection_percent = 101", "can't be over 100"),
+        ] {
+            let outliers = format!("[pool.outlier_detection]\n{outliers}");
+            let err = get_backends_config("http://${pool}", &pool("round_robin", &outliers))
+                .err()
+                .unwrap();
+            assert!(err.contains(expected), "{err}");
+        }
+    }
+
+    #[test]
+    fn imports_expansion() {
+        let dir = tempfile::tempdir().unwrap();
+        let sites = dir.path().join("sites-enabled");
+        fs::create_dir(&sites).unwrap();
+        for (file, service) in [("b.toml", "b"), ("a.toml", "a"), ("c.txt", "c")] {
+            let content = format!("[services.{service}]\ndomain = \"{service}.com\"\n");
+            fs::write(sites.join(file), content).unwrap();
+        }
+
+        let files = expand_import("sites-enabled/*.toml", dir.path()).unwrap();
+        assert_eq!(files, [sites.join("a.toml"), sites.join("b.toml")]);
+        let files = expand_import("sites-enabled", dir.path()).unwrap();
+        assert_eq!(files, [sites.join("a.toml"), sites.join("b.toml")]);
+        assert!(expand_import("empty/*.toml", dir.path())
+            .unwrap()
+            .is_empty());
+
+        let mut config: ConfigToml = toml::from_str("").unwrap();
+        let imports = ["sites-enabled".to_string()];
+        import_sub_toml_configs(
+            &mut config,
+            "main.toml",
+            &imports,
+            dir.path(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(config.services.as_ref().unwrap().len(), 2);
+
+        // Service defined twice.
+        let mut config: ConfigToml = toml::from_str("[services.a]\ndomain = \"a.org\"").unwrap();
+        let err = import_sub_toml_configs(
+            &mut config,
+            "main.toml",
+            &imports,
+            dir.path(),
+            &HashMap::new(),
+        )
+        .unwrap_err();
+        assert!(err.path.ends_with("a.toml"), "{err}");
+        assert_eq!(err.context, ["main.toml"]);
+    }
+
+    #[test]
+    fn restart_settings_ignore_routes() {
+        let mut config = build_config("[services.app]\ndomain = \"example.com\"\n").unwrap();
+        let settings = config.restart_settings();
+        assert_eq!(
+            settings,
+            [
+                "[servers.main] port 80, http2 true, no tls, tcp_nodelay default, tcp_keepalive default, tcp_defer_accept default",
+                "[global] user quark, group quark, accept_loops 1"
+            ]
+        );
+
+        let server = config.servers.get_mut("main").unwrap();
+        server.params.proxy_timeout += 1;
+        server.tls_options.min_version = TlsVersion::Tls13;
+        assert_eq!(config.restart_settings(), settings);
+
+        config.servers.get_mut("main").unwrap().port = 8080;
+        assert_ne!(config.restart_settings(), settings);
+        config.servers.get_mut("main").unwrap().port = 80;
+        config.global.user = "www-data".to_string();
+        assert_ne!(config.restart_settings(), settings);
+    }
+
     #[test]
     fn merge_headers_actions() {
         let ha = header_action_mock();
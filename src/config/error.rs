@@ -0,0 +1,109 @@
+use std::{error::Error, fmt, io};
+
+use super::format::ConfigFormat;
+
+// Error raised while loading the configuration. It keeps the file where the
+// error was found and, for imported files, the chain of files importing it.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: String,
+    pub kind: ConfigErrorKind,
+    pub context: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigErrorKind {
+    Io(io::Error),
+    Parse {
+        error: Box<dyn Error + Send + Sync>,
+        position: Option<(usize, usize)>, // Line and column, starting at 1.
+    },
+    Invalid(String),
+}
+
+impl ConfigError {
+    pub fn io(path: &str, error: io::Error) -> ConfigError {
+        ConfigError::new(path, ConfigErrorKind::Io(error))
+    }
+
+    pub fn parse(path: &str, source: &str, error: toml::de::Error) -> ConfigError {
+        let position = error.span().map(|span| line_and_column(source, span.start));
+        ConfigError::parse_with_position(path, error, position)
+    }
+
+    pub fn parse_with_position(
+        path: &str,
+        error: impl Error + Send + Sync + 'static,
+        position: Option<(usize, usize)>,
+    ) -> ConfigError {
+        ConfigError::new(
+            path,
+            ConfigErrorKind::Parse {
+                error: Box::new(error),
+                position,
+            },
+        )
+    }
+
+    pub fn invalid(path: &str, message: impl Into<String>) -> ConfigError {
+        ConfigError::new(path, ConfigErrorKind::Invalid(message.into()))
+    }
+
+    // Add a file to the import chain.
+    pub fn imported_from(mut self, path: &str) -> ConfigError {
+        self.context.push(path.to_string());
+        self
+    }
+
+    fn new(path: &str, kind: ConfigErrorKind) -> ConfigError {
+        ConfigError {
+            path: path.to_string(),
+            kind,
+            context: Vec::new(),
+        }
+    }
+}
+
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = ConfigFormat::from_path(&self.path).name();
+        match &self.kind {
+            ConfigErrorKind::Io(e) => write!(f, "Failed to open {format} file {}. {e}", self.path)?,
+            ConfigErrorKind::Parse {
+                error,
+                position: Some((line, column)),
+            } => write!(
+                f,
+                "Failed to parse {format} file {}:{line}:{column}.\n{error}",
+                self.path
+            )?,
+            ConfigErrorKind::Parse { error, .. } => {
+                write!(f, "Failed to parse {format} file {}.\n{error}", self.path)?
+            }
+            ConfigErrorKind::Invalid(message) => {
+                write!(f, "Invalid configuration file {}.\n{message}", self.path)?
+            }
+        }
+        for path in &self.context {
+            write!(f, "\n  imported from {path}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            ConfigErrorKind::Io(e) => Some(e),
+            ConfigErrorKind::Parse { error, .. } => Some(error.as_ref() as &(dyn Error + 'static)),
+            ConfigErrorKind::Invalid(_) => None,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+use super::ConfigError;
+
+// Format of a configuration file, chosen from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    // TOML is used when the extension is unknown.
+    pub fn from_path(path: impl AsRef<Path>) -> ConfigFormat {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    // Files loaded when a directory is imported.
+    pub fn is_config_file(path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "toml" | "yaml" | "yml" | "json"))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Json => "json",
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, path: &str, source: &str) -> Result<T, ConfigError> {
+        match self {
+            ConfigFormat::Toml => {
+                toml::from_str(source).map_err(|e| ConfigError::parse(path, source, e))
+            }
+            ConfigFormat::Yaml => serde_yaml::from_str(source).map_err(|e| {
+                let position = e.location().map(|l| (l.line(), l.column()));
+                ConfigError::parse_with_position(path, e, position)
+            }),
+            ConfigFormat::Json => serde_json::from_str(source).map_err(|e| {
+                let position = (e.line() > 0).then(|| (e.line(), e.column()));
+                ConfigError::parse_with_position(path, e, position)
+            }),
+        }
+    }
+
+    // Parse the file as a generic table, to expand its variables.
+    pub fn parse_table(self, path: &str, source: &str) -> Result<Table, ConfigError> {
+        if self == ConfigFormat::Toml {
+            return self.parse(path, source);
+        }
+        match to_toml_value(self.parse(path, source)?) {
+            Some(Value::Table(table)) => Ok(table),
+            _ => Err(ConfigError::invalid(path, "The root must be a table")),
+        }
+    }
+}
+
+// TOML has no null, the null values are removed as if they were not set.
+fn to_toml_value(value: serde_json::Value) -> Option<Value> {
+    Some(match value {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Bool(b) => Value::Boolean(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Float(n.as_f64()?),
+        },
+        serde_json::Value::String(s) => Value::String(s),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.into_iter().filter_map(to_toml_value).collect())
+        }
+        serde_json::Value::Object(map) => Value::Table(
+            map.into_iter()
+                .filter_map(|(k, v)| Some((k, to_toml_value(v)?)))
+                .collect(),
+        ),
+    })
+}
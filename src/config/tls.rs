@@ -1,33 +1,64 @@
 use std::collections::HashMap;
 use std::io::{self, Cursor};
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use arc_swap::ArcSwap;
 use bincode::{Decode, Encode};
+use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
 use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
 use notify::{EventKind, RecommendedWatcher, Watcher};
-use rustls::crypto::aws_lc_rs::sign::any_supported_type;
-use rustls::server::{ClientHello, ResolvesServerCert};
+use pkcs8::der::pem::{LineEnding, PemLabel};
+use pkcs8::der::zeroize::Zeroizing;
+use pkcs8::{Document, EncryptedPrivateKeyInfo, PrivateKeyInfo};
+use rustls::crypto::aws_lc_rs::{self, sign::any_supported_type};
+use rustls::crypto::{GetRandomFailed, SupportedKxGroup};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+    ServerSessionMemoryCache, WebPkiClientVerifier,
+};
 use rustls::sign::CertifiedKey;
-use rustls::ServerConfig;
+use rustls::InconsistentKeys;
+use rustls::{
+    ConfigBuilder, RootCertStore, ServerConfig, SignatureAlgorithm, SignatureScheme,
+    SupportedCipherSuite, SupportedProtocolVersion, TicketRotator, WantsVerifier,
+};
 use rustls_pki_types::pem::PemObject;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::net::UnixStream;
 use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 use x509_parser::parse_x509_certificate;
-use x509_parser::pem::parse_x509_pem;
 use x509_parser::prelude::{GeneralName, ParsedExtension, X509Certificate};
 
 use futures::channel::mpsc::channel;
 
 use crate::ipc;
 
-use super::TlsCertificate;
+use super::{ClientAuthMode, TlsCertificate, TlsOptions, TlsVersion};
 
-pub type CertifiedKeyList = HashMap<String, ArcSwap<CertifiedKey>>;
+// Domain -> certificates of the domain, ECDSA ones first.
+pub type CertifiedKeyList = HashMap<String, Vec<CertifiedKeyEntry>>;
+
+#[derive(Debug, Clone)]
+pub struct CertifiedKeyEntry {
+    path: String, // Identify the certificate on reload.
+    key: Arc<CertifiedKey>,
+}
+
+// Swapped wholesale when the certificates are reloaded.
+pub type SharedCertifiedKeyList = ArcSwap<CertifiedKeyList>;
+
+// Passphrase of the encrypted keys without a key_passphrase_file.
+const KEY_PASSPHRASE_ENV: &str = "QUARK_KEY_PASSPHRASE";
+
+// Key of the default certificate in the CertifiedKeyList.
+// An empty string can't be a valid SNI.
+const DEFAULT_CERT_KEY: &str = "";
 
 pub struct TlsConfig<'a> {
     certs: &'a Vec<IpcCerts>,
@@ -49,105 +80,438 @@ impl<'a> TlsConfig<'a> {
     }
 
     // Generate and return the rustls server config.
-    pub fn get_tls_config(&self, resolver: SniCertResolver) -> ServerConfig {
-        let mut config_tls = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_cert_resolver(Arc::new(resolver));
+    pub fn get_tls_config(
+        &self,
+        resolver: SniCertResolver,
+        options: &TlsOptions,
+        client_auth: Option<(&ClientAuthMode, &[u8])>,
+    ) -> Result<ServerConfig, String> {
+        let builder = server_config_builder(options)?;
+        let builder = match client_auth {
+            Some((mode, ca)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca).map_err(|e| format!("Invalid client CA : {e}"))? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("Invalid client CA : {e}"))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = match mode {
+                    ClientAuthMode::Require => verifier,
+                    ClientAuthMode::Optional => verifier.allow_unauthenticated(),
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| format!("Can't build the client verifier : {e}"))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config_tls = builder.with_cert_resolver(Arc::new(resolver));
+
+        config_tls.alpn_protocols = options.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        // Session resumption.
+        config_tls.session_storage = if options.session_cache_size > 0 {
+            ServerSessionMemoryCache::new(options.session_cache_size)
+        } else {
+            Arc::new(NoServerSessionStorage {})
+        };
+        if options.session_tickets {
+            let ticketer = TicketRotator::new(options.ticket_rotation, ticket_generator)
+                .map_err(|e| format!("Can't create the session ticketer : {e}"))?;
+            config_tls.ticketer = Arc::new(ticketer);
+        } else if options.session_cache_size == 0 {
+            config_tls.send_tls13_tickets = 0;
+        }
+
+        Ok(config_tls)
+    }
+}
 
-        config_tls.alpn_protocols =
-            vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+// The keys of the provider ticketer are only rotated every 6 hours, so it is
+// wrapped in a rotator using the configured interval.
+#[derive(Debug)]
+struct Ticketer(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
+}
+
+fn ticket_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    let ticketer = aws_lc_rs::Ticketer::new().map_err(|_| GetRandomFailed)?;
+    Ok(Box::new(Ticketer(ticketer)))
+}
 
-        config_tls
+// Build the rustls config builder with the protocol versions,
+// cipher suites and key exchange groups defined in the options.
+pub fn server_config_builder(
+    options: &TlsOptions,
+) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, String> {
+    let versions: Vec<&'static SupportedProtocolVersion> = [
+        (TlsVersion::Tls12, &rustls::version::TLS12),
+        (TlsVersion::Tls13, &rustls::version::TLS13),
+    ]
+    .into_iter()
+    .filter(|(version, _)| (options.min_version..=options.max_version).contains(version))
+    .map(|(_, version)| version)
+    .collect();
+
+    let mut provider = aws_lc_rs::default_provider();
+    if !options.cipher_suites.is_empty() {
+        provider.cipher_suites = options
+            .cipher_suites
+            .iter()
+            .map(|name| cipher_suite_from_name(name))
+            .collect::<Result<_, _>>()?;
+    }
+    if !options.kx_groups.is_empty() {
+        provider.kx_groups = options
+            .kx_groups
+            .iter()
+            .map(|name| kx_group_from_name(name))
+            .collect::<Result<_, _>>()?;
     }
+
+    ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&versions)
+        .map_err(|e| e.to_string())
+}
+
+fn cipher_suite_from_name(name: &str) -> Result<SupportedCipherSuite, String> {
+    let suites = aws_lc_rs::ALL_CIPHER_SUITES;
+    suites
+        .iter()
+        .find(|suite| suite.suite().as_str() == Some(name))
+        .copied()
+        .ok_or_else(|| {
+            let supported: Vec<&str> = suites.iter().filter_map(|s| s.suite().as_str()).collect();
+            format!(
+                "unknown cipher suite \"{name}\" (supported: {})",
+                supported.join(", ")
+            )
+        })
+}
+
+fn kx_group_from_name(name: &str) -> Result<&'static dyn SupportedKxGroup, String> {
+    let groups = aws_lc_rs::ALL_KX_GROUPS;
+    groups
+        .iter()
+        .find(|group| group.name().as_str() == Some(name))
+        .copied()
+        .ok_or_else(|| {
+            let supported: Vec<&str> = groups.iter().filter_map(|g| g.name().as_str()).collect();
+            format!(
+                "unknown key exchange group \"{name}\" (supported: {})",
+                supported.join(", ")
+            )
+        })
 }
 
 // Custom SNI resolver.
 #[derive(Debug)]
 pub struct SniCertResolver {
-    certs: Arc<CertifiedKeyList>,
+    certs: Arc<SharedCertifiedKeyList>,
 }
 
 impl ResolvesServerCert for SniCertResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let schemes = client_hello.signature_schemes();
+        let certs = self.certs.load();
         if let Some(server_name) = client_hello.server_name() {
             tracing::trace!("SNI requested: {}", server_name);
 
-            if let Some(cert) = self.certs.get(&server_name.to_string()) {
-                tracing::trace!("SNI resolved to: {}", server_name);
-                return Some(cert.load_full());
+            if let Some(name) = resolve_name(&certs, server_name) {
+                tracing::trace!("SNI resolved to: {}", name);
+                return select_certificate(&certs[&name], schemes);
             }
+            tracing::trace!(
+                "No certificate found for SNI: {}. Available names: {:?}",
+                server_name,
+                available_names(&certs)
+            );
+        } else {
+            tracing::trace!("No SNI provided by client.");
+        }
 
-            //  Try wildcards.
-            let wildcard_name = convert_to_wildcard(server_name);
-            if let Some(cert) = self.certs.get(&wildcard_name) {
-                tracing::trace!("SNI resolved to: {}", wildcard_name);
-                return Some(cert.load_full());
-            }
+        if let Some(entries) = certs.get(DEFAULT_CERT_KEY) {
+            tracing::trace!("SNI resolved to the default certificate");
+            return select_certificate(entries, schemes);
         }
-        tracing::warn!("No SNI provided by client.");
+        tracing::warn!("No certificate found for the client. Handshake rejected.");
         None
     }
 }
 
+// Return the name of the list matching the SNI, the exact name first, then the wildcard.
+fn resolve_name(certs: &CertifiedKeyList, server_name: &str) -> Option<String> {
+    let mut names = vec![server_name.to_string()];
+    names.extend(convert_to_wildcard(server_name));
+    tracing::debug!(
+        "Certificate names tried for SNI {}: {:?}",
+        server_name,
+        names
+    );
+    names.into_iter().find(|name| certs.contains_key(name))
+}
+
+fn available_names(certs: &CertifiedKeyList) -> Vec<&str> {
+    let mut names: Vec<&str> = certs
+        .keys()
+        .map(String::as_str)
+        .filter(|name| *name != DEFAULT_CERT_KEY)
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+// Pick the first certificate the client can verify.
+fn select_certificate(
+    entries: &[CertifiedKeyEntry],
+    schemes: &[SignatureScheme],
+) -> Option<Arc<CertifiedKey>> {
+    entries
+        .iter()
+        .find(|entry| entry.key.key.choose_scheme(schemes).is_some())
+        .or_else(|| entries.first())
+        .map(|entry| entry.key.clone())
+}
+
 impl SniCertResolver {
-    pub fn new(ck_list: Arc<CertifiedKeyList>) -> SniCertResolver {
+    pub fn new(ck_list: Arc<SharedCertifiedKeyList>) -> SniCertResolver {
         SniCertResolver { certs: ck_list }
     }
 }
 
-fn convert_to_wildcard(server_name: &str) -> String {
-    let explode_name: Vec<&str> = server_name.split('.').collect();
-    let mut i: u8 = 0;
-    let wildcard_name: Vec<&str> = explode_name
-        .into_iter()
-        .map(|x| {
-            i += 1;
-            if i == 1 {
-                "*"
-            } else {
-                x
-            }
-        })
-        .collect();
-
-    wildcard_name.join(".")
+// A wildcard only covers the first label (RFC 6125), so a.b.example.com
+// doesn't match *.example.com. IP literals and single labels have no wildcard.
+fn convert_to_wildcard(server_name: &str) -> Option<String> {
+    if server_name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    match server_name.split_once('.') {
+        Some((_, parent)) if !parent.is_empty() => Some(format!("*.{parent}")),
+        _ => None,
+    }
 }
 
 fn add_certificate_to_certified_key_list(cert: &IpcCerts, ck_list: &mut CertifiedKeyList) {
-    let (domains, ck) = get_domains_and_ck(cert);
+    // Already checked by the parent process.
+    let (domains, ck, not_after) = match get_domains_and_ck(cert) {
+        Ok(res) => res,
+        Err(e) => {
+            tracing::error!("{e}");
+            return;
+        }
+    };
+    record_expiry(&cert.path, &domains, not_after);
+
+    if cert.default {
+        insert_certified_key(ck_list, DEFAULT_CERT_KEY, &cert.path, &ck);
+    }
 
     domains.iter().for_each(|domain| {
-        ck_list.insert(domain.to_string(), ArcSwap::new(ck.clone()));
+        insert_certified_key(ck_list, domain, &cert.path, &ck);
     })
 }
 
-pub fn reload_certificates(cert: &IpcCerts, ck_list: Arc<CertifiedKeyList>) {
-    let (domains, ck) = get_domains_and_ck(cert);
+fn insert_certified_key(
+    ck_list: &mut CertifiedKeyList,
+    domain: &str,
+    path: &str,
+    ck: &Arc<CertifiedKey>,
+) {
+    let entries = ck_list.entry(domain.to_string()).or_default();
+    match entries.iter_mut().find(|entry| entry.path == path) {
+        Some(entry) => entry.key = ck.clone(),
+        None => entries.push(CertifiedKeyEntry {
+            path: path.to_string(),
+            key: ck.clone(),
+        }),
+    }
+    // Prefer ECDSA certificates, RSA ones are kept for older clients.
+    entries.sort_by_key(|entry| entry.key.key.algorithm() == SignatureAlgorithm::RSA);
+}
 
-    domains.iter().for_each(|domain| {
-        if let Some(ack) = ck_list.get(domain) {
-            ack.store(ck.clone());
+// Rebuild the list from the reloaded certificates, so the domains added to
+// or removed from their SANs are taken into account.
+// The certificates that couldn't be read keep their previous domains.
+pub fn reload_certificates(certs: &[IpcCerts], ck_list: &SharedCertifiedKeyList) {
+    let mut new_list: CertifiedKeyList = HashMap::new();
+    for cert in certs {
+        add_certificate_to_certified_key_list(cert, &mut new_list);
+    }
+
+    for (domain, entries) in ck_list.load().iter() {
+        entries
+            .iter()
+            .filter(|entry| !certs.iter().any(|cert| cert.path == entry.path))
+            .for_each(|entry| insert_certified_key(&mut new_list, domain, &entry.path, &entry.key));
+    }
+
+    ck_list.store(Arc::new(new_list));
+}
+
+// Return the domains, the certified key and the expiry timestamp.
+// Check that the key matches the certificate and that the chain is ordered.
+fn get_domains_and_ck(cert: &IpcCerts) -> Result<(Vec<String>, Arc<CertifiedKey>, i64), String> {
+    let (path, key_path) = (&cert.path, &cert.key_path);
+    let cert_der =
+        load_certs(&cert.cert).map_err(|e| format!("Invalid certificate {path} : {e}"))?;
+    if cert_der.is_empty() {
+        return Err(format!("No certificate found in {path}"));
+    }
+    let key = load_private_key(&cert.key).map_err(|e| format!("Invalid key {key_path} : {e}"))?;
+    let key_sign =
+        any_supported_type(&key).map_err(|e| format!("Unsupported key {key_path} : {e}"))?;
+
+    let chain = cert_der
+        .iter()
+        .map(|der| parse_x509_certificate(der).map(|(_, x509_cert)| x509_cert))
+        .collect::<Result<Vec<X509Certificate>, _>>()
+        .map_err(|e| format!("Invalid certificate {path} : {e}"))?;
+    check_chain_order(&chain).map_err(|e| format!("Invalid chain in {path} : {e}"))?;
+    let leaf = &chain[0];
+    let (domains, not_after) = (
+        extract_domains_from_x509(leaf),
+        leaf.validity().not_after.timestamp(),
+    );
+
+    let ck = CertifiedKey::new(cert_der.clone(), key_sign);
+    match ck.keys_match() {
+        // The public key can't be extracted from some key types.
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => (),
+        Err(_) => return Err(format!("key {key_path} does not match certificate {path}")),
+    }
+
+    Ok((domains, Arc::new(ck), not_after))
+}
+
+// Each certificate must be signed by the next one, the leaf first.
+fn check_chain_order(chain: &[X509Certificate]) -> Result<(), String> {
+    for (index, pair) in chain.windows(2).enumerate() {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+        if cert.verify_signature(Some(issuer.public_key())).is_err() {
+            return Err(format!(
+                "certificate {} ({}) is not signed by the next one ({}). \
+                The chain must be ordered from the leaf to the root",
+                index + 1,
+                cert.subject(),
+                issuer.subject()
+            ));
         }
-    });
+    }
+    Ok(())
 }
 
-fn get_domains_and_ck(cert: &IpcCerts) -> (Vec<String>, Arc<CertifiedKey>) {
-    let cert_buffer = cert.cert.clone();
-    let cert_der = load_certs(&cert.cert).unwrap();
-    let key = load_private_key(&cert.key).unwrap();
+// Expiry of the loaded certificates. Certificate path -> CertExpiry.
+static CERTS_EXPIRY: LazyLock<DashMap<String, CertExpiry>> = LazyLock::new(DashMap::new);
+static EXPIRY_WARNING_DAYS: AtomicU64 = AtomicU64::new(0);
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
 
-    let key_sign = any_supported_type(&key).unwrap();
+#[derive(Debug)]
+struct CertExpiry {
+    domains: Vec<String>,
+    not_after: i64,
+}
 
-    let ck = Arc::new(CertifiedKey::new(cert_der, key_sign));
+#[derive(Debug, PartialEq)]
+enum ExpiryStatus {
+    Valid,
+    ExpiresSoon(i64), // Remaining days.
+    Expired,
+}
 
-    let (_, pem) = parse_x509_pem(&cert_buffer).unwrap();
+fn expiry_status(not_after: i64, now: i64, warning_days: u64) -> ExpiryStatus {
+    let remaining = not_after - now;
+    if remaining <= 0 {
+        ExpiryStatus::Expired
+    } else if remaining < warning_days as i64 * SECONDS_PER_DAY {
+        ExpiryStatus::ExpiresSoon(remaining / SECONDS_PER_DAY)
+    } else {
+        ExpiryStatus::Valid
+    }
+}
 
-    let domains: Vec<String> = match parse_x509_certificate(&pem.contents) {
-        Ok((_, x509_cert)) => extract_domains_from_x509(&x509_cert),
-        Err(e) => panic!("{e:?}"),
+fn record_expiry(path: &str, domains: &[String], not_after: i64) {
+    let expiry = CertExpiry {
+        domains: domains.to_vec(),
+        not_after,
     };
+    log_expiry(path, &expiry);
+    CERTS_EXPIRY.insert(path.to_string(), expiry);
+}
+
+fn log_expiry(path: &str, expiry: &CertExpiry) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let warning_days = EXPIRY_WARNING_DAYS.load(Ordering::Relaxed);
+    let domains = expiry.domains.join(", ");
+    match expiry_status(expiry.not_after, now, warning_days) {
+        ExpiryStatus::Expired => {
+            tracing::error!("Certificate {} has expired. Domains: {}", path, domains)
+        }
+        ExpiryStatus::ExpiresSoon(days) => tracing::warn!(
+            "Certificate {} expires in {} days. Domains: {}",
+            path,
+            days,
+            domains
+        ),
+        ExpiryStatus::Valid => tracing::trace!("Certificate {} is valid", path),
+    }
+}
 
-    (domains, ck)
+// Check the expiry of the loaded certificates once a day.
+// Certificates are also checked each time they are loaded or reloaded.
+pub fn start_expiry_check(warning_days: u64) {
+    EXPIRY_WARNING_DAYS.store(warning_days, Ordering::Relaxed);
+    tokio::spawn(async {
+        let period = std::time::Duration::from_secs(SECONDS_PER_DAY as u64);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            for entry in CERTS_EXPIRY.iter() {
+                log_expiry(entry.key(), entry.value());
+            }
+        }
+    });
+}
+
+// Loaded certificate, listed by the admin API.
+pub struct LoadedCertificate {
+    pub path: String,
+    pub domains: Vec<String>,
+    pub not_after: i64, // Unix timestamp.
+}
+
+// Certificates loaded by the child process, sorted by path.
+pub fn loaded_certificates() -> Vec<LoadedCertificate> {
+    let mut certs: Vec<LoadedCertificate> = CERTS_EXPIRY
+        .iter()
+        .map(|entry| LoadedCertificate {
+            path: entry.key().clone(),
+            domains: entry.domains.clone(),
+            not_after: entry.not_after,
+        })
+        .collect();
+    certs.sort_by(|a, b| a.path.cmp(&b.path));
+    certs
 }
 
 fn extract_domains_from_x509(x509: &X509Certificate) -> Vec<String> {
@@ -165,6 +529,27 @@ fn extract_domains_from_x509(x509: &X509Certificate) -> Vec<String> {
     domain_names
 }
 
+// Get the subject of the certificate presented by the client.
+pub fn peer_subject(certs: &[CertificateDer]) -> Option<String> {
+    let cert = certs.first()?;
+    match parse_x509_certificate(cert) {
+        Ok((_, x509_cert)) => Some(x509_cert.subject().to_string()),
+        Err(_) => None,
+    }
+}
+
+// Read the CA bundle used to verify the client certificates.
+pub async fn read_client_ca(path: &str) -> Result<Vec<u8>, String> {
+    let ca = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("Can't read the client CA {path} : {e}"))?;
+    match load_certs(&ca) {
+        Ok(certs) if !certs.is_empty() => Ok(ca),
+        Ok(_) => Err(format!("No certificate found in the client CA {path}")),
+        Err(e) => Err(format!("Invalid client CA {path} : {e}")),
+    }
+}
+
 // Load public certificate from buffer.
 fn load_certs(buf: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
     let reader = Cursor::new(buf);
@@ -179,86 +564,108 @@ fn load_private_key(buf: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
     PrivateKeyDer::from_pem_reader(reader).map_err(io::Error::other)
 }
 
-// Start to watch for certificates changes.
-// Run it in a tokio task.
-pub async fn watch_certs(
-    paths_to_watch: &Vec<PathBuf>,
+// Start to watch for certificates changes, they are sent to the child
+// process from a tokio task. Fails if a path can't be watched.
+pub fn watch_certs(
+    paths_to_watch: &[PathBuf],
     port: u16,
     stream: Arc<Mutex<UnixStream>>,
     certs: Vec<TlsCertificate>,
-) {
+    default_cert: Option<TlsCertificate>,
+) -> Result<JoinHandle<()>, String> {
     println!("Watch certificates paths : {paths_to_watch:?}");
 
     let (mut tx, mut rx) = channel(1);
 
+    // The receiver is dropped with the task.
     let mut watcher = RecommendedWatcher::new(
-        move |res| futures::executor::block_on(async { tx.send(res).await.unwrap() }),
+        move |res| {
+            futures::executor::block_on(async {
+                tx.send(res).await.ok();
+            })
+        },
         notify::Config::default(),
     )
-    .unwrap();
+    .map_err(|e| format!("Can't watch the certificates of port {port}: {e}"))?;
 
     for path in paths_to_watch {
         watcher
             .watch(path, notify::RecursiveMode::Recursive)
-            .unwrap();
+            .map_err(|e| {
+                format!(
+                    "Can't watch {} for the certificates of port {port}: {e}",
+                    path.display()
+                )
+            })?;
     }
 
-    // Prepare debounce
-    let notify = Arc::new(Notify::new());
-    let notify_clone = Arc::clone(&notify);
-    let debouncing = Arc::new(AtomicBool::new(false));
-    let debouncing_clone = debouncing.clone();
-
-    // Watch if file changed
-    tokio::spawn(async move {
-        while let Some(res) = rx.next().await {
-            match res {
-                Ok(event) => {
-                    if event.kind == EventKind::Access(AccessKind::Close(AccessMode::Write))
-                        || event.kind == EventKind::Modify(ModifyKind::Name(RenameMode::Both))
-                    {
-                        println!("[Main Process] File changed: {}", event.paths[0].display());
-                        if !debouncing.load(Ordering::Relaxed) {
-                            // Launch debouncing to avoid to send the files multiple times
-                            notify.notify_one();
-                            debouncing.store(true, Ordering::Relaxed);
+    Ok(tokio::spawn(async move {
+        // Stopped when the task is aborted.
+        let _watcher = watcher;
+        // Prepare debounce
+        let notify = Arc::new(Notify::new());
+        let notify_clone = Arc::clone(&notify);
+        let debouncing = Arc::new(AtomicBool::new(false));
+        let debouncing_clone = debouncing.clone();
+
+        // Watch if file changed
+        tokio::spawn(async move {
+            while let Some(res) = rx.next().await {
+                match res {
+                    Ok(event) => {
+                        if event.kind == EventKind::Access(AccessKind::Close(AccessMode::Write))
+                            || event.kind == EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+                        {
+                            println!("[Main Process] File changed: {}", event.paths[0].display());
+                            if !debouncing.load(Ordering::Relaxed) {
+                                // Launch debouncing to avoid to send the files multiple times
+                                notify.notify_one();
+                                debouncing.store(true, Ordering::Relaxed);
+                            }
                         }
                     }
+
+                    Err(e) => eprintln!("watch error: {e:?}"),
                 }
+            }
+        });
 
-                Err(e) => eprintln!("watch error: {e:?}"),
+        // Debounce
+        loop {
+            notify_clone.notified().await;
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            // Reload certificates
+            let mut cert_list: Vec<IpcCerts> = Vec::new();
+            for cert in certs.iter() {
+                match IpcCerts::build(cert).await {
+                    Ok(mut certs) => {
+                        certs.default = default_cert.as_ref() == Some(cert);
+                        cert_list.push(certs)
+                    }
+                    Err(e) => {
+                        eprintln!("[Main Process] Can't reload a certificate of port {port}. {e}")
+                    }
+                }
             }
-        }
-    });
 
-    // Debounce
-    loop {
-        notify_clone.notified().await;
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-        // Reload certificates
-        let mut cert_list: Vec<IpcCerts> = Vec::new();
-        for cert in certs.iter() {
-            match IpcCerts::build(&cert.cert, &cert.key).await {
-                Ok(certs) => cert_list.push(certs),
-                Err(e) => eprintln!("Error. {e}"),
+            if cert_list.is_empty() {
+                eprintln!("[Main Process] No certificate of port {port} could be read, the running ones are kept");
+                debouncing_clone.store(false, Ordering::Relaxed);
+                continue;
             }
-        }
+            let message = ipc::IpcMessage {
+                kind: ipc::MessageKind::CertsReload,
+                key: Some(port.to_string()),
+                payload: cert_list,
+            };
 
-        if cert_list.is_empty() {
-            println!("[Parent] No certificates found");
-            return;
+            // They are sent again when the child reconnects.
+            if let Err(e) = ipc::send_ipc_message(stream.clone(), message).await {
+                eprintln!("[Main Process] Failed to send the certificates of port {port}. {e}");
+            }
+            debouncing_clone.store(false, Ordering::Relaxed);
         }
-        let message = ipc::IpcMessage {
-            kind: "reload".to_string(),
-            key: Some(port.to_string()),
-            payload: cert_list,
-        };
-
-        ipc::send_ipc_message(stream.clone(), message)
-            .await
-            .unwrap();
-        debouncing_clone.store(false, Ordering::Relaxed);
-    }
+    }))
 }
 
 // Struct to send certs via IPC.
@@ -266,34 +673,590 @@ pub async fn watch_certs(
 pub struct IpcCerts {
     pub cert: Vec<u8>,
     pub key: Vec<u8>,
+    pub default: bool, // Used when the SNI doesn't match any certificate.
+    pub path: String,  // Certificate path, for logs.
+    pub key_path: String,
 }
 
 impl IpcCerts {
-    pub async fn build(cert: &str, key: &str) -> Result<IpcCerts, String> {
-        let certfile = tokio::fs::read(cert)
-            .await
-            .map_err(|e| format!("Can't read the certificate {cert} : {e}"))?;
-        let keyfile = tokio::fs::read(key)
+    pub async fn build(tls_cert: &TlsCertificate) -> Result<IpcCerts, String> {
+        let tls_cert = tls_cert.clone();
+        tokio::task::spawn_blocking(move || IpcCerts::build_blocking(&tls_cert))
             .await
-            .map_err(|e| format!("Can't read the key {key} : {e}"))?;
+            .map_err(|e| format!("Can't read the certificate : {e}"))?
+    }
+
+    // Also used to check the certificates while the configuration is built.
+    pub fn build_blocking(tls_cert: &TlsCertificate) -> Result<IpcCerts, String> {
+        let (cert, key) = (&tls_cert.cert, &tls_cert.key);
+        let certfile =
+            std::fs::read(cert).map_err(|e| format!("Can't read the certificate {cert} : {e}"))?;
+        let mut keyfile =
+            std::fs::read(key).map_err(|e| format!("Can't read the key {key} : {e}"))?;
 
-        Ok(IpcCerts {
+        // The child process only receives decrypted keys.
+        if is_encrypted_key(&keyfile) {
+            let passphrase = read_key_passphrase(tls_cert)?;
+            keyfile = decrypt_private_key(key, &keyfile, &passphrase)?;
+        }
+
+        let certs = IpcCerts {
             cert: certfile,
             key: keyfile,
-        })
+            default: false,
+            path: cert.to_string(),
+            key_path: key.to_string(),
+        };
+        get_domains_and_ck(&certs)?;
+
+        Ok(certs)
+    }
+
+    // Whether the certificate is valid for the name, directly or with a
+    // wildcard.
+    pub fn covers(&self, name: &str) -> bool {
+        let Ok((domains, _, _)) = get_domains_and_ck(self) else {
+            return false;
+        };
+        let wildcard = convert_to_wildcard(name);
+        domains
+            .iter()
+            .any(|domain| domain == name || Some(domain) == wildcard.as_ref())
+    }
+}
+
+fn is_encrypted_key(buf: &[u8]) -> bool {
+    String::from_utf8_lossy(buf).contains(&format!(
+        "-----BEGIN {}-----",
+        EncryptedPrivateKeyInfo::PEM_LABEL
+    ))
+}
+
+// Read the passphrase from the key_passphrase_file or from the environment.
+fn read_key_passphrase(tls_cert: &TlsCertificate) -> Result<Zeroizing<String>, String> {
+    let key = &tls_cert.key;
+    match &tls_cert.key_passphrase_file {
+        Some(path) => {
+            let content = std::fs::read_to_string(path).map_err(|e| {
+                format!("Can't read the passphrase file {path} of the key {key} : {e}")
+            })?;
+            let content = Zeroizing::new(content);
+            Ok(Zeroizing::new(
+                content.trim_end_matches(['\r', '\n']).to_string(),
+            ))
+        }
+        None => std::env::var(KEY_PASSPHRASE_ENV)
+            .map(Zeroizing::new)
+            .map_err(|_| {
+                format!(
+                    "The key {key} is encrypted but no passphrase is defined. \
+                Set key_passphrase_file or the {KEY_PASSPHRASE_ENV} environment variable"
+                )
+            }),
     }
 }
 
+// Decrypt a PKCS#8 encrypted key and return it as an unencrypted PEM.
+fn decrypt_private_key(path: &str, buf: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    let pem = std::str::from_utf8(buf).map_err(|e| format!("Invalid key {path} : {e}"))?;
+    let (_, document) =
+        Document::from_pem(pem).map_err(|e| format!("Invalid encrypted key {path} : {e}"))?;
+    let info = EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+        .map_err(|e| format!("Invalid encrypted key {path} : {e}"))?;
+    let decrypted = info.decrypt(passphrase).map_err(|_| {
+        format!("Can't decrypt the key {path} : wrong passphrase or unsupported encryption")
+    })?;
+    let pem = decrypted
+        .to_pem(PrivateKeyInfo::PEM_LABEL, LineEnding::LF)
+        .map_err(|e| format!("Can't decrypt the key {path} : {e}"))?;
+    Ok(pem.as_bytes().to_vec())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::tls::convert_to_wildcard;
+    use rustls::{CipherSuite, ClientConfig, HandshakeKind, NamedGroup};
+    use rustls_pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use crate::config::tls::*;
+    use crate::server::server_utils::NoCertificateVerification;
+
+    fn ecdsa_certs() -> Vec<IpcCerts> {
+        vec![IpcCerts {
+            cert: include_bytes!("../../tests/certs/ecdsa.pem").to_vec(),
+            key: include_bytes!("../../tests/certs/ecdsa.key").to_vec(),
+            default: false,
+            path: "tests/certs/ecdsa.pem".to_string(),
+            key_path: "tests/certs/ecdsa.key".to_string(),
+        }]
+    }
+
+    fn rsa_certs() -> Vec<IpcCerts> {
+        vec![IpcCerts {
+            cert: include_bytes!("../../tests/certs/rsa.pem").to_vec(),
+            key: include_bytes!("../../tests/certs/rsa.key").to_vec(),
+            default: false,
+            path: "tests/certs/rsa.pem".to_string(),
+            key_path: "tests/certs/rsa.key".to_string(),
+        }]
+    }
+
+    fn server_config(certs: &Vec<IpcCerts>, options: &TlsOptions) -> ServerConfig {
+        let mut tls_config = TlsConfig::new(certs);
+        let resolver = SniCertResolver::new(Arc::new(ArcSwap::from_pointee(
+            tls_config.get_certified_key_list(),
+        )));
+        tls_config.get_tls_config(resolver, options, None).unwrap()
+    }
+
+    fn client_config(
+        suites: &[SupportedCipherSuite],
+        kx_groups: &[&'static dyn SupportedKxGroup],
+    ) -> ClientConfig {
+        let mut provider = aws_lc_rs::default_provider();
+        provider.cipher_suites = suites.to_vec();
+        provider.kx_groups = kx_groups.to_vec();
+        ClientConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+            .with_no_client_auth()
+    }
+
+    // Perform a handshake in memory and return the negotiated suite and group.
+    async fn handshake(
+        server: ServerConfig,
+        client: ClientConfig,
+    ) -> Result<(CipherSuite, NamedGroup), io::Error> {
+        handshake_with_name(server, client, "example.com").await
+    }
+
+    async fn handshake_with_name(
+        server: ServerConfig,
+        client: ClientConfig,
+        server_name: &str,
+    ) -> Result<(CipherSuite, NamedGroup), io::Error> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        let connector = TlsConnector::from(Arc::new(client));
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let (client_res, server_res) = tokio::join!(
+            connector.connect(name, client_io),
+            acceptor.accept(server_io)
+        );
+        let stream = client_res?;
+        server_res?;
+        let conn = stream.get_ref().1;
+        Ok((
+            conn.negotiated_cipher_suite().unwrap().suite(),
+            conn.negotiated_key_exchange_group().unwrap().name(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn negotiation_honors_cipher_suites() {
+        let certs = ecdsa_certs();
+        let options = TlsOptions {
+            cipher_suites: vec!["TLS13_CHACHA20_POLY1305_SHA256".to_string()],
+            ..TlsOptions::default()
+        };
+        let server = server_config(&certs, &options);
+        let client = client_config(aws_lc_rs::ALL_CIPHER_SUITES, aws_lc_rs::ALL_KX_GROUPS);
+        let (suite, _) = handshake(server, client).await.unwrap();
+        assert_eq!(suite, CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+
+        // The client doesn't offer the only allowed suite.
+        let server = server_config(&certs, &options);
+        let client = client_config(
+            &[aws_lc_rs::cipher_suite::TLS13_AES_128_GCM_SHA256],
+            aws_lc_rs::ALL_KX_GROUPS,
+        );
+        assert!(handshake(server, client).await.is_err());
+    }
+
+    // Connect once and tell if the session was resumed. The client reads until the
+    // server closes, to receive the TLS 1.3 tickets sent after the handshake.
+    async fn is_resumed(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> bool {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let acceptor = TlsAcceptor::from(server);
+        let connector = TlsConnector::from(client);
+        let name = ServerName::try_from("example.com").unwrap();
+        let (client_res, server_res) = tokio::join!(
+            connector.connect(name, client_io),
+            acceptor.accept(server_io)
+        );
+        let mut client_stream = client_res.unwrap();
+        let mut server_stream = server_res.unwrap();
+        server_stream.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        client_stream.read_to_end(&mut buf).await.unwrap();
+        client_stream.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed)
+    }
+
+    #[tokio::test]
+    async fn session_resumption() {
+        let certs = ecdsa_certs();
+        for (session_tickets, session_cache_size) in [(true, 0), (false, 256), (false, 0)] {
+            let options = TlsOptions {
+                session_tickets,
+                session_cache_size,
+                ..TlsOptions::default()
+            };
+            let server = Arc::new(server_config(&certs, &options));
+            let client = Arc::new(client_config(
+                aws_lc_rs::ALL_CIPHER_SUITES,
+                aws_lc_rs::ALL_KX_GROUPS,
+            ));
+            assert!(!is_resumed(server.clone(), client.clone()).await);
+            let resumed = is_resumed(server, client).await;
+            assert_eq!(resumed, session_tickets || session_cache_size > 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn default_certificate_for_unknown_sni() {
+        let client = || client_config(aws_lc_rs::ALL_CIPHER_SUITES, aws_lc_rs::ALL_KX_GROUPS);
+
+        // Strict SNI, no default certificate.
+        let certs = ecdsa_certs();
+        let server = server_config(&certs, &TlsOptions::default());
+        assert!(handshake_with_name(server, client(), "unknown.org")
+            .await
+            .is_err());
+
+        let mut certs = ecdsa_certs();
+        certs[0].default = true;
+        let server = server_config(&certs, &TlsOptions::default());
+        assert!(handshake_with_name(server, client(), "unknown.org")
+            .await
+            .is_ok());
+
+        // No SNI sent by the client.
+        let server = server_config(&certs, &TlsOptions::default());
+        let mut no_sni_client = client();
+        no_sni_client.enable_sni = false;
+        assert!(handshake(server, no_sni_client).await.is_ok());
+    }
+
+    #[test]
+    fn ecdsa_and_rsa_for_same_domain() {
+        // Declare RSA first, ECDSA must still be preferred.
+        let mut certs = rsa_certs();
+        certs.extend(ecdsa_certs());
+        let ck_list = TlsConfig::new(&certs).get_certified_key_list();
+        let entries = ck_list.get("example.com").unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let algorithm = |schemes: &[SignatureScheme]| {
+            select_certificate(entries, schemes)
+                .unwrap()
+                .key
+                .algorithm()
+        };
+        let all = [
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA256,
+        ];
+        assert_eq!(algorithm(&all), SignatureAlgorithm::ECDSA);
+        assert_eq!(
+            algorithm(&[SignatureScheme::RSA_PKCS1_SHA256]),
+            SignatureAlgorithm::RSA
+        );
+    }
+
+    fn renewed_certs() -> Vec<IpcCerts> {
+        vec![IpcCerts {
+            cert: include_bytes!("../../tests/certs/renewed.pem").to_vec(),
+            key: include_bytes!("../../tests/certs/renewed.key").to_vec(),
+            default: false,
+            path: "tests/certs/ecdsa.pem".to_string(),
+            key_path: "tests/certs/renewed.key".to_string(),
+        }]
+    }
+
+    fn shared_list(certs: &Vec<IpcCerts>) -> SharedCertifiedKeyList {
+        ArcSwap::from_pointee(TlsConfig::new(certs).get_certified_key_list())
+    }
+
+    #[test]
+    fn reload_updates_each_certificate() {
+        let mut certs = rsa_certs();
+        certs.extend(ecdsa_certs());
+        let ck_list = shared_list(&certs);
+        let loaded = |index: usize| {
+            ck_list.load().get("example.com").unwrap()[index]
+                .key
+                .clone()
+        };
+        let (ecdsa, rsa) = (loaded(0), loaded(1));
+
+        // Only the RSA certificate could be read.
+        reload_certificates(&rsa_certs(), &ck_list);
+        assert!(Arc::ptr_eq(&ecdsa, &loaded(0)));
+        assert!(!Arc::ptr_eq(&rsa, &loaded(1)));
+
+        reload_certificates(&certs, &ck_list);
+        assert!(!Arc::ptr_eq(&ecdsa, &loaded(0)));
+    }
+
+    #[test]
+    fn reload_unchanged_domains() {
+        let certs = ecdsa_certs();
+        let ck_list = shared_list(&certs);
+        let mut domains: Vec<String> = ck_list.load().keys().cloned().collect();
+        reload_certificates(&certs, &ck_list);
+        let mut reloaded: Vec<String> = ck_list.load().keys().cloned().collect();
+        domains.sort();
+        reloaded.sort();
+        assert_eq!(domains, reloaded);
+    }
+
+    #[test]
+    fn reload_follows_san_changes() {
+        // Renewed certificate: *.example.com removed, new.example.org added.
+        let ck_list = shared_list(&ecdsa_certs());
+        reload_certificates(&renewed_certs(), &ck_list);
+        let list = ck_list.load();
+        assert!(list.contains_key("example.com"));
+        assert!(list.contains_key("new.example.org"));
+        assert!(!list.contains_key("*.example.com"));
+
+        // And back.
+        reload_certificates(&ecdsa_certs(), &ck_list);
+        let list = ck_list.load();
+        assert!(list.contains_key("*.example.com"));
+        assert!(!list.contains_key("new.example.org"));
+    }
+
+    #[test]
+    fn reload_keeps_other_certificates() {
+        let mut certs = rsa_certs();
+        certs.extend(ecdsa_certs());
+        let ck_list = shared_list(&certs);
+        let mut reloaded = rsa_certs();
+        reloaded.extend(renewed_certs());
+        reload_certificates(&reloaded, &ck_list);
+        let list = ck_list.load();
+        // Still covered by the RSA certificate.
+        assert_eq!(list.get("*.example.com").unwrap().len(), 1);
+        assert_eq!(list.get("example.com").unwrap().len(), 2);
+        assert_eq!(list.get("new.example.org").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn encrypted_private_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let passphrase_file = dir.path().join("passphrase");
+        std::fs::write(&passphrase_file, "quark\n").unwrap();
+        let mut tls_cert = TlsCertificate {
+            cert: "tests/certs/ecdsa.pem".to_string(),
+            key: "tests/certs/ecdsa.encrypted.key".to_string(),
+            key_passphrase_file: Some(passphrase_file.to_string_lossy().to_string()),
+        };
+        let certs = vec![IpcCerts::build(&tls_cert).await.unwrap()];
+        assert!(!is_encrypted_key(&certs[0].key));
+        assert!(TlsConfig::new(&certs)
+            .get_certified_key_list()
+            .contains_key("example.com"));
+
+        std::fs::write(&passphrase_file, "hunter2").unwrap();
+        let err = IpcCerts::build(&tls_cert).await.unwrap_err();
+        assert!(err.contains("tests/certs/ecdsa.encrypted.key"));
+        assert!(!err.contains("hunter2"));
+
+        // Unencrypted keys don't need a passphrase.
+        tls_cert.key = "tests/certs/ecdsa.key".to_string();
+        assert!(IpcCerts::build(&tls_cert).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_authentication() {
+        let ca: &[u8] = include_bytes!("../../tests/certs/chain_ca.pem");
+        let certs = ecdsa_certs();
+        let server = |mode: ClientAuthMode| {
+            let mut tls_config = TlsConfig::new(&certs);
+            let resolver = SniCertResolver::new(Arc::new(ArcSwap::from_pointee(
+                tls_config.get_certified_key_list(),
+            )));
+            tls_config
+                .get_tls_config(resolver, &TlsOptions::default(), Some((&mode, ca)))
+                .unwrap()
+        };
+        // Signed by the client CA, or self-signed.
+        let signed = (
+            include_bytes!("../../tests/certs/chain_leaf.pem").as_slice(),
+            include_bytes!("../../tests/certs/chain_leaf.key").as_slice(),
+        );
+        let self_signed = (
+            include_bytes!("../../tests/certs/ecdsa.pem").as_slice(),
+            include_bytes!("../../tests/certs/ecdsa.key").as_slice(),
+        );
+        let client = |cert: Option<(&[u8], &[u8])>| {
+            let builder = ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification));
+            match cert {
+                Some((cert, key)) => builder
+                    .with_client_auth_cert(
+                        load_certs(cert).unwrap(),
+                        load_private_key(key).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            }
+        };
+        // Subject of the client certificate seen by the server.
+        let connect = |server: ServerConfig, client: ClientConfig| async move {
+            let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+            let acceptor = TlsAcceptor::from(Arc::new(server));
+            let connector = TlsConnector::from(Arc::new(client));
+            let name = ServerName::try_from("example.com").unwrap();
+            let (client_res, server_res) = tokio::join!(
+                connector.connect(name, client_io),
+                acceptor.accept(server_io)
+            );
+            client_res?;
+            let stream = server_res?;
+            Ok::<_, io::Error>(
+                stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(peer_subject),
+            )
+        };
+
+        let subject = connect(server(ClientAuthMode::Require), client(Some(signed)))
+            .await
+            .unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=chain.example.com"));
+        assert!(connect(server(ClientAuthMode::Require), client(None))
+            .await
+            .is_err());
+        assert!(
+            connect(server(ClientAuthMode::Require), client(Some(self_signed)))
+                .await
+                .is_err()
+        );
+
+        // Optional: a client without certificate is accepted, not an invalid one.
+        let subject = connect(server(ClientAuthMode::Optional), client(None))
+            .await
+            .unwrap();
+        assert_eq!(subject, None);
+        assert!(
+            connect(server(ClientAuthMode::Optional), client(Some(self_signed)))
+                .await
+                .is_err()
+        );
+    }
+
+    fn ipc_certs(cert: &[u8], key: &[u8]) -> IpcCerts {
+        IpcCerts {
+            cert: cert.to_vec(),
+            key: key.to_vec(),
+            default: false,
+            path: "cert.pem".to_string(),
+            key_path: "key.pem".to_string(),
+        }
+    }
+
+    #[test]
+    fn key_must_match_certificate() {
+        let ecdsa = ecdsa_certs().remove(0);
+        let rsa = rsa_certs().remove(0);
+        assert!(get_domains_and_ck(&ecdsa).is_ok());
+        let err = get_domains_and_ck(&ipc_certs(&ecdsa.cert, &rsa.key)).unwrap_err();
+        assert_eq!(err, "key key.pem does not match certificate cert.pem");
+    }
+
+    #[test]
+    fn chain_must_be_ordered() {
+        let leaf: &[u8] = include_bytes!("../../tests/certs/chain_leaf.pem");
+        let ca: &[u8] = include_bytes!("../../tests/certs/chain_ca.pem");
+        let key = include_bytes!("../../tests/certs/chain_leaf.key");
+
+        let (domains, _, _) = get_domains_and_ck(&ipc_certs(&[leaf, ca].concat(), key)).unwrap();
+        assert_eq!(domains, ["chain.example.com"]);
+        // Single certificate, signed by a CA that isn't part of the file.
+        assert!(get_domains_and_ck(&ipc_certs(leaf, key)).is_ok());
+
+        let err = get_domains_and_ck(&ipc_certs(&[ca, leaf].concat(), key)).unwrap_err();
+        assert!(err.starts_with("Invalid chain in cert.pem"));
+        // Missing intermediate between the leaf and another certificate.
+        let other: &[u8] = include_bytes!("../../tests/certs/ecdsa.pem");
+        assert!(get_domains_and_ck(&ipc_certs(&[leaf, other].concat(), key)).is_err());
+    }
+
+    #[test]
+    fn certificate_expiry_status() {
+        let now = 1_700_000_000;
+        let day = SECONDS_PER_DAY;
+        assert_eq!(expiry_status(now + 30 * day, now, 21), ExpiryStatus::Valid);
+        assert_eq!(
+            expiry_status(now + 10 * day + 5, now, 21),
+            ExpiryStatus::ExpiresSoon(10)
+        );
+        assert_eq!(expiry_status(now, now, 21), ExpiryStatus::Expired);
+        assert_eq!(expiry_status(now - day, now, 21), ExpiryStatus::Expired);
+    }
+
+    #[test]
+    fn certificate_expiry_recorded_on_load() {
+        let certs = ecdsa_certs();
+        TlsConfig::new(&certs).get_certified_key_list();
+        let expiry = CERTS_EXPIRY.get("tests/certs/ecdsa.pem").unwrap();
+        assert!(expiry.domains.contains(&"example.com".to_string()));
+        assert!(expiry.not_after > 0);
+    }
+
+    #[tokio::test]
+    async fn negotiation_honors_kx_groups() {
+        let certs = ecdsa_certs();
+        let options = TlsOptions {
+            kx_groups: vec!["secp384r1".to_string()],
+            ..TlsOptions::default()
+        };
+        let server = server_config(&certs, &options);
+        let client = client_config(aws_lc_rs::ALL_CIPHER_SUITES, aws_lc_rs::ALL_KX_GROUPS);
+        let (_, group) = handshake(server, client).await.unwrap();
+        assert_eq!(group, NamedGroup::secp384r1);
+    }
 
     #[test]
     fn test_convert_to_wildcard() {
-        assert_eq!(convert_to_wildcard("www.example.com"), "*.example.com");
         assert_eq!(
-            convert_to_wildcard("www.sub.example.com"),
-            "*.sub.example.com"
+            convert_to_wildcard("www.example.com").as_deref(),
+            Some("*.example.com")
+        );
+        assert_eq!(
+            convert_to_wildcard("www.sub.example.com").as_deref(),
+            Some("*.sub.example.com")
+        );
+        assert_eq!(
+            convert_to_wildcard("xn--bcher-kva.example.com").as_deref(),
+            Some("*.example.com")
+        );
+        assert_eq!(convert_to_wildcard("localhost"), None);
+        assert_eq!(convert_to_wildcard("127.0.0.1"), None);
+        assert_eq!(convert_to_wildcard("::1"), None);
+    }
+
+    #[test]
+    fn resolve_sni_names() {
+        let ck_list = TlsConfig::new(&ecdsa_certs()).get_certified_key_list();
+        let resolve = |name: &str| resolve_name(&ck_list, name);
+        assert_eq!(resolve("example.com").as_deref(), Some("example.com"));
+        assert_eq!(resolve("www.example.com").as_deref(), Some("*.example.com"));
+        assert_eq!(
+            resolve("xn--bcher-kva.example.com").as_deref(),
+            Some("*.example.com")
         );
+        // Multi-level names aren't covered by the wildcard.
+        assert_eq!(resolve("a.b.example.com"), None);
+        assert_eq!(resolve("127.0.0.1"), None);
+        assert_eq!(available_names(&ck_list), ["*.example.com", "example.com"]);
     }
 }
@@ -9,9 +9,12 @@ pub struct ConfigToml {
     // field is still required for a fully functional server.
     pub import: Option<Vec<String>>,
     pub global: Option<Global>,
+    pub logs: Option<Logs>,
+    pub defaults: Option<Defaults>,
     pub servers: Option<HashMap<String, Server>>,
     pub services: Option<HashMap<String, Service>>,
     pub loadbalancers: Option<HashMap<String, Loadbalancer>>,
+    pub alerts: Option<Alerts>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +29,9 @@ pub struct Global {
     pub backlog: Option<i32>,
     pub max_connections: Option<usize>,
     pub max_requests: Option<usize>,
+    pub queue_timeout: Option<u64>,
+    pub max_connections_retry_after: Option<u64>,
+    pub over_max_connections: Option<OverMaxConnections>,
     pub keepalive: Option<bool>,
     pub keepalive_timeout: Option<u64>,
     pub keepalive_interval: Option<u64>,
@@ -33,8 +39,97 @@ pub struct Global {
     pub http_header_timeout: Option<u64>,
     pub idle_timeout: Option<u64>,
     pub idle_check_interval: Option<u64>,
+    pub max_connection_lifetime: Option<u64>,
     pub max_conn_per_ip: Option<usize>,
     pub tls_proxy_verify: Option<bool>,
+    pub tls: Option<TlsOptions>,
+    pub cert_expiry_warning: Option<u64>,
+    pub connection_stats_interval: Option<u64>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub orphan_shutdown: Option<bool>,
+    pub accept_loops: Option<usize>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    pub tcp_defer_accept: Option<u64>,
+    pub deny_trace: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverMaxConnections {
+    Respond,
+    Close,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TcpKeepalive {
+    pub time: Option<u64>,
+    pub interval: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsOptions {
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+    pub cipher_suites: Option<Vec<String>>,
+    pub kx_groups: Option<Vec<String>>,
+    pub session_tickets: Option<bool>,
+    pub ticket_rotation: Option<u32>,
+    pub session_cache_size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Logs {
+    pub path: Option<String>,
+    pub level: Option<String>,
+    pub stdout: Option<bool>,
+    pub access_log: Option<bool>,
+    pub access_log_path: Option<String>,
+    pub access_log_format: Option<String>,
+    pub access_log_sample: Option<f64>,
+    pub access_log_sample_not_found: Option<bool>,
+    pub file: Option<String>,
+    pub rotate: Option<String>,
+    pub rotate_size: Option<String>,
+    pub keep: Option<usize>,
+    pub compress: Option<bool>,
+    pub output: Option<LogOutputs>,
+    pub syslog_facility: Option<String>,
+    pub error_log: Option<String>,
+}
+
+// output = "journald" or output = ["file", "journald"].
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LogOutputs {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Webhook called when a service returns too many errors.
+#[derive(Debug, Deserialize)]
+pub struct Alerts {
+    pub webhook: String,
+    pub threshold: Option<AlertThreshold>,
+    pub cooldown: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertThreshold {
+    pub status: Option<String>,
+    pub count: Option<u64>,
+    pub window: Option<String>,
+}
+
+// Used by the servers, services and locations which don't define their own.
+#[derive(Debug, Deserialize)]
+pub struct Defaults {
+    pub proxy_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub upstream_idle_timeout: Option<u64>,
+    pub headers: Option<Headers>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,24 +137,65 @@ pub struct Server {
     pub port: Option<u16>,
     pub https_port: Option<u16>,
     pub proxy_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub upstream_idle_timeout: Option<u64>,
     pub headers: Option<Headers>,
+    pub tls: Option<TlsOptions>,
+    pub default_certificate: Option<DefaultCertificate>,
+    pub strict_sni: Option<bool>,
+    pub alpn: Option<Vec<String>>,
+    pub http2: Option<bool>,
+    pub tls_handshake_timeout: Option<u64>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    pub tcp_defer_accept: Option<u64>,
+    pub unknown_host: Option<String>,
+    pub normalize_path: Option<NormalizePath>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizePath {
+    Rewrite,
+    Redirect,
+    Off,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DefaultCertificate {
+    pub cert: String,
+    pub key: String,
+    pub key_passphrase_file: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Service {
     pub domain: String,
+    pub aliases: Option<Vec<String>>,
     pub server: Option<String>,
     pub locations: Option<Vec<Locations>>,
     pub file_servers: Option<Vec<FileServers>>,
     pub redirections: Option<Vec<Redirections>>,
     pub tls: Option<Tls>,
+    pub tls_optional: Option<bool>,
     pub headers: Option<Headers>,
+    pub proxy_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub upstream_idle_timeout: Option<u64>,
+    pub enabled: Option<bool>,
+    pub timing_header: Option<bool>,
+    pub upstream_header: Option<bool>,
+    pub www_redirect: Option<bool>,
+    pub forwarded_headers: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub deny_trace: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Headers {
     pub locations: Option<HeaderType>,
     pub file_servers: Option<HeaderAction>,
+    pub redirections: Option<HeaderAction>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -76,9 +212,47 @@ pub struct HeaderAction {
 
 #[derive(Debug, Deserialize)]
 pub struct Tls {
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    pub certificates: Option<Vec<Certificate>>,
+    pub key_passphrase_file: Option<String>,
+    pub redirection: Option<TlsRedirection>,
+    pub client_auth: Option<ClientAuth>,
+}
+
+// redirection = false, or a table with its options.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TlsRedirection {
+    Enabled(bool),
+    Table(TlsRedirectionTable),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsRedirectionTable {
+    pub code: Option<u16>,
+    pub exclude_paths: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Certificate {
     pub certificate: String,
     pub key: String,
-    pub redirection: Option<bool>,
+    pub key_passphrase_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientAuth {
+    pub ca: String,
+    pub mode: Option<ClientAuthMode>,
+    pub header: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    Require,
+    Optional,
 }
 
 #[derive(Debug, Deserialize)]
@@ -86,15 +260,70 @@ pub struct Locations {
     pub source: String,
     pub target: String,
     pub headers: Option<HeaderType>,
+    pub proxy_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub upstream_idle_timeout: Option<u64>,
+    pub cache: Option<Cache>,
+    pub upstream_http2: Option<UpstreamHttp2>,
+    pub request_buffering: Option<bool>,
+    pub max_buffered_body: Option<String>,
+    pub over_max_buffered_body: Option<OverMaxBufferedBody>,
+    pub buffering_timeout: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    pub r#override: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamHttp2 {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverMaxBufferedBody {
+    Reject,
+    Stream,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Cache {
+    pub ttl: String,
+    pub max_size: Option<String>,
+    pub max_entry_size: Option<String>,
+    pub methods: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FileServers {
     pub source: String,
     pub target: String,
-    pub authorized_dirs: Option<Vec<String>>,
+    pub authorized_dirs: Option<Vec<AuthorizedDir>>,
     pub custom_404: Option<String>,
     pub headers: Option<HeaderAction>,
+    pub canonical_index_redirect: Option<bool>,
+    pub max_concurrent: Option<usize>,
+    pub listing_hide: Option<Vec<String>>,
+    pub autoindex: Option<bool>,
+    pub r#override: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+// An authorized dir is either a path or a table with its options.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AuthorizedDir {
+    Path(String),
+    Table(AuthorizedDirTable),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizedDirTable {
+    pub path: String,
+    pub autoindex: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -102,11 +331,63 @@ pub struct Redirections {
     pub source: String,
     pub target: String,
     pub code: Option<u16>,
+    pub headers: Option<HeaderAction>, // Response headers only.
+    pub preserve_query: Option<bool>,
+    pub r#override: Option<bool>,
+    pub enabled: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Loadbalancer {
     pub algo: String,
-    pub backends: Vec<String>,
+    #[serde(default)]
+    pub backends: Vec<Backend>,
+    pub backends_dns: Option<BackendsDns>,
     pub weights: Option<Vec<u32>>,
+    pub ewma_decay: Option<f64>,
+    pub outlier_detection: Option<OutlierDetection>,
+}
+
+// Backends with more errors than the others are set aside for a while.
+#[derive(Debug, Deserialize)]
+pub struct OutlierDetection {
+    pub interval: Option<u64>,
+    pub error_percent: Option<u32>,
+    pub min_requests: Option<u64>,
+    pub ejection_time: Option<u64>,
+    pub max_ejection_time: Option<u64>,
+    pub max_ejection_percent: Option<u32>,
+}
+
+// A backend is either an url or a table with its options.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Backend {
+    Url(String),
+    Table(BackendTable),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackendTable {
+    pub url: String,
+    pub weight: Option<u32>,
+    pub max_conns: Option<usize>,
+    pub backup: Option<bool>,
+}
+
+impl Backend {
+    pub fn url(&self) -> &str {
+        match self {
+            Backend::Url(url) => url,
+            Backend::Table(table) => &table.url,
+        }
+    }
+}
+
+// Backends resolved from the A/AAAA records of a DNS name.
+#[derive(Debug, Deserialize)]
+pub struct BackendsDns {
+    pub name: String,
+    pub port: Option<u16>,
+    pub refresh: Option<u64>,
 }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use toml::{Table, Value};
+
+const VARS_TABLE: &str = "vars";
+const VAR_PREFIX: &str = "${vars.";
+
+// Expand the ${vars.<name>} references of all the string values of a config file.
+// The [vars] of the file override the inherited ones (from the main config for
+// an imported file). Returns the variables, to be inherited by the imports.
+pub fn expand_vars(
+    table: &mut Table,
+    inherited: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut raw = inherited.clone();
+    if let Some(vars) = table.remove(VARS_TABLE) {
+        let Value::Table(vars) = vars else {
+            return Err(format!("[{VARS_TABLE}] must be a table"));
+        };
+        for (name, value) in vars {
+            let Value::String(value) = value else {
+                return Err(format!("{VARS_TABLE}.{name} must be a string"));
+            };
+            raw.insert(name, value);
+        }
+    }
+
+    // Variables can reference other variables.
+    let mut vars = HashMap::new();
+    for name in raw.keys() {
+        resolve_var(name, &raw, &mut vars, &mut Vec::new())?;
+    }
+
+    for (key, value) in table.iter_mut() {
+        expand_value(value, key, &vars)?;
+    }
+    Ok(vars)
+}
+
+fn resolve_var(
+    name: &str,
+    raw: &HashMap<String, String>,
+    resolved: &mut HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String, String> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    if stack.iter().any(|n| n == name) {
+        stack.push(name.to_string());
+        return Err(format!(
+            "Recursive variable: {}",
+            stack
+                .iter()
+                .map(|n| format!("{VARS_TABLE}.{n}"))
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        ));
+    }
+    stack.push(name.to_string());
+    let value = replace_refs(&raw[name], |var| {
+        if !raw.contains_key(var) {
+            return Err(format!(
+                "Undefined variable {VARS_TABLE}.{var} in {VARS_TABLE}.{name}"
+            ));
+        }
+        resolve_var(var, raw, resolved, stack)
+    })?;
+    stack.pop();
+
+    resolved.insert(name.to_string(), value.clone());
+    Ok(value)
+}
+
+fn expand_value(
+    value: &mut Value,
+    path: &str,
+    vars: &HashMap<String, String>,
+) -> Result<(), String> {
+    match value {
+        Value::String(s) => {
+            *s = replace_refs(s, |var| {
+                vars.get(var)
+                    .cloned()
+                    .ok_or_else(|| format!("Undefined variable {VARS_TABLE}.{var}"))
+            })
+            .map_err(|e| format!("{e} in {path}"))?;
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                expand_value(value, &format!("{path}[{i}]"), vars)?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                expand_value(value, &format!("{path}.{key}"), vars)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// Replace each ${vars.<name>} of the string with the value returned by `lookup`.
+// Other ${...} references (load balancers) and unterminated ones are kept as is.
+fn replace_refs<F>(input: &str, mut lookup: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Result<String, String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find(VAR_PREFIX) {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + VAR_PREFIX.len()..];
+        let Some(end) = after.find('}') else {
+            output.push_str(&rest[start..]);
+            return Ok(output);
+        };
+        output.push_str(&lookup(&after[..end])?);
+        rest = &after[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(config: &str, inherited: &[(&str, &str)]) -> Result<Table, String> {
+        let mut table: Table = toml::from_str(config).unwrap();
+        let inherited = inherited
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        expand_vars(&mut table, &inherited)?;
+        Ok(table)
+    }
+
+    #[test]
+    fn expand_references() {
+        let table = expand(
+            r#"
+            vars = { backend = "10.0.0.5", url = "http://${vars.backend}:${vars.port}" }
+            [services.app]
+            domain = "example.com"
+            locations = [
+              { source = "/*", target = "${vars.url}" },
+              { source = "/lb/*", target = "http://${pool}" },
+            ]
+            "#,
+            &[("port", "8080"), ("backend", "10.0.0.1")],
+        )
+        .unwrap();
+        assert!(!table.contains_key("vars"));
+        let locations = table["services"]["app"]["locations"].as_array().unwrap();
+        assert_eq!(
+            locations[0]["target"].as_str(),
+            Some("http://10.0.0.5:8080")
+        );
+        assert_eq!(locations[1]["target"].as_str(), Some("http://${pool}"));
+    }
+
+    #[test]
+    fn invalid_references() {
+        let err = expand(
+            "[services.app]\nlocations = [{ target = \"${vars.missing}\" }]",
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Undefined variable vars.missing in services.app.locations[0].target"
+        );
+
+        let err = expand(
+            "vars = { a = \"${vars.b}\", b = \"x${vars.a}\" }\nkey = \"${vars.a}\"",
+            &[],
+        )
+        .unwrap_err();
+        assert!(err.starts_with("Recursive variable: vars."), "{err}");
+
+        let err = expand("vars = { a = \"${vars.b}\" }", &[]).unwrap_err();
+        assert_eq!(err, "Undefined variable vars.b in vars.a");
+    }
+}
@@ -1,41 +1,106 @@
 // Some http errors.
 use http_body_util::Full;
-use hyper::{Response, StatusCode};
+use hyper::{
+    header::{HeaderValue, ALLOW},
+    Response, StatusCode,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-use crate::{server::server_utils::ProxyHandlerBody, utils::get_project_version};
+use crate::{
+    middleware::REQUEST_ID_HEADER,
+    server::server_utils::ProxyHandlerBody,
+    utils::{escape_html, get_project_version},
+};
+
+// Marks the pages built here, to print their context once the request is known.
+#[derive(Clone, Copy)]
+struct ErrorPage;
+
+// Printed in small print on an error page, to find the log lines of a
+// screenshot.
+struct ErrorContext<'a> {
+    request_id: &'a str,
+    time: OffsetDateTime, // UTC.
+}
 
 pub fn not_found() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::NOT_FOUND)
+    error_builder(StatusCode::NOT_FOUND, None)
 }
 
 pub fn forbidden() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::FORBIDDEN)
+    error_builder(StatusCode::FORBIDDEN, None)
 }
 
 pub fn service_unavailable() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::SERVICE_UNAVAILABLE)
+    error_builder(StatusCode::SERVICE_UNAVAILABLE, None)
 }
 
 pub fn internal_server_error() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::INTERNAL_SERVER_ERROR)
+    error_builder(StatusCode::INTERNAL_SERVER_ERROR, None)
 }
 
 pub fn bad_gateway() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::BAD_GATEWAY)
+    error_builder(StatusCode::BAD_GATEWAY, None)
 }
 
 pub fn gateway_timeout() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::GATEWAY_TIMEOUT)
+    error_builder(StatusCode::GATEWAY_TIMEOUT, None)
 }
 
 pub fn bad_request() -> Response<ProxyHandlerBody> {
-    error_builder(StatusCode::BAD_REQUEST)
+    error_builder(StatusCode::BAD_REQUEST, None)
+}
+
+pub fn request_timeout() -> Response<ProxyHandlerBody> {
+    error_builder(StatusCode::REQUEST_TIMEOUT, None)
+}
+
+pub fn payload_too_large() -> Response<ProxyHandlerBody> {
+    error_builder(StatusCode::PAYLOAD_TOO_LARGE, None)
+}
+
+// allow: the methods accepted instead, e.g. "GET, HEAD".
+pub fn method_not_allowed(allow: &str) -> Response<ProxyHandlerBody> {
+    let mut res = error_builder(StatusCode::METHOD_NOT_ALLOWED, None);
+    if let Ok(allow) = HeaderValue::from_str(allow) {
+        res.headers_mut().insert(ALLOW, allow);
+    }
+    res
+}
+
+// Print the context on an error page built without it. The other responses,
+// e.g. the error pages of the backends, are left untouched.
+pub fn add_context(res: &mut Response<ProxyHandlerBody>, request_id: &str) {
+    if res.extensions_mut().remove::<ErrorPage>().is_none() {
+        return;
+    }
+    let context = ErrorContext {
+        request_id,
+        time: OffsetDateTime::now_utc(),
+    };
+    let (parts, body) = error_builder(res.status(), Some(&context)).into_parts();
+    // Keeps the headers set since, e.g. the custom headers of a file server.
+    res.headers_mut().extend(parts.headers);
+    *res.body_mut() = body;
 }
 
-fn error_builder(status: StatusCode) -> Response<ProxyHandlerBody> {
+fn error_builder(status: StatusCode, context: Option<&ErrorContext>) -> Response<ProxyHandlerBody> {
     let version = get_project_version();
     let code = status.as_u16();
     let msg = status.canonical_reason().unwrap();
+    let details = context.map_or(String::new(), |context| {
+        // Whole seconds, like the logs.
+        let time = context
+            .time
+            .replace_nanosecond(0)
+            .unwrap_or(context.time)
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        format!(
+            "<p><small>Request {} - {time}</small></p>",
+            escape_html(context.request_id)
+        )
+    });
     let text = format!(
         "<html>\
         <head><title>{code} {msg}</title></head>\
@@ -45,12 +110,49 @@ fn error_builder(status: StatusCode) -> Response<ProxyHandlerBody> {
         <h4>{msg}</h4>\
         <hr/>
         <p>{version}</p>\
+        {details}\
         </body>\
         </html>",
     );
 
-    Response::builder()
+    let mut res = Response::builder()
         .status(status)
+        .extension(ErrorPage)
         .body(ProxyHandlerBody::Full(Full::from(text)))
-        .unwrap()
+        .unwrap();
+    if let Some(request_id) = context.and_then(|c| HeaderValue::from_str(c.request_id).ok()) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn error_page_context() {
+        let mut res = not_found();
+        res.headers_mut()
+            .insert("x-custom", HeaderValue::from_static("1"));
+        add_context(&mut res, "<id>");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["x-custom"], "1");
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "<id>");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Request &lt;id&gt; - 20"), "{body}");
+        assert!(body.contains("Z</small>"), "{body}");
+
+        // Only the pages built here.
+        let mut res = Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(ProxyHandlerBody::Empty)
+            .unwrap();
+        add_context(&mut res, "id");
+        assert!(res.headers().get(REQUEST_ID_HEADER).is_none());
+        assert!(matches!(res.body(), ProxyHandlerBody::Empty));
+    }
 }
@@ -1,13 +1,23 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{IoSlice, IoSliceMut, Read, Write},
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use bincode::{Decode, Encode};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
 use nix::unistd::getuid;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest},
     net::UnixStream,
     sync::Mutex,
     time::{sleep, timeout, Duration},
 };
+use twox_hash::XxHash3_64;
 
 const QUARK_SOCKET_NAME: &str = "quark.sock";
 
@@ -19,7 +29,70 @@ const QUARK_SOCKET_PATH: &str = "/run/quark/";
 
 const QUARK_TMP_SOCKET_PATH: &str = "/tmp/";
 
-pub fn get_socket_path() -> String {
+const QUARK_SOCKET_ENV: &str = "QUARK_SOCKET";
+
+// Changed when the payload of a message changes, e.g. a field added to the
+// InternalConfig. The main process and a server of another version refuse to
+// talk, a message from the other version can't be decoded.
+pub const IPC_PROTOCOL_VERSION: u32 = 7;
+
+// The server warns when it receives nothing from the main process for
+// HEARTBEAT_TIMEOUT, the main process is likely dead or stuck. The main process
+// warns the same way without acks, and restarts a server stuck for
+// HEARTBEAT_RESTART_TIMEOUT.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+pub const HEARTBEAT_RESTART_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A frame is the magic number, the size of the message, the message and its
+// checksum. A stream out of sync is detected at the next frame.
+const FRAME_MAGIC: [u8; 4] = *b"QRK\x02";
+const FRAME_HEADER_LEN: usize = 8;
+
+// Limit of the size of a message, checked before it is read. The messages
+// with certificates or CA bundles get CERTS_SIZE_FACTOR times more.
+pub const DEFAULT_MAX_MESSAGE_SIZE_MB: usize = 4;
+const CERTS_SIZE_FACTOR: usize = 16;
+
+// Set once at startup from --ipc-max-message-size, the same for both processes.
+static MAX_MESSAGE_SIZE: OnceLock<usize> = OnceLock::new();
+
+pub fn set_max_message_size(megabytes: usize) {
+    let _ = MAX_MESSAGE_SIZE.set(megabytes.saturating_mul(1024 * 1024));
+}
+
+fn max_message_size() -> usize {
+    *MAX_MESSAGE_SIZE.get_or_init(|| DEFAULT_MAX_MESSAGE_SIZE_MB * 1024 * 1024)
+}
+
+// The largest message of any kind.
+pub fn max_frame_size() -> usize {
+    max_message_size().saturating_mul(CERTS_SIZE_FACTOR)
+}
+
+// Maximum number of descriptors in one message (SCM_MAX_FD on Linux).
+pub const MAX_PASSED_FDS: usize = 253;
+
+// Size of sun_path, including the terminating null byte.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+const MAX_SOCKET_PATH_LEN: usize = 104;
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+const MAX_SOCKET_PATH_LEN: usize = 108;
+
+// The path given on the command line, then the QUARK_SOCKET environment
+// variable, then the default path.
+pub fn get_socket_path(custom_path: Option<&str>) -> String {
+    if let Some(path) = custom_path {
+        return path.to_string();
+    }
+    if let Some(path) = std::env::var(QUARK_SOCKET_ENV)
+        .ok()
+        .filter(|p| !p.is_empty())
+    {
+        return path;
+    }
+
     if getuid().is_root() {
         return PathBuf::from(QUARK_SOCKET_PATH)
             .join(QUARK_SOCKET_NAME)
@@ -33,6 +106,114 @@ pub fn get_socket_path() -> String {
         .to_string()
 }
 
+// The admin socket is next to the IPC socket: /run/quark/quark.sock ->
+// /run/quark/quark-admin.sock.
+pub fn get_admin_socket_path(socket_path: &str) -> String {
+    let path = Path::new(socket_path);
+    let stem = path
+        .file_stem()
+        .map_or("quark".into(), |stem| stem.to_string_lossy());
+    path.with_file_name(format!("{stem}-admin.sock"))
+        .to_string_lossy()
+        .to_string()
+}
+
+// The lock file is next to the IPC socket: /run/quark/quark.sock ->
+// /run/quark/quark.lock.
+pub fn get_lock_path(socket_path: &str) -> String {
+    let path = Path::new(socket_path);
+    let stem = path
+        .file_stem()
+        .map_or("quark".into(), |stem| stem.to_string_lossy());
+    path.with_file_name(format!("{stem}.lock"))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Held by the main process, so that two instances never use the same socket.
+// The lock file contains the pid of the main process.
+pub struct InstanceLock {
+    path: String,
+    _file: File,
+    // Removed with the lock file, on release.
+    socket_paths: Vec<String>,
+}
+
+impl InstanceLock {
+    // Lock the file, then remove the socket files left by a previous run.
+    pub fn acquire(path: &str, socket_paths: &[&str]) -> Result<InstanceLock, String> {
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o644)
+                .open(path)
+                .map_err(|e| format!("Can't open the lock file {path}: {e}"))?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    let mut pid = String::new();
+                    file.read_to_string(&mut pid).ok();
+                    let pid = Some(pid.trim()).filter(|pid| !pid.is_empty());
+                    return Err(format!(
+                        "Another instance of quark is running (pid {}), it holds the lock {path}. Use another --socket-path to run several instances.",
+                        pid.unwrap_or("unknown")
+                    ));
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(format!("Can't lock the file {path}: {e}"))
+                }
+            }
+            // The previous instance removes the file before unlocking it, the
+            // file at the path may be a new one.
+            let locked = file
+                .metadata()
+                .map_err(|e| format!("Can't read the lock file {path}: {e}"))?;
+            let current = std::fs::metadata(path).ok();
+            if !current.is_some_and(|m| m.dev() == locked.dev() && m.ino() == locked.ino()) {
+                continue;
+            }
+            file.set_len(0)
+                .and_then(|_| writeln!(file, "{}", std::process::id()))
+                .map_err(|e| format!("Can't write the lock file {path}: {e}"))?;
+
+            for socket_path in socket_paths.iter().filter(|p| Path::new(p).exists()) {
+                println!("[Main Process] Removing the stale socket file {socket_path}");
+                std::fs::remove_file(socket_path)
+                    .map_err(|e| format!("Can't remove the socket file {socket_path}: {e}"))?;
+            }
+            return Ok(InstanceLock {
+                path: path.to_string(),
+                _file: file,
+                socket_paths: socket_paths.iter().map(|p| p.to_string()).collect(),
+            });
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    // The file is unlocked when it is closed, after the removals.
+    fn drop(&mut self) {
+        for socket_path in &self.socket_paths {
+            std::fs::remove_file(socket_path).ok();
+        }
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+pub fn check_socket_path(path: &str) -> Result<(), String> {
+    if path.len() >= MAX_SOCKET_PATH_LEN {
+        return Err(format!(
+            "The socket path {path} is too long ({} bytes, the maximum is {}).",
+            path.len(),
+            MAX_SOCKET_PATH_LEN - 1
+        ));
+    }
+    Ok(())
+}
+
 pub async fn connect_to_socket(socket_path: &str) -> Result<UnixStream, std::io::Error> {
     // Try to connect to the socket for 5 seconds.
     timeout(Duration::from_secs(5), async {
@@ -53,45 +234,495 @@ pub async fn connect_to_socket(socket_path: &str) -> Result<UnixStream, std::io:
     })
 }
 
+// Kind of a message, decoded before its payload. New kinds are added at the
+// end, a peer that doesn't know them skips the message.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Hello,        // IpcHello, the first message of both sides.
+    Config,       // InternalConfig
+    Certs,        // HashMap<u16, Vec<IpcCerts>>, by https port.
+    ClientCa,     // HashMap<u16, Vec<u8>>, by https port.
+    Listeners,    // Vec<u16>, port of each listening socket sent next.
+    Ready,        // u32, pid of the server accepting connections.
+    CertsReload,  // Vec<IpcCerts> of the https port in the key.
+    ConfigReload, // ConfigReload
+    LogLevel,     // LogLevelChange
+    Attach,       // IpcAttach, sent by the child after the hello.
+    Heartbeat,    // IpcHeartbeat, sent by the main process every HEARTBEAT_INTERVAL.
+    HeartbeatAck, // IpcHeartbeat, the answer of the server.
+}
+
+impl MessageKind {
+    pub fn max_size(&self) -> usize {
+        match self {
+            MessageKind::Certs
+            | MessageKind::ClientCa
+            | MessageKind::CertsReload
+            | MessageKind::ConfigReload => max_frame_size(),
+            _ => max_message_size(),
+        }
+    }
+}
+
 #[derive(Encode, Decode, Debug)]
 pub struct IpcMessage<T> {
-    pub kind: String,
+    pub kind: MessageKind,
     pub key: Option<String>,
     pub payload: T,
 }
 
-pub async fn send_ipc_message<T>(
-    stream: Arc<Mutex<UnixStream>>, //UnixStream,
+// Never changed, so that any two versions can compare theirs.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct IpcHello {
+    pub protocol: u32,
+    pub version: String,
+}
+
+// The time it was sent and the heartbeats missed by the sender so far, each
+// side reports those of the other.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct IpcHeartbeat {
+    pub time: u64, // Unix time in milliseconds.
+    pub missed: u64,
+}
+
+impl IpcHeartbeat {
+    pub fn new(missed: u64) -> IpcHeartbeat {
+        IpcHeartbeat {
+            time: unix_millis(),
+            missed,
+        }
+    }
+
+    // Time since it was sent, both processes share the clock.
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(unix_millis().saturating_sub(self.time))
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl IpcHello {
+    fn current() -> IpcHello {
+        IpcHello {
+            protocol: IPC_PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    fn check(&self, peer: &str) -> Result<(), String> {
+        if self.protocol != IPC_PROTOCOL_VERSION {
+            return Err(format!(
+                "The {peer} runs quark {} (IPC protocol {}), this process runs quark {} (IPC protocol {}). Restart Quark to run the same version in both processes.",
+                self.version,
+                self.protocol,
+                env!("CARGO_PKG_VERSION"),
+                IPC_PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Identify a child connecting to the main process. A child connects again
+// when the stream breaks, the main process then sends the certificates again.
+#[derive(Encode, Decode, Debug)]
+pub struct IpcAttach {
+    pub pid: u32,
+    pub reconnect: bool,
+}
+
+// Connect to the main process, check its version and identify this child.
+pub async fn attach_to_parent(
+    socket_path: &str,
+    reconnect: bool,
+) -> Result<UnixStream, Box<dyn std::error::Error>> {
+    let mut stream = connect_to_socket(socket_path).await?;
+    exchange_hello(&mut stream, "main process").await?;
+    let attach = IpcMessage {
+        kind: MessageKind::Attach,
+        key: None,
+        payload: IpcAttach {
+            pid: std::process::id(),
+            reconnect,
+        },
+    };
+    write_ipc_message(&mut stream, &attach).await?;
+    Ok(stream)
+}
+
+// Both sides send their version first, then check the one of the peer.
+pub async fn exchange_hello(
+    stream: &mut UnixStream,
+    peer: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hello = IpcMessage {
+        kind: MessageKind::Hello,
+        key: None,
+        payload: IpcHello::current(),
+    };
+    write_ipc_message(stream, &hello).await?;
+    let frame = receive_ipc_frame(stream, MessageKind::Hello.max_size())
+        .await
+        .map_err(|e| {
+            format!(
+                "Can't read the version of the {peer}, it may run another version of quark. {e}"
+            )
+        })?;
+    // A version without the hello message sends something else.
+    let hello = decode_ipc_message::<IpcHello>(&frame)
+        .ok()
+        .filter(|message| message.kind == MessageKind::Hello)
+        .ok_or_else(|| format!("The {peer} runs an older version of quark, without version check. Restart Quark to run the same version in both processes."))?;
+    hello.payload.check(peer)?;
+    Ok(())
+}
+
+pub async fn send_ipc_message<T, W>(
+    stream: Arc<Mutex<W>>,
     message: IpcMessage<T>,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     T: Encode + Decode<bincode::config::Configuration>,
+    W: AsyncWrite + Unpin,
 {
-    // Encode the message into vec of bytes.
-    let encoded_message = bincode::encode_to_vec(&message, bincode::config::standard())?;
-    // Get the size of the message in bytes.
-    let message_size: [u8; 4] = (encoded_message.len() as u32).to_be_bytes();
-    // First call. Send the size of the message to the child process. (4 bytes)
-    stream.lock().await.write_all(&message_size).await?;
-    // Second call. Send the message to the child process.
-    stream.lock().await.write_all(&encoded_message).await?;
+    // Hold the lock for the whole frame, the frames of two tasks can't interleave.
+    write_ipc_message(&mut *stream.lock().await, &message).await
+}
+
+async fn write_ipc_message<T>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &IpcMessage<T>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Encode,
+{
+    let frame = encode_frame(message)?;
+    stream.write_all(&frame).await?;
     Ok(())
 }
 
+fn encode_frame<T>(message: &IpcMessage<T>) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    T: Encode,
+{
+    // Encode the message into vec of bytes.
+    let encoded_message = bincode::encode_to_vec(message, bincode::config::standard())?;
+    // The peer would refuse it, the error is clearer here.
+    let max_size = message.kind.max_size();
+    if encoded_message.len() > max_size {
+        return Err(format!(
+            "{:?}: {}",
+            message.kind,
+            too_large(encoded_message.len(), max_size)
+        )
+        .into());
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + encoded_message.len() + 4);
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.extend_from_slice(&(encoded_message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&encoded_message);
+    frame.extend_from_slice(&checksum(&encoded_message).to_be_bytes());
+    Ok(frame)
+}
+
+fn checksum(message: &[u8]) -> u32 {
+    XxHash3_64::oneshot(message) as u32
+}
+
+fn too_large(size: usize, max_size: usize) -> String {
+    format!("IPC message of {size} bytes, the maximum is {max_size} (see --ipc-max-message-size)")
+}
+
+// Receive a message of the given kind, anything else is an error.
 pub async fn receive_ipc_message<T>(
-    stream: &mut UnixStream,
+    stream: &mut (impl AsyncRead + Unpin),
+    kind: MessageKind,
 ) -> Result<IpcMessage<T>, Box<dyn std::error::Error>>
 where
     T: Encode + Decode<()>,
 {
-    // Read the size of the message.
-    let mut message_size = [0u8; 4];
-    stream.read_exact(&mut message_size).await?;
-    // Read the message.
-    let buf_size = u32::from_be_bytes(message_size) as usize;
-    let mut buf = vec![0u8; buf_size];
+    let buf = receive_ipc_frame(stream, kind.max_size()).await?;
+    match ipc_message_kind(&buf)? {
+        Some(received) if received == kind => decode_ipc_message(&buf),
+        received => Err(format!("Expected a {kind:?} message, received {received:?}").into()),
+    }
+}
+
+// Read a raw message. Used when the payload type depends on the kind.
+// The connection must be closed after an error, the stream is out of sync.
+pub async fn receive_ipc_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // Read the magic number and the size of the message.
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let buf_size = check_frame_header(&header, max_size)?;
+    // Read the message and its checksum.
+    let mut buf = vec![0u8; buf_size + 4];
     stream.read_exact(&mut buf).await?;
+    let sum = buf.split_off(buf_size);
+    if checksum(&buf).to_be_bytes()[..] != sum[..] {
+        return Err("IPC message corrupted, the checksum doesn't match".into());
+    }
+    Ok(buf)
+}
+
+// Return the size of the message.
+fn check_frame_header(header: &[u8; FRAME_HEADER_LEN], max_size: usize) -> Result<usize, String> {
+    if header[..4] != FRAME_MAGIC {
+        return Err(format!(
+            "IPC stream out of sync, invalid frame header {header:02x?}"
+        ));
+    }
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if size > max_size {
+        return Err(too_large(size, max_size));
+    }
+    Ok(size)
+}
+
+pub fn decode_ipc_message<T>(buf: &[u8]) -> Result<IpcMessage<T>, Box<dyn std::error::Error>>
+where
+    T: Encode + Decode<()>,
+{
     let (message, _): (IpcMessage<T>, _) =
-        bincode::decode_from_slice(&buf, bincode::config::standard())?;
+        bincode::decode_from_slice(buf, bincode::config::standard())?;
     Ok(message)
 }
+
+// The kind is the first field of the message, it can be read before the payload.
+// None for a kind added by a newer version, the frame can be skipped.
+pub fn ipc_message_kind(buf: &[u8]) -> Result<Option<MessageKind>, Box<dyn std::error::Error>> {
+    match bincode::decode_from_slice(buf, bincode::config::standard()) {
+        Ok((kind, _)) => Ok(Some(kind)),
+        Err(bincode::error::DecodeError::UnexpectedVariant { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Another handle on the same socket, so that a task can read it while the
+// frames are still written through the shared stream.
+pub fn clone_stream(stream: &UnixStream) -> std::io::Result<UnixStream> {
+    let fd = stream.as_fd().try_clone_to_owned()?;
+    let stream = std::os::unix::net::UnixStream::from(fd);
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
+}
+
+// Send file descriptors (SCM_RIGHTS), attached to a single byte. The receiver
+// must read this byte with receive_fds, a plain read would close them.
+pub async fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> std::io::Result<()> {
+    let byte = [0u8];
+    let iov = [IoSlice::new(&byte)];
+    let rights = [ControlMessage::ScmRights(fds)];
+    stream
+        .async_io(Interest::WRITABLE, || {
+            sendmsg::<()>(stream.as_raw_fd(), &iov, &rights, MsgFlags::empty(), None)
+                .map_err(std::io::Error::from)
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn receive_fds(stream: &UnixStream) -> std::io::Result<Vec<OwnedFd>> {
+    let mut byte = [0u8];
+    let mut space = nix::cmsg_space!([RawFd; MAX_PASSED_FDS]);
+    stream
+        .async_io(Interest::READABLE, || {
+            let mut iov = [IoSliceMut::new(&mut byte)];
+            let message = recvmsg::<()>(
+                stream.as_raw_fd(),
+                &mut iov,
+                Some(&mut space),
+                MsgFlags::empty(),
+            )
+            .map_err(std::io::Error::from)?;
+            if message.bytes == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            if message.flags.contains(MsgFlags::MSG_CTRUNC) {
+                return Err(std::io::Error::other("too many file descriptors"));
+            }
+            let mut fds = Vec::new();
+            for cmsg in message.cmsgs().map_err(std::io::Error::from)? {
+                if let ControlMessageOwned::ScmRights(received) = cmsg {
+                    // The descriptors are new in this process.
+                    fds.extend(
+                        received
+                            .into_iter()
+                            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                    );
+                }
+            }
+            Ok(fds)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_path() {
+        assert_eq!(get_socket_path(Some("/tmp/a.sock")), "/tmp/a.sock");
+        assert_eq!(
+            get_admin_socket_path("/run/quark/quark.sock"),
+            "/run/quark/quark-admin.sock"
+        );
+        assert!(check_socket_path("/run/quark/quark.sock").is_ok());
+        let long_path = format!("/tmp/{}.sock", "a".repeat(MAX_SOCKET_PATH_LEN));
+        assert!(check_socket_path(&long_path).is_err());
+    }
+
+    #[test]
+    fn kind_read_before_payload() {
+        let message = IpcMessage {
+            kind: MessageKind::CertsReload,
+            key: Some("443".to_string()),
+            payload: vec![1u32, 2, 3],
+        };
+        let frame = encode_frame(&message).unwrap();
+        let header: [u8; FRAME_HEADER_LEN] = frame[..FRAME_HEADER_LEN].try_into().unwrap();
+        let buf = &frame[FRAME_HEADER_LEN..frame.len() - 4];
+        assert_eq!(check_frame_header(&header, 100), Ok(buf.len()));
+        assert!(check_frame_header(&header, 4).is_err());
+        assert!(check_frame_header(&[0, 0, 0, 12, 0, 0, 0, 0], 100).is_err());
+        assert_eq!(
+            ipc_message_kind(buf).unwrap(),
+            Some(MessageKind::CertsReload)
+        );
+        let decoded = decode_ipc_message::<Vec<u32>>(buf).unwrap();
+        assert_eq!(decoded.key.as_deref(), Some("443"));
+        assert_eq!(decoded.payload, [1, 2, 3]);
+
+        // A kind from a newer version.
+        let buf = bincode::encode_to_vec((200u32, "payload"), bincode::config::standard()).unwrap();
+        assert_eq!(ipc_message_kind(&buf).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame() {
+        let message = IpcMessage {
+            kind: MessageKind::CertsReload,
+            key: Some("443".to_string()),
+            payload: vec![1u32, 2, 3],
+        };
+        let frame = encode_frame(&message).unwrap();
+        let mut reader = frame.as_slice();
+        assert!(receive_ipc_frame(&mut reader, 100).await.is_ok());
+
+        // A byte of the message, then of the checksum, changed in transit.
+        for position in [FRAME_HEADER_LEN + 1, frame.len() - 1] {
+            let mut corrupted = frame.clone();
+            corrupted[position] ^= 0xff;
+            let mut reader = corrupted.as_slice();
+            let error = receive_ipc_frame(&mut reader, 100).await.unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "IPC message corrupted, the checksum doesn't match"
+            );
+        }
+    }
+
+    #[test]
+    fn single_instance() {
+        let dir = std::env::temp_dir().join(format!("quark-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("quark.sock").to_string_lossy().to_string();
+        let lock_path = get_lock_path(&socket_path);
+        assert!(lock_path.ends_with("/quark.lock"));
+        // A socket left by a crashed run.
+        std::fs::write(&socket_path, "").unwrap();
+
+        let lock = InstanceLock::acquire(&lock_path, &[&socket_path]).unwrap();
+        assert!(!Path::new(&socket_path).exists());
+        let error = InstanceLock::acquire(&lock_path, &[&socket_path])
+            .err()
+            .unwrap();
+        assert!(error.starts_with(&format!(
+            "Another instance of quark is running (pid {})",
+            std::process::id()
+        )));
+        drop(lock);
+        assert!(!Path::new(&lock_path).exists());
+        drop(InstanceLock::acquire(&lock_path, &[&socket_path]).unwrap());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_handshake() {
+        let (mut parent, mut child) = UnixStream::pair().unwrap();
+        let (parent_res, child_res) = tokio::join!(
+            exchange_hello(&mut parent, "server process"),
+            exchange_hello(&mut child, "main process")
+        );
+        assert!(parent_res.is_ok() && child_res.is_ok());
+
+        let newer = IpcHello {
+            protocol: IPC_PROTOCOL_VERSION + 1,
+            version: "9.9.9".to_string(),
+        };
+        let error = newer.check("main process").unwrap_err();
+        assert!(error.starts_with(&format!(
+            "The main process runs quark 9.9.9 (IPC protocol {})",
+            IPC_PROTOCOL_VERSION + 1
+        )));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_ack() {
+        let (parent, mut child) = UnixStream::pair().unwrap();
+        let mut parent_reader = clone_stream(&parent).unwrap();
+        let parent = Arc::new(Mutex::new(parent));
+        let heartbeat = IpcMessage {
+            kind: MessageKind::Heartbeat,
+            key: None,
+            payload: IpcHeartbeat::new(2),
+        };
+        send_ipc_message(Arc::clone(&parent), heartbeat)
+            .await
+            .unwrap();
+
+        let received = receive_ipc_message::<IpcHeartbeat>(&mut child, MessageKind::Heartbeat)
+            .await
+            .unwrap();
+        assert_eq!(received.payload.missed, 2);
+        assert!(received.payload.delay() < Duration::from_secs(1));
+        let ack = IpcMessage {
+            kind: MessageKind::HeartbeatAck,
+            key: None,
+            payload: IpcHeartbeat::new(0),
+        };
+        write_ipc_message(&mut child, &ack).await.unwrap();
+
+        // Read on the copy of the stream.
+        let ack =
+            receive_ipc_message::<IpcHeartbeat>(&mut parent_reader, MessageKind::HeartbeatAck)
+                .await
+                .unwrap();
+        assert_eq!(ack.payload.missed, 0);
+        assert!(ack.payload.time >= received.payload.time);
+    }
+
+    #[tokio::test]
+    async fn pass_listening_socket() {
+        let (parent, child) = UnixStream::pair().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        send_fds(&parent, &[listener.as_raw_fd()]).await.unwrap();
+        let fds = receive_fds(&child).await.unwrap();
+        assert_eq!(fds.len(), 1);
+        let inherited = std::net::TcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(
+            inherited.local_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+    }
+}
@@ -0,0 +1,12 @@
+// The modules of the main and server processes, also built as a library for
+// the integration tests.
+pub mod alerts;
+pub mod cli;
+pub mod config;
+pub mod http_response;
+pub mod ipc;
+pub mod load_balancing;
+pub mod logs;
+pub mod middleware;
+pub mod server;
+pub mod utils;
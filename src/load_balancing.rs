@@ -1,18 +1,119 @@
+mod outliers;
+mod stats;
+
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicUsize, Arc},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use arc_swap::ArcSwap;
 use twox_hash::XxHash3_64;
 
-use crate::config::Locations;
+use crate::{
+    config::{BackendOptions, DnsBackends, Locations, OutlierDetection},
+    utils::get_current_time,
+};
+
+use stats::TargetStats;
+pub use stats::{BackendStats, BackendStatsSnapshot, DURATION_BUCKETS};
 
 const ALGO_ROUND_ROBIN: &str = "round_robin";
 const ALGO_IP_HASH: &str = "ip_hash";
+pub const ALGO_LEAST_TIME: &str = "least_time";
+// With least_time, a backend other than the fastest one is tried once every
+// this many requests.
+const PROBE_INTERVAL: usize = 10;
 
 #[derive(Debug)]
 pub struct LoadBalancerConfig {
     round_robin: HashMap<u32, RoundRobinConfig>, // id -> RoundRobinConfig
+    dns: HashMap<u32, DnsTarget>,                // id -> DnsTarget
+    limits: HashMap<u32, BackendsLimits>,        // id -> BackendsLimits
+    // Kept by a reload for the same backends, reset otherwise.
+    stats: HashMap<u32, Arc<TargetStats>>,  // id -> TargetStats
+    backend_sets: HashMap<u32, BackendSet>, // id -> BackendSet
+    outliers: HashMap<u32, OutlierDetection>, // id -> OutlierDetection
+}
+
+// Backends of a target. The ids change on each reload, a target takes over
+// the counters of a previous one with the same backends.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum BackendSet {
+    Urls(Vec<String>),
+    Dns {
+        name: String,
+        port: Option<u16>,
+        target: String,
+    },
+}
+
+impl BackendSet {
+    fn of(target: &Locations) -> BackendSet {
+        match &target.dns {
+            Some(dns) => BackendSet::Dns {
+                name: dns.name.clone(),
+                port: dns.port,
+                target: dns.target.clone(),
+            },
+            None => BackendSet::Urls(target.params.location.clone()),
+        }
+    }
+}
+
+// Nothing is allocated to select a backend, the url is borrowed.
+pub struct SelectedBackend {
+    pub index: usize,
+    discovered: Option<Arc<Vec<String>>>, // Backends resolved through DNS.
+    pub guard: Option<ConnGuard>,         // Keep it while the backend is used.
+    pub stats: Option<Arc<BackendStats>>,
+    pub in_flight: Option<InFlight>, // Same, with least_time.
+}
+
+impl SelectedBackend {
+    // Url of the backend, among the servers given to balance.
+    pub fn url<'a>(&'a self, servers: &'a [String]) -> &'a str {
+        let servers = self
+            .discovered
+            .as_deref()
+            .map_or(servers, |backends| backends);
+        &servers[self.index]
+    }
+}
+
+// Backends with a max_conns limit or used as backup.
+#[derive(Debug)]
+struct BackendsLimits {
+    options: Vec<BackendOptions>,
+    active: Vec<Arc<AtomicUsize>>, // Requests in progress of each backend.
+}
+
+pub struct ConnGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A request in progress on a backend, counted by its stats.
+pub struct InFlight(Arc<BackendStats>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Backends of a location discovered through DNS.
+#[derive(Debug)]
+struct DnsTarget {
+    config: DnsBackends,
+    backends: ArcSwap<Vec<String>>, // Last known good set.
 }
 
 #[derive(Debug)]
@@ -23,13 +124,93 @@ struct RoundRobinConfig {
 
 impl LoadBalancerConfig {
     pub fn new(targets: Vec<&Locations>) -> Arc<Self> {
+        LoadBalancerConfig::build(targets, None)
+    }
+
+    // On a reload, the targets whose backends are unchanged keep the stats,
+    // the requests in progress, the round robin position and the resolved
+    // addresses of the previous config.
+    pub fn reload(targets: Vec<&Locations>, previous: &LoadBalancerConfig) -> Arc<Self> {
+        LoadBalancerConfig::build(targets, Some(previous))
+    }
+
+    fn build(targets: Vec<&Locations>, previous: Option<&LoadBalancerConfig>) -> Arc<Self> {
         let mut round_robin = HashMap::new();
+        let mut dns = HashMap::new();
+        let mut limits = HashMap::new();
+        let mut stats = HashMap::new();
+        let mut backend_sets = HashMap::new();
+        let mut outliers = HashMap::new();
+
+        // Each previous target is taken over at most once.
+        let mut previous_ids: HashMap<&BackendSet, Vec<u32>> = HashMap::new();
+        for (id, set) in previous.iter().flat_map(|p| &p.backend_sets) {
+            previous_ids.entry(set).or_default().push(*id);
+        }
+        for ids in previous_ids.values_mut() {
+            ids.sort_unstable_by(|a, b| b.cmp(a));
+        }
+
         for target in targets {
+            // A location routed from several domains is listed again.
+            if backend_sets.contains_key(&target.id) {
+                continue;
+            }
+            let set = BackendSet::of(target);
+            let carried = previous.zip(previous_ids.get_mut(&set).and_then(Vec::pop));
+            backend_sets.insert(target.id, set);
+
+            let target_stats = carried
+                .and_then(|(previous, id)| previous.stats.get(&id).cloned())
+                .unwrap_or_else(|| {
+                    Arc::new(TargetStats::new(&target.params.location, target.ewma_decay))
+                });
+            target_stats.set_ewma_decay(target.ewma_decay);
+            match &target.outlier_detection {
+                Some(config) => {
+                    outliers.insert(target.id, config.clone());
+                }
+                None => {
+                    for (_, backend) in target_stats.backends() {
+                        backend.ejection.clear();
+                    }
+                }
+            }
+            stats.insert(target.id, target_stats);
+            if !target.backend_options.is_empty() {
+                let count = target.backend_options.len();
+                let active = carried
+                    .and_then(|(previous, id)| previous.limits.get(&id))
+                    .filter(|previous| previous.active.len() == count)
+                    .map_or_else(
+                        || (0..count).map(|_| Arc::new(AtomicUsize::new(0))).collect(),
+                        |previous| previous.active.clone(),
+                    );
+                let backends_limits = BackendsLimits {
+                    options: target.backend_options.clone(),
+                    active,
+                };
+                limits.insert(target.id, backends_limits);
+            }
+            if let Some(config) = &target.dns {
+                let backends = carried
+                    .and_then(|(previous, id)| previous.dns.get(&id))
+                    .map_or_else(Arc::default, |previous| previous.backends.load_full());
+                let dns_target = DnsTarget {
+                    config: config.clone(),
+                    backends: ArcSwap::new(backends),
+                };
+                dns.insert(target.id, dns_target);
+            }
             if let Some(algo) = &target.algo {
-                // Create a config for round robin if defined.
-                if ALGO_ROUND_ROBIN == algo.as_str() {
+                // Create a config for round robin if defined, least_time
+                // falls back to it.
+                if [ALGO_ROUND_ROBIN, ALGO_LEAST_TIME].contains(&algo.as_str()) {
+                    let index = carried
+                        .and_then(|(previous, id)| previous.round_robin.get(&id))
+                        .map_or(0, |previous| previous.index.load(Ordering::Relaxed));
                     let mut rr_config = RoundRobinConfig {
-                        index: AtomicUsize::new(0),
+                        index: AtomicUsize::new(index),
                         weights_indices: None,
                     };
                     // Configure weighted round robin if weights are set.
@@ -44,56 +225,293 @@ impl LoadBalancerConfig {
                 }
             }
         }
-        Arc::new(LoadBalancerConfig { round_robin })
+        Arc::new(LoadBalancerConfig {
+            round_robin,
+            dns,
+            limits,
+            stats,
+            backend_sets,
+            outliers,
+        })
+    }
+
+    // Resolve the DNS backends, then refresh them in the background
+    // as long as this config is used.
+    pub async fn start_discovery(self: &Arc<Self>) {
+        for (id, target) in self.dns.iter() {
+            target.refresh().await;
+
+            let lb = Arc::downgrade(self);
+            let id = *id;
+            let refresh = Duration::from_secs(target.config.refresh);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(refresh).await;
+                    // The config has been replaced by a reload.
+                    let Some(lb) = lb.upgrade() else {
+                        break;
+                    };
+                    lb.dns[&id].refresh().await;
+                    if let Some(stats) = lb.stats.get(&id) {
+                        stats.retain(&lb.dns[&id].backends.load());
+                    }
+                }
+            });
+        }
+    }
+
+    // Compare the error ratios of the backends of each target with an
+    // outlier detection, and return the ejected ones in time, as long as
+    // this config is used.
+    pub fn start_outlier_detection(self: &Arc<Self>) {
+        for (id, config) in self.outliers.iter() {
+            let lb = Arc::downgrade(self);
+            let id = *id;
+            let interval = config.interval;
+            tokio::spawn(async move {
+                for tick in 1.. {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    // The config has been replaced by a reload.
+                    let Some(lb) = lb.upgrade() else {
+                        break;
+                    };
+                    let Some(stats) = lb.stats.get(&id) else {
+                        break;
+                    };
+                    let backends = stats.backends();
+                    let now = get_current_time();
+                    outliers::release_ejected(&backends, now);
+                    if tick % interval == 0 {
+                        outliers::eject_outliers(&lb.outliers[&id], &backends, now);
+                    }
+                }
+            });
+        }
     }
 
+    // Returns None if no backend is available.
     pub fn balance(
-        self: &Arc<Self>,
+        &self,
+        id: &u32,
+        servers: &[String],
+        algo: &Option<String>,
+        ip: &str,
+    ) -> Option<SelectedBackend> {
+        let discovered = self.dns.get(id).map(|target| target.backends.load_full());
+        let servers = discovered.as_deref().map_or(servers, |backends| backends);
+
+        let mut index = self.select(id, servers, algo, ip)?;
+        if let (Some(stats), true) = (self.stats.get(id), self.outliers.contains_key(id)) {
+            index = not_ejected(stats, servers, index);
+        }
+        let (index, guard) = match self.limits.get(id) {
+            Some(limits) => {
+                let (index, guard) = limits.acquire(index)?;
+                (index, Some(guard))
+            }
+            None => (index, None),
+        };
+        let stats = self
+            .stats
+            .get(id)
+            .map(|stats| stats.backend(&servers[index]));
+        let in_flight = match (algo.as_deref(), &stats) {
+            (Some(ALGO_LEAST_TIME), Some(stats)) => {
+                stats.in_flight.fetch_add(1, Ordering::Relaxed);
+                Some(InFlight(Arc::clone(stats)))
+            }
+            _ => None,
+        };
+        Some(SelectedBackend {
+            index,
+            discovered,
+            guard,
+            stats,
+            in_flight,
+        })
+    }
+
+    // Stats of the backends of a location, sorted by url.
+    pub fn backend_stats(&self, id: &u32) -> Vec<(String, BackendStatsSnapshot)> {
+        self.stats
+            .get(id)
+            .map_or_else(Vec::new, |stats| stats.snapshot())
+    }
+
+    // Index of the backend to use.
+    fn select(
+        &self,
         id: &u32,
         servers: &[String],
         algo: &Option<String>,
         ip: &str,
-    ) -> String {
+    ) -> Option<usize> {
         let srv_nbr = servers.len();
         // Only one server or no loadbalancing config.
-        if srv_nbr == 1 {
-            return servers.first().unwrap().to_string();
+        if srv_nbr <= 1 {
+            return (srv_nbr == 1).then_some(0);
         }
         if let Some(algo) = algo {
             match algo.as_str() {
                 ALGO_ROUND_ROBIN => {
                     let rr = self.round_robin.get(id).unwrap();
-                    let index = rr.index.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let index = rr.index.fetch_add(1, Ordering::Relaxed);
                     match &rr.weights_indices {
                         // Use weighted round robin.
-                        Some(weights_indices) => {
-                            return servers
-                                .get(weights_indices[index % weights_indices.len()])
-                                .unwrap()
-                                .to_string();
+                        Some(weights_indices) if !weights_indices.is_empty() => {
+                            return Some(weights_indices[index % weights_indices.len()]);
                         }
                         // Use normal round robin.
-                        None => {
-                            return servers.get(index % srv_nbr).unwrap().to_string();
+                        _ => {
+                            return Some(index % srv_nbr);
                         }
                     }
                 }
-                ALGO_IP_HASH => {
-                    let hash = XxHash3_64::oneshot(ip.as_bytes());
-                    let index = hash % srv_nbr as u64;
-                    return servers.get(index as usize).unwrap().to_string();
-                }
+                ALGO_IP_HASH => return Some(rendezvous(ip, servers)),
+                ALGO_LEAST_TIME => return Some(self.least_time(id, servers)),
                 _ => {}
             }
         }
         // Default.
-        servers.first().unwrap().to_string()
+        Some(0)
+    }
+
+    // The backend with the lowest average response time, multiplied by its
+    // requests in progress plus one. The backends are compared in turn from
+    // a rotating start, so equal ones share the requests like round robin.
+    fn least_time(&self, id: &u32, servers: &[String]) -> usize {
+        let count = self
+            .round_robin
+            .get(id)
+            .map_or(0, |rr| rr.index.fetch_add(1, Ordering::Relaxed));
+        let start = count % servers.len();
+        let Some(stats) = self.stats.get(id) else {
+            return start;
+        };
+        let mut best: Option<(usize, f64)> = None;
+        let mut cold = None;
+        for i in 0..servers.len() {
+            let index = (start + i) % servers.len();
+            let backend = stats.backend(&servers[index]);
+            let Some(average) = backend.average() else {
+                cold.get_or_insert(index);
+                continue;
+            };
+            let in_flight = backend.in_flight.load(Ordering::Relaxed);
+            let score = average * (in_flight + 1) as f64;
+            if best.is_none_or(|(_, s)| score < s) {
+                best = Some((index, score));
+            }
+        }
+        match (best, cold) {
+            // No response time at all.
+            (None, _) => start,
+            // The others are probed from time to time: a backend without
+            // response yet first, then the slower ones in turn, so that
+            // their averages follow a recovery.
+            (Some(_), Some(cold)) if count.is_multiple_of(PROBE_INTERVAL) => cold,
+            (Some((index, _)), None) if count.is_multiple_of(PROBE_INTERVAL) => {
+                let probe = count / PROBE_INTERVAL % (servers.len() - 1);
+                (index + 1 + probe) % servers.len()
+            }
+            (Some((index, _)), _) => index,
+        }
     }
 }
 
+// Rendezvous hashing: the client goes to the backend with the highest score.
+// The scores only depend on the ip and the url of each backend, so adding or
+// removing a backend only moves about 1/N of the clients, wherever it is in
+// the list, and the same client gets the same backend after a restart.
+fn rendezvous(ip: &str, servers: &[String]) -> usize {
+    let seed = XxHash3_64::oneshot(ip.as_bytes());
+    servers
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, url)| XxHash3_64::oneshot_with_seed(seed, url.as_bytes()))
+        .map_or(0, |(index, _)| index)
+}
+
+// The selected backend, or the next one which isn't ejected. The selected
+// one if they all are.
+fn not_ejected(stats: &TargetStats, servers: &[String], selected: usize) -> usize {
+    (0..servers.len())
+        .map(|i| (selected + i) % servers.len())
+        .find(|&i| !stats.backend(&servers[i]).ejection.is_ejected())
+        .unwrap_or(selected)
+}
+
+impl BackendsLimits {
+    // Use the selected backend if it isn't a backup and has a free slot.
+    // Otherwise, the next primary backends are tried, then the backups.
+    fn acquire(&self, selected: usize) -> Option<(usize, ConnGuard)> {
+        let count = self.options.len();
+        let primaries = (0..count)
+            .map(|i| (selected + i) % count)
+            .filter(|&i| !self.options[i].backup);
+        let backups = (0..count).filter(|&i| self.options[i].backup);
+
+        for index in primaries.chain(backups) {
+            let active = &self.active[index];
+            let previous = active.fetch_add(1, Ordering::Relaxed);
+            match self.options[index].max_conns {
+                Some(max) if previous >= max => {
+                    active.fetch_sub(1, Ordering::Relaxed);
+                }
+                _ => return Some((index, ConnGuard(active.clone()))),
+            }
+        }
+        None
+    }
+}
+
+impl DnsTarget {
+    // Replace the backends if the resolution succeeds.
+    async fn refresh(&self) {
+        let name = &self.config.name;
+        match resolve_backends(&self.config).await {
+            Ok(backends) if backends.is_empty() => {
+                tracing::warn!("DNS name {name} has no address, keeping the last known backends");
+            }
+            Ok(backends) => {
+                if **self.backends.load() != backends {
+                    tracing::info!("Backends of {name} updated: {}", backends.join(", "));
+                    self.backends.store(Arc::new(backends));
+                }
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "DNS resolution of {name} failed, keeping the last known backends: {e}"
+                );
+            }
+        }
+    }
+}
+
+// The addresses are sorted so the same set of records gives the same list,
+// whatever the order of the DNS answer. It keeps ip_hash stable between refreshes.
+async fn resolve_backends(config: &DnsBackends) -> std::io::Result<Vec<String>> {
+    let addrs = tokio::net::lookup_host((config.name.as_str(), config.port.unwrap_or(0))).await?;
+    let mut addrs: Vec<_> = addrs.collect();
+    addrs.sort();
+    addrs.dedup();
+    let backends = addrs
+        .into_iter()
+        .map(|addr| {
+            let host = match (config.port, addr.ip()) {
+                (Some(_), _) => addr.to_string(),
+                (None, IpAddr::V6(ip)) => format!("[{ip}]"),
+                (None, IpAddr::V4(ip)) => ip.to_string(),
+            };
+            config.target.replace(&config.var, &host)
+        })
+        .collect();
+    Ok(backends)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::{ConfigHeaders, TargetParams};
+    use crate::config::{ConfigHeaders, ForwardedHeaders, TargetParams, UpstreamHttp2};
 
     use super::*;
 
@@ -106,20 +524,346 @@ mod tests {
             },
             algo: Some("round_robin".to_string()),
             weights,
+            ewma_decay: 0.2,
+            outlier_detection: None,
+            proxy_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            cache: None,
+            upstream_http2: UpstreamHttp2::Never,
+            request_buffering: None,
+            max_concurrent: None,
+            forwarded_headers: ForwardedHeaders::default(),
+            dns: None,
+            backend_options: Vec::new(),
         };
         let lb = LoadBalancerConfig::new(vec![&location]);
         (0..count)
             .map(|_| {
-                lb.clone().balance(
+                lb.balance(
                     &location.id,
                     &location.params.location,
                     &location.algo,
                     "1.1.1.1",
                 )
+                .unwrap()
+                .url(&location.params.location)
+                .to_string()
             })
             .collect()
     }
 
+    fn dns_location(name: &str) -> Locations {
+        Locations {
+            id: 1,
+            params: TargetParams {
+                location: Vec::new(),
+                headers: ConfigHeaders::default(),
+            },
+            algo: Some("ip_hash".to_string()),
+            weights: None,
+            ewma_decay: 0.2,
+            outlier_detection: None,
+            proxy_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            cache: None,
+            upstream_http2: UpstreamHttp2::Never,
+            request_buffering: None,
+            max_concurrent: None,
+            forwarded_headers: ForwardedHeaders::default(),
+            backend_options: Vec::new(),
+            dns: Some(DnsBackends {
+                name: name.to_string(),
+                port: Some(8080),
+                refresh: 30,
+                target: "http://${pool}/api".to_string(),
+                var: "${pool}".to_string(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_backends() {
+        let location = dns_location("localhost");
+        let lb = LoadBalancerConfig::new(vec![&location]);
+        // Nothing resolved yet.
+        assert!(lb.balance(&1, &[], &location.algo, "1.1.1.1").is_none());
+
+        lb.start_discovery().await;
+        let backends = lb.dns[&1].backends.load_full();
+        assert!(backends.contains(&"http://127.0.0.1:8080/api".to_string()));
+        assert!(backends.is_sorted());
+        let backend = lb.balance(&1, &[], &location.algo, "1.1.1.1").unwrap();
+        assert!(backends.iter().any(|url| url == backend.url(&[])));
+
+        // A failed resolution keeps the last known backends.
+        let location = dns_location("");
+        let lb = LoadBalancerConfig::new(vec![&location]);
+        lb.dns[&1]
+            .backends
+            .store(Arc::new(vec!["http://10.0.0.1:8080/api".to_string()]));
+        lb.dns[&1].refresh().await;
+        let backend = lb.balance(&1, &[], &location.algo, "1.1.1.1").unwrap();
+        assert_eq!(backend.url(&[]), "http://10.0.0.1:8080/api");
+    }
+
+    #[test]
+    fn max_conns_and_backup() {
+        let option = |max_conns, backup| BackendOptions { max_conns, backup };
+        let location = Locations {
+            id: 2,
+            params: TargetParams {
+                location: vec!["a".to_string(), "b".to_string(), "backup".to_string()],
+                headers: ConfigHeaders::default(),
+            },
+            algo: Some("round_robin".to_string()),
+            weights: Some(vec![1, 1, 0]),
+            ewma_decay: 0.2,
+            outlier_detection: None,
+            proxy_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            cache: None,
+            upstream_http2: UpstreamHttp2::Never,
+            request_buffering: None,
+            max_concurrent: None,
+            forwarded_headers: ForwardedHeaders::default(),
+            dns: None,
+            backend_options: vec![
+                option(Some(1), false),
+                option(Some(1), false),
+                option(None, true),
+            ],
+        };
+        let lb = LoadBalancerConfig::new(vec![&location]);
+        let balance = || {
+            lb.balance(&2, &location.params.location, &location.algo, "1.1.1.1")
+                .unwrap()
+        };
+
+        let servers = &location.params.location;
+        let first = balance();
+        let second = balance();
+        assert_eq!((first.url(servers), second.url(servers)), ("a", "b"));
+        // Both primaries are busy.
+        assert_eq!(balance().url(servers), "backup");
+        drop(second);
+        assert_eq!(balance().url(servers), "b");
+    }
+
+    #[test]
+    fn reload_keeps_counters() {
+        let location = |id: u32, backends: &[&str]| Locations {
+            id,
+            params: TargetParams {
+                location: backends.iter().map(|b| b.to_string()).collect(),
+                headers: ConfigHeaders::default(),
+            },
+            algo: Some("round_robin".to_string()),
+            weights: None,
+            ewma_decay: 0.2,
+            outlier_detection: None,
+            proxy_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            cache: None,
+            upstream_http2: UpstreamHttp2::Never,
+            request_buffering: None,
+            max_concurrent: None,
+            forwarded_headers: ForwardedHeaders::default(),
+            dns: None,
+            backend_options: vec![
+                BackendOptions {
+                    max_conns: Some(1),
+                    backup: false,
+                };
+                backends.len()
+            ],
+        };
+        let first = location(1, &["a", "b", "c"]);
+        let lb = LoadBalancerConfig::new(vec![&first]);
+        let servers = &first.params.location;
+        let in_flight = lb.balance(&1, servers, &first.algo, "1.1.1.1").unwrap();
+        assert_eq!(in_flight.url(servers), "a");
+        in_flight
+            .stats
+            .as_ref()
+            .unwrap()
+            .record(Duration::from_millis(1), false);
+
+        // The same backends under a new id: the round robin goes on, the
+        // request in flight still holds the slot of "a", and the stats
+        // are kept.
+        let same = location(7, &["a", "b", "c"]);
+        let reloaded = LoadBalancerConfig::reload(vec![&same], &lb);
+        let servers = &same.params.location;
+        let next = reloaded
+            .balance(&7, servers, &same.algo, "1.1.1.1")
+            .unwrap();
+        assert_eq!(next.url(servers), "b");
+        drop(next);
+        let next = reloaded
+            .balance(&7, servers, &same.algo, "1.1.1.1")
+            .unwrap();
+        assert_eq!(next.url(servers), "c");
+        drop(next);
+        let next = reloaded
+            .balance(&7, servers, &same.algo, "1.1.1.1")
+            .unwrap();
+        assert_eq!(next.url(servers), "b");
+        drop(next);
+        assert_eq!(reloaded.backend_stats(&7)[0].1.requests, 1);
+        drop(in_flight);
+        let all: Vec<_> = (0..3)
+            .map(|_| {
+                reloaded
+                    .balance(&7, servers, &same.algo, "1.1.1.1")
+                    .unwrap()
+            })
+            .collect();
+        let mut urls: Vec<_> = all.iter().map(|backend| backend.url(servers)).collect();
+        urls.sort();
+        assert_eq!(urls, ["a", "b", "c"]);
+        drop(all);
+
+        // A backend added: everything starts again.
+        let changed = location(2, &["a", "b", "c", "d"]);
+        let reloaded = LoadBalancerConfig::reload(vec![&changed], &reloaded);
+        let servers = &changed.params.location;
+        let next = reloaded
+            .balance(&2, servers, &changed.algo, "1.1.1.1")
+            .unwrap();
+        assert_eq!(next.url(servers), "a");
+        assert_eq!(reloaded.backend_stats(&2)[0].1.requests, 0);
+    }
+
+    #[test]
+    fn least_time() {
+        let location = Locations {
+            id: 4,
+            params: TargetParams {
+                location: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                headers: ConfigHeaders::default(),
+            },
+            algo: Some("least_time".to_string()),
+            weights: None,
+            ewma_decay: 0.2,
+            outlier_detection: None,
+            proxy_timeout: None,
+            connect_timeout: None,
+            upstream_idle_timeout: None,
+            cache: None,
+            upstream_http2: UpstreamHttp2::Never,
+            request_buffering: None,
+            max_concurrent: None,
+            forwarded_headers: ForwardedHeaders::default(),
+            dns: None,
+            backend_options: Vec::new(),
+        };
+        let lb = LoadBalancerConfig::new(vec![&location]);
+        let servers = &location.params.location;
+        let balance = || lb.balance(&4, servers, &location.algo, "1.1.1.1").unwrap();
+        let distribution = |count: usize| {
+            let mut counts = HashMap::new();
+            for _ in 0..count {
+                *counts
+                    .entry(balance().url(servers).to_string())
+                    .or_insert(0) += 1;
+            }
+            ["a", "b", "c"].map(|url| counts.get(url).copied().unwrap_or(0))
+        };
+        let record = |url: &str, ms: u64| {
+            lb.stats[&4]
+                .backend(url)
+                .record(Duration::from_millis(ms), false)
+        };
+
+        // No response time yet: round robin.
+        assert_eq!(distribution(30), [10, 10, 10]);
+
+        // "c" is cold, probed once every 10 requests.
+        record("a", 20);
+        record("b", 50);
+        assert_eq!(distribution(100), [90, 0, 10]);
+
+        // All have a response time, the fastest gets everything but the
+        // probes of the others.
+        record("c", 80);
+        assert_eq!(distribution(50), [45, 2, 3]);
+        // "a" slows down, the average follows it.
+        for _ in 0..10 {
+            record("a", 200);
+        }
+        assert_eq!(distribution(50), [2, 45, 3]);
+
+        // Equal averages: the requests in progress break the tie, and the
+        // idle backends are used in turn.
+        lb.stats[&4].set_ewma_decay(1.0);
+        for url in ["a", "b", "c"] {
+            record(url, 30);
+        }
+        let busy = balance();
+        let busy_url = busy.url(servers).to_string();
+        let others: Vec<_> = (0..4).map(|_| balance().url(servers).to_string()).collect();
+        assert!(others.iter().all(|url| *url != busy_url), "{others:?}");
+        assert_ne!(others[0], others[1]);
+        drop(busy);
+
+        // Each request in progress counts for the average.
+        record("a", 20);
+        record("b", 50);
+        record("c", 80);
+        let held: Vec<_> = (0..3).map(|_| balance()).collect();
+        let urls: Vec<_> = held.iter().map(|backend| backend.url(servers)).collect();
+        assert_eq!(urls, ["a", "a", "b"]);
+    }
+
+    #[test]
+    fn ejected_backends_skipped() {
+        let mut location = dns_location("");
+        location.dns = None;
+        location.algo = Some("round_robin".to_string());
+        location.params.location = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        location.outlier_detection = Some(OutlierDetection {
+            interval: 10,
+            error_percent: 10,
+            min_requests: 10,
+            ejection_time: 30,
+            max_ejection_time: 300,
+            max_ejection_percent: 50,
+        });
+        let lb = LoadBalancerConfig::new(vec![&location]);
+        let servers = &location.params.location;
+        let stats = &lb.stats[&1];
+        for url in ["a", "b", "c"] {
+            for _ in 0..20 {
+                stats
+                    .backend(url)
+                    .record(Duration::from_millis(1), url == "b");
+            }
+        }
+        outliers::eject_outliers(&lb.outliers[&1], &stats.backends(), 0);
+
+        // The requests of "b" go to the next backend.
+        let urls = |lb: &LoadBalancerConfig, count: usize| -> Vec<String> {
+            (0..count)
+                .map(|_| {
+                    let backend = lb.balance(&1, servers, &location.algo, "1.1.1.1").unwrap();
+                    backend.url(servers).to_string()
+                })
+                .collect()
+        };
+        assert_eq!(urls(&lb, 6), ["a", "c", "c", "a", "c", "c"]);
+        assert_eq!(lb.backend_stats(&1)[1].1.ejections, 1);
+
+        // Back once the outlier detection is removed.
+        location.outlier_detection = None;
+        let lb = LoadBalancerConfig::reload(vec![&location], &lb);
+        assert!(!lb.backend_stats(&1)[1].1.ejected);
+        assert_eq!(urls(&lb, 3), ["a", "b", "c"]);
+    }
+
     #[test]
     fn round_robin() {
         let lb = mock_load_balancer(None, 4);
@@ -131,4 +875,57 @@ mod tests {
         let lb = mock_load_balancer(Some(vec![4, 2, 1]), 8);
         assert_eq!(lb, vec!["a", "a", "a", "a", "b", "b", "c", "a"]);
     }
+
+    #[test]
+    fn ip_hash_remapping() {
+        let backends = |names: &[&str]| -> Vec<String> {
+            names
+                .iter()
+                .map(|name| format!("http://{name}:8080"))
+                .collect()
+        };
+        let ips: Vec<String> = (0..10_000u32)
+            .map(|i| std::net::Ipv4Addr::from(i.wrapping_mul(2_654_435_761)).to_string())
+            .collect();
+        let algo = Some("ip_hash".to_string());
+        let lb = LoadBalancerConfig::new(Vec::new());
+        let assign = |servers: &[String]| -> Vec<String> {
+            ips.iter()
+                .map(|ip| {
+                    lb.balance(&0, servers, &algo, ip)
+                        .unwrap()
+                        .url(servers)
+                        .to_string()
+                })
+                .collect()
+        };
+
+        let three = assign(&backends(&["a", "b", "c"]));
+        // Inserted in the middle of the list.
+        let four = assign(&backends(&["a", "d", "b", "c"]));
+        let moved: Vec<_> = three.iter().zip(&four).filter(|(x, y)| x != y).collect();
+        // About 1/4 of the clients, all to the new backend.
+        let fraction = moved.len() as f64 / ips.len() as f64;
+        assert!((0.22..0.28).contains(&fraction), "{fraction}");
+        assert!(moved.iter().all(|(_, to)| *to == "http://d:8080"));
+
+        // Removed: only its clients move, about 1/3 of them.
+        let two = assign(&backends(&["a", "c"]));
+        let moved: Vec<_> = three.iter().zip(&two).filter(|(x, y)| x != y).collect();
+        let fraction = moved.len() as f64 / ips.len() as f64;
+        assert!((0.30..0.37).contains(&fraction), "{fraction}");
+        assert!(moved.iter().all(|(from, _)| *from == "http://b:8080"));
+
+        // The order of the list doesn't matter, and the hash has no random
+        // seed, so a client keeps its backend after a restart.
+        assert_eq!(assign(&backends(&["c", "b", "a"])), three);
+        let pinned =
+            ["a", "a", "a", "b", "a", "b", "b", "c"].map(|name| format!("http://{name}:8080"));
+        assert_eq!(three[..8], pinned);
+        let counts = ["a", "b", "c"].map(|name| {
+            let url = format!("http://{name}:8080");
+            three.iter().filter(|backend| **backend == url).count()
+        });
+        assert!(counts.iter().all(|&count| count > 3000), "{counts:?}");
+    }
 }
@@ -0,0 +1,242 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::config::OutlierDetection;
+
+use super::stats::BackendStats;
+
+// Ejection state of a backend, kept with its stats.
+#[derive(Debug, Default)]
+pub struct Ejection {
+    ejected: AtomicBool, // Read on each request.
+    until: AtomicU64,    // Seconds since the start of the server.
+    // Ejections in a row, lowered by each clean interval. The ejection time
+    // grows with it.
+    streak: AtomicU64,
+    count: AtomicU64, // Since the start.
+    // Counters of the backend at the start of the current interval.
+    window_requests: AtomicU64,
+    window_errors: AtomicU64,
+}
+
+impl Ejection {
+    pub fn is_ejected(&self) -> bool {
+        self.ejected.load(Ordering::Relaxed)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Used when the outlier detection is removed from the config.
+    pub fn clear(&self) {
+        self.ejected.store(false, Ordering::Relaxed);
+        self.streak.store(0, Ordering::Relaxed);
+    }
+}
+
+// Requests and errors of a backend over the last interval.
+struct Window<'a> {
+    url: &'a str,
+    stats: &'a BackendStats,
+    requests: u64,
+    errors: u64,
+}
+
+impl Window<'_> {
+    fn ratio(&self) -> f64 {
+        self.errors as f64 / self.requests as f64
+    }
+}
+
+// Compare the error ratio of each backend over the last interval with the
+// ratio of the whole pool, and eject the backends above it by more than
+// error_percent, the worst first. At most max_ejection_percent of the
+// backends are ejected at once, so the pool is never emptied by a wave of
+// errors shared by all of them.
+pub fn eject_outliers(
+    config: &OutlierDetection,
+    backends: &[(String, Arc<BackendStats>)],
+    now: u64,
+) {
+    let mut windows = Vec::with_capacity(backends.len());
+    let mut ejected = 0;
+    for (url, stats) in backends {
+        let snapshot = stats.snapshot();
+        let ejection = &stats.ejection;
+        let requests = snapshot.requests
+            - ejection
+                .window_requests
+                .swap(snapshot.requests, Ordering::Relaxed);
+        let errors = snapshot.errors
+            - ejection
+                .window_errors
+                .swap(snapshot.errors, Ordering::Relaxed);
+        if ejection.is_ejected() {
+            ejected += 1;
+            continue;
+        }
+        windows.push(Window {
+            url,
+            stats,
+            requests,
+            errors,
+        });
+    }
+
+    let requests: u64 = windows.iter().map(|w| w.requests).sum();
+    if requests == 0 {
+        return;
+    }
+    let errors: u64 = windows.iter().map(|w| w.errors).sum();
+    let average = errors as f64 / requests as f64;
+    let threshold = average + config.error_percent as f64 / 100.0;
+
+    let (mut outliers, clean): (Vec<_>, Vec<_>) = windows
+        .into_iter()
+        .filter(|w| w.requests >= config.min_requests)
+        .partition(|w| w.ratio() > threshold);
+    for window in clean {
+        let _ =
+            window
+                .stats
+                .ejection
+                .streak
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| s.checked_sub(1));
+    }
+
+    let max_ejected = backends.len() * config.max_ejection_percent as usize / 100;
+    outliers.sort_by(|a, b| b.ratio().total_cmp(&a.ratio()));
+    for window in outliers {
+        if ejected >= max_ejected {
+            tracing::warn!(
+                "Backend {} not ejected, {}% of the backends are already ejected: {:.1}% of errors, {:.1}% for the pool",
+                window.url,
+                config.max_ejection_percent,
+                window.ratio() * 100.0,
+                average * 100.0
+            );
+            continue;
+        }
+        ejected += 1;
+        let ejection = &window.stats.ejection;
+        let streak = ejection.streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let duration = config
+            .ejection_time
+            .saturating_mul(streak)
+            .min(config.max_ejection_time);
+        ejection.until.store(now + duration, Ordering::Relaxed);
+        ejection.count.fetch_add(1, Ordering::Relaxed);
+        ejection.ejected.store(true, Ordering::Relaxed);
+        tracing::warn!(
+            "Backend {} ejected for {duration}s: {:.1}% of errors over the last {}s, {:.1}% for the pool",
+            window.url,
+            window.ratio() * 100.0,
+            config.interval,
+            average * 100.0
+        );
+    }
+}
+
+// Return the backends whose ejection time is over.
+pub fn release_ejected(backends: &[(String, Arc<BackendStats>)], now: u64) {
+    for (url, stats) in backends {
+        let ejection = &stats.ejection;
+        if ejection.is_ejected() && ejection.until.load(Ordering::Relaxed) <= now {
+            ejection.ejected.store(false, Ordering::Relaxed);
+            tracing::info!("Backend {url} returned to the pool");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config() -> OutlierDetection {
+        OutlierDetection {
+            interval: 10,
+            error_percent: 10,
+            min_requests: 10,
+            ejection_time: 30,
+            max_ejection_time: 75,
+            max_ejection_percent: 50,
+        }
+    }
+
+    fn record(stats: &BackendStats, requests: usize, errors: usize) {
+        for i in 0..requests {
+            stats.record(Duration::from_millis(1), i < errors);
+        }
+    }
+
+    #[test]
+    fn outlier_ejection() {
+        let config = config();
+        let backends: Vec<_> = (0..4)
+            .map(|i| (format!("http://b{i}"), Arc::new(BackendStats::default())))
+            .collect();
+        let ejected = || -> Vec<bool> {
+            backends
+                .iter()
+                .map(|(_, s)| s.ejection.is_ejected())
+                .collect()
+        };
+
+        // 20% of errors for b0 while the others have none: 8% for the pool.
+        // b3 has too few requests to be judged.
+        record(&backends[0].1, 100, 20);
+        record(&backends[1].1, 100, 0);
+        record(&backends[2].1, 100, 0);
+        record(&backends[3].1, 5, 5);
+        eject_outliers(&config, &backends, 0);
+        assert_eq!(ejected(), [true, false, false, false]);
+        assert_eq!(backends[0].1.ejection.count(), 1);
+
+        // Errors shared by all the backends don't eject anybody.
+        for (_, stats) in &backends[1..] {
+            record(stats, 100, 50);
+        }
+        eject_outliers(&config, &backends, 10);
+        assert_eq!(ejected(), [true, false, false, false]);
+
+        release_ejected(&backends, 29);
+        assert!(backends[0].1.ejection.is_ejected());
+        release_ejected(&backends, 30);
+        assert_eq!(ejected(), [false; 4]);
+
+        // Ejected again for twice as long.
+        record(&backends[0].1, 100, 40);
+        for (_, stats) in &backends[1..] {
+            record(stats, 100, 0);
+        }
+        eject_outliers(&config, &backends, 40);
+        assert_eq!(ejected(), [true, false, false, false]);
+        assert_eq!(backends[0].1.ejection.until.load(Ordering::Relaxed), 100);
+
+        // The worst first, and no more than half of the backends.
+        record(&backends[1].1, 100, 90);
+        record(&backends[2].1, 100, 0);
+        record(&backends[3].1, 100, 80);
+        eject_outliers(&config, &backends, 50);
+        assert_eq!(ejected(), [true, true, false, false]);
+
+        // Capped by max_ejection_time, and a clean interval lowers the streak.
+        release_ejected(&backends, 100);
+        assert_eq!(ejected(), [false; 4]);
+        record(&backends[0].1, 100, 100);
+        for (_, stats) in &backends[1..] {
+            record(stats, 100, 0);
+        }
+        eject_outliers(&config, &backends, 100);
+        assert_eq!(ejected(), [true, false, false, false]);
+        assert_eq!(backends[0].1.ejection.until.load(Ordering::Relaxed), 175);
+        let streak = |i: usize| backends[i].1.ejection.streak.load(Ordering::Relaxed);
+        assert_eq!((streak(0), streak(1)), (3, 0));
+        assert_eq!(backends[0].1.ejection.count(), 3);
+    }
+}
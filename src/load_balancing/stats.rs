@@ -0,0 +1,205 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+
+use super::outliers::Ejection;
+
+// Upper bounds of the duration buckets, in milliseconds. The last bucket
+// counts the slower requests.
+pub const DURATION_BUCKETS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+// Milliseconds counted in the average response time for an error, at least:
+// a backend refusing the connections answers fast.
+const ERROR_TIME: f64 = 5000.0;
+
+// Counters of a backend, updated without locking or allocating.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    duration_sum: AtomicU64, // In milliseconds.
+    duration_max: AtomicU64, // In milliseconds.
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    // Moving average of the response times in milliseconds, the bits of an
+    // f64, 0 before the first response. Used by least_time.
+    average: AtomicU64,
+    ewma_decay: AtomicU64,      // Bits of an f64, the weight of a new response.
+    pub in_flight: AtomicUsize, // Counted for least_time only.
+    pub ejection: Ejection,     // With an outlier detection.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendStatsSnapshot {
+    pub requests: u64,
+    pub errors: u64,
+    pub duration_sum: u64,
+    pub duration_max: u64,
+    pub buckets: [u64; DURATION_BUCKETS.len() + 1],
+    pub ejected: bool,
+    pub ejections: u64,
+}
+
+impl BackendStats {
+    // Time until the response headers were received. The timeouts, the
+    // connection errors and the 5xx responses are errors.
+    pub fn record(&self, duration: Duration, error: bool) {
+        let ms = duration.as_millis() as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.duration_sum.fetch_add(ms, Ordering::Relaxed);
+        self.duration_max.fetch_max(ms, Ordering::Relaxed);
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+
+        let decay = f64::from_bits(self.ewma_decay.load(Ordering::Relaxed));
+        let time = match error {
+            true => (duration.as_secs_f64() * 1000.0).max(ERROR_TIME),
+            false => duration.as_secs_f64() * 1000.0,
+        };
+        let _ = self
+            .average
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let average = match f64::from_bits(bits) {
+                    0.0 => time,
+                    previous => decay * time + (1.0 - decay) * previous,
+                };
+                Some(average.to_bits())
+            });
+    }
+
+    // None until the first response.
+    pub fn average(&self) -> Option<f64> {
+        match self.requests.load(Ordering::Relaxed) {
+            0 => None,
+            _ => Some(f64::from_bits(self.average.load(Ordering::Relaxed))),
+        }
+    }
+
+    pub fn snapshot(&self) -> BackendStatsSnapshot {
+        BackendStatsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            duration_sum: self.duration_sum.load(Ordering::Relaxed),
+            duration_max: self.duration_max.load(Ordering::Relaxed),
+            buckets: self.buckets.each_ref().map(|b| b.load(Ordering::Relaxed)),
+            ejected: self.ejection.is_ejected(),
+            ejections: self.ejection.count(),
+        }
+    }
+}
+
+// Stats of the backends of a location, by url. The entry of a backend is
+// created by its first request, later lookups don't allocate.
+#[derive(Debug, Default)]
+pub struct TargetStats {
+    backends: DashMap<String, Arc<BackendStats>>,
+    ewma_decay: AtomicU64, // Bits of an f64, given to the new backends.
+}
+
+impl TargetStats {
+    pub fn new(backends: &[String], ewma_decay: f64) -> TargetStats {
+        let stats = TargetStats::default();
+        stats.set_ewma_decay(ewma_decay);
+        for url in backends {
+            stats.backend(url);
+        }
+        stats
+    }
+
+    pub fn backend(&self, url: &str) -> Arc<BackendStats> {
+        if let Some(stats) = self.backends.get(url) {
+            return Arc::clone(&stats);
+        }
+        let entry = self.backends.entry(url.to_string()).or_insert_with(|| {
+            let stats = BackendStats::default();
+            stats
+                .ewma_decay
+                .store(self.ewma_decay.load(Ordering::Relaxed), Ordering::Relaxed);
+            Arc::new(stats)
+        });
+        Arc::clone(&entry)
+    }
+
+    // Changed by a reload, the averages are kept.
+    pub fn set_ewma_decay(&self, ewma_decay: f64) {
+        self.ewma_decay
+            .store(ewma_decay.to_bits(), Ordering::Relaxed);
+        for backend in self.backends.iter() {
+            backend
+                .ewma_decay
+                .store(ewma_decay.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    // Forget the backends removed from the DNS records, so the memory stays
+    // bounded when the addresses change.
+    pub fn retain(&self, backends: &[String]) {
+        self.backends.retain(|url, _| backends.contains(url));
+    }
+
+    // Backends known so far, in no particular order.
+    pub fn backends(&self) -> Vec<(String, Arc<BackendStats>)> {
+        self.backends
+            .iter()
+            .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+            .collect()
+    }
+
+    // Sorted by url.
+    pub fn snapshot(&self) -> Vec<(String, BackendStatsSnapshot)> {
+        let mut stats: Vec<_> = self
+            .backends
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().snapshot()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_stats() {
+        let stats = TargetStats::new(&["http://a".to_string(), "http://b".to_string()], 0.5);
+        let a = stats.backend("http://a");
+        a.record(Duration::from_millis(3), false);
+        a.record(Duration::from_millis(40), true);
+        a.record(Duration::from_secs(10), true);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let (url, a) = &snapshot[0];
+        assert_eq!(url, "http://a");
+        assert_eq!((a.requests, a.errors), (3, 2));
+        assert_eq!((a.duration_sum, a.duration_max), (10_043, 10_000));
+        assert_eq!(a.buckets, [1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(snapshot[1].1.requests, 0);
+
+        // Moving average with a decay of 0.5, an error counts as 5 seconds.
+        let b = stats.backend("http://b");
+        assert_eq!(b.average(), None);
+        b.record(Duration::from_millis(10), false);
+        assert_eq!(b.average(), Some(10.0));
+        b.record(Duration::from_millis(30), false);
+        assert_eq!(b.average(), Some(20.0));
+        b.record(Duration::from_millis(1), true);
+        assert_eq!(b.average(), Some(2510.0));
+
+        stats.backend("http://c");
+        stats.retain(&["http://c".to_string()]);
+        assert_eq!(stats.snapshot().len(), 1);
+    }
+}
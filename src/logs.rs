@@ -1,40 +1,362 @@
-use tracing_appender::{non_blocking::WorkerGuard, rolling};
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer};
+pub mod access;
+pub mod rotation;
+pub mod syslog;
 
-pub fn start_logs(path: String) -> WorkerGuard {
-    let appender = rolling::never(path, "logs.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use bincode::{Decode, Encode};
+use rotation::{LogRotation, RotatingFile};
+use syslog::Syslog;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{self, Rotation},
+};
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Layer};
+
+use crate::utils::chown_to_run_as_user;
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_LOG_FILE: &str = "logs.log";
+const DEFAULT_ERROR_LOG_FILE: &str = "error.log";
+const ERROR_LOG_LEVEL: &str = "warn";
+
+#[cfg(debug_assertions)]
+const DEFAULT_STDOUT_LOG_LEVEL: &str = "trace";
+
+#[cfg(not(debug_assertions))]
+const DEFAULT_STDOUT_LOG_LEVEL: &str = DEFAULT_LOG_LEVEL;
+
+// Levels set in turn by SIGUSR1.
+const CYCLED_LOG_LEVELS: [&str; 3] = ["info", "debug", "trace"];
+
+// Sent to the child process to change the level of its logs without a restart.
+#[derive(Debug, Clone, Encode, Decode)]
+pub enum LogLevelChange {
+    Cycle, // Next level of CYCLED_LOG_LEVELS, for each output.
+    Set {
+        output: Option<LogOutput>, // None for both.
+        level: String,
+    },
+}
+
+// The file level also applies to the journald and syslog outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum LogOutput {
+    File,
+    Stdout,
+}
+
+// Destinations of the diagnostic logs, besides the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum LogDestination {
+    File,
+    Journald,
+    Syslog { facility: u8 },
+}
+
+impl LogDestination {
+    pub const NAMES: [&str; 3] = ["file", "journald", "syslog"];
+}
+
+impl fmt::Display for LogDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogDestination::File => write!(f, "file"),
+            LogDestination::Journald => write!(f, "journald"),
+            LogDestination::Syslog { facility } => write!(f, "syslog (facility {facility})"),
+        }
+    }
+}
+
+impl fmt::Display for LogOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogOutput::File => write!(f, "file"),
+            LogOutput::Stdout => write!(f, "stdout"),
+        }
+    }
+}
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+struct OutputLevel {
+    level: Mutex<String>,
+    reload: ReloadFilter,
+}
+
+// Set by start_logs. No stdout level if the logs aren't printed.
+static FILE_LEVEL: OnceLock<OutputLevel> = OnceLock::new();
+static STDOUT_LEVEL: OnceLock<OutputLevel> = OnceLock::new();
+
+fn level_filter(level: &str) -> EnvFilter {
+    EnvFilter::new(format!("quark={level}"))
+}
+
+pub fn change_level(change: LogLevelChange) -> Result<(), String> {
+    let outputs = [
+        (LogOutput::File, FILE_LEVEL.get()),
+        (LogOutput::Stdout, STDOUT_LEVEL.get()),
+    ];
+    let (only, new_level) = match &change {
+        LogLevelChange::Cycle => (None, None),
+        LogLevelChange::Set { output, level } => {
+            check_level(level)?;
+            (*output, Some(level.as_str()))
+        }
+    };
+    for (output, output_level) in outputs {
+        let Some(output_level) = output_level.filter(|_| only.is_none_or(|o| o == output)) else {
+            continue;
+        };
+        let mut level = output_level.level.lock().unwrap_or_else(|e| e.into_inner());
+        let next = new_level.unwrap_or_else(|| next_cycled_level(&level));
+        (output_level.reload)(level_filter(next)).map_err(|e| e.to_string())?;
+        *level = next.to_string();
+        tracing::warn!("Log level of the {output} logs set to {next}");
+    }
+    Ok(())
+}
+
+fn next_cycled_level(level: &str) -> &'static str {
+    match CYCLED_LOG_LEVELS.iter().position(|l| *l == level) {
+        Some(i) => CYCLED_LOG_LEVELS[(i + 1) % CYCLED_LOG_LEVELS.len()],
+        None => CYCLED_LOG_LEVELS[0],
+    }
+}
+
+pub fn check_level(level: &str) -> Result<(), String> {
+    if LOG_LEVELS.contains(&level) {
+        Ok(())
+    } else {
+        Err(format!(
+            "unknown log level \"{level}\" (allowed: {})",
+            LOG_LEVELS.join(", ")
+        ))
+    }
+}
+
+// Writer of a log file, rotated as configured.
+pub fn log_file(dir: &Path, file_name: &str, rotation: &LogRotation) -> Box<dyn Write + Send> {
+    create_log_dir(dir);
+    let (time_rotation, keep) = match rotation {
+        LogRotation::Never => return Box::new(rolling::never(dir, file_name)),
+        LogRotation::Size(size) => {
+            return Box::new(
+                RotatingFile::open(dir, file_name, size.clone())
+                    .expect("initializing rotating log file failed"),
+            )
+        }
+        LogRotation::Daily { keep } => (Rotation::DAILY, keep),
+        LogRotation::Hourly { keep } => (Rotation::HOURLY, keep),
+    };
+    // The date is inserted before the extension.
+    let (prefix, suffix) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (file_name, ""),
+    };
+    let appender = rolling::Builder::new()
+        .rotation(time_rotation)
+        .filename_prefix(prefix)
+        .filename_suffix(suffix)
+        .max_log_files(keep + 1) // The current file is counted.
+        .build(dir)
+        .expect("initializing rolling file appender failed");
+    Box::new(appender)
+}
+
+// The logs are opened before the privileges are dropped, so the directories
+// created here are given to the server user to be rotated later.
+fn create_log_dir(dir: &Path) {
+    match create_dirs(dir) {
+        Ok(created) => {
+            for dir in created {
+                if let Err(e) = chown_to_run_as_user(&dir) {
+                    eprintln!("Failed to give {} to the server user: {e}", dir.display());
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to create the directory {}: {e}", dir.display()),
+    }
+}
+
+// Create a directory and its missing parents, which are returned.
+fn create_dirs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let created: Vec<PathBuf> = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    fs::create_dir_all(dir)?;
+    Ok(created)
+}
+
+// Writer of the error log. A relative path is placed in the logs directory.
+// Like its directory, the file created here is given to the server user.
+fn error_log_file(
+    logs_dir: &str,
+    error_log: &str,
+    rotation: &LogRotation,
+) -> Box<dyn Write + Send> {
+    let path = Path::new(logs_dir).join(error_log);
+    let dir = path.parent().unwrap_or(Path::new(logs_dir));
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(DEFAULT_ERROR_LOG_FILE);
+    create_log_dir(dir);
+    // The time rotations write to dated files, created later.
+    if matches!(rotation, LogRotation::Never | LogRotation::Size(_)) && !path.exists() {
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(_) => {
+                if let Err(e) = chown_to_run_as_user(&path) {
+                    eprintln!("Failed to give {} to the server user: {e}", path.display());
+                }
+            }
+            Err(e) => eprintln!("Failed to create the error log {}: {e}", path.display()),
+        }
+    }
+    log_file(dir, file_name, rotation)
+}
+
+fn non_blocking(writer: Box<dyn Write + Send>) -> (NonBlocking, WorkerGuard) {
+    tracing_appender::non_blocking::NonBlockingBuilder::default()
         .buffered_lines_limit(2048)
         .lossy(true)
-        .finish(appender);
+        .finish(writer)
+}
+
+// Without a configured level, the terminal gets more details in debug builds.
+// A guard is returned for each log file, the other outputs are written
+// without a worker thread.
+pub fn start_logs(
+    path: String,
+    level: Option<&str>,
+    stdout: bool,
+    outputs: &[LogDestination],
+    file_name: &str,
+    error_log: Option<&str>,
+    rotation: &LogRotation,
+) -> Vec<WorkerGuard> {
+    let mut guards = Vec::new();
+    let file_layer = outputs.contains(&LogDestination::File).then(|| {
+        let (writer, guard) = non_blocking(log_file(Path::new(&path), file_name, rotation));
+        guards.push(guard);
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false)
+            .with_file(false)
+            .with_line_number(false)
+    });
+
+    // Only the warnings and errors, whatever the level of the other outputs.
+    let error_layer = error_log.map(|error_log| {
+        let (writer, guard) = non_blocking(error_log_file(&path, error_log, rotation));
+        guards.push(guard);
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false)
+            .with_file(false)
+            .with_line_number(false)
+            .with_filter(level_filter(ERROR_LOG_LEVEL))
+    });
 
-    #[cfg(debug_assertions)]
-    let terminal_filter = EnvFilter::new("quark=trace");
+    // The logging isn't started yet, the errors are only printed.
+    let journald_layer = outputs
+        .contains(&LogDestination::Journald)
+        .then(tracing_journald::layer)
+        .and_then(|layer| {
+            layer
+                .map_err(|e| eprintln!("Failed to connect to journald: {e}"))
+                .ok()
+        });
 
-    let file_filter = EnvFilter::new("quark=info");
+    // The severity of the message gives the level, the daemon adds the time.
+    let syslog_layer = outputs
+        .iter()
+        .find_map(|output| match output {
+            LogDestination::Syslog { facility } => Some(*facility),
+            _ => None,
+        })
+        .and_then(|facility| {
+            Syslog::connect(facility)
+                .map_err(|e| eprintln!("Failed to connect to syslog: {e}"))
+                .ok()
+        })
+        .map(|syslog| {
+            tracing_subscriber::fmt::layer()
+                .with_writer(syslog)
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_file(false)
+                .with_line_number(false)
+        });
 
-    #[cfg(debug_assertions)]
-    let terminal_layer = tracing_subscriber::fmt::layer()
-        .with_file(false)
-        .with_writer(std::io::stdout)
-        .with_filter(terminal_filter);
+    // The filters can be replaced at runtime, see change_level.
+    let terminal_layer = stdout.then(|| {
+        let terminal_level = level.unwrap_or(DEFAULT_STDOUT_LOG_LEVEL);
+        let (terminal_filter, handle) = reload::Layer::new(level_filter(terminal_level));
+        let _ = STDOUT_LEVEL.set(OutputLevel {
+            level: Mutex::new(terminal_level.to_string()),
+            reload: Box::new(move |filter| handle.reload(filter)),
+        });
+        tracing_subscriber::fmt::layer()
+            .with_file(false)
+            .with_writer(std::io::stdout)
+            .with_filter(terminal_filter)
+    });
 
-    let file_layer = tracing_subscriber::fmt::layer()
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .with_file(false)
-        .with_line_number(false)
+    // The file, journald and syslog outputs share the same level.
+    let file_level = level.unwrap_or(DEFAULT_LOG_LEVEL);
+    let (file_filter, handle) = reload::Layer::new(level_filter(file_level));
+    let _ = FILE_LEVEL.set(OutputLevel {
+        level: Mutex::new(file_level.to_string()),
+        reload: Box::new(move |filter| handle.reload(filter)),
+    });
+    // Layer::and_then, not Option::and_then.
+    let outputs_layer = Layer::and_then(Layer::and_then(file_layer, journald_layer), syslog_layer)
         .with_filter(file_filter);
 
-    #[cfg(debug_assertions)]
     let subscriber = tracing_subscriber::registry()
         .with(terminal_layer)
-        .with(file_layer);
-
-    #[cfg(not(debug_assertions))]
-    let subscriber = tracing_subscriber::registry().with(file_layer);
+        .with(outputs_layer)
+        .with(error_layer);
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    guard
+    guards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_log_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("quark/errors");
+        assert_eq!(
+            create_dirs(&dir).unwrap(),
+            [dir.clone(), root.path().join("quark")]
+        );
+        assert!(dir.is_dir());
+        // Nothing to give to the server user.
+        assert!(create_dirs(&dir).unwrap().is_empty());
+        assert!(create_dirs(root.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cycled_levels() {
+        assert_eq!(next_cycled_level("info"), "debug");
+        assert_eq!(next_cycled_level("debug"), "trace");
+        assert_eq!(next_cycled_level("trace"), "info");
+        assert_eq!(next_cycled_level("warn"), "info");
+    }
 }
@@ -0,0 +1,473 @@
+use std::{
+    io::Write,
+    path::Path,
+    sync::{LazyLock, OnceLock},
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+use hyper::{body::Incoming, header, Request};
+use serde::Serialize;
+use time::{
+    format_description::{self, well_known::Rfc3339, BorrowedFormatItem},
+    OffsetDateTime,
+};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+use super::rotation::LogRotation;
+
+pub const DEFAULT_ACCESS_LOG_FILE: &str = "access.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum AccessLogFormat {
+    Combined, // Combined Log Format, as written by Apache and nginx.
+    Json,     // One JsonLine per request.
+}
+
+impl AccessLogFormat {
+    pub fn parse(format: &str) -> Result<AccessLogFormat, String> {
+        match format {
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!(
+                "unknown access log format \"{format}\" (allowed: combined, json)"
+            )),
+        }
+    }
+}
+
+// Share of the requests written to the access log, when it can't keep up with
+// the traffic. The errors are always written.
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub struct AccessLogSampling {
+    pub rate: f64,              // 1 to write every request.
+    pub sample_not_found: bool, // Sample the 404 responses like the successes.
+}
+
+impl AccessLogSampling {
+    pub const ALL: AccessLogSampling = AccessLogSampling {
+        rate: 1.0,
+        sample_not_found: false,
+    };
+
+    pub fn new(rate: f64, sample_not_found: bool) -> Result<AccessLogSampling, String> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!(
+                "access_log_sample must be between 0 and 1, not {rate}"
+            ));
+        }
+        Ok(AccessLogSampling {
+            rate,
+            sample_not_found,
+        })
+    }
+
+    // Rate the request was sampled at, None if it isn't written. The decision
+    // only depends on the request id, so it is the same on each proxy.
+    fn sample(&self, status: u16, request_id: &str) -> Option<f64> {
+        let error = match status {
+            404 => !self.sample_not_found,
+            status => status >= 400,
+        };
+        if error || self.rate >= 1.0 {
+            return Some(1.0);
+        }
+        (sample_point(request_id) < self.rate).then_some(self.rate)
+    }
+}
+
+// Position of the request id in [0, 1). FNV-1a and the splitmix64 finalizer:
+// the hashers of std are randomly seeded, and the generated ids only differ
+// by a counter.
+fn sample_point(request_id: &str) -> f64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in request_id.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+struct AccessLog {
+    writer: NonBlocking,
+    format: AccessLogFormat,
+    sampling: AccessLogSampling,
+}
+
+// Set once at startup, the access log is disabled if it is not set.
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+// Common Log Format timestamp, e.g. 10/Oct/2000:13:55:36 +0000.
+static CLF_TIME_FORMAT: LazyLock<Vec<BorrowedFormatItem<'static>>> = LazyLock::new(|| {
+    format_description::parse(
+        "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]",
+    )
+    .expect("valid time format")
+});
+
+// Open the access log file. A relative file name is placed in the logs directory.
+pub fn start_access_log(
+    logs_dir: &str,
+    file: &str,
+    format: AccessLogFormat,
+    sampling: AccessLogSampling,
+    rotation: &LogRotation,
+) -> WorkerGuard {
+    let path = Path::new(logs_dir).join(file);
+    let dir = path.parent().unwrap_or(Path::new(logs_dir));
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(DEFAULT_ACCESS_LOG_FILE);
+    let appender = super::log_file(dir, file_name, rotation);
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    let _ = ACCESS_LOG.set(AccessLog {
+        writer,
+        format,
+        sampling,
+    });
+    guard
+}
+
+pub fn is_enabled() -> bool {
+    ACCESS_LOG.get().is_some()
+}
+
+// Added by the handler to the extensions of the response, once the target of
+// the request is known.
+#[derive(Debug, Clone)]
+pub struct AccessTarget {
+    pub target_type: &'static str,
+    pub upstream: Option<String>,      // Backend of a proxied request.
+    pub upstream_index: Option<usize>, // Among the backends of the target.
+    // Until the response headers of the backend were received.
+    pub upstream_time: Option<Duration>,
+}
+
+// Request side of an access log line, completed with the response.
+pub struct AccessEntry {
+    client_ip: String,
+    time: OffsetDateTime,
+    start: Instant,
+    method: String,
+    host: String,
+    path: String, // With the query string.
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+}
+
+// Fields of a JSON access log line. The names are relied upon by the log
+// pipelines: add new fields, but never rename or remove one.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    // RFC 3339, UTC, when the request was received.
+    timestamp: String,
+    client_ip: &'a str,
+    method: &'a str,
+    host: &'a str,
+    // With the query string.
+    path: &'a str,
+    status: u16,
+    // Until the last byte of the response body was sent.
+    duration_ms: u64,
+    // Body only, without the headers. Partial when aborted.
+    bytes_sent: u64,
+    // Approximated: the status line and headers as HTTP/1 text.
+    header_bytes: u64,
+    // The body wasn't sent completely, e.g. the client went away.
+    aborted: bool,
+    // Backend url, for the proxied requests.
+    upstream: Option<&'a str>,
+    // Index of the backend among the ones of the target, from 0.
+    upstream_index: Option<usize>,
+    // Part of duration_ms spent waiting for the response headers of the
+    // backend, for the proxied requests.
+    upstream_ms: Option<u64>,
+    // location, file_server or redirection. Null when no route matched.
+    target_type: Option<&'a str>,
+    request_id: &'a str,
+    // With access_log_sample, each line stands for 1 / sample_rate requests.
+    // 1 for the errors, always written.
+    sample_rate: f64,
+}
+
+impl AccessEntry {
+    // None when the access log is disabled.
+    pub fn from_request(
+        req: &Request<Incoming>,
+        client_ip: &str,
+        request_id: &str,
+    ) -> Option<AccessEntry> {
+        ACCESS_LOG.get()?;
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+        let host = req
+            .uri()
+            .authority()
+            .map(|a| a.to_string())
+            .or_else(|| header(header::HOST))
+            .unwrap_or_default();
+        Some(AccessEntry {
+            client_ip: client_ip.to_string(),
+            time: OffsetDateTime::now_utc(),
+            start: Instant::now(),
+            method: req.method().to_string(),
+            host,
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_string(),
+            version: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            request_id: request_id.to_string(),
+        })
+    }
+
+    // Combined Log Format, the remote user is never known. Followed by the
+    // duration in milliseconds, like $request_time of nginx.
+    fn format_combined(&self, status: u16, sent: &SentResponse) -> String {
+        let bytes = match sent.body_bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {status} {bytes} \"{}\" \"{}\" {}\n",
+            self.client_ip,
+            self.time.format(&CLF_TIME_FORMAT).unwrap_or_default(),
+            escape(&self.method),
+            escape(&self.path),
+            self.version,
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            self.duration(sent).as_millis(),
+        )
+    }
+
+    // Until the last byte of the body was sent, or until now when aborted.
+    fn duration(&self, sent: &SentResponse) -> Duration {
+        sent.end
+            .unwrap_or_else(Instant::now)
+            .duration_since(self.start)
+    }
+
+    fn format_json(
+        &self,
+        status: u16,
+        sent: &SentResponse,
+        target: Option<&AccessTarget>,
+        sample_rate: f64,
+    ) -> String {
+        let duration = self.duration(sent);
+        let line = JsonLine {
+            timestamp: self.time.format(&Rfc3339).unwrap_or_default(),
+            client_ip: &self.client_ip,
+            method: &self.method,
+            host: &self.host,
+            path: &self.path,
+            status,
+            duration_ms: duration.as_millis() as u64,
+            bytes_sent: sent.body_bytes,
+            header_bytes: sent.header_bytes,
+            aborted: sent.end.is_none(),
+            upstream: target.and_then(|t| t.upstream.as_deref()),
+            upstream_index: target.and_then(|t| t.upstream_index),
+            upstream_ms: target
+                .and_then(|t| t.upstream_time)
+                .map(|time| time.as_millis() as u64),
+            target_type: target.map(|t| t.target_type),
+            request_id: &self.request_id,
+            sample_rate,
+        };
+        let mut json = serde_json::to_string(&line).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+}
+
+// What was sent of a response, counted by the middleware.
+#[derive(Debug, Default)]
+pub struct SentResponse {
+    pub header_bytes: u64, // Approximated.
+    pub body_bytes: u64,
+    // When the last frame of the body was sent, None while it's streamed
+    // and for an aborted response.
+    pub end: Option<Instant>,
+}
+
+// The line is written once the body is dropped, after the last frame was
+// sent or when the client went away.
+pub struct AccessRecord {
+    entry: AccessEntry,
+    status: u16,
+    target: Option<AccessTarget>,
+}
+
+impl AccessRecord {
+    pub fn new(entry: AccessEntry, status: u16, target: Option<AccessTarget>) -> AccessRecord {
+        AccessRecord {
+            entry,
+            status,
+            target,
+        }
+    }
+
+    pub fn write(self, sent: &SentResponse) {
+        let Some(log) = ACCESS_LOG.get() else {
+            return;
+        };
+        let Some(sample_rate) = log.sampling.sample(self.status, &self.entry.request_id) else {
+            return;
+        };
+        let line = match log.format {
+            AccessLogFormat::Combined => self.entry.format_combined(self.status, sent),
+            AccessLogFormat::Json => {
+                self.entry
+                    .format_json(self.status, sent, self.target.as_ref(), sample_rate)
+            }
+        };
+        let _ = log.writer.clone().write_all(line.as_bytes());
+    }
+}
+
+// Escape the quotes, backslashes and control characters of the client values.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            client_ip: "192.168.0.1".to_string(),
+            time: OffsetDateTime::from_unix_timestamp(971186136).unwrap(),
+            start: Instant::now(),
+            method: "GET".to_string(),
+            host: "example.com".to_string(),
+            path: "/index.html?a=1".to_string(),
+            version: "HTTP/1.1".to_string(),
+            referer: None,
+            user_agent: Some("curl/8.0 \"quoted\"".to_string()),
+            request_id: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn combined_log_format() {
+        let entry = entry();
+        let sent = |body_bytes| SentResponse {
+            header_bytes: 80,
+            body_bytes,
+            end: Some(entry.start + Duration::from_millis(35)),
+        };
+        assert_eq!(
+            entry.format_combined(200, &sent(2326)),
+            "192.168.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html?a=1 HTTP/1.1\" 200 2326 \"-\" \"curl/8.0 \\\"quoted\\\"\" 35\n"
+        );
+        assert!(entry.format_combined(304, &sent(0)).contains("\" 304 - \""));
+    }
+
+    #[test]
+    fn json_log_format() {
+        let target = AccessTarget {
+            target_type: "location",
+            upstream: Some("http://10.0.0.1:3000".to_string()),
+            upstream_index: Some(1),
+            upstream_time: Some(Duration::from_millis(20)),
+        };
+        let failed = entry();
+        let sent = SentResponse {
+            header_bytes: 80,
+            body_bytes: 12,
+            end: Some(failed.start + Duration::from_millis(35)),
+        };
+        let line = failed.format_json(502, &sent, Some(&target), 1.0);
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["timestamp"], "2000-10-10T13:55:36Z");
+        assert_eq!(json["host"], "example.com");
+        assert_eq!(json["path"], "/index.html?a=1");
+        assert_eq!(json["status"], 502);
+        assert_eq!(json["duration_ms"], 35);
+        assert_eq!(json["bytes_sent"], 12);
+        assert_eq!(json["header_bytes"], 80);
+        assert_eq!(json["aborted"], false);
+        assert_eq!(json["upstream"], "http://10.0.0.1:3000");
+        assert_eq!(json["upstream_index"], 1);
+        assert_eq!(json["upstream_ms"], 20);
+        assert_eq!(json["target_type"], "location");
+        assert_eq!(json["request_id"], "abc");
+        assert_eq!(json["sample_rate"], 1.0);
+        assert!(line.ends_with("}\n"));
+
+        let line = entry().format_json(400, &SentResponse::default(), None, 0.25);
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["aborted"], true);
+        assert!(json["upstream"].is_null() && json["upstream_ms"].is_null());
+        assert!(json["upstream_index"].is_null());
+        assert!(json["target_type"].is_null());
+        assert_eq!(json["sample_rate"], 0.25);
+    }
+
+    #[test]
+    fn sampling() {
+        assert!(AccessLogSampling::new(1.5, false).is_err());
+        assert!(AccessLogSampling::new(-0.1, false).is_err());
+        assert!(AccessLogSampling::new(f64::NAN, false).is_err());
+        assert_eq!(AccessLogSampling::ALL.sample(200, "abc"), Some(1.0));
+
+        let sampling = AccessLogSampling::new(0.1, false).unwrap();
+        let ids: Vec<String> = (0..10_000u64)
+            .map(|count| format!("{:016x}{count:016x}", 0x5eed))
+            .collect();
+        let sampled = ids
+            .iter()
+            .filter(|id| sampling.sample(200, id).is_some())
+            .count();
+        assert!((800..1200).contains(&sampled), "{sampled}");
+        // The same decision for the same request.
+        for id in &ids[..100] {
+            assert_eq!(sampling.sample(302, id), sampling.sample(302, id));
+            assert_eq!(sampling.sample(302, id), sampling.sample(200, id));
+        }
+        let skipped = ids
+            .iter()
+            .find(|id| sampling.sample(200, id).is_none())
+            .unwrap();
+        for status in [400, 403, 404, 500, 502] {
+            assert_eq!(sampling.sample(status, skipped), Some(1.0), "{status}");
+        }
+        let sampling = AccessLogSampling::new(0.1, true).unwrap();
+        assert_eq!(sampling.sample(404, skipped), None);
+        assert_eq!(sampling.sample(410, skipped), Some(1.0));
+        let sampled = ids
+            .iter()
+            .find(|id| sampling.sample(200, id).is_some())
+            .unwrap();
+        assert_eq!(sampling.sample(404, sampled), Some(0.1));
+
+        let errors_only = AccessLogSampling::new(0.0, false).unwrap();
+        assert!(ids.iter().all(|id| errors_only.sample(200, id).is_none()));
+    }
+}
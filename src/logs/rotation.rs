@@ -0,0 +1,215 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
+
+use bincode::{Decode, Encode};
+use flate2::{write::GzEncoder, Compression};
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum LogRotation {
+    Never,
+    Size(SizeRotation),
+    // The files are named <stem>.<date>.<extension>, e.g. logs.2025-01-31.log.
+    Daily { keep: usize },
+    Hourly { keep: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct SizeRotation {
+    pub size: u64, // In bytes.
+    pub keep: usize,
+    pub compress: bool,
+}
+
+impl LogRotation {
+    pub const MODES: [&str; 4] = ["never", "size", "daily", "hourly"];
+}
+
+// Log file renamed to <name>.1 once it reaches the maximum size, the older
+// files are shifted to <name>.2, <name>.3... and the ones beyond `keep` are
+// deleted. It is written by the worker thread of the non blocking writer, each
+// write is a whole line, so the lines are never split between two files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: SizeRotation,
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    pub fn open(dir: &Path, file_name: &str, rotation: SizeRotation) -> io::Result<RotatingFile> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(file_name);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            rotation,
+            compressing: None,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // The previous file must be compressed before being shifted.
+        if let Some(handle) = self.compressing.take() {
+            let _ = handle.join();
+        }
+        self.file.flush()?;
+
+        for ext in ["", ".gz"] {
+            let oldest = self.rotated_path(self.rotation.keep, ext);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for i in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(i, ext);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(i + 1, ext))?;
+                }
+            }
+        }
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let rotated = self.rotated_path(1, "");
+            fs::rename(&self.path, &rotated)?;
+            if self.rotation.compress {
+                self.compressing = Some(std::thread::spawn(move || {
+                    if let Err(e) = compress(&rotated) {
+                        eprintln!("Failed to compress the log file {}: {e}", rotated.display());
+                    }
+                }));
+            }
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize, ext: &str) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}{ext}"));
+        PathBuf::from(name)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.rotation.size {
+            // Keep writing to the current file if it can't be rotated.
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate the log file {}: {e}", self.path.display());
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Replace the file with <file>.gz.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let mut input = BufReader::new(File::open(path)?);
+    let mut encoder = GzEncoder::new(File::create(&gz_name)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+// Size like "100MB", "512KB", "1GB" or a number of bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "K" => 1024,
+        "MB" | "M" => 1024 * 1024,
+        "GB" | "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size \"{size}\" (e.g. \"100MB\")")),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size \"{size}\" (e.g. \"100MB\")"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100MB"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("512 kb"), Ok(512 * 1024));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("0MB").is_err());
+        assert!(parse_size("10 TB").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn rotate_and_delete_old_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = SizeRotation {
+            size: 10,
+            keep: 2,
+            compress: false,
+        };
+        let mut file = RotatingFile::open(dir.path(), "logs.log", rotation).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("logs.log"), "line 4\n");
+        assert_eq!(read("logs.log.1"), "line 3\n");
+        assert_eq!(read("logs.log.2"), "line 2\n");
+        assert!(!dir.path().join("logs.log.3").exists());
+    }
+
+    #[test]
+    fn compress_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = SizeRotation {
+            size: 10,
+            keep: 3,
+            compress: true,
+        };
+        let mut file = RotatingFile::open(dir.path(), "access.log", rotation).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.compressing.take().unwrap().join().unwrap();
+        assert!(dir.path().join("access.log.1.gz").exists());
+        assert!(dir.path().join("access.log.2.gz").exists());
+        assert!(!dir.path().join("access.log.1").exists());
+
+        let gz = File::open(dir.path().join("access.log.2.gz")).unwrap();
+        let mut content = String::new();
+        io::Read::read_to_string(&mut flate2::read::GzDecoder::new(gz), &mut content).unwrap();
+        assert_eq!(content, "line 1\n");
+    }
+}
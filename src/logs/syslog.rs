@@ -0,0 +1,179 @@
+use std::{
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[cfg(target_os = "freebsd")]
+const SYSLOG_SOCKET_PATH: &str = "/var/run/log";
+
+#[cfg(target_os = "macos")]
+const SYSLOG_SOCKET_PATH: &str = "/var/run/syslog";
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+const SYSLOG_SOCKET_PATH: &str = "/dev/log";
+
+pub const DEFAULT_SYSLOG_FACILITY: &str = "daemon";
+
+// Facility names and codes, RFC 5424.
+const FACILITIES: [(&str, u8); 20] = [
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+pub fn parse_facility(name: &str) -> Result<u8, String> {
+    FACILITIES
+        .iter()
+        .find(|(facility, _)| *facility == name)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| {
+            let names: Vec<&str> = FACILITIES.iter().map(|(name, _)| *name).collect();
+            format!(
+                "unknown syslog facility \"{name}\" (allowed: {})",
+                names.join(", ")
+            )
+        })
+}
+
+// Sends each event to the local syslog daemon, one datagram per event. The
+// level of the event gives the severity of the message.
+pub struct Syslog {
+    path: PathBuf,
+    socket: Mutex<UnixDatagram>,
+    facility: u8,
+}
+
+pub struct SyslogMessage<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+}
+
+impl Syslog {
+    pub fn connect(facility: u8) -> io::Result<Syslog> {
+        Syslog::connect_to(Path::new(SYSLOG_SOCKET_PATH), facility)
+    }
+
+    fn connect_to(path: &Path, facility: u8) -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Syslog {
+            path: path.to_path_buf(),
+            socket: Mutex::new(socket),
+            facility,
+        })
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let mut socket = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+        if socket.send(message).is_ok() {
+            return Ok(());
+        }
+        // The daemon may have been restarted.
+        let new_socket = UnixDatagram::unbound()?;
+        new_socket.connect(&self.path)?;
+        *socket = new_socket;
+        socket.send(message).map(|_| ())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage {
+            syslog: self,
+            severity: severity(&Level::INFO),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogMessage {
+            syslog: self,
+            severity: severity(meta.level()),
+        }
+    }
+}
+
+impl Write for SyslogMessage<'_> {
+    // The formatter writes a whole event at once.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let message = format_message(self.syslog.facility, self.severity, buf);
+        self.syslog.send(&message)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+// <PRI>quark[pid]: message, the daemon adds the time and the host name.
+fn format_message(facility: u8, severity: u8, event: &[u8]) -> Vec<u8> {
+    let event = event.strip_suffix(b"\n").unwrap_or(event);
+    let mut message = format!(
+        "<{}>quark[{}]: ",
+        facility as u16 * 8 + severity as u16,
+        std::process::id()
+    )
+    .into_bytes();
+    message.extend_from_slice(event);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn facilities() {
+        assert_eq!(parse_facility("daemon"), Ok(3));
+        assert_eq!(parse_facility("local7"), Ok(23));
+        assert!(parse_facility("local8").is_err());
+    }
+
+    #[test]
+    fn send_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let daemon = UnixDatagram::bind(&path).unwrap();
+
+        let syslog = Syslog::connect_to(&path, parse_facility("local0").unwrap()).unwrap();
+        syslog.make_writer().write_all(b"Server started\n").unwrap();
+
+        let mut buf = [0; 128];
+        let len = daemon.recv(&mut buf).unwrap();
+        let expected = format!("<134>quark[{}]: Server started", std::process::id());
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(), expected);
+    }
+}
@@ -1,119 +1,864 @@
-mod config;
-mod http_response;
-mod ipc;
-mod load_balancing;
-mod logs;
-mod middleware;
-mod server;
-mod utils;
-
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{set_permissions, Permissions};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::os::unix::fs::{chown, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use quark::{cli, config, ipc, logs, server, utils};
 
 use config::tls::{self, IpcCerts};
 use config::{InternalConfig, Options};
+use ipc::MessageKind;
 
-use nix::unistd::{getuid, User};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getuid, Pid};
+use socket2::SockRef;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::Child;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::Mutex;
-use utils::QUARK_USER_AND_GROUP;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+// Time given to a new child to accept connections.
+const CHILD_READY_TIMEOUT: Duration = Duration::from_secs(30);
+// Time given to a child to identify itself on the IPC socket.
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A crashed child is restarted after 1s, 2s, 4s... The main process gives up
+// and exits when it crashes more often, so systemd can take over.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+// Exit code of a Rust program after a panic.
+const PANIC_EXIT_CODE: i32 = 101;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use nix::sys::signal::{kill, Signal};
-    use nix::unistd::Pid;
-
     // If the child process flag is set, run the server as a child process.
     if std::env::args().any(|arg| arg == "--child-process") {
         return server::server_process().await;
     }
 
+    // Only validate the configuration, without binding any socket.
+    let options: Options = argh::from_env();
+    if let Some(Err(e)) = options.log_level.as_deref().map(logs::check_level) {
+        eprintln!("Invalid --log-level: {e}");
+        std::process::exit(1);
+    }
+    match options.ipc_max_message_size {
+        Some(0) => {
+            eprintln!("Invalid --ipc-max-message-size: it must be at least 1");
+            std::process::exit(1);
+        }
+        Some(megabytes) => ipc::set_max_message_size(megabytes),
+        None => {}
+    }
+    if options.check {
+        return check_config(&options).await;
+    }
+    if options.print_config {
+        return print_config(&options);
+    }
+
     // If not, run a new process flagged as a child process.
 
-    let socket_path = ipc::get_socket_path();
-    // Clean the socket file if it exists.
-    if std::path::Path::new(&socket_path).exists() {
-        println!("[Main Process] Removing socket file");
-        std::fs::remove_file(&socket_path)?;
+    let socket_path = ipc::get_socket_path(options.socket_path.as_deref());
+    let admin_socket_path = ipc::get_admin_socket_path(&socket_path);
+    // Talk to the running server, without touching its sockets.
+    if let Some(command) = options.command {
+        return cli::run(command, &admin_socket_path).await;
+    }
+    if let Err(e) = ipc::check_socket_path(&socket_path)
+        .and_then(|_| ipc::check_socket_path(&admin_socket_path))
+    {
+        eprintln!("{e}");
+        std::process::exit(1);
     }
-
     // Take the rest of the arguments and pass them to the child process.
     let mut child_args: Vec<String> = std::env::args().skip(1).collect();
     child_args.insert(0, "--child-process".to_string());
+    // The child uses the same socket, wherever the path comes from.
+    if options.socket_path.is_none() {
+        child_args.extend(["--socket-path".to_string(), socket_path.clone()]);
+    }
 
-    // Create the child process.
-    let mut child = std::process::Command::new(std::env::current_exe()?)
-        .args(child_args)
-        .spawn()?;
+    // The path is read now, an upgrade runs the binary installed at the same
+    // path even if the file was replaced.
+    let child_command = ChildCommand {
+        program: std::env::current_exe()?,
+        args: child_args,
+    };
 
-    // Check for SIGTERM.
-    let child_id = child.id() as i32;
+    // Run the main process. The socket files are removed when it returns.
+    let res = main_process(&socket_path, &child_command).await;
 
-    // Run the main process.
-    main_process().await?;
+    // The exit code of the child when it exited by itself.
+    let code = res.unwrap_or_else(|e| {
+        eprintln!("[Main Process] {e}");
+        1
+    });
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
 
-    println!("[Main Process] Sending SIGTERM to child");
-    kill(Pid::from_raw(child_id), Signal::SIGTERM).ok();
-    std::fs::remove_file(ipc::get_socket_path()).ok();
+struct ChildCommand {
+    program: PathBuf,
+    args: Vec<String>,
+}
 
-    child.wait()?;
-    Ok(())
+impl ChildCommand {
+    // A child is never left orphaned when the main process fails.
+    fn spawn(&self) -> std::io::Result<Child> {
+        tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .kill_on_drop(true)
+            .spawn()
+    }
 }
 
-async fn main_process() -> Result<(), Box<dyn std::error::Error>> {
-    let is_root = getuid().is_root();
-    let socket_path = ipc::get_socket_path();
-    let quark_user = if is_root {
-        Some(
-            User::from_name(QUARK_USER_AND_GROUP)
-                .expect("User lookup failed")
-                .expect("User not found"),
-        )
-    } else {
-        None
+// Killed by a signal (e.g. by the OOM killer) or panicked. The other exits are
+// deliberate, restarting wouldn't help.
+fn crashed(status: &ExitStatus) -> bool {
+    status.signal().is_some() || status.code() == Some(PANIC_EXIT_CODE)
+}
+
+// Delay before the next restart, or None when the child crashes too often.
+fn restart_delay(restarts: &mut VecDeque<Instant>, now: Instant) -> Option<Duration> {
+    while restarts
+        .front()
+        .is_some_and(|restart| now.duration_since(*restart) >= RESTART_WINDOW)
+    {
+        restarts.pop_front();
+    }
+    if restarts.len() >= MAX_RESTARTS {
+        return None;
+    }
+    let delay = Duration::from_secs(1 << restarts.len()).min(MAX_RESTART_DELAY);
+    restarts.push_back(now);
+    Some(delay)
+}
+
+fn terminate(child: &Child) {
+    if let Some(id) = child.id() {
+        kill(Pid::from_raw(id as i32), Signal::SIGTERM).ok();
+    }
+}
+
+// Stop a child gracefully, it is reaped in the background. Its stream is
+// kept open until then, it would try to reconnect otherwise.
+fn stop_child(mut child: Child, stream: Option<Arc<Mutex<UnixStream>>>) {
+    terminate(&child);
+    tokio::spawn(async move {
+        let id = child.id().unwrap_or_default();
+        if let Ok(status) = child.wait().await {
+            println!("[Main Process] Child process {id} exited ({status})");
+        }
+        drop(stream);
+    });
+}
+
+// A child connected to the IPC socket, after the version check.
+struct ChildConnection {
+    pid: u32,
+    reconnect: bool,
+    stream: UnixStream,
+}
+
+// Accept the connections of the children for the whole life of the main
+// process: the new ones, and those reconnecting after the stream broke.
+fn accept_children(ipc_listener: UnixListener) -> mpsc::Receiver<ChildConnection> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let stream = match ipc_listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("[Main Process] IPC accept error: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            // A slow peer doesn't block the others.
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(ATTACH_TIMEOUT, attach_child(stream)).await {
+                    Ok(Ok(connection)) => {
+                        tx.send(connection).await.ok();
+                    }
+                    Ok(Err(e)) => eprintln!("[Main Process] IPC connection refused. {e}"),
+                    Err(_) => eprintln!("[Main Process] IPC connection refused, no attach message"),
+                }
+            });
+        }
+    });
+    rx
+}
+
+async fn attach_child(mut stream: UnixStream) -> Result<ChildConnection, String> {
+    ipc::exchange_hello(&mut stream, "server process")
+        .await
+        .map_err(|e| e.to_string())?;
+    let attach = ipc::receive_ipc_message::<ipc::IpcAttach>(&mut stream, MessageKind::Attach)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(ChildConnection {
+        pid: attach.payload.pid,
+        reconnect: attach.payload.reconnect,
+        stream,
+    })
+}
+
+// Wait for the connection of a new child.
+async fn wait_connection(
+    connections: &mut mpsc::Receiver<ChildConnection>,
+    pid: u32,
+) -> Result<UnixStream, Box<dyn std::error::Error>> {
+    while let Some(connection) = connections.recv().await {
+        if connection.pid == pid && !connection.reconnect {
+            println!("[Main Process] Connection accepted");
+            return Ok(connection.stream);
+        }
+        eprintln!(
+            "[Main Process] Unexpected IPC connection of the process {}, closed",
+            connection.pid
+        );
+    }
+    Err("The IPC socket is closed".into())
+}
+
+// Return the exit code of the main process.
+async fn main_process(
+    socket_path: &str,
+    child_command: &ChildCommand,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    // Get options from command line.
+    let options: Options = argh::from_env();
+
+    // Load the TOML config file and build the internal config. All the
+    // certificates are checked, so that they can be fixed at once.
+    let internal_config = load_config(&options).await.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    // Sent again to a child restarted after a crash.
+    let mut running_config = internal_config.clone();
+
+    let run_as = match getuid().is_root() {
+        true => Some(utils::lookup_run_as(
+            &internal_config.global.user,
+            &internal_config.global.group,
+        )?),
+        false => None,
     };
 
     // Create a unix socket listener.
-    if let Some(parent) = Path::new(&socket_path).parent() {
+    // Only a directory created here is given to the server user,
+    // a custom path can be in a shared directory.
+    if let Some(parent) = Path::new(socket_path).parent().filter(|p| !p.exists()) {
         std::fs::create_dir_all(parent)
             .map_err(|e| format!("Can't create socket directory {parent:?}: {e}"))?;
 
-        if let Some(user) = &quark_user {
-            chown(parent, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
+        if let Some((user, group)) = &run_as {
+            chown(parent, Some(user.uid.as_raw()), Some(group.gid.as_raw())).map_err(|e| {
+                format!("Can't give the directory {parent:?} to {}: {e}", user.name)
+            })?;
         }
     }
 
-    let listener = tokio::net::UnixListener::bind(&socket_path)
-        .map_err(|e| format!("Can't use the socket at {} : {}", &socket_path, e))?;
+    // Another instance would lose its socket.
+    let _lock = ipc::InstanceLock::acquire(
+        &ipc::get_lock_path(socket_path),
+        &[socket_path, &ipc::get_admin_socket_path(socket_path)],
+    )?;
 
-    if let Some(user) = &quark_user {
-        let path = Path::new(&socket_path);
-        chown(path, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
-        set_permissions(&socket_path, Permissions::from_mode(0o600))?;
+    let ipc_listener = UnixListener::bind(socket_path)
+        .map_err(|e| format!("Can't use the socket at {} : {}", socket_path, e))?;
+
+    if run_as.is_some() {
+        chown_socket(socket_path, &internal_config)?;
+        set_permissions(socket_path, Permissions::from_mode(0o600))
+            .map_err(|e| format!("Can't set the permissions of the socket {socket_path}: {e}"))?;
     }
+    let mut connections = accept_children(ipc_listener);
+
+    let mut tls_files = read_tls_files(&internal_config).await?;
+
+    // The listening sockets are kept here, each child receives them. Those
+    // passed by systemd are used as they are, the other ports are bound.
+    let mut listeners = {
+        let activated = activated_listeners(utils::systemd_listeners()?);
+        if !activated.is_empty() {
+            let ports = server::listener_ports(&internal_config);
+            for port in ports.iter().filter(|port| !activated.contains_key(port)) {
+                eprintln!(
+                    "[Main Process] Warning: port {port} isn't passed by systemd, binding it"
+                );
+            }
+            for port in activated.keys().filter(|port| !ports.contains(port)) {
+                eprintln!("[Main Process] Warning: the socket of port {port} passed by systemd isn't used");
+            }
+        }
+        let activated = activated
+            .into_iter()
+            .map(|(port, fd)| (port, vec![fd]))
+            .collect();
+        // Only the copies are kept, they aren't inherited by the child.
+        bind_listeners(&internal_config, &activated)?
+    };
+
+    // Wait for SIGTERM or SIGINT. Reload the configuration on SIGHUP, start a
+    // new child on SIGUSR2.
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
 
-    println!("[Main Process] Waiting for connection");
-    let (stream, _) = listener.accept().await?;
+    // The child is killed when it is dropped, if anything fails from here.
+    let (mut child, mut stream) = start_child(
+        child_command,
+        &mut connections,
+        internal_config,
+        &mut tls_files,
+        &listeners,
+    )
+    .await?;
+
+    // Watch certificates
+    let mut watchers = watch_certificates(tls_files.paths_to_watch, tls_files.servers, &stream)?;
+    let (stuck_tx, mut stuck_rx) = mpsc::unbounded_channel();
+    let mut heartbeat = start_heartbeat(&stream, child.id(), stuck_tx.clone());
+
+    let mut restarts = VecDeque::new();
+    'main: loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                println!("[Main Process] SIGTERM received");
+                break;
+            }
+            _ = sigint.recv() => {
+                println!("[Main Process] SIGINT received");
+                break;
+            }
+            status = child.wait() => {
+                let status = status?;
+                heartbeat.abort();
+                if !crashed(&status) {
+                    println!("[Main Process] The server exited ({status})");
+                    return Ok(status.code().unwrap_or_default());
+                }
+                eprintln!("[Main Process] The server crashed ({status})");
+                loop {
+                    let Some(delay) = restart_delay(&mut restarts, Instant::now()) else {
+                        return Err(format!(
+                            "The server crashed again after {MAX_RESTARTS} restarts in {}s, giving up",
+                            RESTART_WINDOW.as_secs()
+                        )
+                        .into());
+                    };
+                    println!("[Main Process] Restarting the server in {}s", delay.as_secs());
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = sigterm.recv() => break 'main,
+                        _ = sigint.recv() => break 'main,
+                    }
+                    match restart_child(child_command, &mut connections, &running_config, &listeners)
+                        .await
+                    {
+                        Ok((restarted, restarted_stream, tls_files)) => {
+                            child = restarted;
+                            stream = restarted_stream;
+                            replace_watchers(&mut watchers, tls_files, &stream);
+                            heartbeat = start_heartbeat(&stream, child.id(), stuck_tx.clone());
+                            println!(
+                                "[Main Process] Server restarted ({})",
+                                child.id().unwrap_or_default()
+                            );
+                            break;
+                        }
+                        Err(e) => eprintln!("[Main Process] Restart failed. {e}"),
+                    }
+                }
+            }
+            Some(connection) = connections.recv() => {
+                if !connection.reconnect || Some(connection.pid) != child.id() {
+                    eprintln!(
+                        "[Main Process] Unexpected IPC connection of the process {}, closed",
+                        connection.pid
+                    );
+                    continue;
+                }
+                println!("[Main Process] The server reconnected, sending the certificates again");
+                *stream.lock().await = connection.stream;
+                // The acks come on the new stream.
+                heartbeat.abort();
+                heartbeat = start_heartbeat(&stream, child.id(), stuck_tx.clone());
+                match resync_child(&running_config, &stream).await {
+                    Ok(tls_files) => replace_watchers(&mut watchers, tls_files, &stream),
+                    Err(e) => eprintln!("[Main Process] Failed to send the certificates. {e}"),
+                }
+            }
+            Some(pid) = stuck_rx.recv() => kill_stuck(&mut child, pid),
+            _ = sighup.recv() => {
+                println!("[Main Process] SIGHUP received, reloading the configuration");
+                match reload_config(&options, &running_config, &stream).await {
+                    Ok((internal_config, tls_files)) => {
+                        running_config = internal_config;
+                        // Watch the certificates of the new configuration.
+                        replace_watchers(&mut watchers, tls_files, &stream);
+                        println!("[Main Process] Configuration reloaded");
+                    }
+                    Err(e) => eprintln!(
+                        "[Main Process] Reload failed, the running configuration is kept. {e}"
+                    ),
+                }
+            }
+            _ = sigusr1.recv() => {
+                println!("[Main Process] SIGUSR1 received, changing the log level");
+                let message = ipc::IpcMessage {
+                    kind: MessageKind::LogLevel,
+                    key: None,
+                    payload: logs::LogLevelChange::Cycle,
+                };
+                if let Err(e) = ipc::send_ipc_message(stream.clone(), message).await {
+                    eprintln!("[Main Process] Failed to send the log level change. {e}");
+                }
+            }
+            _ = sigusr2.recv() => {
+                println!("[Main Process] SIGUSR2 received, starting a new server");
+                match upgrade(&options, child_command, &mut connections, &mut listeners).await {
+                    Ok(upgraded) => {
+                        println!(
+                            "[Main Process] New server ready, stopping the previous one ({})",
+                            child.id().unwrap_or_default()
+                        );
+                        let previous_stream = std::mem::replace(&mut stream, upgraded.stream);
+                        stop_child(
+                            std::mem::replace(&mut child, upgraded.child),
+                            Some(previous_stream),
+                        );
+                        running_config = upgraded.config;
+                        if run_as.is_some() {
+                            if let Err(e) = chown_socket(socket_path, &running_config) {
+                                eprintln!("[Main Process] {e}");
+                            }
+                        }
+                        replace_watchers(&mut watchers, upgraded.tls_files, &stream);
+                        heartbeat.abort();
+                        heartbeat = start_heartbeat(&stream, child.id(), stuck_tx.clone());
+                    }
+                    Err(e) => eprintln!(
+                        "[Main Process] Upgrade failed, the running server is kept. {e}"
+                    ),
+                }
+            }
+        }
+    }
+
+    println!("[Main Process] Sending SIGTERM to child");
+    terminate(&child);
+    child.wait().await?;
+    Ok(0)
+}
+
+// Tell the child that the main process is alive, and read its acks on a copy
+// of the stream: nothing else is read once the child is ready. The child
+// reconnects by itself when the stream is broken, the failures are ignored.
+// A child answering nothing for HEARTBEAT_RESTART_TIMEOUT while the stream is
+// open is stuck, its pid is sent to be killed.
+fn start_heartbeat(
+    stream: &Arc<Mutex<UnixStream>>,
+    pid: Option<u32>,
+    stuck: mpsc::UnboundedSender<u32>,
+) -> JoinHandle<()> {
+    spawn_heartbeat(stream, pid, stuck, HEARTBEAT_TIMINGS)
+}
+
+// Delays of the heartbeat of the main process, shorter in the tests.
+#[derive(Clone, Copy)]
+struct HeartbeatTimings {
+    interval: Duration,
+    warning: Duration, // Without ack, before a warning.
+    restart: Duration, // Without ack, before the child is killed.
+}
+
+const HEARTBEAT_TIMINGS: HeartbeatTimings = HeartbeatTimings {
+    interval: ipc::HEARTBEAT_INTERVAL,
+    warning: ipc::HEARTBEAT_TIMEOUT,
+    restart: ipc::HEARTBEAT_RESTART_TIMEOUT,
+};
+
+fn spawn_heartbeat(
+    stream: &Arc<Mutex<UnixStream>>,
+    pid: Option<u32>,
+    stuck: mpsc::UnboundedSender<u32>,
+    timings: HeartbeatTimings,
+) -> JoinHandle<()> {
+    let stream = Arc::clone(stream);
+    tokio::spawn(async move {
+        let (ack_tx, mut acks) = mpsc::unbounded_channel();
+        let reader = ipc::clone_stream(&*stream.lock().await);
+        let mut reading = reader.is_ok();
+        if let Err(e) = &reader {
+            eprintln!("[Main Process] Can't read the heartbeat acks of the server. {e}");
+        }
+        // Never dropped while a frame is read, the stream would be out of sync.
+        let read_acks = async move {
+            if let Ok(reader) = reader {
+                read_heartbeat_acks(reader, ack_tx).await;
+            }
+        };
+        tokio::pin!(read_acks);
+        let mut interval = tokio::time::interval(timings.interval);
+        // Sending time of the first heartbeat without ack yet: a main process
+        // too busy to send them doesn't take the child for a stuck one.
+        let mut unacked: Option<Instant> = None;
+        let mut missed = 0;
+        let mut late = false;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    // The acks already read count, even if the main process
+                    // was busy until now.
+                    while let Ok(ack) = acks.try_recv() {
+                        heartbeat_acked(&ack, &mut late);
+                        unacked = None;
+                    }
+                    let silence = unacked.map_or(Duration::ZERO, |sent| sent.elapsed());
+                    if reading && silence >= timings.warning && !late {
+                        late = true;
+                        missed += 1;
+                        eprintln!(
+                            "[Main Process] Warning: no heartbeat ack from the server for {}s, it may be stuck (missed heartbeats on this connection: {missed})",
+                            timings.warning.as_secs()
+                        );
+                    }
+                    if reading && silence >= timings.restart {
+                        if let Some(pid) = pid {
+                            stuck.send(pid).ok();
+                        }
+                        return;
+                    }
+                    let message = ipc::IpcMessage {
+                        kind: MessageKind::Heartbeat,
+                        key: None,
+                        payload: ipc::IpcHeartbeat::new(missed),
+                    };
+                    if ipc::send_ipc_message(Arc::clone(&stream), message).await.is_ok() {
+                        unacked.get_or_insert_with(Instant::now);
+                    }
+                }
+                Some(ack) = acks.recv() => {
+                    heartbeat_acked(&ack, &mut late);
+                    unacked = None;
+                }
+                // The stream is broken, the heartbeat is started again when
+                // the child reconnects.
+                _ = &mut read_acks, if reading => reading = false,
+            }
+        }
+    })
+}
+
+fn heartbeat_acked(ack: &ipc::IpcHeartbeat, late: &mut bool) {
+    if std::mem::take(late) {
+        println!(
+            "[Main Process] The server is responding again (ack after {}ms)",
+            ack.delay().as_millis()
+        );
+    }
+}
+
+// Restarted like after a crash, once it is killed.
+fn kill_stuck(child: &mut Child, pid: u32) {
+    if Some(pid) == child.id() {
+        eprintln!(
+            "[Main Process] The server ({pid}) sent no heartbeat ack for {}s, killing it",
+            ipc::HEARTBEAT_RESTART_TIMEOUT.as_secs()
+        );
+        child.start_kill().ok();
+    }
+}
+
+// Until the stream is broken. The other kinds of messages are skipped.
+async fn read_heartbeat_acks(
+    mut reader: UnixStream,
+    acks: mpsc::UnboundedSender<ipc::IpcHeartbeat>,
+) {
+    loop {
+        let Ok(frame) =
+            ipc::receive_ipc_frame(&mut reader, MessageKind::HeartbeatAck.max_size()).await
+        else {
+            return;
+        };
+        if let Ok(Some(MessageKind::HeartbeatAck)) = ipc::ipc_message_kind(&frame) {
+            if let Ok(message) = ipc::decode_ipc_message::<ipc::IpcHeartbeat>(&frame) {
+                acks.send(message.payload).ok();
+            }
+        }
+    }
+}
+
+// Give the IPC socket to the user of the server process, which connects again
+// with this user when the stream breaks.
+fn chown_socket(socket_path: &str, internal_config: &InternalConfig) -> Result<(), String> {
+    let global = &internal_config.global;
+    let (user, group) = utils::lookup_run_as(&global.user, &global.group)?;
+    chown(
+        socket_path,
+        Some(user.uid.as_raw()),
+        Some(group.gid.as_raw()),
+    )
+    .map_err(|e| {
+        format!(
+            "Can't give the socket {socket_path} to {}: {e}",
+            global.user
+        )
+    })
+}
+
+// Sockets passed by systemd by port. Only the first socket of a port is used,
+// the others are closed.
+fn activated_listeners(sockets: Vec<(u16, OwnedFd)>) -> HashMap<u16, OwnedFd> {
+    let mut activated = HashMap::new();
+    for (port, fd) in sockets {
+        match activated.entry(port) {
+            Entry::Vacant(entry) => {
+                entry.insert(fd);
+            }
+            Entry::Occupied(_) => {
+                eprintln!(
+                    "[Main Process] Warning: systemd passed several sockets for port {port}, closing socket {}",
+                    fd.as_raw_fd()
+                );
+                drop(fd);
+            }
+        }
+    }
+    activated
+}
+
+// Bind the listening sockets of the configuration, one per accept loop of
+// each port. The sockets of the current ports are shared, so no connection is
+// refused during an upgrade.
+fn bind_listeners(
+    internal_config: &InternalConfig,
+    current: &HashMap<u16, Vec<OwnedFd>>,
+) -> Result<HashMap<u16, Vec<OwnedFd>>, String> {
+    let accept_loops = server::accept_loops(internal_config);
+    let ports = server::listener_ports(internal_config);
+    if ports.len() * accept_loops > ipc::MAX_PASSED_FDS {
+        return Err(format!(
+            "Too many listening sockets ({} port(s) with {accept_loops} accept loop(s), the maximum is {})",
+            ports.len(),
+            ipc::MAX_PASSED_FDS
+        ));
+    }
+    let reuse_port = accept_loops > 1;
+    let mut listeners = HashMap::new();
+    for port in ports {
+        let current = current.get(&port).map(Vec::as_slice).unwrap_or_default();
+        // More accept loops than before: the current sockets must allow the
+        // new ones on their port.
+        if reuse_port && current.len() < accept_loops {
+            for fd in current {
+                SockRef::from(fd)
+                    .set_reuse_port(true)
+                    .map_err(|e| format!("Can't share port {port}: {e}"))?;
+            }
+        }
+        let mut fds = Vec::with_capacity(accept_loops);
+        for index in 0..accept_loops {
+            let fd = match current.get(index) {
+                Some(fd) => fd.try_clone(),
+                None => server::bind_listener(port, internal_config.global.backlog, reuse_port)
+                    .map(OwnedFd::from),
+            }
+            .map_err(|e| format!("Can't listen on port {port}: {e}"))?;
+            fds.push(fd);
+        }
+        listeners.insert(port, fds);
+    }
+    Ok(listeners)
+}
+
+// Spawn a child and wait until it accepts connections. The child is stopped
+// if it fails or isn't ready in time.
+async fn start_child(
+    child_command: &ChildCommand,
+    connections: &mut mpsc::Receiver<ChildConnection>,
+    internal_config: InternalConfig,
+    tls_files: &mut TlsFiles,
+    listeners: &HashMap<u16, Vec<OwnedFd>>,
+) -> Result<(Child, Arc<Mutex<UnixStream>>), Box<dyn std::error::Error>> {
+    let mut child = child_command.spawn()?;
+    let pid = child.id().unwrap_or_default();
+    let init = async {
+        println!("[Main Process] Waiting for connection");
+        let stream = wait_connection(connections, pid).await?;
+        init_child(stream, internal_config, tls_files, listeners).await
+    };
+    let res = tokio::select! {
+        res = tokio::time::timeout(CHILD_READY_TIMEOUT, init) => res
+            .unwrap_or_else(|_| Err("The new server wasn't ready in time".into())),
+        status = child.wait() => match status {
+            Ok(status) => Err(format!("The new server exited ({status})").into()),
+            Err(e) => Err(e.into()),
+        },
+    };
+    match res {
+        Ok(stream) => Ok((child, stream)),
+        Err(e) => {
+            stop_child(child, None);
+            Err(e)
+        }
+    }
+}
+
+// Send the config, the certificates and the listening sockets to a new child,
+// then wait for its ready message.
+async fn init_child(
+    stream: UnixStream,
+    internal_config: InternalConfig,
+    tls_files: &mut TlsFiles,
+    listeners: &HashMap<u16, Vec<OwnedFd>>,
+) -> Result<Arc<Mutex<UnixStream>>, Box<dyn std::error::Error>> {
     let stream = Arc::new(Mutex::new(stream));
-    println!("[Main Process] Connection accepted");
 
-    // Get options from command line.
-    let options: Options = argh::from_env();
+    // Send the config to the child process.
+    let message = ipc::IpcMessage {
+        kind: MessageKind::Config,
+        key: None,
+        payload: internal_config,
+    };
+    ipc::send_ipc_message(stream.clone(), message).await?;
+
+    // Send the certs to the child process.
+    let message = ipc::IpcMessage {
+        kind: MessageKind::Certs,
+        key: None,
+        payload: std::mem::take(&mut tls_files.certs),
+    };
+    ipc::send_ipc_message(stream.clone(), message).await?;
 
-    // Load the TOML config file and build the internal config.
-    let internal_config = InternalConfig::build_from(options.config);
+    // Send the client authentication CAs to the child process.
+    let message = ipc::IpcMessage {
+        kind: MessageKind::ClientCa,
+        key: None,
+        payload: std::mem::take(&mut tls_files.client_cas),
+    };
+    ipc::send_ipc_message(stream.clone(), message).await?;
 
-    let mut paths_to_watch_list: HashMap<u16, Vec<PathBuf>> = HashMap::new();
-    let mut cert_list: HashMap<u16, Vec<IpcCerts>> = HashMap::new();
-    let mut tls_servers: HashMap<u16, Vec<config::TlsCertificate>> = HashMap::new();
+    // Send the port of each listening socket, then the sockets.
+    let (ports, fds): (Vec<u16>, Vec<RawFd>) = listeners
+        .iter()
+        .flat_map(|(port, fds)| fds.iter().map(|fd| (*port, fd.as_raw_fd())))
+        .unzip();
+    let message = ipc::IpcMessage {
+        kind: MessageKind::Listeners,
+        key: None,
+        payload: ports,
+    };
+    ipc::send_ipc_message(stream.clone(), message).await?;
+    ipc::send_fds(&*stream.lock().await, &fds).await?;
 
-    for server in internal_config.servers.values() {
+    // Wait until the child accepts connections.
+    ipc::receive_ipc_message::<u32>(&mut *stream.lock().await, MessageKind::Ready).await?;
+    Ok(stream)
+}
+
+// Start a child with the running configuration after a crash. The
+// certificates are read again, they may have been renewed since.
+async fn restart_child(
+    child_command: &ChildCommand,
+    connections: &mut mpsc::Receiver<ChildConnection>,
+    running_config: &InternalConfig,
+    listeners: &HashMap<u16, Vec<OwnedFd>>,
+) -> Result<(Child, Arc<Mutex<UnixStream>>, TlsFiles), Box<dyn std::error::Error>> {
+    let mut tls_files = read_tls_files(running_config).await?;
+    let (child, stream) = start_child(
+        child_command,
+        connections,
+        running_config.clone(),
+        &mut tls_files,
+        listeners,
+    )
+    .await?;
+    Ok((child, stream, tls_files))
+}
+
+struct Upgrade {
+    child: Child,
+    stream: Arc<Mutex<UnixStream>>,
+    config: InternalConfig,
+    tls_files: TlsFiles,
+}
+
+// Start a new child with the configuration and the binary on disk. The ports
+// removed from the configuration are closed once it is ready. Nothing changes
+// if anything fails, the running child keeps serving.
+async fn upgrade(
+    options: &Options,
+    child_command: &ChildCommand,
+    connections: &mut mpsc::Receiver<ChildConnection>,
+    listeners: &mut HashMap<u16, Vec<OwnedFd>>,
+) -> Result<Upgrade, Box<dyn std::error::Error>> {
+    let internal_config = load_config(options).await?;
+    if getuid().is_root() {
+        let global = &internal_config.global;
+        utils::lookup_run_as(&global.user, &global.group)?;
+    }
+    let mut tls_files = read_tls_files(&internal_config).await?;
+    let next_listeners = bind_listeners(&internal_config, listeners)?;
+
+    let (child, stream) = start_child(
+        child_command,
+        connections,
+        internal_config.clone(),
+        &mut tls_files,
+        &next_listeners,
+    )
+    .await?;
+    *listeners = next_listeners;
+    Ok(Upgrade {
+        child,
+        stream,
+        config: internal_config,
+        tls_files,
+    })
+}
+
+// Files read by the parent process for the https ports.
+struct TlsFiles {
+    certs: HashMap<u16, Vec<IpcCerts>>,
+    client_cas: HashMap<u16, Vec<u8>>,
+    paths_to_watch: HashMap<u16, Vec<PathBuf>>,
+    servers: HashMap<u16, (Vec<config::TlsCertificate>, Option<config::TlsCertificate>)>,
+}
+
+async fn read_tls_files(internal_config: &InternalConfig) -> Result<TlsFiles, String> {
+    let mut tls_files = TlsFiles {
+        certs: HashMap::new(),
+        client_cas: HashMap::new(),
+        paths_to_watch: HashMap::new(),
+        servers: HashMap::new(),
+    };
+
+    for (name, server) in &internal_config.servers {
         if let Some(tls_certs) = &server.tls {
             let port = server.https_port;
-            tls_servers.insert(port, tls_certs.clone());
+            let context = |e: String| format!("[servers.{name}] https port {port}: {e}");
+            tls_files
+                .servers
+                .insert(port, (tls_certs.clone(), server.default_cert.clone()));
             println!("[Main Process] Server {port} is configured with TLS");
             println!("[Main Process] tls {tls_certs:#?}");
             for cert in tls_certs {
@@ -122,65 +867,409 @@ async fn main_process() -> Result<(), Box<dyn std::error::Error>> {
                 // Check if the file is a symlink.
                 if path.is_symlink() {
                     // If it is, add the target of the symlink to the list of paths to watch.
-                    let target = std::fs::canonicalize(path).unwrap();
-                    add_path_to_watcher(target, port, &mut paths_to_watch_list);
+                    let target = std::fs::canonicalize(path).map_err(|e| {
+                        context(format!("Can't resolve the link {} : {e}", cert.cert))
+                    })?;
+                    add_path_to_watcher(target, port, &mut tls_files.paths_to_watch);
                 }
                 // Add the directory of the file to the list of paths to watch.
-                add_path_to_watcher(path.to_path_buf(), port, &mut paths_to_watch_list);
+                add_path_to_watcher(path.to_path_buf(), port, &mut tls_files.paths_to_watch);
                 // Read the certificate and the key.
-                match IpcCerts::build(&cert.cert, &cert.key).await {
-                    Ok(certs) => {
-                        cert_list.entry(port).or_default().push(certs);
-                    }
-                    Err(e) => panic!("Error. {e}"),
-                }
+                let mut certs = IpcCerts::build(cert).await.map_err(context)?;
+                certs.default = server.default_cert.as_ref() == Some(cert);
+                tls_files.certs.entry(port).or_default().push(certs);
+            }
+            // Read the CA bundle used for client authentication.
+            if let Some(client_auth) = &server.client_auth {
+                let ca = tls::read_client_ca(&client_auth.ca)
+                    .await
+                    .map_err(context)?;
+                tls_files.client_cas.insert(port, ca);
             }
         }
     }
 
-    println!("[Main Process] paths to watch {paths_to_watch_list:#?}");
+    println!(
+        "[Main Process] paths to watch {:#?}",
+        tls_files.paths_to_watch
+    );
+    Ok(tls_files)
+}
 
-    // Send the config to the child process.
-    let message = ipc::IpcMessage {
-        kind: "config".to_string(),
-        key: None,
-        payload: internal_config,
-    };
-    ipc::send_ipc_message(stream.clone(), message).await?;
+// Watch the certificates of each https port. The watchers already started
+// are stopped if one fails.
+fn watch_certificates(
+    paths_to_watch_list: HashMap<u16, Vec<PathBuf>>,
+    tls_servers: HashMap<u16, (Vec<config::TlsCertificate>, Option<config::TlsCertificate>)>,
+    stream: &Arc<Mutex<UnixStream>>,
+) -> Result<Vec<JoinHandle<()>>, String> {
+    let mut watchers = Vec::new();
+    for (port, paths_to_watch) in paths_to_watch_list {
+        let Some((certs, default_cert)) = tls_servers.get(&port).cloned() else {
+            continue;
+        };
+        match tls::watch_certs(
+            &paths_to_watch,
+            port,
+            Arc::clone(stream),
+            certs,
+            default_cert,
+        ) {
+            Ok(watcher) => watchers.push(watcher),
+            Err(e) => {
+                watchers.iter().for_each(|watcher| watcher.abort());
+                return Err(e);
+            }
+        }
+    }
+    Ok(watchers)
+}
+
+// Watch the certificates of a new configuration or a new child. A failure
+// only stops the automatic reload of the certificates.
+fn replace_watchers(
+    watchers: &mut Vec<JoinHandle<()>>,
+    tls_files: TlsFiles,
+    stream: &Arc<Mutex<UnixStream>>,
+) {
+    watchers.iter().for_each(|watcher| watcher.abort());
+    *watchers = watch_certificates(tls_files.paths_to_watch, tls_files.servers, stream)
+        .unwrap_or_else(|e| {
+            eprintln!("[Main Process] {e}. The certificates won't be reloaded when they change.");
+            Vec::new()
+        });
+}
+
+// Build the new configuration and send it to the child process with the
+// certificates. The running configuration isn't changed if anything fails.
+async fn reload_config(
+    options: &Options,
+    running_config: &InternalConfig,
+    stream: &Arc<Mutex<UnixStream>>,
+) -> Result<(InternalConfig, TlsFiles), Box<dyn std::error::Error>> {
+    let internal_config = load_config(options).await?;
+
+    let restart_settings = running_config.restart_settings();
+    let settings = internal_config.restart_settings();
+    if settings != restart_settings {
+        return Err(format!(
+            "The listeners changed, a restart or an upgrade (SIGUSR2) is required.\nRunning: {restart_settings:#?}\nNew: {settings:#?}"
+        )
+        .into());
+    }
+
+    let mut tls_files = read_tls_files(&internal_config).await?;
 
-    // Send the certs to the child process.
     let message = ipc::IpcMessage {
-        kind: "certs".to_string(),
+        kind: MessageKind::ConfigReload,
         key: None,
-        payload: cert_list,
+        payload: config::ConfigReload {
+            config: internal_config.clone(),
+            client_cas: std::mem::take(&mut tls_files.client_cas),
+        },
     };
     ipc::send_ipc_message(stream.clone(), message).await?;
+    send_certs(stream, std::mem::take(&mut tls_files.certs)).await?;
 
-    // Watch certificates
-    for (port, paths_to_watch) in paths_to_watch_list {
-        let stream = Arc::clone(&stream);
-        let certs = tls_servers.get(&port).unwrap().clone();
-        tokio::task::spawn(async move {
-            tls::watch_certs(&paths_to_watch, port, stream, certs).await;
-        });
+    Ok((internal_config, tls_files))
+}
+
+// Send the certificates of the running configuration to a reconnected child,
+// they may have been renewed while it was disconnected.
+async fn resync_child(
+    running_config: &InternalConfig,
+    stream: &Arc<Mutex<UnixStream>>,
+) -> Result<TlsFiles, Box<dyn std::error::Error>> {
+    let mut tls_files = read_tls_files(running_config).await?;
+    send_certs(stream, std::mem::take(&mut tls_files.certs)).await?;
+    Ok(tls_files)
+}
+
+// Send the certificates of each https port.
+async fn send_certs(
+    stream: &Arc<Mutex<UnixStream>>,
+    certs: HashMap<u16, Vec<IpcCerts>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (port, certs) in certs {
+        let message = ipc::IpcMessage {
+            kind: MessageKind::CertsReload,
+            key: Some(port.to_string()),
+            payload: certs,
+        };
+        ipc::send_ipc_message(stream.clone(), message).await?;
     }
+    Ok(())
+}
 
-    // Wait for SIGTERM or SIGINT.
-    let mut sigterm = signal(SignalKind::terminate())?;
-    let mut sigint = signal(SignalKind::interrupt())?;
-    tokio::select! {
-        _ = sigterm.recv() => println!("[Main Process] SIGTERM received"),
-        _ = sigint.recv() => println!("[Main Process] SIGINT received"),
+// Build the config and validate the files it references.
+async fn load_config(options: &Options) -> Result<InternalConfig, Box<dyn std::error::Error>> {
+    let internal_config = options.build_config()?;
+    // The user and group are ignored when not started as root.
+    let errors = validate_config(&internal_config, getuid().is_root()).await;
+    if !errors.is_empty() {
+        return Err(format!(
+            "Invalid configuration, {} error(s) found.\n{}",
+            errors.len(),
+            errors.join("\n")
+        )
+        .into());
+    }
+    Ok(internal_config)
+}
+
+// Validate the config file and the files it references, then exit.
+async fn check_config(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let internal_config = options.build_config().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let errors = validate_config(&internal_config, true).await;
+
+    if errors.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    for error in errors.iter() {
+        eprintln!("Error: {error}");
     }
+    eprintln!("Invalid configuration. {} error(s) found.", errors.len());
+    std::process::exit(1);
+}
 
+// Secrets (header values, passwords in urls) are redacted.
+fn print_config(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let internal_config = options.build_config().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    print!("{}", internal_config.dump());
     Ok(())
 }
 
+// Errors of a built config: cross validation, user and group of the server,
+// certificates and client CAs.
+async fn validate_config(internal_config: &InternalConfig, run_as: bool) -> Vec<String> {
+    let mut errors = internal_config.check();
+    if run_as {
+        let global = &internal_config.global;
+        if let Err(e) = utils::lookup_run_as(&global.user, &global.group) {
+            errors.push(e);
+        }
+    }
+
+    let mut names: Vec<&String> = internal_config.servers.keys().collect();
+    names.sort();
+    for name in names {
+        let server = &internal_config.servers[name];
+        let mut certs = Vec::new();
+        let mut cert_errors = Vec::new();
+        for cert in server.tls.iter().flatten() {
+            match IpcCerts::build(cert).await {
+                Ok(cert) => certs.push(cert),
+                // With the services to fix, or tls_optional to set.
+                Err(e) => {
+                    let owners = match server.cert_services.get(&cert.cert) {
+                        Some(services) => {
+                            let mut services: Vec<String> = services
+                                .iter()
+                                .map(|service| format!("[services.{service}]"))
+                                .collect();
+                            services.sort();
+                            services.join(", ")
+                        }
+                        None => format!("[servers.{name}] default_certificate"),
+                    };
+                    cert_errors.push(format!("{owners}: {e}"));
+                }
+            }
+        }
+        cert_errors.sort();
+        errors.extend(cert_errors);
+        // The aliases are served with the certificates of the server, the
+        // clients reject them if no certificate covers them.
+        for alias in &server.tls_aliases {
+            if !certs.is_empty() && !certs.iter().any(|cert| cert.covers(&alias.domain)) {
+                eprintln!(
+                    "[Main Process] Warning: no certificate covers the alias {} of [services.{}], its https requests will fail",
+                    alias.domain, alias.service
+                );
+            }
+        }
+        if let Some(client_auth) = &server.client_auth {
+            if let Err(e) = tls::read_client_ca(&client_auth.ca).await {
+                errors.push(e);
+            }
+        }
+    }
+    errors
+}
+
 fn add_path_to_watcher(target: PathBuf, port: u16, list: &mut HashMap<u16, Vec<PathBuf>>) {
-    let directory = target.parent().unwrap();
+    // A bare file name is in the current directory.
+    let directory = match target.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
     let pathbuf = directory.to_path_buf();
     let paths_to_watch = list.entry(port).or_default();
     if !paths_to_watch.contains(&pathbuf) {
         paths_to_watch.push(pathbuf);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watched_directories() {
+        let mut list = HashMap::new();
+        add_path_to_watcher(PathBuf::from("cert.pem"), 443, &mut list);
+        add_path_to_watcher(PathBuf::from("/etc/quark/cert.pem"), 443, &mut list);
+        add_path_to_watcher(PathBuf::from("/etc/quark/key.pem"), 443, &mut list);
+        assert_eq!(
+            list[&443],
+            [PathBuf::from("."), PathBuf::from("/etc/quark")]
+        );
+    }
+
+    #[test]
+    fn duplicate_activated_socket_closed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let first = OwnedFd::from(listener);
+        let extra = first.try_clone().unwrap();
+        let (first_fd, extra_fd) = (first.as_raw_fd(), extra.as_raw_fd());
+        let fd_path = |fd: RawFd| PathBuf::from(format!("/proc/self/fd/{fd}"));
+
+        let activated = activated_listeners(vec![(port, first), (port, extra)]);
+        assert_eq!(activated.len(), 1);
+        assert_eq!(activated[&port].as_raw_fd(), first_fd);
+        assert!(fd_path(first_fd).exists());
+        assert!(!fd_path(extra_fd).exists());
+    }
+
+    #[test]
+    fn restart_backoff() {
+        assert!(crashed(&ExitStatus::from_raw(9)));
+        assert!(crashed(&ExitStatus::from_raw(PANIC_EXIT_CODE << 8)));
+        assert!(!crashed(&ExitStatus::from_raw(1 << 8)));
+        assert!(!crashed(&ExitStatus::from_raw(0)));
+
+        let mut restarts = VecDeque::new();
+        let start = Instant::now();
+        let delays: Vec<_> = (0..MAX_RESTARTS as u64)
+            .map(|i| restart_delay(&mut restarts, start + Duration::from_secs(i)))
+            .collect();
+        assert_eq!(delays[0], Some(Duration::from_secs(1)));
+        assert_eq!(delays[4], Some(Duration::from_secs(16)));
+        assert_eq!(
+            restart_delay(&mut restarts, start + Duration::from_secs(10)),
+            None
+        );
+        // The first restarts are out of the window.
+        assert_eq!(
+            restart_delay(
+                &mut restarts,
+                start + RESTART_WINDOW + Duration::from_secs(1)
+            ),
+            Some(Duration::from_secs(8))
+        );
+    }
+
+    async fn config_errors(global: &str, run_as: bool) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!("[global]\n{global}\n[services.test]\ndomain = \"localhost\"\n[[services.test.locations]]\nsource = \"/*\"\ntarget = \"http://127.0.0.1:8080\"\n"),
+        )
+        .unwrap();
+        let config = InternalConfig::build_from(path.to_string_lossy().to_string()).unwrap();
+        validate_config(&config, run_as).await
+    }
+
+    #[tokio::test]
+    async fn run_as_checked() {
+        assert!(config_errors("user = \"root\"\ngroup = \"root\"", true)
+            .await
+            .is_empty());
+        assert_eq!(
+            config_errors("user = \"quark-missing\"\ngroup = \"root\"", true).await,
+            ["The user \"quark-missing\" doesn't exist, create it or set another one with [global] user or --user"]
+        );
+        assert_eq!(
+            config_errors("user = \"root\"\ngroup = \"quark-missing\"", true).await,
+            ["The group \"quark-missing\" doesn't exist, create it or set another one with [global] group or --group"]
+        );
+        // Ignored when not started as root.
+        assert!(config_errors("user = \"quark-missing\"", false)
+            .await
+            .is_empty());
+    }
+
+    const TEST_TIMINGS: HeartbeatTimings = HeartbeatTimings {
+        interval: Duration::from_millis(20),
+        warning: Duration::from_millis(60),
+        restart: Duration::from_millis(200),
+    };
+
+    // A heartbeat on one end of a socket pair, and the other end.
+    fn heartbeat_pair(
+        pid: Option<u32>,
+    ) -> (JoinHandle<()>, mpsc::UnboundedReceiver<u32>, UnixStream) {
+        let (main, server) = UnixStream::pair().unwrap();
+        let (stuck_tx, stuck_rx) = mpsc::unbounded_channel();
+        let heartbeat = spawn_heartbeat(&Arc::new(Mutex::new(main)), pid, stuck_tx, TEST_TIMINGS);
+        (heartbeat, stuck_rx, server)
+    }
+
+    #[tokio::test]
+    async fn stuck_server_killed_and_restarted() {
+        let mut child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        // The server end reads nothing and answers nothing.
+        let (heartbeat, mut stuck_rx, _server) = heartbeat_pair(child.id());
+        let pid = tokio::time::timeout(Duration::from_secs(5), stuck_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(pid), child.id());
+        assert!(heartbeat.await.is_ok());
+
+        // Killed, it is restarted like after a crash.
+        kill_stuck(&mut child, pid);
+        let status = child.wait().await.unwrap();
+        assert!(crashed(&status));
+    }
+
+    #[tokio::test]
+    async fn acked_server_kept() {
+        let (heartbeat, mut stuck_rx, mut server) = heartbeat_pair(Some(1));
+        let writer = Arc::new(Mutex::new(ipc::clone_stream(&server).unwrap()));
+        let answer = tokio::spawn(async move {
+            let max_size = MessageKind::Heartbeat.max_size();
+            while ipc::receive_ipc_frame(&mut server, max_size).await.is_ok() {
+                let ack = ipc::IpcMessage {
+                    kind: MessageKind::HeartbeatAck,
+                    key: None,
+                    payload: ipc::IpcHeartbeat::new(0),
+                };
+                if ipc::send_ipc_message(Arc::clone(&writer), ack)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+        tokio::time::sleep(TEST_TIMINGS.restart * 3).await;
+        assert!(stuck_rx.try_recv().is_err());
+        assert!(!heartbeat.is_finished());
+        heartbeat.abort();
+        answer.abort();
+    }
+}
@@ -6,29 +6,45 @@ use std::{
         Arc,
     },
     task::{Context, Poll},
+    time::Instant,
 };
 
 use hyper::{
-    body::{Body, Frame, Incoming},
+    body::{Body, Buf, Frame, Incoming},
+    header::HeaderValue,
+    http::response::Parts,
     service::Service,
     Request, Response,
 };
 use pin_project_lite::pin_project;
 
-use crate::{server::server_utils::ProxyHandlerBody, utils::get_current_time};
+use crate::{
+    http_response,
+    logs::access::{AccessEntry, AccessRecord, AccessTarget, SentResponse},
+    server::{server_utils::ProxyHandlerBody, stats::ListenerStats},
+    utils::{generate_request_id, get_current_time},
+};
+
+// Kept when sent by the client or a load balancer in front of Quark,
+// otherwise generated, and forwarded to the backends.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
 
 #[derive(Clone)]
 pub struct ServerService<S> {
     inner: S,
     last_activity: Arc<AtomicU64>,
+    client_ip: Arc<str>, // For the access log.
+    stats: Arc<ListenerStats>,
 }
 
 impl<S> ServerService<S> {
-    pub fn new(inner: S) -> Self {
+    pub fn new(inner: S, client_ip: Arc<str>, stats: Arc<ListenerStats>) -> Self {
         let now = get_current_time();
         Self {
             inner,
             last_activity: Arc::new(AtomicU64::new(now)),
+            client_ip,
+            stats,
         }
     }
 
@@ -53,40 +69,131 @@ where
     type Error = S::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
         self.update_activity();
         let inner = self.inner.clone();
         let last_activity = Arc::clone(&self.last_activity);
+        let stats = Arc::clone(&self.stats);
+        let request_id = request_id(&mut req);
+        let access_entry = AccessEntry::from_request(&req, &self.client_ip, &request_id);
 
         Box::pin(async move {
-            let res = inner.call(req).await?;
-            let (parts, body) = res.into_parts();
-            let tracking_body = ActivityTrackingBody::new(body, last_activity);
+            let mut res = inner.call(req).await?;
+            http_response::add_context(&mut res, &request_id);
+            let (mut parts, body) = res.into_parts();
+            let target = parts.extensions.remove::<AccessTarget>();
+            let access =
+                access_entry.map(|entry| AccessRecord::new(entry, parts.status.as_u16(), target));
+            let completion = ResponseCompletion {
+                sent: SentResponse {
+                    header_bytes: header_bytes(&parts),
+                    ..SentResponse::default()
+                },
+                access,
+                stats,
+            };
+            let tracker = ActivityTracker {
+                last_activity,
+                completion,
+            };
+            let tracking_body = ActivityTrackingBody::new(body, tracker);
             Ok(Response::from_parts(parts, tracking_body))
         })
     }
 }
 
+fn request_id(req: &mut Request<Incoming>) -> String {
+    if let Some(id) = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+    {
+        return id.to_string();
+    }
+    let id = generate_request_id();
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        req.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    id
+}
+
+// Size of the status line and headers written by hyper for HTTP/1, without
+// the ones it adds (date, content-length...). HTTP/2 compresses them.
+fn header_bytes(parts: &Parts) -> u64 {
+    let status_line = "HTTP/1.1 200 OK\r\n".len() - "OK".len()
+        + parts.status.canonical_reason().unwrap_or_default().len();
+    let headers: usize = parts
+        .headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + ": ".len() + value.len() + "\r\n".len())
+        .sum();
+    (status_line + headers + "\r\n".len()) as u64
+}
+
+// Delivers what was sent of a response to the access log and the listener
+// stats when the body is dropped: after its last frame, or when the client
+// went away, the response is then aborted with a partial count.
+pub struct ResponseCompletion {
+    sent: SentResponse,
+    access: Option<AccessRecord>,
+    stats: Arc<ListenerStats>,
+}
+
+impl ResponseCompletion {
+    // The body may be dropped later, e.g. when the connection is kept alive.
+    fn finish(&mut self) {
+        self.sent.end.get_or_insert_with(Instant::now);
+    }
+}
+
+impl Drop for ResponseCompletion {
+    fn drop(&mut self) {
+        self.stats
+            .response_sent(self.sent.body_bytes, self.sent.end.is_none());
+        if let Some(access) = self.access.take() {
+            access.write(&self.sent);
+        }
+    }
+}
+
+// What a body wrapped in an ObservedBody does with its frames.
+pub trait ObserveFrames {
+    // A frame was polled, with the size of its data.
+    fn frame(&mut self, data: Option<usize>);
+
+    // The body ended, or is known to be empty before being polled.
+    fn end(&mut self) {}
+
+    // Polled while the body waits for its next frame, an error aborts it.
+    fn poll_wait(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Error> {
+        Poll::Pending
+    }
+}
+
 pin_project! {
-    pub struct ActivityTrackingBody<B> {
+    pub struct ObservedBody<B, O> {
         #[pin]
         inner: B,
-        last_activity: Arc<AtomicU64>,
+        observer: O,
     }
 }
 
-impl<B> ActivityTrackingBody<B> {
-    fn new(inner: B, last_activity: Arc<AtomicU64>) -> Self {
-        Self {
-            inner,
-            last_activity,
+impl<B: Body, O: ObserveFrames> ObservedBody<B, O> {
+    pub fn new(inner: B, mut observer: O) -> Self {
+        // Not polled at all by hyper, e.g. a redirection.
+        if inner.is_end_stream() {
+            observer.end();
         }
+        Self { inner, observer }
     }
 }
 
-impl<B> Body for ActivityTrackingBody<B>
+impl<B, O> Body for ObservedBody<B, O>
 where
     B: Body,
+    B::Error: From<std::io::Error>,
+    O: ObserveFrames,
 {
     type Data = B::Data;
     type Error = B::Error;
@@ -95,16 +202,25 @@ where
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        let this = self.project();
-        match this.inner.poll_frame(cx) {
+        let mut this = self.project();
+        match this.inner.as_mut().poll_frame(cx) {
             Poll::Ready(Some(Ok(frame))) => {
-                if frame.is_data() {
-                    // Update last activity.
-                    let now = get_current_time();
-                    this.last_activity.store(now, Ordering::Relaxed);
+                this.observer
+                    .frame(frame.data_ref().map(|data| data.remaining()));
+                // Hyper doesn't poll again once the end of the stream is known.
+                if this.inner.is_end_stream() {
+                    this.observer.end();
                 }
                 Poll::Ready(Some(Ok(frame)))
             }
+            Poll::Ready(None) => {
+                this.observer.end();
+                Poll::Ready(None)
+            }
+            Poll::Pending => match this.observer.poll_wait(cx) {
+                Poll::Ready(err) => Poll::Ready(Some(Err(err.into()))),
+                Poll::Pending => Poll::Pending,
+            },
             other => other,
         }
     }
@@ -117,3 +233,90 @@ where
         self.inner.size_hint()
     }
 }
+
+pub type ActivityTrackingBody<B> = ObservedBody<B, ActivityTracker>;
+
+// Keeps the connection active while a response is sent, and counts it.
+pub struct ActivityTracker {
+    last_activity: Arc<AtomicU64>,
+    completion: ResponseCompletion,
+}
+
+impl ObserveFrames for ActivityTracker {
+    fn frame(&mut self, data: Option<usize>) {
+        if let Some(len) = data {
+            let now = get_current_time();
+            self.last_activity.store(now, Ordering::Relaxed);
+            self.completion.sent.body_bytes += len as u64;
+        }
+    }
+
+    fn end(&mut self) {
+        self.completion.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::server::server_utils::BoxedFrameStream;
+
+    fn tracked(
+        body: ProxyHandlerBody,
+        stats: &Arc<ListenerStats>,
+    ) -> ActivityTrackingBody<ProxyHandlerBody> {
+        let completion = ResponseCompletion {
+            sent: SentResponse::default(),
+            access: None,
+            stats: Arc::clone(stats),
+        };
+        let tracker = ActivityTracker {
+            last_activity: Arc::new(AtomicU64::new(0)),
+            completion,
+        };
+        ActivityTrackingBody::new(body, tracker)
+    }
+
+    #[tokio::test]
+    async fn response_bytes() {
+        let stats = Arc::new(ListenerStats::new(80, "http", None));
+        let counters = || {
+            let snapshot = stats.snapshot();
+            (snapshot.bytes_sent, snapshot.responses_aborted)
+        };
+
+        let body = tracked(ProxyHandlerBody::Full(Full::from("hello world")), &stats);
+        body.collect().await.unwrap();
+        // Never polled by hyper, but complete.
+        drop(tracked(ProxyHandlerBody::Empty, &stats));
+        assert_eq!(counters(), (11, 0));
+
+        // The client went away after the first frame.
+        let frames: Vec<std::io::Result<Frame<Bytes>>> = vec![
+            Ok(Frame::data(Bytes::from("abc"))),
+            Ok(Frame::data(Bytes::from("defg"))),
+        ];
+        let stream: BoxedFrameStream = Box::pin(futures::stream::iter(frames));
+        let mut body = tracked(
+            ProxyHandlerBody::StreamBody(StreamBody::new(stream)),
+            &stats,
+        );
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+        assert_eq!(counters(), (14, 1));
+
+        let (parts, _) = Response::builder()
+            .status(404)
+            .header("content-type", "text/html")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(
+            header_bytes(&parts) as usize,
+            "HTTP/1.1 404 Not Found\r\ncontent-type: text/html\r\n\r\n".len()
+        );
+    }
+}
@@ -1,117 +1,318 @@
-mod handler;
+mod admin;
+mod buffering;
+mod cache;
+pub mod handler;
+mod limits;
 mod serve_file;
 pub mod server_utils;
+pub mod stats;
 
 use std::collections::HashMap;
 use std::future::Future;
 use std::io;
 use std::net::{IpAddr, Ipv6Addr};
+use std::os::fd::OwnedFd;
 use std::pin::Pin;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 use std::{net::SocketAddr, sync::Arc};
 
 use ::futures::future::join_all;
+use arc_swap::ArcSwap;
 use dashmap::DashMap;
 use hyper::body::Incoming;
+use hyper::header::{HeaderValue, CONNECTION, RETRY_AFTER};
 use hyper::service::service_fn;
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::Client;
+use hyper::Request;
+use hyper_rustls::ConfigBuilderExt;
 use hyper_util::rt::TokioTimer;
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto::Builder,
 };
-use server_utils::welcome_server;
-use socket2::{Domain, Protocol, Socket, Type};
+use server_utils::{welcome_port, welcome_server};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit};
 
+use rustls::server::Acceptor;
+use rustls::{ProtocolVersion, ServerConfig, ServerConnection};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::LazyConfigAcceptor;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, Instrument};
 
-use crate::config::tls::{reload_certificates, IpcCerts, SniCertResolver, TlsConfig};
-use crate::config::{self, InternalConfig, Locations, Options, TargetType};
-use crate::ipc::{self, IpcMessage};
+use crate::config::tls::{
+    peer_subject, reload_certificates, start_expiry_check, IpcCerts, SharedCertifiedKeyList,
+    SniCertResolver, TlsConfig,
+};
+use crate::config::{
+    self, ClientAuthMode, ConfigReload, InternalConfig, Locations, Options, TargetType, TcpOptions,
+    MAIN_SERVER_NAME,
+};
+use crate::ipc::{self, IpcHeartbeat, IpcMessage, MessageKind};
 use crate::middleware::ServerService;
+use crate::server::admin::AdminState;
 use crate::server::handler::ServerHandler;
-use crate::server::server_utils::NoCertificateVerification;
-use crate::utils::{drop_privileges, format_ip, CACHED_CURRENT_TIME, QUARK_USER_AND_GROUP};
-use crate::{load_balancing, logs};
+use crate::server::limits::TargetLimits;
+use crate::server::server_utils::{acquire_permit, NoCertificateVerification, ProxyClients};
+use crate::server::stats::{HeartbeatStats, ListenerStats, TlsParams};
+use crate::utils::{self, drop_privileges, format_ip, CACHED_CURRENT_TIME};
+use crate::{alerts, http_response, load_balancing, logs};
+
+// The connections are closed gracefully within 10 seconds on shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reconnections to the main process are tried after 100ms, 200ms... up to this.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// A connection over max_connections is answered with 503s during this time.
+const REJECTED_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Connections over max_connections answered at the same time by a listener,
+// the next ones are closed.
+const MAX_REJECTED_CONNECTIONS: usize = 64;
 
 pub async fn server_process() -> Result<(), Box<dyn std::error::Error>> {
     // Create a cancellation token to stop the server gracefully.
     let shutdown_token = CancellationToken::new();
     let ipc_shutdown_token = shutdown_token.clone();
 
+    // Get options from command line.
+    let options: Options = argh::from_env();
+    if let Some(megabytes) = options.ipc_max_message_size {
+        ipc::set_max_message_size(megabytes);
+    }
+
     // Wait for parent init.
-    let socket_path = ipc::get_socket_path();
-    let mut stream = match ipc::connect_to_socket(&socket_path).await {
+    let socket_path = ipc::get_socket_path(options.socket_path.as_deref());
+    let mut stream = match ipc::attach_to_parent(&socket_path, false).await {
         Ok(stream) => stream,
         Err(e) => {
-            println!("Failed to connect to parent process: {e}");
+            eprintln!("[Child Process] Failed to connect to the main process. {e}");
             std::process::exit(1);
         }
     };
 
     // Get the InternalConfig from the parent process.
-    let message_sc = ipc::receive_ipc_message::<InternalConfig>(&mut stream).await?;
+    let message_sc =
+        ipc::receive_ipc_message::<InternalConfig>(&mut stream, MessageKind::Config).await?;
     let internal_config = message_sc.payload;
+    // Before the log files are created, they are given to this user.
+    utils::set_run_as(&internal_config.global.user, &internal_config.global.group);
 
     // Get the certs from the parent process.
     let message_certs =
-        ipc::receive_ipc_message::<HashMap<u16, Vec<IpcCerts>>>(&mut stream).await?;
+        ipc::receive_ipc_message::<HashMap<u16, Vec<IpcCerts>>>(&mut stream, MessageKind::Certs)
+            .await?;
     let tls_certs = message_certs.payload;
     let tls_certs = Arc::new(tls_certs);
 
-    // Watch for certificates changes.
+    // Get the client authentication CAs from the parent process.
+    let message_client_ca =
+        ipc::receive_ipc_message::<HashMap<u16, Vec<u8>>>(&mut stream, MessageKind::ClientCa)
+            .await?;
+    let client_cas = message_client_ca.payload;
+
+    // Get the listening sockets, bound by the parent process.
+    let message_listeners =
+        ipc::receive_ipc_message::<Vec<u16>>(&mut stream, MessageKind::Listeners).await?;
+    let fds = ipc::receive_fds(&stream).await?;
+    if fds.len() != message_listeners.payload.len() {
+        return Err(format!(
+            "Expected {} listening sockets from the parent process, received {}",
+            message_listeners.payload.len(),
+            fds.len()
+        )
+        .into());
+    }
+    // A port is repeated for each of its sockets.
+    let mut inherited: HashMap<u16, Vec<OwnedFd>> = HashMap::new();
+    for (port, fd) in message_listeners.payload.into_iter().zip(fds) {
+        inherited.entry(port).or_default().push(fd);
+    }
+
+    // Watch for certificates changes and configuration reloads.
+    let (mut reader, writer) = stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    let ipc_writer = Arc::clone(&writer);
+    let ipc_socket_path = socket_path.clone();
     let (tx, _) = tokio::sync::broadcast::channel::<Arc<IpcMessage<Vec<IpcCerts>>>>(16);
+    let (config_tx, config_rx) = tokio::sync::mpsc::unbounded_channel::<ConfigReload>();
     let tx_clone = tx.clone();
+    let parent_alive = Arc::new(Notify::new());
+    let ipc_parent_alive = Arc::clone(&parent_alive);
+    let heartbeats = Arc::new(HeartbeatStats::default());
+    let ipc_heartbeats = Arc::clone(&heartbeats);
     tokio::spawn(async move {
         loop {
-            match ipc::receive_ipc_message::<Vec<IpcCerts>>(&mut stream).await {
-                Ok(msg) => {
-                    let msg = Arc::new(msg);
-                    let _ = tx_clone.send(msg);
+            let res = match ipc::receive_ipc_frame(&mut reader, ipc::max_frame_size()).await {
+                Ok(frame) => {
+                    ipc_parent_alive.notify_one();
+                    dispatch_ipc_message(
+                        &frame,
+                        &tx_clone,
+                        &config_tx,
+                        &ipc_heartbeats,
+                        &ipc_writer,
+                    )
                 }
-                Err(err) => {
-                    tracing::error!("IPC stream error: {err:#}");
-                    ipc_shutdown_token.cancel();
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res.map_err(|e| format!("{e:#}")) {
+                // Nothing to resynchronize once the server is stopping.
+                if ipc_shutdown_token.is_cancelled() {
                     break;
                 }
+                tracing::error!("IPC stream error: {err}, reconnecting to the main process");
+                match reconnect_to_parent(&ipc_socket_path, &ipc_writer, &ipc_shutdown_token).await
+                {
+                    Some(next_reader) => reader = next_reader,
+                    None => break,
+                }
             }
         }
     });
 
-    // Get options from command line.
-    let options: Options = argh::from_env();
     // Init logs. Declare a var to keep the guard alive in this scope.
-    let _guard = logs::start_logs(options.logs);
+    // The command line options take precedence over the config file.
+    let logs_config = &internal_config.logs;
+    let logs_path = options
+        .logs
+        .or(logs_config.path.clone())
+        .unwrap_or(config::DEFAULT_LOG_PATH.to_string());
+    let _access_guard = logs_config.access_log.then(|| {
+        logs::access::start_access_log(
+            &logs_path,
+            logs_config
+                .access_log_path
+                .as_deref()
+                .unwrap_or(logs::access::DEFAULT_ACCESS_LOG_FILE),
+            logs_config.access_log_format,
+            logs_config.access_log_sampling,
+            &logs_config.rotation,
+        )
+    });
+    let _guards = logs::start_logs(
+        logs_path,
+        options
+            .log_level
+            .as_deref()
+            .or(logs_config.level.as_deref()),
+        logs_config.stdout,
+        &logs_config.outputs,
+        logs_config
+            .file
+            .as_deref()
+            .unwrap_or(logs::DEFAULT_LOG_FILE),
+        logs_config.error_log.as_deref(),
+        &logs_config.rotation,
+    );
 
     check_sigterm(shutdown_token.clone());
+    tokio::spawn(watch_parent(
+        parent_alive,
+        Arc::clone(&heartbeats),
+        ipc::HEARTBEAT_TIMEOUT,
+        internal_config.global.orphan_shutdown,
+        shutdown_token.clone(),
+    ));
+    ignore_parent_signals();
 
     update_cached_time_worker();
 
-    init_servers(internal_config, tls_certs, tx, shutdown_token).await?;
+    init_servers(
+        internal_config,
+        ipc::get_admin_socket_path(&socket_path),
+        tls_certs,
+        client_cas,
+        inherited,
+        ParentIpc {
+            certs_tx: tx,
+            config_rx,
+            writer,
+            heartbeats,
+        },
+        shutdown_token,
+    )
+    .await?;
     tracing::info!("Server exited");
     Ok(())
 }
 
+fn dispatch_ipc_message(
+    frame: &[u8],
+    tx: &tokio::sync::broadcast::Sender<Arc<IpcMessage<Vec<IpcCerts>>>>,
+    config_tx: &tokio::sync::mpsc::UnboundedSender<ConfigReload>,
+    heartbeats: &Arc<HeartbeatStats>,
+    writer: &Arc<Mutex<OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match ipc::ipc_message_kind(frame)? {
+        Some(MessageKind::ConfigReload) => {
+            let msg = ipc::decode_ipc_message::<ConfigReload>(frame)?;
+            let _ = config_tx.send(msg.payload);
+        }
+        Some(MessageKind::LogLevel) => {
+            let msg = ipc::decode_ipc_message::<logs::LogLevelChange>(frame)?;
+            if let Err(e) = logs::change_level(msg.payload) {
+                tracing::error!("Failed to change the log level: {e}");
+            }
+        }
+        Some(MessageKind::CertsReload) => {
+            let msg = ipc::decode_ipc_message::<Vec<IpcCerts>>(frame)?;
+            let _ = tx.send(Arc::new(msg));
+        }
+        Some(MessageKind::Heartbeat) => {
+            let msg = ipc::decode_ipc_message::<IpcHeartbeat>(frame)?;
+            heartbeats.received(&msg.payload);
+            let ack = IpcMessage {
+                kind: MessageKind::HeartbeatAck,
+                key: None,
+                payload: IpcHeartbeat::new(heartbeats.missed_total()),
+            };
+            // Sent by another task, the certificates and reloads after it
+            // don't wait for a main process too slow to read.
+            let writer = Arc::clone(writer);
+            tokio::spawn(async move {
+                if let Err(e) = ipc::send_ipc_message(writer, ack).await {
+                    tracing::debug!("Failed to answer the heartbeat: {e}");
+                }
+            });
+        }
+        Some(kind) => tracing::warn!("Unexpected IPC message {kind:?}, skipped"),
+        None => tracing::warn!("Unknown IPC message kind, skipped"),
+    }
+    Ok(())
+}
+
+// Messages exchanged with the parent process.
+struct ParentIpc {
+    certs_tx: tokio::sync::broadcast::Sender<Arc<IpcMessage<Vec<IpcCerts>>>>,
+    config_rx: tokio::sync::mpsc::UnboundedReceiver<ConfigReload>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    heartbeats: Arc<HeartbeatStats>,
+}
+
 async fn init_servers(
     internal_config: InternalConfig,
+    admin_socket_path: String,
     tls_certs: Arc<HashMap<u16, Vec<IpcCerts>>>,
-    tx: tokio::sync::broadcast::Sender<Arc<IpcMessage<Vec<IpcCerts>>>>,
+    mut client_cas: HashMap<u16, Vec<u8>>,
+    mut inherited: HashMap<u16, Vec<OwnedFd>>,
+    parent: ParentIpc,
     shutdown_token: CancellationToken,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Starting server");
+    let tx = parent.certs_tx;
 
     // List of servers to start.
     let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = Vec::new();
 
     let http_builder = build_http(&internal_config.global);
     let http = Arc::new(http_builder);
+    // Used by the listeners where HTTP/2 is disabled.
+    let http1 = Arc::new(build_http(&internal_config.global).http1_only());
 
     let tls_config = if internal_config.global.tls_proxy_verify {
         rustls::ClientConfig::builder()
@@ -125,17 +326,12 @@ async fn init_servers(
             .with_no_client_auth()
     };
 
-    let https_client = HttpsConnectorBuilder::new()
-        .with_tls_config(tls_config)
-        .https_or_http()
-        .enable_http1()
-        .build();
-
-    let client: Arc<Client<HttpsConnector<HttpConnector>, Incoming>> =
-        Arc::new(Client::builder(TokioExecutor::new()).build(https_client));
+    let clients = Arc::new(ProxyClients::new(tls_config));
     let max_conns = Arc::new(tokio::sync::Semaphore::new(internal_config.global.max_conn));
     let max_req = Arc::new(tokio::sync::Semaphore::new(internal_config.global.max_req));
+    let queue_timeout = Duration::from_millis(internal_config.global.queue_timeout);
     let default_backlog = internal_config.global.backlog;
+    let accept_loops = accept_loops(&internal_config);
 
     #[cfg(debug_assertions)]
     println!("Config: {:#?}", internal_config.servers);
@@ -146,24 +342,111 @@ async fn init_servers(
     if internal_config.empty {
         tracing::warn!("No services defined in the config file. Starting a welcome server.");
         tracing::warn!("Don't keep this server running in production without configuration!");
-        welcome_server(http.clone(), shutdown_token).await;
+        let main = &internal_config.servers[MAIN_SERVER_NAME];
+        let over_max_conn = OverMaxConnections::new(&internal_config.global);
+        let listener = take_listeners(
+            &mut inherited,
+            welcome_port(main),
+            1,
+            default_backlog,
+            main.tcp_options.defer_accept,
+        )?
+        .remove(0);
+        servers.push(Box::pin(welcome_server(
+            Arc::clone(&http),
+            Arc::new(PlainAcceptor),
+            listener,
+            Arc::clone(&max_conns),
+            over_max_conn.clone(),
+            shutdown_token.clone(),
+        )));
+        // With the default certificate of the main server.
+        if main.tls.is_some() {
+            let https_params = HttpsServerParams {
+                port: main.https_port,
+                handshake_timeout: main
+                    .tls_handshake_timeout
+                    .unwrap_or(internal_config.global.tls_handshake_timeout),
+                tls_options: main.tls_options.clone(),
+                client_auth: None,
+            };
+            let listener = take_listeners(
+                &mut inherited,
+                main.https_port,
+                1,
+                default_backlog,
+                main.tcp_options.defer_accept,
+            )?
+            .remove(0);
+            match build_tls_state(tx.clone(), &tls_certs, &https_params) {
+                Ok(tls) => {
+                    let acceptor = TlsAcceptorWrapper {
+                        tls,
+                        handshake_timeout: https_params.handshake_timeout,
+                        stats: Arc::new(ListenerStats::new(main.https_port, "https", None)),
+                    };
+                    servers.push(Box::pin(welcome_server(
+                        Arc::clone(&http),
+                        Arc::new(acceptor),
+                        listener,
+                        Arc::clone(&max_conns),
+                        over_max_conn,
+                        shutdown_token.clone(),
+                    )));
+                }
+                Err(err) => tracing::error!(
+                    "failed to build the TLS config on port {}: {err}",
+                    main.https_port
+                ),
+            }
+        }
+        notify_ready(&parent.writer).await;
+        join_all(servers).await;
         return Ok(());
     }
 
-    let lb_config = generate_loadbalancing_config(&internal_config.servers);
+    let lb_config = generate_loadbalancing_config(&internal_config.servers, None).await;
+    let limits = TargetLimits::new(&internal_config.servers);
+
+    start_expiry_check(internal_config.global.cert_expiry_warning);
+    if let Some(alerts) = &internal_config.alerts {
+        alerts::start_alerts(alerts.clone());
+    }
+
+    // Shown by the admin API, the servers are moved below.
+    let running_config = internal_config.clone();
+    // Connection counters of each listener.
+    let mut listeners: Vec<Arc<ListenerStats>> = Vec::new();
+
+    // Servers updated when the configuration is reloaded.
+    let mut reloadable_servers: HashMap<String, ReloadableServer> = HashMap::new();
 
     // Build a server for each port defined in the config file.
-    for (_, server) in internal_config.servers {
-        let http = Arc::clone(&http);
-        let client = Arc::clone(&client);
+    for (name, server) in internal_config.servers {
+        let http_plain = if server.http2 {
+            Arc::clone(&http)
+        } else {
+            Arc::clone(&http1)
+        };
+        let clients = Arc::clone(&clients);
         let max_conns = Arc::clone(&max_conns);
         let max_req = Arc::clone(&max_req);
         let lb_config = Arc::clone(&lb_config);
+        let limits = Arc::clone(&limits);
         let tx = tx.clone();
 
-        let server_params = Arc::new(server.params);
-        let server_handler =
-            handler::ServerHandler::builder(server_params, lb_config, max_req, client);
+        let server_handler = handler::ServerHandler::builder(
+            server.params,
+            lb_config,
+            limits,
+            max_req,
+            queue_timeout,
+            clients,
+        );
+        let mut reloadable = ReloadableServer {
+            handler: Arc::clone(&server_handler),
+            tls: None,
+        };
 
         let limiter = internal_config
             .global
@@ -173,73 +456,245 @@ async fn init_servers(
         // Declare https server if tls is enabled in the server config.
         if let Some(_tls) = &server.tls {
             // Clone arcs for the next asynvc task.
-            let http = Arc::clone(&http);
+            let http = if server.tls_options.alpn.iter().any(|p| p == "h2") {
+                Arc::clone(&http)
+            } else {
+                Arc::clone(&http1)
+            };
             let max_conns = Arc::clone(&max_conns);
             let server_handler = Arc::clone(&server_handler);
-            let tls_certs = Arc::clone(&tls_certs).clone();
             let limiter = limiter.clone();
+            let client_auth = server.client_auth.as_ref().and_then(|client_auth| {
+                client_cas
+                    .remove(&server.https_port)
+                    .map(|ca| (client_auth.mode.clone(), ca))
+            });
+            let https_params = HttpsServerParams {
+                port: server.https_port,
+                handshake_timeout: server
+                    .tls_handshake_timeout
+                    .unwrap_or(internal_config.global.tls_handshake_timeout),
+                tls_options: server.tls_options.clone(),
+                client_auth,
+            };
+            let tls_state = build_tls_state(tx.clone(), &tls_certs, &https_params);
 
             let https_config = HttpServerConfig {
                 max_conns,
+                queue_timeout,
+                tcp_options: server.tcp_options,
                 http,
                 server_handler,
                 idle_timeout: internal_config.global.idle_timeout,
                 idle_check_interval: internal_config.global.idle_check_interval,
+                max_connection_lifetime: internal_config.global.max_connection_lifetime,
+                over_max_conn: OverMaxConnections::new(&internal_config.global),
                 limiter,
                 shutdown_token: shutdown_token.clone(),
             };
 
-            let listener =
-                build_tcp_listener(server.https_port, default_backlog).map_err(|err| {
-                    tracing::error!("failed to create https listener: {err:#}");
-                    err
-                })?;
-
-            let https_server = https_server(
-                https_config,
-                tx,
-                tls_certs,
-                internal_config.global.tls_handshake_timeout,
+            let https_listeners = take_listeners(
+                &mut inherited,
                 server.https_port,
-                listener,
-            );
-
-            servers.push(Box::pin(https_server));
+                accept_loops,
+                default_backlog,
+                server.tcp_options.defer_accept,
+            )
+            .map_err(|err| {
+                tracing::error!("failed to create https listener: {err:#}");
+                err
+            })?;
+
+            match tls_state {
+                Ok(tls_state) => {
+                    reloadable.tls = Some(Arc::clone(&tls_state));
+                    let loop_stats = ListenerStats::for_accept_loops(
+                        server.https_port,
+                        "https",
+                        https_listeners.len(),
+                    );
+                    listeners.extend(loop_stats.iter().cloned());
+                    // One accept loop per socket, sharing the handler.
+                    for (stats, listener) in loop_stats.into_iter().zip(https_listeners) {
+                        let https_server = https_server(
+                            https_config.clone(),
+                            stats,
+                            Arc::clone(&tls_state),
+                            https_params.clone(),
+                            listener,
+                        );
+                        servers.push(Box::pin(https_server));
+                    }
+                }
+                Err(err) => tracing::error!(
+                    "failed to build the TLS config on port {}: {err}",
+                    server.https_port
+                ),
+            }
         }
+        reloadable_servers.insert(name, reloadable);
 
         let http_config = HttpServerConfig {
             max_conns,
-            http,
+            queue_timeout,
+            tcp_options: server.tcp_options,
+            http: http_plain,
             server_handler,
             idle_timeout: internal_config.global.idle_timeout,
             idle_check_interval: internal_config.global.idle_check_interval,
+            max_connection_lifetime: internal_config.global.max_connection_lifetime,
+            over_max_conn: OverMaxConnections::new(&internal_config.global),
             limiter,
             shutdown_token: shutdown_token.clone(),
         };
 
-        let listener = build_tcp_listener(server.port, default_backlog).map_err(|err| {
+        let http_listeners = take_listeners(
+            &mut inherited,
+            server.port,
+            accept_loops,
+            default_backlog,
+            server.tcp_options.defer_accept,
+        )
+        .map_err(|err| {
             tracing::error!("failed to create http listener: {err:#}");
             err
         })?;
+        let loop_stats = ListenerStats::for_accept_loops(server.port, "http", http_listeners.len());
+        listeners.extend(loop_stats.iter().cloned());
         // Default http server. (Always enabled)
-        let http_server = http_server(http_config, listener);
-
-        servers.push(Box::pin(http_server));
+        for (stats, listener) in loop_stats.into_iter().zip(http_listeners) {
+            let http_server = http_server(http_config.clone(), stats, listener);
+            servers.push(Box::pin(http_server));
+        }
     }
 
-    // Drop privileges from root to "quark" user.
+    // Drop privileges from root to the [global] user and group.
     // If we are not root, it wont do anything.
-    match drop_privileges(QUARK_USER_AND_GROUP) {
-        Ok(msg) => tracing::warn!("{}", msg),
+    let global = &internal_config.global;
+    match drop_privileges() {
+        Ok(true) => tracing::warn!("Privileges dropped to {}:{}", global.user, global.group),
+        Ok(false) => {
+            tracing::debug!("Not running as root, the user and group settings are ignored")
+        }
         Err(err) => return Err(err),
     }
 
+    listeners.sort_by_key(|stats| stats.port);
+    stats::start_summary(
+        listeners.clone(),
+        internal_config.global.connection_stats_interval,
+    );
+    let admin_state = Arc::new(AdminState::new(
+        running_config,
+        Arc::clone(&lb_config),
+        Arc::clone(&limits),
+        listeners.clone(),
+        parent.heartbeats,
+    ));
+    tokio::spawn(admin::serve_admin(
+        admin_socket_path,
+        Arc::clone(&admin_state),
+        shutdown_token.clone(),
+    ));
+    tokio::spawn(watch_config_reload(
+        parent.config_rx,
+        reloadable_servers,
+        lb_config,
+        limits,
+        admin_state,
+    ));
+    notify_ready(&parent.writer).await;
+
     // Start all the servers.
     join_all(servers).await;
 
+    // The servers stopped accepting, let the open connections finish.
+    stats::wait_closed(&listeners, DRAIN_TIMEOUT).await;
+
     Ok(())
 }
 
+// Warn when the main process stops sending heartbeats for the timeout. With
+// orphan_shutdown, the server stops so that systemd restarts both processes.
+async fn watch_parent(
+    alive: Arc<Notify>,
+    heartbeats: Arc<HeartbeatStats>,
+    timeout: Duration,
+    orphan_shutdown: bool,
+    shutdown_token: CancellationToken,
+) {
+    let mut lost = false;
+    loop {
+        tokio::select! {
+            _ = alive.notified() => {
+                if lost {
+                    tracing::warn!("The main process is responding again");
+                    lost = false;
+                }
+            }
+            _ = tokio::time::sleep(timeout), if !lost => {
+                lost = true;
+                // Counted even when the error isn't logged.
+                let missed = heartbeats.record_missed();
+                tracing::error!(
+                    "No heartbeat from the main process for {}s, the certificates and the configuration can't be reloaded until it is back (missed heartbeats: {missed})",
+                    timeout.as_secs()
+                );
+                if orphan_shutdown {
+                    tracing::error!("Stopping the server without its main process (orphan_shutdown)");
+                    shutdown_token.cancel();
+                    return;
+                }
+            }
+            _ = shutdown_token.cancelled() => return,
+        }
+    }
+}
+
+// Connect again to the main process until it succeeds or the server stops.
+// The writer is replaced, the reader of the new stream is returned.
+async fn reconnect_to_parent(
+    socket_path: &str,
+    writer: &Mutex<OwnedWriteHalf>,
+    shutdown_token: &CancellationToken,
+) -> Option<OwnedReadHalf> {
+    let mut delay = Duration::from_millis(100);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown_token.cancelled() => return None,
+        }
+        let attached = ipc::attach_to_parent(socket_path, true)
+            .await
+            .map_err(|e| e.to_string());
+        match attached {
+            Ok(stream) => {
+                let (reader, next_writer) = stream.into_split();
+                *writer.lock().await = next_writer;
+                info!("Reconnected to the main process");
+                return Some(reader);
+            }
+            Err(e) => {
+                tracing::warn!("Can't reconnect to the main process: {e}");
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+// Tell the parent process that the servers are accepting connections. During
+// an upgrade, the previous server is stopped only then.
+async fn notify_ready(writer: &Arc<Mutex<OwnedWriteHalf>>) {
+    let message = IpcMessage {
+        kind: MessageKind::Ready,
+        key: None,
+        payload: std::process::id(),
+    };
+    if let Err(e) = ipc::send_ipc_message(Arc::clone(writer), message).await {
+        tracing::error!("Failed to notify the parent process: {e}");
+    }
+}
+
 fn build_http(global_config: &config::Global) -> Builder<TokioExecutor> {
     let mut http_builder = Builder::new(TokioExecutor::new());
 
@@ -262,8 +717,10 @@ fn build_http(global_config: &config::Global) -> Builder<TokioExecutor> {
     http_builder
 }
 
-fn generate_loadbalancing_config(
+// The previous config is given on a reload.
+async fn generate_loadbalancing_config(
     servers: &HashMap<String, config::Server>,
+    previous: Option<&load_balancing::LoadBalancerConfig>,
 ) -> Arc<load_balancing::LoadBalancerConfig> {
     let mut targets: Vec<&Locations> = Vec::new();
     for (_, server) in servers.iter() {
@@ -279,13 +736,21 @@ fn generate_loadbalancing_config(
         }
     }
 
-    load_balancing::LoadBalancerConfig::new(targets)
+    let lb_config = match previous {
+        Some(previous) => load_balancing::LoadBalancerConfig::reload(targets, previous),
+        None => load_balancing::LoadBalancerConfig::new(targets),
+    };
+    lb_config.start_discovery().await;
+    lb_config.start_outlier_detection();
+    lb_config
 }
 
 struct PlainAcceptor;
 struct TlsAcceptorWrapper {
-    acceptor: TlsAcceptor,
+    tls: Arc<TlsState>,
     handshake_timeout: u64,
+    // Handshake failures are counted to make TLS scanners visible.
+    stats: Arc<ListenerStats>,
 }
 
 trait StreamAcceptor: Send + Sync + 'static {
@@ -295,6 +760,14 @@ trait StreamAcceptor: Send + Sync + 'static {
         stream: tokio::net::TcpStream,
     ) -> impl Future<Output = Result<Self::Stream, std::io::Error>> + Send;
     fn protocol(&self) -> &'static str;
+    // Subject of the verified client certificate, if any.
+    fn peer_subject(&self, _stream: &Self::Stream) -> Option<String> {
+        None
+    }
+    // Entered while the connection is served.
+    fn connection_span(&self, _stream: &Self::Stream) -> tracing::Span {
+        tracing::Span::none()
+    }
 }
 
 impl StreamAcceptor for PlainAcceptor {
@@ -310,23 +783,98 @@ impl StreamAcceptor for PlainAcceptor {
 impl StreamAcceptor for TlsAcceptorWrapper {
     type Stream = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
     async fn accept(&self, stream: tokio::net::TcpStream) -> Result<Self::Stream, std::io::Error> {
-        match tokio::time::timeout(
+        let peer = stream.peer_addr().ok();
+        // Keep the SNI to log it if the handshake fails after the ClientHello.
+        let mut sni: Option<String> = None;
+        let handshake = async {
+            let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+            sni = start.client_hello().server_name().map(String::from);
+            start.into_stream(self.tls.server_config.load_full()).await
+        };
+        let res = match tokio::time::timeout(
             tokio::time::Duration::from_secs(self.handshake_timeout),
-            self.acceptor.accept(stream),
+            handshake,
         )
         .await
         {
             Ok(res) => res,
             Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        };
+        match &res {
+            Ok(stream) => self
+                .stats
+                .handshake_completed(tls_params(stream.get_ref().1)),
+            Err(err) => self.record_handshake_failure(err, peer, sni.as_deref()),
         }
+        res
     }
     fn protocol(&self) -> &'static str {
         "https"
     }
+    fn peer_subject(&self, stream: &Self::Stream) -> Option<String> {
+        stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(peer_subject)
+    }
+    // The negotiated parameters are logged with each request of the connection.
+    fn connection_span(&self, stream: &Self::Stream) -> tracing::Span {
+        let conn = stream.get_ref().1;
+        let params = tls_params(conn);
+        tracing::info_span!(
+            "Connection",
+            tls_version = params.version,
+            cipher = params.cipher_suite,
+            alpn = params.alpn,
+            sni = conn.server_name(),
+        )
+    }
+}
+
+fn tls_params(conn: &ServerConnection) -> TlsParams {
+    TlsParams {
+        version: match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "1.2",
+            Some(ProtocolVersion::TLSv1_3) => "1.3",
+            _ => "unknown",
+        },
+        cipher_suite: conn
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .unwrap_or("unknown"),
+        // Only the configured protocols can be negotiated.
+        alpn: conn.alpn_protocol().map(|alpn| match alpn {
+            b"h2" => "h2",
+            b"http/1.1" => "http/1.1",
+            _ => "other",
+        }),
+    }
+}
+
+impl TlsAcceptorWrapper {
+    fn record_handshake_failure(
+        &self,
+        err: &std::io::Error,
+        peer: Option<SocketAddr>,
+        sni: Option<&str>,
+    ) {
+        let timeout = err.kind() == std::io::ErrorKind::TimedOut;
+        let kind = if timeout { "timeout" } else { "error" };
+        let count = self.stats.handshake_failed(timeout);
+        tracing::warn!(
+            port = self.stats.port,
+            peer = peer.map(|p| format_ip(p.ip())).unwrap_or_default(),
+            sni = sni.unwrap_or("-"),
+            total = count,
+            "TLS handshake {kind}: {err:#}"
+        );
+    }
 }
 
 async fn run_server<A: StreamAcceptor>(
     config: HttpServerConfig,
+    stats: Arc<ListenerStats>,
     listener: TcpListener,
     acceptor: Arc<A>,
 ) {
@@ -347,15 +895,22 @@ async fn run_server<A: StreamAcceptor>(
                 continue;
             }
         };
+        stats.accepted();
+        if let Err(err) = set_stream_options(&stream, &config.tcp_options) {
+            tracing::warn!("failed to set the TCP options of a connection: {err:#}");
+        }
 
-        let client_ip = format_ip(address.ip());
+        // Shared by the requests of the connection.
+        let client_ip: Arc<str> = Arc::from(format_ip(address.ip()));
         let ip_addr = address.ip();
         let acceptor = acceptor.clone();
         let max_conns = Arc::clone(&config.max_conns);
         let server_handler = Arc::clone(&config.server_handler);
         let limiter = config.limiter.clone();
+        let over_max_conn = config.over_max_conn.clone();
         let http = config.http.clone();
         let shutdown_token = config.shutdown_token.clone();
+        let stats = Arc::clone(&stats);
 
         tokio::task::spawn(async move {
             // Limit ip only if defined in the config file.
@@ -363,7 +918,8 @@ async fn run_server<A: StreamAcceptor>(
                 match limiter.try_acquire(ip_addr) {
                     Some(guard) => Some(guard),
                     None => {
-                        tracing::warn!(ip = %ip_addr,
+                        stats.rejected_ip_limit();
+                        tracing::warn!(ip = %ip_addr, port = stats.port,
                                 "Connection limit exceeded");
                         return;
                     }
@@ -372,91 +928,208 @@ async fn run_server<A: StreamAcceptor>(
                 None
             };
 
-            let _permit = match max_conns.try_acquire_owned() {
-                Ok(p) => p,
-                Err(_) => {
-                    tracing::error!("Too many connection. Connection closed.");
-                    return;
+            let mut rejection = None;
+            let _permit = match acquire_permit(&max_conns, config.queue_timeout).await {
+                Some((permit, waited)) => {
+                    if !waited.is_zero() {
+                        tracing::info!(
+                            "Connection from {client_ip} on port {} queued for {}ms",
+                            stats.port,
+                            waited.as_millis()
+                        );
+                    }
+                    Some(permit)
                 }
-            };
-
-            let protocol = acceptor.protocol().to_string();
-            let service = service_fn(move |req| {
-                let server_handler = Arc::clone(&server_handler);
-                let client_ip = client_ip.clone();
-                let protocol = protocol.clone();
-                let handler_params = handler::HandlerParams {
-                    req,
-                    client_ip,
-                    scheme: protocol,
-                };
-                async move { server_handler.handle(handler_params).await }
-            });
-            let service = ServerService::new(service);
-
-            let stream = match acceptor.accept(stream).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    tracing::error!("failed to perform TLS handshake: {err:#}");
-                    return;
+                None => {
+                    stats.rejected_limit();
+                    rejection = over_max_conn.rejection();
+                    let action = match rejection {
+                        Some(_) => "503 sent",
+                        None => "closed",
+                    };
+                    tracing::error!(
+                        ip = %ip_addr,
+                        port = stats.port,
+                        "Too many connections on port {}, {action} to {client_ip}.",
+                        stats.port
+                    );
+                    // Dropped before the handshake, the cheapest for the server.
+                    if rejection.is_none() {
+                        return;
+                    }
+                    None
                 }
             };
+            // Until the end of the task, the handshake included.
+            let _active = stats.active();
 
-            let conn = http.serve_connection(TokioIo::new(stream), service.clone());
-            tokio::pin!(conn);
-
-            let mut check_interval =
-                tokio::time::interval(Duration::from_secs(config.idle_check_interval));
-            check_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
-            loop {
-                tokio::select! {
-                    res = conn.as_mut() => {
-                        match res {
-                            Ok(_) => {
-                                tracing::info!("Connection closed");
-                            },
-                            Err(err) => {
-                                tracing::error!("failed to serve connection: {err:#}");
+            // Failures are logged by the acceptor.
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let span = acceptor.connection_span(&stream);
+            if let Some((retry_after, _slot)) = rejection {
+                reject_connection(http, stream, retry_after)
+                    .instrument(span)
+                    .await;
+                return;
+            }
+            span.in_scope(|| tracing::debug!("Connection established"));
+
+            async move {
+                let client_cert_subject: Option<Arc<str>> =
+                    acceptor.peer_subject(&stream).map(Arc::from);
+                let access_ip = Arc::clone(&client_ip);
+                let port = stats.port;
+                let service = service_fn(move |req| {
+                    let server_handler = Arc::clone(&server_handler);
+                    let handler_params = handler::HandlerParams {
+                        req,
+                        client_ip: Arc::clone(&client_ip),
+                        scheme: acceptor.protocol(),
+                        port,
+                        client_cert_subject: client_cert_subject.clone(),
+                    };
+                    async move { server_handler.handle(handler_params).await }
+                });
+                let service = ServerService::new(service, access_ip, Arc::clone(&stats));
+
+                let conn = http.serve_connection(TokioIo::new(stream), service.clone());
+                tokio::pin!(conn);
+
+                let mut check_interval =
+                    tokio::time::interval(Duration::from_secs(config.idle_check_interval));
+                check_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+                let opened = Instant::now();
+                // Past max_connection_lifetime, no new request is accepted and
+                // the ones in flight are completed.
+                let mut closing = false;
+
+                loop {
+                    tokio::select! {
+                        res = conn.as_mut() => {
+                            match res {
+                                Ok(_) => {
+                                    tracing::info!("Connection closed");
+                                },
+                                // Already logged by the handler.
+                                Err(err) if is_rejected(&*err) => {}
+                                Err(err) => {
+                                    tracing::error!("failed to serve connection: {err:#}");
+                                }
                             }
+                            break;
                         }
-                        break;
-                    }
-                    _ = check_interval.tick() => {
-                        let idle_secs = service.seconds_since_last_activity();
-
-                        if idle_secs >= config.idle_timeout {
-                           tracing::warn!(
-                                idle_seconds = idle_secs,
-                                "Connection idle timeout, closing connection"
-                           );
-
+                        _ = check_interval.tick() => {
+                            let idle_secs = service.seconds_since_last_activity();
+
+                            if idle_secs >= config.idle_timeout {
+                               tracing::warn!(
+                                    idle_seconds = idle_secs,
+                                    "Connection idle timeout, closing connection"
+                               );
+                                stats.reaped(true);
+
+                                conn.as_mut().graceful_shutdown();
+                                if tokio::time::timeout(
+                                    Duration::from_secs(5),
+                                    conn.as_mut()
+                                ).await.is_err() {
+                                    tracing::warn!("Connection shutdown timeout");
+                                }
+                                break;
+                            } else if !closing
+                                && config.max_connection_lifetime > 0
+                                && opened.elapsed().as_secs() >= config.max_connection_lifetime
+                            {
+                                tracing::info!(
+                                    lifetime_seconds = opened.elapsed().as_secs(),
+                                    "Connection lifetime reached, closing connection"
+                                );
+                                stats.reaped(false);
+                                // A streamed response is never cut, hyper
+                                // closes the connection once it is sent.
+                                conn.as_mut().graceful_shutdown();
+                                closing = true;
+                            } else {
+                                tracing::debug!(
+                                    idle_seconds = idle_secs,
+                                    "Connection idle"
+                                );
+                            }
+                        }
+                        _ = shutdown_token.cancelled() => {
+                            tracing::warn!("Shutting down connection");
                             conn.as_mut().graceful_shutdown();
-                            if tokio::time::timeout(
+                            let _ = tokio::time::timeout(
                                 Duration::from_secs(5),
                                 conn.as_mut()
-                            ).await.is_err() {
-                                tracing::warn!("Connection shutdown timeout");
-                            }
+                            ).await;
                             break;
-                        } else {
-                            tracing::debug!(
-                                idle_seconds = idle_secs,
-                                "Connection idle"
-                            );
                         }
                     }
-                    _ = shutdown_token.cancelled() => {
-                        tracing::warn!("Shutting down connection");
-                        conn.as_mut().graceful_shutdown();
-                        let _ = tokio::time::timeout(
-                            Duration::from_secs(5),
-                            conn.as_mut()
-                        ).await;
-                        break;
-                    }
                 }
             }
+            .instrument(span)
+            .await;
+        });
+    }
+}
+
+// The connection was closed on purpose by the handler.
+fn is_rejected(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<handler::RejectedRequest>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+// Answer the requests of a connection over max_connections with a 503, the
+// client sees the overload instead of a reset.
+async fn reject_connection<S>(
+    http: Arc<Builder<TokioExecutor>>,
+    stream: S,
+    retry_after: Option<HeaderValue>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        let retry_after = retry_after.clone();
+        async move {
+            let mut res = http_response::service_unavailable();
+            if let Some(retry_after) = retry_after {
+                res.headers_mut().insert(RETRY_AFTER, retry_after);
+            }
+            if req.version() < hyper::Version::HTTP_2 {
+                res.headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok::<_, std::convert::Infallible>(res)
+        }
+    });
+    // An HTTP/2 connection stays open until the timeout.
+    let conn = http.serve_connection(TokioIo::new(stream), service);
+    let _ = tokio::time::timeout(REJECTED_CONNECTION_TIMEOUT, conn).await;
+}
+
+// The configuration is reloaded (SIGHUP) and the log level changed (SIGUSR1)
+// by the parent process. Ignore these signals when they are sent to the whole
+// process group, the default action would stop the child.
+fn ignore_parent_signals() {
+    for (kind, name) in [
+        (SignalKind::hangup(), "SIGHUP"),
+        (SignalKind::user_defined1(), "SIGUSR1"),
+        (SignalKind::user_defined2(), "SIGUSR2"),
+    ] {
+        tokio::spawn(async move {
+            let mut signal = signal(kind).unwrap();
+            while signal.recv().await.is_some() {
+                tracing::debug!("[Child Process] {name} ignored");
+            }
         });
     }
 }
@@ -470,52 +1143,110 @@ fn check_sigterm(shutdown_token: CancellationToken) {
     });
 }
 
+// Shared by the accept loops of a port.
+#[derive(Clone)]
 struct HttpServerConfig {
     max_conns: Arc<tokio::sync::Semaphore>,
+    queue_timeout: Duration, // Wait for a permit of max_conns.
+    tcp_options: TcpOptions,
     http: Arc<Builder<TokioExecutor>>,
     server_handler: Arc<ServerHandler>,
     idle_timeout: u64,
     idle_check_interval: u64,
+    max_connection_lifetime: u64, // 0 for no limit.
+    over_max_conn: OverMaxConnections,
     limiter: Option<Arc<ConnectionLimiter>>,
     shutdown_token: CancellationToken,
 }
 
+// What a connection over max_connections gets.
+#[derive(Clone)]
+enum OverMaxConnections {
+    // A 503, with this Retry-After, while a slot is free.
+    Respond(Option<HeaderValue>, Arc<tokio::sync::Semaphore>),
+    Close,
+}
+
+impl OverMaxConnections {
+    fn new(global: &config::Global) -> OverMaxConnections {
+        if global.close_over_max_conn {
+            return OverMaxConnections::Close;
+        }
+        OverMaxConnections::Respond(
+            (global.max_conn_retry_after > 0)
+                .then(|| HeaderValue::from(global.max_conn_retry_after)),
+            Arc::new(tokio::sync::Semaphore::new(MAX_REJECTED_CONNECTIONS)),
+        )
+    }
+
+    // The Retry-After of the 503 of a connection over max_connections and
+    // the slot kept while it is answered, None to close it.
+    fn rejection(&self) -> Option<(Option<HeaderValue>, OwnedSemaphorePermit)> {
+        match self {
+            OverMaxConnections::Respond(retry_after, slots) => {
+                let slot = Arc::clone(slots).try_acquire_owned().ok()?;
+                Some((retry_after.clone(), slot))
+            }
+            OverMaxConnections::Close => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HttpsServerParams {
+    port: u16,
+    handshake_timeout: u64,
+    tls_options: config::TlsOptions,
+    client_auth: Option<(ClientAuthMode, Vec<u8>)>,
+}
+
 async fn https_server(
     config: HttpServerConfig,
-    tx: tokio::sync::broadcast::Sender<Arc<IpcMessage<Vec<IpcCerts>>>>,
-    tls_certs: Arc<HashMap<u16, Vec<IpcCerts>>>,
-    handshake_timeout: u64,
-    port: u16,
+    stats: Arc<ListenerStats>,
+    tls: Arc<TlsState>,
+    params: HttpsServerParams,
     listener: TcpListener,
 ) {
-    let tls_acceptor = build_tls_acceptor_with_reload(port, tx, tls_certs).await;
     let acceptor = Arc::new(TlsAcceptorWrapper {
-        acceptor: tls_acceptor,
-        handshake_timeout,
+        tls,
+        handshake_timeout: params.handshake_timeout,
+        stats: Arc::clone(&stats),
     });
 
-    run_server(config, listener, acceptor).await;
+    run_server(config, stats, listener, acceptor).await;
 }
 
-async fn http_server(config: HttpServerConfig, listener: TcpListener) {
+async fn http_server(config: HttpServerConfig, stats: Arc<ListenerStats>, listener: TcpListener) {
     let acceptor = Arc::new(PlainAcceptor);
-    run_server(config, listener, acceptor).await;
+    run_server(config, stats, listener, acceptor).await;
 }
 
-async fn build_tls_acceptor_with_reload(
-    port: u16,
+// TLS state of a https listener.
+struct TlsState {
+    // Rebuilt when the configuration is reloaded.
+    server_config: ArcSwap<ServerConfig>,
+    // Updated when the certificates are reloaded.
+    ck_list: Arc<SharedCertifiedKeyList>,
+}
+
+// Part of a server that can be updated without a restart.
+struct ReloadableServer {
+    handler: Arc<ServerHandler>,
+    tls: Option<Arc<TlsState>>,
+}
+
+fn build_tls_state(
     tx: tokio::sync::broadcast::Sender<Arc<IpcMessage<Vec<IpcCerts>>>>,
-    tls_certs: Arc<HashMap<u16, Vec<IpcCerts>>>,
-) -> TlsAcceptor {
+    tls_certs: &HashMap<u16, Vec<IpcCerts>>,
+    params: &HttpsServerParams,
+) -> Result<Arc<TlsState>, String> {
     let mut rx = tx.subscribe();
+    let port = params.port;
 
     let tls_certs = tls_certs.get(&port).unwrap();
-
-    let tls_config = Arc::new(tokio::sync::Mutex::new(TlsConfig::new(tls_certs)));
-    let ck_list = {
-        let mut guard = tls_config.lock().await;
-        Arc::new(guard.get_certified_key_list())
-    };
+    let ck_list = Arc::new(ArcSwap::from_pointee(
+        TlsConfig::new(tls_certs).get_certified_key_list(),
+    ));
 
     // Spawn a task to watch for certificates changes.
     let port_string = port.to_string();
@@ -524,26 +1255,217 @@ async fn build_tls_acceptor_with_reload(
         while let Ok(msg) = rx.recv().await {
             if msg.key.as_ref().unwrap() == &port_string {
                 info!("New certificates for port {}", port);
-                msg.payload.iter().for_each(|cert| {
-                    reload_certificates(cert, ck_list_clone.clone());
-                })
+                reload_certificates(&msg.payload, &ck_list_clone);
             }
         }
     });
 
-    // Generate the sni resolver pass it to the tls_config
-    // to get the rustls server config.
-    let resolver = SniCertResolver::new(ck_list);
-    let server_config = {
-        let guard = tls_config.lock().await;
-        guard.get_tls_config(resolver)
+    let client_auth = params
+        .client_auth
+        .as_ref()
+        .map(|(mode, ca)| (mode, ca.as_slice()));
+    let server_config = build_server_config(&ck_list, &params.tls_options, client_auth)?;
+
+    Ok(Arc::new(TlsState {
+        server_config: ArcSwap::from_pointee(server_config),
+        ck_list,
+    }))
+}
+
+// Generate the sni resolver and pass it to the tls_config
+// to get the rustls server config.
+fn build_server_config(
+    ck_list: &Arc<SharedCertifiedKeyList>,
+    options: &config::TlsOptions,
+    client_auth: Option<(&ClientAuthMode, &[u8])>,
+) -> Result<ServerConfig, String> {
+    let resolver = SniCertResolver::new(Arc::clone(ck_list));
+    TlsConfig::new(&Vec::new()).get_tls_config(resolver, options, client_auth)
+}
+
+// Apply the configurations sent by the parent process on SIGHUP.
+// The parent only sends configurations with the same listeners.
+async fn watch_config_reload(
+    mut config_rx: tokio::sync::mpsc::UnboundedReceiver<ConfigReload>,
+    servers: HashMap<String, ReloadableServer>,
+    mut lb_config: Arc<load_balancing::LoadBalancerConfig>,
+    mut limits: Arc<TargetLimits>,
+    admin_state: Arc<AdminState>,
+) {
+    while let Some(reload) = config_rx.recv().await {
+        lb_config = generate_loadbalancing_config(&reload.config.servers, Some(&lb_config)).await;
+        limits = TargetLimits::reload(&reload.config.servers, &limits);
+        admin_state.update(
+            reload.config.clone(),
+            Arc::clone(&lb_config),
+            Arc::clone(&limits),
+        );
+        for (name, server) in reload.config.servers {
+            let Some(reloadable) = servers.get(&name) else {
+                tracing::warn!("Server {name} isn't running, restart to start it");
+                continue;
+            };
+            if let Some(tls) = &reloadable.tls {
+                let client_auth = server.client_auth.as_ref().and_then(|client_auth| {
+                    reload
+                        .client_cas
+                        .get(&server.https_port)
+                        .map(|ca| (&client_auth.mode, ca.as_slice()))
+                });
+                match build_server_config(&tls.ck_list, &server.tls_options, client_auth) {
+                    Ok(server_config) => tls.server_config.store(Arc::new(server_config)),
+                    Err(err) => tracing::error!(
+                        "failed to reload the TLS config on port {}: {err}",
+                        server.https_port
+                    ),
+                }
+            }
+            reloadable
+                .handler
+                .reload(server.params, Arc::clone(&lb_config), Arc::clone(&limits));
+        }
+        tracing::info!("Configuration reloaded");
+    }
+}
+
+// Ports of the listening sockets. They are bound by the parent process, so
+// they outlive the child during an upgrade.
+pub fn listener_ports(internal_config: &InternalConfig) -> Vec<u16> {
+    if internal_config.empty {
+        let main = &internal_config.servers[MAIN_SERVER_NAME];
+        let https_port = main.tls.as_ref().map(|_| main.https_port);
+        return std::iter::once(welcome_port(main))
+            .chain(https_port)
+            .collect();
+    }
+    let mut ports: Vec<u16> = internal_config
+        .servers
+        .values()
+        .flat_map(|server| {
+            let https_port = server.tls.as_ref().map(|_| server.https_port);
+            std::iter::once(server.port).chain(https_port)
+        })
+        .collect();
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+// Listening sockets of each port, the welcome server has a single one.
+pub fn accept_loops(internal_config: &InternalConfig) -> usize {
+    match internal_config.empty {
+        true => 1,
+        false => internal_config.global.accept_loops,
+    }
+}
+
+// Use the sockets received from the parent process, or bind new ones.
+fn take_listeners(
+    inherited: &mut HashMap<u16, Vec<OwnedFd>>,
+    port: u16,
+    accept_loops: usize,
+    backlog: i32,
+    defer_accept: Option<u64>,
+) -> io::Result<Vec<TcpListener>> {
+    let (listeners, origin) = match inherited.remove(&port) {
+        Some(fds) => (
+            fds.into_iter()
+                .map(tcp_listener_from_fd)
+                .collect::<io::Result<Vec<_>>>()?,
+            " (inherited socket)",
+        ),
+        None => (
+            (0..accept_loops)
+                .map(|_| build_tcp_listener(port, backlog, accept_loops > 1))
+                .collect::<io::Result<Vec<_>>>()?,
+            "",
+        ),
     };
+    match listeners.len() {
+        1 => info!("Server listening on port {port}{origin}"),
+        count => info!("Server listening on port {port}{origin}, {count} accept loops"),
+    }
+    // Set again on the inherited sockets, the configuration may have changed.
+    for listener in &listeners {
+        set_defer_accept(listener, defer_accept).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("can't set tcp_defer_accept on port {port}: {e}"),
+            )
+        })?;
+    }
+    Ok(listeners)
+}
+
+fn tcp_listener_from_fd(fd: OwnedFd) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
 
-    // Create the tls acceptor with the rustls server config.
-    TlsAcceptor::from(Arc::new(server_config))
+// Options of an accepted connection, the OS defaults are kept when unset.
+fn set_stream_options(stream: &tokio::net::TcpStream, options: &TcpOptions) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        stream.set_nodelay(nodelay)?;
+    }
+    if let Some(keepalive) = &options.keepalive {
+        let mut params = socket2::TcpKeepalive::new();
+        if let Some(time) = keepalive.time {
+            params = params.with_time(Duration::from_secs(time));
+        }
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(Duration::from_secs(interval));
+        }
+        if let Some(retries) = keepalive.retries {
+            params = params.with_retries(retries);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
 }
 
-fn build_tcp_listener(port: u16, backlog: i32) -> io::Result<TcpListener> {
+// TCP_DEFER_ACCEPT, which neither socket2 nor nix provide.
+#[cfg(target_os = "linux")]
+mod defer_accept {
+    use nix::{getsockopt_impl, libc, setsockopt_impl, sockopt_impl};
+
+    sockopt_impl!(
+        TcpDeferAccept,
+        Both,
+        libc::IPPROTO_TCP,
+        libc::TCP_DEFER_ACCEPT,
+        libc::c_int
+    );
+}
+
+// Wake up the accept loop only once the client sent data, or after the
+// timeout in seconds. Unset, it's disabled: an inherited socket may have it.
+#[cfg(target_os = "linux")]
+fn set_defer_accept(listener: &TcpListener, timeout: Option<u64>) -> io::Result<()> {
+    use nix::{libc, sys::socket::setsockopt};
+
+    let timeout = libc::c_int::try_from(timeout.unwrap_or(0)).unwrap_or(libc::c_int::MAX);
+    setsockopt(listener, defer_accept::TcpDeferAccept, &timeout)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_defer_accept(_listener: &TcpListener, timeout: Option<u64>) -> io::Result<()> {
+    if timeout.is_some() {
+        tracing::warn!("tcp_defer_accept is only supported on Linux, ignored");
+    }
+    Ok(())
+}
+
+fn build_tcp_listener(port: u16, backlog: i32, reuse_port: bool) -> io::Result<TcpListener> {
+    // E.g. another process listening on the port.
+    let socket = bind_listener(port, backlog, reuse_port)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't listen on port {port}: {e}")))?;
+    // Create and return the listener.
+    TcpListener::from_std(socket.into())
+}
+
+pub fn bind_listener(port: u16, backlog: i32, reuse_port: bool) -> io::Result<Socket> {
     // Build TCP Socket and Socket Address.
     let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
     let socket_addr: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
@@ -551,15 +1473,18 @@ fn build_tcp_listener(port: u16, backlog: i32) -> io::Result<TcpListener> {
     socket.set_only_v6(false)?;
     // Allow reuse of the address.
     socket.set_reuse_address(true)?;
+    // Several sockets of the same port share the connections, one per
+    // accept loop. Not set otherwise, another process can't bind the port.
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
     // Define that the socket is non-blocking. Otherwise tokio can't accept it.
     socket.set_nonblocking(true)?;
     // Bind the socket to the address.
     socket.bind(&socket_addr.into())?;
     // Define the backlog.
     socket.listen(backlog)?;
-    // Create and return the listener.
-    info!("Server listening on port {}", port);
-    TcpListener::from_std(socket.into())
+    Ok(socket)
 }
 
 #[derive(Clone)]
@@ -638,6 +1563,8 @@ mod tests {
         time::Duration,
     };
 
+    use super::*;
+    use crate::config::build_config;
     use crate::server::ConnectionLimiter;
 
     #[test]
@@ -756,4 +1683,464 @@ mod tests {
             "The limit of 10 connections was not enforced: {total_success}"
         );
     }
+
+    #[test]
+    fn reuse_port_only_with_accept_loops() {
+        let port = |socket: &Socket| socket.local_addr().unwrap().as_socket().unwrap().port();
+        // A single socket: another process can't bind its port.
+        let single = bind_listener(0, 16, false).unwrap();
+        assert!(bind_listener(port(&single), 16, true).is_err());
+        assert!(bind_listener(port(&single), 16, false).is_err());
+
+        let first = bind_listener(0, 16, true).unwrap();
+        assert!(bind_listener(port(&first), 16, true).is_ok());
+        assert!(bind_listener(port(&first), 16, false).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn defer_accept_reset_when_unset() {
+        use nix::sys::socket::getsockopt;
+
+        let socket = bind_listener(0, 16, false).unwrap();
+        let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+        let inherit = |listener: TcpListener| {
+            let fd = OwnedFd::from(listener.into_std().unwrap());
+            HashMap::from([(port, vec![fd])])
+        };
+
+        let mut inherited = HashMap::from([(port, vec![OwnedFd::from(socket)])]);
+        let listener = take_listeners(&mut inherited, port, 1, 16, Some(5))
+            .unwrap()
+            .remove(0);
+        assert!(getsockopt(&listener, defer_accept::TcpDeferAccept).unwrap() > 0);
+
+        // Removed from the configuration before an upgrade.
+        let listener = take_listeners(&mut inherit(listener), port, 1, 16, None)
+            .unwrap()
+            .remove(0);
+        assert_eq!(
+            getsockopt(&listener, defer_accept::TcpDeferAccept).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn welcome_listener_ports() {
+        let build = |toml: &str| build_config(toml).unwrap();
+
+        let config = build("");
+        let default_port = if nix::unistd::getuid().is_root() {
+            80
+        } else {
+            8080
+        };
+        assert_eq!(listener_ports(&config), vec![default_port]);
+
+        // The ports of the main server, over HTTPS with its default
+        // certificate.
+        let config = build(
+            r#"
+            [servers.main]
+            port = 8081
+            https_port = 8444
+            default_certificate = { cert = "/etc/quark/cert.pem", key = "/etc/quark/key.pem" }
+            "#,
+        );
+        assert_eq!(listener_ports(&config), vec![8081, 8444]);
+        let main = &config.servers[MAIN_SERVER_NAME];
+        assert_eq!(
+            main.default_cert,
+            main.tls.as_ref().unwrap().first().cloned()
+        );
+        assert_eq!(
+            config.restart_settings()[0],
+            "no services (welcome server), port 8081, https_port 8444"
+        );
+    }
+
+    // A plain http listener of example.com with the given [global] settings.
+    async fn spawn_listener(global: &str) -> (SocketAddr, Arc<ListenerStats>) {
+        let mut config = build_config(format!(
+            r#"
+            [global]
+            {global}
+
+            [services.app]
+            domain = "example.com"
+            locations = [{{ source = "/*", target = "http://127.0.0.1:9" }}]
+            "#
+        ))
+        .unwrap();
+        let tls_config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let server_handler = ServerHandler::builder(
+            config.servers.remove("main").unwrap().params,
+            crate::server::load_balancing::LoadBalancerConfig::new(Vec::new()),
+            Arc::default(),
+            Arc::new(tokio::sync::Semaphore::new(10)),
+            Duration::ZERO,
+            Arc::new(ProxyClients::new(tls_config)),
+        );
+        let http_config = HttpServerConfig {
+            max_conns: Arc::new(tokio::sync::Semaphore::new(config.global.max_conn)),
+            queue_timeout: Duration::ZERO,
+            tcp_options: Default::default(),
+            http: Arc::new(Builder::new(TokioExecutor::new())),
+            server_handler,
+            idle_timeout: config.global.idle_timeout,
+            idle_check_interval: config.global.idle_check_interval,
+            max_connection_lifetime: config.global.max_connection_lifetime,
+            over_max_conn: OverMaxConnections::new(&config.global),
+            limiter: None,
+            shutdown_token: CancellationToken::new(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(ListenerStats::new(addr.port(), "http", None));
+        tokio::spawn(run_server(
+            http_config,
+            Arc::clone(&stats),
+            listener,
+            Arc::new(PlainAcceptor),
+        ));
+        (addr, stats)
+    }
+
+    // A listener with max_connections = 1 and an open connection.
+    async fn saturated_listener(
+        over_max_conn: &str,
+    ) -> (SocketAddr, Arc<ListenerStats>, tokio::net::TcpStream) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (addr, stats) = spawn_listener(&format!(
+            r#"
+            max_connections = 1
+            max_connections_retry_after = 5
+            over_max_connections = "{over_max_conn}"
+            "#
+        ))
+        .await;
+
+        // Answered once it holds the only permit.
+        let mut open = tokio::net::TcpStream::connect(addr).await.unwrap();
+        open.write_all(b"GET / HTTP/1.1\r\nHost: unknown.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = open.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 400"));
+        (addr, stats, open)
+    }
+
+    async fn exchange(addr: SocketAddr) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        // Closed before the request with over_max_connections = "close".
+        let _ = stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await;
+        let mut res = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut res)).await;
+        String::from_utf8_lossy(&res).to_lowercase()
+    }
+
+    #[tokio::test]
+    async fn over_max_connections() {
+        let (addr, stats, _open) = saturated_listener("respond").await;
+        for _ in 0..2 {
+            let res = exchange(addr).await;
+            assert!(res.starts_with("http/1.1 503"), "{res}");
+            assert!(res.contains("retry-after: 5\r\n"), "{res}");
+            assert!(res.contains("connection: close\r\n"), "{res}");
+        }
+        assert_eq!(stats.snapshot().rejected_limit, 2);
+
+        // Closed once too many are answered, until they time out.
+        let mut idle = Vec::new();
+        for _ in 0..MAX_REJECTED_CONNECTIONS {
+            idle.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        let rejected = 2 + MAX_REJECTED_CONNECTIONS as u64;
+        while stats.snapshot().rejected_limit < rejected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(exchange(addr).await, "");
+        tokio::time::sleep(REJECTED_CONNECTION_TIMEOUT).await;
+        assert!(exchange(addr).await.starts_with("http/1.1 503"));
+
+        let (addr, stats, _open) = saturated_listener("close").await;
+        assert_eq!(exchange(addr).await, "");
+        assert_eq!(stats.snapshot().rejected_limit, 1);
+    }
+
+    // A https acceptor with the example.com certificate, and its counters.
+    fn tls_acceptor(handshake_timeout: u64) -> Arc<TlsAcceptorWrapper> {
+        let certs = vec![IpcCerts {
+            cert: include_bytes!("../tests/certs/ecdsa.pem").to_vec(),
+            key: include_bytes!("../tests/certs/ecdsa.key").to_vec(),
+            default: true,
+            path: "tests/certs/ecdsa.pem".to_string(),
+            key_path: "tests/certs/ecdsa.key".to_string(),
+        }];
+        let ck_list = Arc::new(ArcSwap::from_pointee(
+            TlsConfig::new(&certs).get_certified_key_list(),
+        ));
+        let server_config =
+            build_server_config(&ck_list, &config::TlsOptions::default(), None).unwrap();
+        Arc::new(TlsAcceptorWrapper {
+            tls: Arc::new(TlsState {
+                server_config: ArcSwap::from_pointee(server_config),
+                ck_list,
+            }),
+            handshake_timeout,
+            stats: Arc::new(ListenerStats::new(443, "https", None)),
+        })
+    }
+
+    // Result of the handshake of the next connection accepted.
+    async fn accept_tls(
+        acceptor: &Arc<TlsAcceptorWrapper>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = Arc::clone(acceptor);
+        let handshake = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            acceptor.accept(stream).await.map(|_| ())
+        });
+        (addr, handshake)
+    }
+
+    #[tokio::test]
+    async fn handshake_failures_counted() {
+        use tokio::io::AsyncWriteExt;
+
+        let acceptor = tls_acceptor(1);
+
+        // Nothing sent before the timeout.
+        let (addr, handshake) = accept_tls(&acceptor).await;
+        let _silent = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let err = handshake.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Plain http on the https port.
+        let (addr, handshake) = accept_tls(&acceptor).await;
+        let mut plain = tokio::net::TcpStream::connect(addr).await.unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let err = handshake.await.unwrap().unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::TimedOut);
+
+        let stats = acceptor.stats.snapshot();
+        assert_eq!(stats.tls_handshake_failed, 2);
+        assert_eq!(stats.tls_handshake_timeouts, 1);
+        assert!(stats.tls_handshakes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshakes_counted_by_parameters() {
+        let acceptor = tls_acceptor(5);
+        // Returns the cipher suite negotiated by the client.
+        let handshake = |version: &'static rustls::SupportedProtocolVersion, alpn: &[u8]| {
+            let acceptor = Arc::clone(&acceptor);
+            let mut client_config =
+                rustls::ClientConfig::builder_with_protocol_versions(&[version])
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                    .with_no_client_auth();
+            client_config.alpn_protocols = vec![alpn.to_vec()];
+            async move {
+                let (addr, accepted) = accept_tls(&acceptor).await;
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+                let name = rustls::pki_types::ServerName::try_from("example.com").unwrap();
+                let client = connector.connect(name, stream).await.unwrap();
+                accepted.await.unwrap().unwrap();
+                let suite = client.get_ref().1.negotiated_cipher_suite().unwrap();
+                suite.suite().as_str().unwrap()
+            }
+        };
+
+        let tls13 = handshake(&rustls::version::TLS13, b"h2").await;
+        assert_eq!(handshake(&rustls::version::TLS13, b"h2").await, tls13);
+        let tls12 = handshake(&rustls::version::TLS12, b"http/1.1").await;
+
+        let stats = acceptor.stats.snapshot();
+        assert_eq!(stats.tls_handshake_failed, 0);
+        let handshakes: Vec<_> = stats
+            .tls_handshakes
+            .iter()
+            .map(|h| {
+                (
+                    h.params.version,
+                    h.params.cipher_suite,
+                    h.params.alpn,
+                    h.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            handshakes,
+            [
+                ("1.2", tls12, Some("http/1.1"), 1),
+                ("1.3", tls13, Some("h2"), 2)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_stream_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("quark.sock").to_string_lossy().to_string();
+        let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+        // The writer of the broken stream.
+        let (_, broken) = tokio::net::UnixStream::pair().unwrap();
+        let writer = Arc::new(Mutex::new(broken.into_split().1));
+        let shutdown_token = CancellationToken::new();
+
+        let reconnect = tokio::spawn({
+            let writer = Arc::clone(&writer);
+            let shutdown_token = shutdown_token.clone();
+            async move { reconnect_to_parent(&socket_path, &writer, &shutdown_token).await }
+        });
+        // The first attempt fails without a hello, the next one succeeds.
+        drop(listener.accept().await.unwrap());
+        let (mut parent, _) = listener.accept().await.unwrap();
+        ipc::exchange_hello(&mut parent, "server process")
+            .await
+            .unwrap();
+        let attach = ipc::receive_ipc_message::<ipc::IpcAttach>(&mut parent, MessageKind::Attach)
+            .await
+            .unwrap();
+        assert_eq!(attach.payload.pid, std::process::id());
+        assert!(attach.payload.reconnect);
+        let mut reader = reconnect.await.unwrap().unwrap();
+
+        // Both ends of the new stream are used.
+        let heartbeat = |missed| IpcMessage {
+            kind: MessageKind::Heartbeat,
+            key: None,
+            payload: IpcHeartbeat::new(missed),
+        };
+        ipc::send_ipc_message(Arc::clone(&writer), heartbeat(1))
+            .await
+            .unwrap();
+        let received =
+            ipc::receive_ipc_message::<IpcHeartbeat>(&mut parent, MessageKind::Heartbeat)
+                .await
+                .unwrap();
+        assert_eq!(received.payload.missed, 1);
+        let parent = Arc::new(Mutex::new(parent));
+        ipc::send_ipc_message(parent, heartbeat(2)).await.unwrap();
+        let received =
+            ipc::receive_ipc_message::<IpcHeartbeat>(&mut reader, MessageKind::Heartbeat)
+                .await
+                .unwrap();
+        assert_eq!(received.payload.missed, 2);
+
+        // Given up once the server stops.
+        shutdown_token.cancel();
+        let socket_path = dir
+            .path()
+            .join("missing.sock")
+            .to_string_lossy()
+            .to_string();
+        assert!(reconnect_to_parent(&socket_path, &writer, &shutdown_token)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn parent_heartbeats_watched() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let alive = Arc::new(Notify::new());
+        let heartbeats = Arc::new(HeartbeatStats::default());
+        let shutdown_token = CancellationToken::new();
+        let watch = tokio::spawn(watch_parent(
+            Arc::clone(&alive),
+            Arc::clone(&heartbeats),
+            TIMEOUT,
+            false,
+            shutdown_token.clone(),
+        ));
+        for _ in 0..10 {
+            alive.notify_one();
+            tokio::time::sleep(TIMEOUT / 4).await;
+        }
+        assert_eq!(heartbeats.missed_total(), 0);
+
+        // Counted once while the main process is lost, the server keeps running.
+        tokio::time::sleep(TIMEOUT * 3).await;
+        assert_eq!(heartbeats.missed_total(), 1);
+        assert!(!shutdown_token.is_cancelled());
+        // Lost again after it is back.
+        alive.notify_one();
+        tokio::time::sleep(TIMEOUT * 2).await;
+        assert_eq!(heartbeats.missed_total(), 2);
+        shutdown_token.cancel();
+        watch.await.unwrap();
+
+        // With orphan_shutdown, the server stops.
+        let heartbeats = Arc::new(HeartbeatStats::default());
+        let shutdown_token = CancellationToken::new();
+        tokio::time::timeout(
+            TIMEOUT * 5,
+            watch_parent(
+                alive,
+                Arc::clone(&heartbeats),
+                TIMEOUT,
+                true,
+                shutdown_token.clone(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(shutdown_token.is_cancelled());
+        assert_eq!(heartbeats.missed_total(), 1);
+    }
+
+    #[tokio::test]
+    async fn connections_reaped() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: unknown.com\r\n\r\n";
+        let mut buf = [0; 1024];
+        // The activity of the connections is timed with the cached time.
+        update_cached_time_worker();
+
+        // Idle after a request.
+        let (addr, stats) = spawn_listener("idle_timeout = 1\nidle_check_interval = 1").await;
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        idle.write_all(REQUEST).await.unwrap();
+        assert!(idle.read(&mut buf).await.unwrap() > 0);
+        let closed = tokio::time::timeout(Duration::from_secs(5), idle.read(&mut buf)).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.reaped_idle, snapshot.reaped_lifetime), (1, 0));
+
+        // Busy, but open for too long. The connection is kept while active.
+        let (addr, stats) =
+            spawn_listener("idle_check_interval = 1\nmax_connection_lifetime = 1").await;
+        let mut busy = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut responses = 0;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while busy.write_all(REQUEST).await.is_ok() {
+                match busy.read(&mut buf).await {
+                    Ok(n) if n > 0 => responses += 1,
+                    _ => break,
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(responses > 5, "{responses}");
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.reaped_idle, snapshot.reaped_lifetime), (0, 1));
+    }
 }
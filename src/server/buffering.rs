@@ -0,0 +1,210 @@
+// Request bodies read completely before the backend is requested: a slow
+// client doesn't hold a connection to the backend during its upload, and
+// the body can be sent again.
+use std::{io, os::unix::fs::FileExt, path::Path, sync::Arc};
+
+use futures::StreamExt;
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use tokio::io::AsyncWriteExt;
+
+use crate::utils::generate_request_id;
+
+use super::server_utils::{BoxedFrameStream, ProxyHandlerBody};
+
+// Larger bodies are written to a temporary file.
+pub const MEMORY_LIMIT: u64 = 1024 * 1024;
+// Size of the frames read back from the file.
+const FILE_CHUNK_SIZE: u64 = 64 * 1024;
+
+pub enum BufferedBody {
+    Memory(Bytes),
+    // Removed from the disk once written, and freed when the last body
+    // reading it is dropped.
+    File { file: Arc<std::fs::File>, len: u64 },
+}
+
+pub enum Buffered {
+    Complete(BufferedBody),
+    // Over the limit: the part already read, followed by the rest.
+    TooLarge(ProxyHandlerBody),
+}
+
+impl BufferedBody {
+    pub fn len(&self) -> u64 {
+        match self {
+            BufferedBody::Memory(bytes) => bytes.len() as u64,
+            BufferedBody::File { len, .. } => *len,
+        }
+    }
+
+    // A new body on each call, e.g. to retry the request.
+    pub fn body(&self) -> ProxyHandlerBody {
+        match self {
+            BufferedBody::Memory(bytes) => ProxyHandlerBody::Full(Full::new(bytes.clone())),
+            BufferedBody::File { file, len } => {
+                let (file, len) = (Arc::clone(file), *len);
+                let stream = futures::stream::unfold(0, move |offset| {
+                    let file = Arc::clone(&file);
+                    async move {
+                        if offset >= len {
+                            return None;
+                        }
+                        let size = FILE_CHUNK_SIZE.min(len - offset);
+                        let read = tokio::task::spawn_blocking(move || {
+                            let mut buf = vec![0; size as usize];
+                            file.read_exact_at(&mut buf, offset).map(|_| buf)
+                        })
+                        .await
+                        .unwrap_or_else(|err| Err(io::Error::other(err)));
+                        Some(match read {
+                            Ok(buf) => (Ok(Frame::data(Bytes::from(buf))), offset + size),
+                            // Ends the stream after the error.
+                            Err(err) => (Err(err), len),
+                        })
+                    }
+                });
+                let stream: BoxedFrameStream = Box::pin(stream);
+                ProxyHandlerBody::StreamBody(StreamBody::new(stream))
+            }
+        }
+    }
+}
+
+// Read the body up to max bytes, in memory up to memory_limit bytes and then
+// in a file of dir. The trailers of a request are dropped.
+pub async fn buffer(
+    mut body: ProxyHandlerBody,
+    max: u64,
+    memory_limit: u64,
+    dir: &Path,
+) -> io::Result<Buffered> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut file: Option<tokio::fs::File> = None;
+    let mut len = 0;
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else {
+            continue;
+        };
+        len += data.len() as u64;
+        if len > max {
+            let read: BoxedFrameStream = match file {
+                Some(file) => {
+                    let written = BufferedBody::File {
+                        file: into_std(file).await?,
+                        len: len - data.len() as u64,
+                    };
+                    Box::pin(BodyStream::new(written.body()))
+                }
+                None => Box::pin(futures::stream::iter(
+                    chunks.into_iter().map(|chunk| Ok(Frame::data(chunk))),
+                )),
+            };
+            let stream = read
+                .chain(futures::stream::once(async { Ok(Frame::data(data)) }))
+                .chain(BodyStream::new(body));
+            let stream: BoxedFrameStream = Box::pin(stream);
+            return Ok(Buffered::TooLarge(ProxyHandlerBody::StreamBody(
+                StreamBody::new(stream),
+            )));
+        }
+        match &mut file {
+            Some(file) => file.write_all(&data).await?,
+            None if len > memory_limit => {
+                let mut new_file = temp_file(dir).await?;
+                for chunk in chunks.drain(..) {
+                    new_file.write_all(&chunk).await?;
+                }
+                new_file.write_all(&data).await?;
+                file = Some(new_file);
+            }
+            None => chunks.push(data),
+        }
+    }
+    Ok(Buffered::Complete(match file {
+        Some(file) => BufferedBody::File {
+            file: into_std(file).await?,
+            len,
+        },
+        None if chunks.len() == 1 => BufferedBody::Memory(chunks.remove(0)),
+        None => BufferedBody::Memory(Bytes::from(chunks.concat())),
+    }))
+}
+
+// A tokio file writes in the background, flushed before being read.
+async fn into_std(mut file: tokio::fs::File) -> io::Result<Arc<std::fs::File>> {
+    file.flush().await?;
+    Ok(Arc::new(file.into_std().await))
+}
+
+// Created with a random name and removed at once, so that nothing is left
+// behind by a crash.
+async fn temp_file(dir: &Path) -> io::Result<tokio::fs::File> {
+    let path = dir.join(format!("quark-body-{}", generate_request_id()));
+    let file = tokio::fs::OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static [u8]]) -> ProxyHandlerBody {
+        let frames: Vec<io::Result<Frame<Bytes>>> = chunks
+            .iter()
+            .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk))))
+            .collect();
+        let stream: BoxedFrameStream = Box::pin(futures::stream::iter(frames));
+        ProxyHandlerBody::StreamBody(StreamBody::new(stream))
+    }
+
+    async fn collect(body: ProxyHandlerBody) -> Bytes {
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn buffered_bodies() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+
+        // Kept in memory under the limit.
+        let body = chunked(&[b"hello ", b"world"]);
+        let Buffered::Complete(buffered) = buffer(body, 100, 20, dir.path()).await.unwrap() else {
+            panic!("body over the limit");
+        };
+        assert!(matches!(buffered, BufferedBody::Memory(_)));
+        assert_eq!(buffered.len(), 11);
+        assert_eq!(collect(buffered.body()).await, "hello world");
+
+        // Spilled to a file, unlinked at once, and read again on each body.
+        let body = chunked(&[b"0123456789", b"0123456789", b"abc"]);
+        let Buffered::Complete(buffered) = buffer(body, 100, 15, dir.path()).await.unwrap() else {
+            panic!("body over the limit");
+        };
+        assert!(matches!(buffered, BufferedBody::File { .. }));
+        assert_eq!(files(), 0);
+        assert_eq!(buffered.len(), 23);
+        for _ in 0..2 {
+            assert_eq!(collect(buffered.body()).await, "01234567890123456789abc");
+        }
+
+        // Over the max, the body is still complete when streamed, from
+        // the memory and from the file.
+        for memory_limit in [100, 5] {
+            let body = chunked(&[b"0123456789", b"0123456789", b"abc"]);
+            let Buffered::TooLarge(body) =
+                buffer(body, 15, memory_limit, dir.path()).await.unwrap()
+            else {
+                panic!("body under the limit");
+            };
+            assert_eq!(collect(body).await, "01234567890123456789abc");
+        }
+        assert_eq!(files(), 0);
+    }
+}
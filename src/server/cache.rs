@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use futures::StreamExt;
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{
+    HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, SET_COOKIE, VARY,
+};
+use hyper::{http::response, HeaderMap, Request, Response, StatusCode};
+
+use crate::config::CacheConfig;
+
+use super::server_utils::{BoxedFrameStream, ProxyHandlerBody};
+
+const CACHE_STATUS_HEADER: &str = "x-cache";
+
+// Responses of a location, stored for ttl seconds. The least recently used
+// ones are evicted when max_size is reached.
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Vec<Entry>>, // Variants of a key, by Vary values.
+    lru: BTreeMap<u64, String>,       // Keys by last use of their variants.
+    tick: u64,
+    size: u64, // Of all the entries, in bytes.
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Request headers listed by the Vary header of the response.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored: Instant,
+    last_use: u64,
+    size: u64,
+}
+
+impl Entry {
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+}
+
+impl Entries {
+    fn touch(&mut self, key: &str, index: usize) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.map.get_mut(key).and_then(|v| v.get_mut(index)) {
+            let key = self.lru.remove(&entry.last_use).unwrap_or_default();
+            entry.last_use = tick;
+            self.lru.insert(tick, key);
+        }
+    }
+
+    fn remove(&mut self, key: &str, index: usize) {
+        let Some(variants) = self.map.get_mut(key) else {
+            return;
+        };
+        let entry = variants.swap_remove(index);
+        if variants.is_empty() {
+            self.map.remove(key);
+        }
+        self.lru.remove(&entry.last_use);
+        self.size -= entry.size;
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some((tick, key)) = self.lru.pop_first() else {
+            return;
+        };
+        let index = self
+            .map
+            .get(&key)
+            .and_then(|variants| variants.iter().position(|e| e.last_use == tick));
+        if let Some(index) = index {
+            self.remove(&key, index);
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> ResponseCache {
+        ResponseCache {
+            config,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // Key of a request that can be served from the cache, None to bypass it.
+    // The responses for an authenticated user are never shared.
+    pub fn key<B>(&self, req: &Request<B>, url: &str) -> Option<String> {
+        let method = req.method();
+        if !self.config.methods.iter().any(|m| m == method.as_str())
+            || req.headers().contains_key(AUTHORIZATION)
+        {
+            return None;
+        }
+        Some(format!("{method} {url}"))
+    }
+
+    pub fn get(
+        &self,
+        key: &str,
+        request_headers: &HeaderMap,
+        now: Instant,
+    ) -> Option<Response<ProxyHandlerBody>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let variants = entries.map.get(key)?;
+        let index = variants.iter().position(|e| e.matches(request_headers))?;
+        let entry = &variants[index];
+        let age = now.saturating_duration_since(entry.stored);
+        if age.as_secs() >= self.config.ttl {
+            entries.remove(key, index);
+            return None;
+        }
+        let mut res = Response::new(ProxyHandlerBody::Full(Full::new(entry.body.clone())));
+        *res.status_mut() = entry.status;
+        *res.headers_mut() = entry.headers.clone();
+        entries.touch(key, index);
+        drop(entries);
+
+        res.headers_mut()
+            .insert(AGE, HeaderValue::from(age.as_secs()));
+        set_cache_status(&mut res, "HIT");
+        Some(res)
+    }
+
+    // Read the body of a response from the backend and store it if it can
+    // be shared. The response is marked as a miss either way.
+    pub async fn store(
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
+        res: Response<ProxyHandlerBody>,
+    ) -> Response<ProxyHandlerBody> {
+        let (parts, body) = res.into_parts();
+        let vary = match self.is_storable(&parts) {
+            true => vary_headers(&parts.headers, request_headers),
+            false => None,
+        };
+        let body = match vary {
+            Some(vary) => match read_body(body, self.config.max_entry_size).await {
+                Ok(bytes) => {
+                    self.insert(key, &parts, vary, bytes.clone(), Instant::now());
+                    ProxyHandlerBody::Full(Full::new(bytes))
+                }
+                Err(body) => body,
+            },
+            None => body,
+        };
+        let mut res = Response::from_parts(parts, body);
+        set_cache_status(&mut res, "MISS");
+        res
+    }
+
+    // Only the complete and public responses are stored.
+    fn is_storable(&self, parts: &response::Parts) -> bool {
+        let headers = &parts.headers;
+        let private = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| {
+                let directive = directive.trim();
+                ["no-store", "no-cache", "private"]
+                    .iter()
+                    .any(|d| directive.eq_ignore_ascii_case(d))
+            });
+        let too_large = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length > self.config.max_entry_size);
+        parts.status == StatusCode::OK
+            && !headers.contains_key(SET_COOKIE)
+            && !private
+            && !too_large
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        parts: &response::Parts,
+        vary: Vec<(HeaderName, Option<HeaderValue>)>,
+        body: Bytes,
+        now: Instant,
+    ) {
+        // The memory used by the response, approximately.
+        let headers_size: usize = parts
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = (key.len() + headers_size + body.len()) as u64;
+        if size > self.config.max_entry_size {
+            return;
+        }
+
+        // Replaces the variant for the same values of the Vary headers only.
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let index = entries
+            .map
+            .get(&key)
+            .and_then(|variants| variants.iter().position(|e| e.vary == vary));
+        if let Some(index) = index {
+            entries.remove(&key, index);
+        }
+        while entries.size + size > self.config.max_size {
+            entries.evict_oldest();
+        }
+        entries.tick += 1;
+        let last_use = entries.tick;
+        entries.lru.insert(last_use, key.clone());
+        entries.size += size;
+        entries.map.entry(key).or_default().push(Entry {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body,
+            vary,
+            stored: now,
+            last_use,
+            size,
+        });
+    }
+}
+
+// X-Cache header of the responses of a location with a cache.
+pub fn set_cache_status<B>(res: &mut Response<B>, status: &'static str) {
+    res.headers_mut().insert(
+        HeaderName::from_static(CACHE_STATUS_HEADER),
+        HeaderValue::from_static(status),
+    );
+}
+
+// Values of the request headers the response depends on, None when it
+// depends on anything else (Vary: *).
+fn vary_headers(
+    response_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    let mut vary = Vec::new();
+    for value in response_headers.get_all(VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = request_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+    }
+    Some(vary)
+}
+
+// The complete body when it is at most limit bytes. Otherwise, a body with
+// the part already read followed by the rest.
+async fn read_body(mut body: ProxyHandlerBody, limit: u64) -> Result<Bytes, ProxyHandlerBody> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut size = 0;
+    loop {
+        let next = match body.frame().await {
+            None => break,
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(data) => {
+                    size += data.len() as u64;
+                    chunks.push(data);
+                    if size <= limit {
+                        continue;
+                    }
+                    None
+                }
+                // Trailers.
+                Err(frame) => Some(Ok(frame)),
+            },
+            Some(Err(err)) => {
+                let read = chunks.into_iter().map(|chunk| Ok(Frame::data(chunk)));
+                let stream = futures::stream::iter(read.chain(std::iter::once(Err(err))));
+                let stream: BoxedFrameStream = Box::pin(stream);
+                return Err(ProxyHandlerBody::StreamBody(StreamBody::new(stream)));
+            }
+        };
+        let read = chunks.into_iter().map(|chunk| Ok(Frame::data(chunk)));
+        let stream = futures::stream::iter(read.chain(next)).chain(BodyStream::new(body));
+        let stream: BoxedFrameStream = Box::pin(stream);
+        return Err(ProxyHandlerBody::StreamBody(StreamBody::new(stream)));
+    }
+    Ok(match chunks.len() {
+        1 => chunks.remove(0),
+        _ => Bytes::from(chunks.concat()),
+    })
+}
+
+#[cfg(test)]
+impl ResponseCache {
+    fn size(&self) -> (usize, u64) {
+        let entries = self.entries.lock().unwrap();
+        (entries.map.values().map(Vec::len).sum(), entries.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cache(max_size: u64) -> ResponseCache {
+        ResponseCache::new(CacheConfig {
+            ttl: 2,
+            max_size,
+            max_entry_size: 100,
+            methods: vec!["GET".to_string()],
+        })
+    }
+
+    fn response(headers: &[(&'static str, &'static str)]) -> response::Parts {
+        let mut res = Response::builder().status(StatusCode::OK);
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(()).unwrap().into_parts().0
+    }
+
+    fn insert(cache: &ResponseCache, key: &str, body: &str, now: Instant) {
+        let parts = response(&[("etag", "1")]);
+        let body = Bytes::copy_from_slice(body.as_bytes());
+        cache.insert(key.to_string(), &parts, Vec::new(), body, now);
+    }
+
+    #[test]
+    fn eviction_and_memory_accounting() {
+        let now = Instant::now();
+        // Key, "etag: 1" and body: 16 bytes each.
+        let cache = cache(50);
+        insert(&cache, "GET /a", "12345", now);
+        insert(&cache, "GET /b", "12345", now);
+        insert(&cache, "GET /c", "12345", now);
+        assert_eq!(cache.size(), (3, 48));
+
+        // The least recently used one is evicted.
+        let headers = HeaderMap::new();
+        assert!(cache.get("GET /a", &headers, now).is_some());
+        insert(&cache, "GET /d", "12345", now);
+        assert_eq!(cache.size(), (3, 48));
+        assert!(cache.get("GET /b", &headers, now).is_none());
+        assert!(cache.get("GET /a", &headers, now).is_some());
+
+        // Replaced, then two evicted for a larger one.
+        insert(&cache, "GET /a", "123", now);
+        assert_eq!(cache.size(), (3, 46));
+        insert(&cache, "GET /e", &"e".repeat(25), now);
+        assert_eq!(cache.size(), (2, 50));
+        assert!(cache.get("GET /c", &headers, now).is_none());
+        assert!(cache.get("GET /d", &headers, now).is_none());
+
+        // Larger than max_entry_size.
+        insert(&cache, "GET /f", &"f".repeat(100), now);
+        assert!(cache.get("GET /f", &headers, now).is_none());
+        assert_eq!(cache.size(), (2, 50));
+
+        // Expired.
+        let res = cache
+            .get("GET /a", &headers, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(res.headers()["x-cache"], "HIT");
+        assert_eq!(res.headers()["age"], "1");
+        assert_eq!(res.headers()["etag"], "1");
+        assert!(cache
+            .get("GET /a", &headers, now + Duration::from_secs(2))
+            .is_none());
+        assert_eq!(cache.size(), (1, 36));
+    }
+
+    #[tokio::test]
+    async fn bypass_and_vary() {
+        let cache = cache(1000);
+        let get = Request::get("/").body(()).unwrap();
+        assert_eq!(
+            cache.key(&get, "http://example.com/"),
+            Some("GET http://example.com/".to_string())
+        );
+        let post = Request::post("/").body(()).unwrap();
+        assert_eq!(cache.key(&post, "http://example.com/"), None);
+        let authorized = Request::get("/")
+            .header("authorization", "Bearer token")
+            .body(())
+            .unwrap();
+        assert_eq!(cache.key(&authorized, "http://example.com/"), None);
+
+        assert!(cache.is_storable(&response(&[])));
+        assert!(!cache.is_storable(&response(&[("set-cookie", "id=1")])));
+        assert!(!cache.is_storable(&response(&[("cache-control", "max-age=0, Private")])));
+        assert!(!cache.is_storable(&response(&[("content-length", "101")])));
+        let mut not_found = response(&[]);
+        not_found.status = StatusCode::NOT_FOUND;
+        assert!(!cache.is_storable(&not_found));
+
+        // A variant per url, for the request headers listed by Vary.
+        let mut gzip = HeaderMap::new();
+        gzip.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        let res = Response::builder()
+            .header("vary", "Accept-Encoding")
+            .body(ProxyHandlerBody::Full(Full::new(Bytes::from("gzipped"))))
+            .unwrap();
+        let res = cache.store("GET /".to_string(), &gzip, res).await;
+        assert_eq!(res.headers()["x-cache"], "MISS");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "gzipped");
+        let now = Instant::now();
+        assert!(cache.get("GET /", &gzip, now).is_some());
+        assert!(cache.get("GET /", &HeaderMap::new(), now).is_none());
+
+        // Another variant of the same url doesn't replace the first one.
+        let res = Response::builder()
+            .header("vary", "Accept-Encoding")
+            .body(ProxyHandlerBody::Full(Full::new(Bytes::from("plain"))))
+            .unwrap();
+        cache
+            .store("GET /".to_string(), &HeaderMap::new(), res)
+            .await;
+        let plain = cache.get("GET /", &HeaderMap::new(), now).unwrap();
+        let body = plain.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "plain");
+        let gzipped = cache.get("GET /", &gzip, now).unwrap();
+        let body = gzipped.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "gzipped");
+        assert_eq!(cache.size().0, 2);
+
+        let res = Response::builder()
+            .header("vary", "*")
+            .body(ProxyHandlerBody::Empty)
+            .unwrap();
+        cache.store("GET /star".to_string(), &gzip, res).await;
+        assert!(cache.get("GET /star", &gzip, now).is_none());
+    }
+
+    #[tokio::test]
+    async fn read_larger_body() {
+        let chunks = ["0123456789", "abcdefghij", "klmno"];
+        let stream = futures::stream::iter(
+            chunks.map(|chunk| Ok::<_, std::io::Error>(Frame::data(Bytes::from(chunk)))),
+        );
+        let stream: BoxedFrameStream = Box::pin(stream);
+        let body = ProxyHandlerBody::StreamBody(StreamBody::new(stream));
+        let Err(body) = read_body(body, 12).await else {
+            panic!("the body is larger than the limit");
+        };
+        // Nothing is lost.
+        let body = body.collect().await.unwrap().to_bytes();
+        assert_eq!(body, "0123456789abcdefghijklmno");
+
+        let body = ProxyHandlerBody::Full(Full::new(Bytes::from("small")));
+        assert!(matches!(read_body(body, 12).await, Ok(bytes) if bytes == "small"));
+    }
+}
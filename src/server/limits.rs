@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::{Server, TargetType};
+
+// Requests in progress of the locations and file servers with a
+// max_concurrent, indexed by their ids.
+#[derive(Default)]
+pub struct TargetLimits(HashMap<u32, TargetLimit>);
+
+struct TargetLimit {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    rejected: AtomicU64, // Since the last reload.
+    key: TargetKey,
+}
+
+// The ids change on each reload, a target takes over the semaphore of a
+// previous one serving the same backends or directory in the same server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TargetKey {
+    Location(String, Vec<String>),
+    FileServer(String, String),
+}
+
+pub struct LimitReached;
+
+#[derive(Debug, PartialEq)]
+pub struct LimitSnapshot {
+    pub max: usize,
+    pub active: usize,
+    pub rejected: u64,
+}
+
+impl TargetLimits {
+    pub fn new(servers: &HashMap<String, Server>) -> Arc<TargetLimits> {
+        TargetLimits::build(servers, None)
+    }
+
+    // On a reload, the targets with an unchanged max_concurrent keep the
+    // permits of the requests in progress, the limit holds across it.
+    pub fn reload(servers: &HashMap<String, Server>, previous: &TargetLimits) -> Arc<TargetLimits> {
+        TargetLimits::build(servers, Some(previous))
+    }
+
+    fn build(
+        servers: &HashMap<String, Server>,
+        previous: Option<&TargetLimits>,
+    ) -> Arc<TargetLimits> {
+        // Each previous semaphore is taken over at most once.
+        let mut previous_limits: HashMap<&TargetKey, Vec<&TargetLimit>> = HashMap::new();
+        for limit in previous.iter().flat_map(|p| p.0.values()) {
+            previous_limits.entry(&limit.key).or_default().push(limit);
+        }
+
+        let mut limits = HashMap::new();
+        let targets = servers.iter().flat_map(|(name, server)| {
+            server
+                .params
+                .routes
+                .values()
+                .flatten()
+                .filter_map(move |route| match &route.target {
+                    TargetType::Location(location) => Some((
+                        location.id,
+                        location.max_concurrent?,
+                        TargetKey::Location(name.clone(), location.params.location.clone()),
+                    )),
+                    TargetType::FileServer(file_server) => Some((
+                        file_server.id,
+                        file_server.max_concurrent?,
+                        TargetKey::FileServer(name.clone(), file_server.params.location.clone()),
+                    )),
+                    TargetType::Redirection(_) => None,
+                })
+        });
+        for (id, max, key) in targets {
+            // A target routed from several domains or dirs is listed again.
+            if limits.contains_key(&id) {
+                continue;
+            }
+            let semaphore = previous_limits
+                .get_mut(&key)
+                .and_then(|candidates| {
+                    let index = candidates.iter().position(|limit| limit.max == max)?;
+                    Some(candidates.swap_remove(index))
+                })
+                .map_or_else(
+                    || Arc::new(Semaphore::new(max)),
+                    |previous| Arc::clone(&previous.semaphore),
+                );
+            let limit = TargetLimit {
+                semaphore,
+                max,
+                rejected: AtomicU64::new(0),
+                key,
+            };
+            limits.insert(id, limit);
+        }
+        Arc::new(TargetLimits(limits))
+    }
+
+    // A permit to keep while the request is served, None for a target
+    // without limit.
+    pub fn acquire(&self, id: u32) -> Result<Option<SemaphorePermit<'_>>, LimitReached> {
+        let Some(limit) = self.0.get(&id) else {
+            return Ok(None);
+        };
+        match limit.semaphore.try_acquire() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => {
+                limit.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(LimitReached)
+            }
+        }
+    }
+
+    pub fn snapshot(&self, id: u32) -> Option<LimitSnapshot> {
+        self.0.get(&id).map(|limit| LimitSnapshot {
+            max: limit.max,
+            active: limit.max - limit.semaphore.available_permits(),
+            rejected: limit.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::build_config;
+
+    #[test]
+    fn target_limits() {
+        let config = build_config(
+            r#"
+            [services.app]
+            domain = "example.com"
+            locations = [
+              { source = "/api/*", target = "http://127.0.0.1:3000", max_concurrent = 2 },
+              { source = "/*", target = "http://127.0.0.1:3000" },
+            ]
+            file_servers = [
+              { source = "/static/*", target = "/var/www/", authorized_dirs = ["/img"], max_concurrent = 1 },
+            ]
+            "#,
+        )
+        .unwrap();
+        let limits = TargetLimits::new(&config.servers);
+        let id = |path: &str| match &config.servers["main"].params.routes["example.com"]
+            .iter()
+            .find(|r| r.path == path)
+            .unwrap()
+            .target
+        {
+            TargetType::Location(location) => location.id,
+            TargetType::FileServer(file_server) => file_server.id,
+            TargetType::Redirection(_) => unreachable!(),
+        };
+
+        // Unlimited.
+        assert!(matches!(limits.acquire(id("")), Ok(None)));
+        assert_eq!(limits.snapshot(id("")), None);
+
+        let api = id("/api");
+        let first = limits.acquire(api).ok().flatten().unwrap();
+        let _second = limits.acquire(api).ok().flatten().unwrap();
+        assert!(limits.acquire(api).is_err());
+        drop(first);
+        assert!(limits.acquire(api).is_ok_and(|permit| permit.is_some()));
+        assert_eq!(
+            limits.snapshot(api),
+            Some(LimitSnapshot {
+                max: 2,
+                active: 1,
+                rejected: 1
+            })
+        );
+
+        // The authorized dirs share the limit of their file server.
+        assert_eq!(id("/static"), id("/static/img"));
+        let _permit = limits.acquire(id("/static")).ok().flatten().unwrap();
+        assert!(limits.acquire(id("/static/img")).is_err());
+    }
+
+    #[test]
+    fn target_limits_reload() {
+        let config = |max| {
+            build_config(format!(
+                r#"
+                [services.app]
+                domain = "example.com"
+                locations = [
+                  {{ source = "/api/*", target = "http://127.0.0.1:3000", max_concurrent = {max} }},
+                  {{ source = "/*", target = "http://127.0.0.1:3001", max_concurrent = 1 }},
+                ]
+                "#
+            ))
+            .unwrap()
+        };
+        let id = |config: &crate::config::InternalConfig, path: &str| match &config.servers["main"]
+            .params
+            .routes["example.com"]
+            .iter()
+            .find(|r| r.path == path)
+            .unwrap()
+            .target
+        {
+            TargetType::Location(location) => location.id,
+            _ => unreachable!(),
+        };
+
+        let first = config(2);
+        let limits = TargetLimits::new(&first.servers);
+        let _api = limits.acquire(id(&first, "/api")).ok().flatten().unwrap();
+        let _root = limits.acquire(id(&first, "")).ok().flatten().unwrap();
+
+        // The ids change, the requests in progress still count.
+        let second = config(2);
+        assert_ne!(id(&first, "/api"), id(&second, "/api"));
+        let reloaded = TargetLimits::reload(&second.servers, &limits);
+        let api = id(&second, "/api");
+        assert_eq!(reloaded.snapshot(api).unwrap().active, 1);
+        let _permit = reloaded.acquire(api).ok().flatten().unwrap();
+        assert!(reloaded.acquire(api).is_err());
+        assert!(reloaded.acquire(id(&second, "")).is_err());
+
+        // A new max_concurrent starts with a new semaphore.
+        let third = config(3);
+        let reloaded = TargetLimits::reload(&third.servers, &reloaded);
+        assert_eq!(reloaded.snapshot(id(&third, "/api")).unwrap().active, 0);
+        assert_eq!(reloaded.snapshot(id(&third, "")).unwrap().active, 1);
+    }
+}
@@ -1,29 +1,69 @@
-use std::path::{Component, Path, PathBuf};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use futures::TryStreamExt;
-use http_body_util::{Full, StreamBody};
-use hyper::{body::Frame, Response, StatusCode};
+use futures::future::ready;
+use futures::{stream, StreamExt, TryStreamExt};
+use http_body_util::StreamBody;
+use hyper::{
+    body::{Bytes, Frame},
+    Response, StatusCode,
+};
 use time::{
-    format_description::{self},
+    format_description::{self, BorrowedFormatItem},
     OffsetDateTime,
 };
 use tokio_util::io::ReaderStream;
 
-use crate::{http_response, utils};
+use crate::{config::GlobSet, http_response, utils};
 
 use super::server_utils::{BoxedFrameStream, ProxyHandlerBody};
 
+const INDEX_FILE: &str = "index.html";
+// Number of directory entries sent in each frame of a listing.
+const LISTING_ROWS_PER_FRAME: usize = 64;
+// Longest file name of the common Linux file systems, in bytes.
+const NAME_MAX: usize = 255;
+// Seconds between two warnings about the rejected paths, sent in bursts by
+// the scanners.
+const INVALID_PATH_WARN_INTERVAL: u64 = 10;
+
+// Second of the last warning (get_current_time + 1, 0 before the first), and
+// the paths rejected since.
+static INVALID_PATH_WARNED: AtomicU64 = AtomicU64::new(0);
+static INVALID_PATHS_SKIPPED: AtomicU64 = AtomicU64::new(0);
+
 pub async fn serve_file(
     location: &str,
     new_path: &str,
     source_url: &str,
     fallback_file: &Option<String>,
-    forbidden_dir: bool,
+    listing: Option<&GlobSet>, // Globs of the hidden entries, None if forbidden.
     has_custom_404: bool,
+    canonical_index_redirect: bool,
 ) -> Response<ProxyHandlerBody> {
     let new_path = utils::get_base_path(new_path); // clean file path.
+
+    // Raw for the logs, decoded for the disk: "/my%20file" is "my file".
     let path = format!("{}{}", utils::remove_last_slash(location), new_path);
-    let mut file_path = sanitize_path(&path);
+    // The raw bytes which aren't UTF-8 are refused by hyper, the escaped
+    // ones by the decoding.
+    let checked = utils::percent_decode_path(new_path).and_then(|decoded_path| {
+        let file_path = sanitize_path(&format!(
+            "{}{}",
+            utils::remove_last_slash(location),
+            decoded_path
+        ))?;
+        Ok((decoded_path, file_path))
+    });
+    let (decoded_path, mut file_path) = match checked {
+        Ok(checked) => checked,
+        Err(reason) => {
+            warn_invalid_path(&path, reason);
+            return http_response::bad_request();
+        }
+    };
 
     // Serve Single Page Application
     let spa_mode = fallback_file.is_some() && !has_custom_404;
@@ -46,9 +86,24 @@ pub async fn serve_file(
 
     tracing::info!("Serve static file : {}", path);
 
+    // Redirect a direct request for the index file to its directory
+    // so the same page isn't reachable from two different URLs.
+    if canonical_index_redirect
+        && file_path.file_name() == Some(INDEX_FILE.as_ref())
+        && file_path.is_file()
+    {
+        if let Some(location) = canonical_index_location(source_url) {
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header("Location", location)
+                .body(ProxyHandlerBody::Empty)
+                .unwrap();
+        }
+    }
+
     if file_path.is_dir() {
         // Try to open index.html.
-        file_path.push("index.html");
+        file_path.push(INDEX_FILE);
         return match open_file(&file_path, StatusCode::OK).await {
             Ok(resp) => resp,
             // Default forbidden response if the path is a dir.
@@ -63,8 +118,20 @@ pub async fn serve_file(
                         .unwrap();
                 }
 
-                if !forbidden_dir {
-                    return display_directory_content(&mut file_path, new_path).await;
+                if let Some(hide) = listing {
+                    return match display_directory_content(&mut file_path, &decoded_path, hide)
+                        .await
+                    {
+                        Ok(resp) => resp,
+                        Err(err) => {
+                            tracing::error!("Can't list directory {}: {}", path, err);
+                            if err.kind() == std::io::ErrorKind::NotFound {
+                                http_response::not_found()
+                            } else {
+                                http_response::forbidden()
+                            }
+                        }
+                    };
                 }
 
                 http_response::forbidden()
@@ -93,18 +160,23 @@ pub async fn serve_file(
     }
 }
 
+// Stream the directory listing so memory stays bounded
+// whatever the number of entries in the directory. The entries whose
+// name matches a glob of hide are left out.
 async fn display_directory_content(
     file_path: &mut PathBuf,
     current_path: &str,
-) -> Response<ProxyHandlerBody> {
+    hide: &GlobSet,
+) -> Result<Response<ProxyHandlerBody>, std::io::Error> {
     file_path.pop(); // Remove index.html
-    let mut dir = tokio::fs::read_dir(file_path).await.unwrap();
-    let title = if current_path.is_empty() {
+    let dir = tokio::fs::read_dir(&file_path).await?;
+    let hide = hide.clone();
+    let title = utils::escape_html(if current_path.is_empty() {
         "/"
     } else {
         current_path
-    };
-    let mut html = vec![format!(
+    });
+    let mut header = format!(
         "<html><head><meta charset=\"UTF-8\">\
         <title>Index of {title}</title>\
         <style>table {{border-collapse: collapse;}}\
@@ -115,49 +187,113 @@ async fn display_directory_content(
         <h1>Index of {title}</h1><hr/>\
         <table style=\"width:100%; text-align: left; table-layout: fixed;\">\
         <tr><th>Name</th><th>Last modified</th><th>Size</th></tr>",
-    )];
+    );
 
     if !current_path.is_empty() {
-        html.push("<tr><td>↩ <a href=\"..\">..</a></td><td>-</td><td>-</td></tr>".to_string());
-    }
-
-    while let Some(entry) = dir.next_entry().await.unwrap() {
-        let path = entry.path();
-        let metadata = tokio::fs::metadata(&path).await.unwrap();
-        let file_name = path.file_name().unwrap().to_str().unwrap();
-        // get and format last modified.
-        let modified = metadata.modified().unwrap();
-        let datetime = OffsetDateTime::from(modified);
-        let format =
-            format_description::parse("[day]-[month repr:short]-[year] [hour]:[minute]:[second]")
-                .unwrap();
-        let last_modif = datetime.format(&format).unwrap();
-        // get and format file size.
-        let size: String;
-        let icon: &str;
-        if metadata.is_dir() {
-            size = String::from("-");
-            icon = "📁";
+        header.push_str("\n<tr><td>↩ <a href=\"..\">..</a></td><td>-</td><td>-</td></tr>");
+    }
+
+    let format =
+        format_description::parse("[day]-[month repr:short]-[year] [hour]:[minute]:[second]")
+            .map_err(std::io::Error::other)?;
+
+    let version = utils::get_project_version();
+    let footer = format!("\n</table><p>{version}</p></body></html>");
+
+    let rows = stream::unfold(Some((dir, format, hide)), |state| async move {
+        let (mut dir, format, hide) = state?;
+        let mut chunk = String::new();
+        let mut count = 0;
+        let mut finished = false;
+        while count < LISTING_ROWS_PER_FRAME {
+            let entry = match dir.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    finished = true;
+                    break;
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to read directory entry: {}", err);
+                    finished = true;
+                    break;
+                }
+            };
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if hide.matches(&name) {
+                continue;
+            }
+            if let Some(row) = directory_row(&entry, &format).await {
+                chunk.push('\n');
+                chunk.push_str(&row);
+                count += 1;
+            }
+        }
+        if chunk.is_empty() {
+            return None;
+        }
+        let next_state = if finished {
+            None
         } else {
-            size = utils::format_size(metadata.len());
-            icon = "📄";
+            Some((dir, format, hide))
         };
+        Some((Ok(Frame::data(Bytes::from(chunk))), next_state))
+    });
 
-        html.push(format!(
-            "<tr>\
-            <td>{icon} <a href=\"{file_name}\">{file_name}</a></td>\
-            <td>{last_modif}</td>\
-            <td>{size}</td>\
-            </tr>",
-        ));
-    }
-    let version = utils::get_project_version();
-    html.push(format!("</table><p>{version}</p></body></html>"));
-    let html = html.join("\n");
-    Response::builder()
+    let body_stream = stream::once(ready(Ok(Frame::data(Bytes::from(header)))))
+        .chain(rows)
+        .chain(stream::once(ready(Ok(Frame::data(Bytes::from(footer))))));
+    let boxed_stream: BoxedFrameStream = Box::pin(body_stream);
+
+    Ok(Response::builder()
         .status(StatusCode::OK)
-        .body(ProxyHandlerBody::Full(Full::from(html)))
-        .unwrap()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(ProxyHandlerBody::StreamBody(StreamBody::new(boxed_stream)))
+        .unwrap())
+}
+
+// Build the html table row of a directory entry.
+async fn directory_row(
+    entry: &tokio::fs::DirEntry,
+    format: &[BorrowedFormatItem<'_>],
+) -> Option<String> {
+    let path = entry.path();
+    // Skip entries we can't stat (broken symlinks, permission errors...).
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            tracing::debug!("Skip directory entry {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    let file_name = entry.file_name();
+    let file_name = file_name.to_string_lossy();
+    let href = utils::escape_html(&utils::percent_encode_name(&file_name));
+    let file_name = utils::escape_html(&file_name);
+    // get and format last modified.
+    let last_modif = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| OffsetDateTime::from(modified).format(format).ok())
+        .unwrap_or_else(|| String::from("-"));
+    // get and format file size.
+    let size: String;
+    let icon: &str;
+    if metadata.is_dir() {
+        size = String::from("-");
+        icon = "📁";
+    } else {
+        size = utils::format_size(metadata.len());
+        icon = "📄";
+    };
+
+    Some(format!(
+        "<tr>\
+        <td>{icon} <a href=\"{href}\">{file_name}</a></td>\
+        <td>{last_modif}</td>\
+        <td>{size}</td>\
+        </tr>",
+    ))
 }
 
 // Open a file and stream its content in a http response.
@@ -190,18 +326,347 @@ async fn open_file(
     }
 }
 
-fn sanitize_path(path: &str) -> PathBuf {
+// Remove the index file name from the url, keeping the query string.
+fn canonical_index_location(source_url: &str) -> Option<String> {
+    let (url, query) = match source_url.find('?') {
+        Some(index) => source_url.split_at(index),
+        None => (source_url, ""),
+    };
+    let dir = url.strip_suffix(INDEX_FILE)?;
+    if !dir.ends_with('/') {
+        return None;
+    }
+    Some(format!("{dir}{query}"))
+}
+
+// Drop the "." and ".." components, so that the path stays under its root.
+// The paths which can't be a file of the root are rejected: NUL bytes, too
+// long names, and the Windows separators and drives ("C:\x", "C:").
+fn sanitize_path(path: &str) -> Result<PathBuf, &'static str> {
+    if path.contains('\0') {
+        return Err("NUL byte");
+    }
+    if path.contains('\\') {
+        return Err("backslash");
+    }
     let mut clean_path = PathBuf::new();
 
     for component in Path::new(path).components() {
         match component {
-            Component::Normal(part) => clean_path.push(part),
+            Component::Normal(part) => {
+                let bytes = part.as_encoded_bytes();
+                if bytes.len() > NAME_MAX {
+                    return Err("name too long");
+                }
+                if bytes.len() == 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+                    return Err("Windows drive");
+                }
+                clean_path.push(part)
+            }
             Component::ParentDir => continue,
             Component::CurDir => continue,
             Component::RootDir => clean_path.push("/"),
-            Component::Prefix(_) => continue,
+            Component::Prefix(_) => return Err("Windows prefix"),
         }
     }
 
-    clean_path
+    Ok(clean_path)
+}
+
+// At most one warning every INVALID_PATH_WARN_INTERVAL seconds, the others
+// are counted in the next one.
+fn warn_invalid_path(path: &str, reason: &str) {
+    let now = utils::get_current_time() + 1;
+    let last = INVALID_PATH_WARNED.load(Ordering::Relaxed);
+    let due = last == 0 || now >= last + INVALID_PATH_WARN_INTERVAL;
+    if !due
+        || INVALID_PATH_WARNED
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        INVALID_PATHS_SKIPPED.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("Invalid file path {path:?}: {reason}, 400 sent");
+        return;
+    }
+    match INVALID_PATHS_SKIPPED.swap(0, Ordering::Relaxed) {
+        0 => tracing::warn!("Invalid file path {path:?}: {reason}, 400 sent"),
+        skipped => tracing::warn!(
+            "Invalid file path {path:?}: {reason}, 400 sent ({skipped} more since the last warning)"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    async fn listing_body(dir: &Path, hide: &[String]) -> String {
+        let mut file_path = dir.join(INDEX_FILE);
+        let hide = GlobSet::new(hide).unwrap();
+        let res = display_directory_content(&mut file_path, "/dir/", &hide)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn directory_listing_skips_broken_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.txt"), "content").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("missing"), dir.path().join("broken")).unwrap();
+
+        let body = listing_body(dir.path(), &[]).await;
+        assert!(body.contains("file.txt"));
+        assert!(!body.contains("broken"));
+    }
+
+    #[tokio::test]
+    async fn directory_listing_escapes_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let name = OsStr::from_bytes(b"<bad\xff>.txt");
+        std::fs::write(dir.path().join(name), "content").unwrap();
+
+        let body = listing_body(dir.path(), &[]).await;
+        assert!(body.contains("&lt;bad\u{FFFD}&gt;.txt"));
+        assert!(!body.contains("<bad"));
+    }
+
+    #[tokio::test]
+    async fn directory_listing_hides_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["report.pdf", "upload.tmp", "Upload.TMP"] {
+            std::fs::write(dir.path().join(name), "content").unwrap();
+        }
+        std::fs::create_dir(dir.path().join(".snapshot")).unwrap();
+        std::fs::create_dir(dir.path().join("lost+found")).unwrap();
+        let hide = ["*.tmp", ".snapshot", "lost+found"].map(String::from);
+
+        let body = listing_body(dir.path(), &hide).await;
+        assert!(body.contains("report.pdf"));
+        // Case-sensitive.
+        assert!(body.contains("Upload.TMP"));
+        assert!(!body.contains("upload.tmp"));
+        assert!(!body.contains(".snapshot"));
+        assert!(!body.contains("lost+found"));
+
+        // Still served when requested.
+        let res = serve_file(
+            &dir.path().to_string_lossy(),
+            "/upload.tmp",
+            "http://example.com/upload.tmp",
+            &None,
+            Some(&GlobSet::new(&hide).unwrap()),
+            false,
+            false,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn percent_encoded_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("my file.pdf"), "pdf").unwrap();
+        std::fs::write(root.join("caf\u{e9}.txt"), "txt").unwrap();
+        std::fs::write(root.join("100%.txt"), "txt").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let status = |path: &'static str| {
+            let root = root.to_string_lossy().to_string();
+            async move {
+                serve_file(
+                    &root,
+                    path,
+                    &format!("http://example.com{path}"),
+                    &None,
+                    Some(&GlobSet::default()),
+                    false,
+                    false,
+                )
+                .await
+                .status()
+            }
+        };
+
+        for path in [
+            "/my%20file.pdf",
+            "/caf%C3%A9.txt",
+            "/caf%c3%a9.txt",
+            "/100%25.txt",
+        ] {
+            assert_eq!(status(path).await, StatusCode::OK, "{path}");
+        }
+        // Decoded once, a literal "%20" isn't a space.
+        assert_eq!(status("/my%2520file.pdf").await, StatusCode::NOT_FOUND);
+        // The decoded ".." are dropped like the raw ones.
+        assert_eq!(status("/%2e%2e/secret.txt").await, StatusCode::NOT_FOUND);
+        for path in [
+            "/%2e%2e%2fsecret.txt",
+            "/..%2Fsecret.txt",
+            "/my%00file.pdf",
+            "/100%.txt",
+            "/my%2",
+            "/%C0%AE%C0%AE/secret.txt",
+            "/%FF.txt",
+        ] {
+            assert_eq!(status(path).await, StatusCode::BAD_REQUEST, "{path}");
+        }
+
+        // The links of a listing are encoded.
+        let body = listing_body(&root, &[]).await;
+        assert!(body.contains("href=\"my%20file.pdf\""), "{body}");
+        assert!(body.contains("href=\"100%25.txt\""), "{body}");
+        assert!(body.contains(">my file.pdf<"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn hostile_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/file.txt"), "file").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "SECRET").unwrap();
+        let root = root.to_string_lossy().to_string();
+        let serve = |path: String| {
+            let root = root.clone();
+            async move {
+                let res = serve_file(
+                    &root,
+                    &path,
+                    &format!("http://example.com{path}"),
+                    &None,
+                    Some(&GlobSet::default()),
+                    false,
+                    false,
+                )
+                .await;
+                let status = res.status();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                (status, String::from_utf8_lossy(&body).to_string())
+            }
+        };
+
+        let long = "x".repeat(NAME_MAX + 1);
+        let rejected = [
+            "/sub/file.txt%00".to_string(),
+            "/sub\\file.txt".to_string(),
+            "/..\\secret.txt".to_string(),
+            "/C:/secret.txt".to_string(),
+            "/sub/c:".to_string(),
+            "/%5C..%5Csecret.txt".to_string(),
+            format!("/{long}"),
+            format!("/sub/%{}", "78".repeat(NAME_MAX + 1)),
+            "/%C0%AE%C0%AE/secret.txt".to_string(),
+            "/%ED%A0%80".to_string(), // UTF-16 surrogate.
+        ];
+        for path in rejected {
+            assert_eq!(
+                serve(path.clone()).await.0,
+                StatusCode::BAD_REQUEST,
+                "{path}"
+            );
+        }
+        assert_eq!(
+            serve(format!("/sub/{}", "x".repeat(NAME_MAX))).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            serve("/../../sub/./file.txt".to_string()).await,
+            (StatusCode::OK, "file".to_string())
+        );
+
+        // Random paths from the characters of the traversals: never a panic
+        // or a file outside of the root.
+        let alphabet: Vec<char> = "./\\%2eEfF5c0C:aAx\u{e9}".chars().collect();
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..2000 {
+            let mut path = String::from("/");
+            for _ in 0..(seed % 24) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                path.push(alphabet[(seed % alphabet.len() as u64) as usize]);
+            }
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let (status, body) = serve(path.clone()).await;
+            assert!(!body.contains("SECRET"), "{path}");
+            assert!(
+                [
+                    StatusCode::OK,
+                    StatusCode::PERMANENT_REDIRECT,
+                    StatusCode::BAD_REQUEST,
+                    StatusCode::NOT_FOUND
+                ]
+                .contains(&status),
+                "{path}: {status}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_listing_huge_dir_is_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = 10_000;
+        for i in 0..entries {
+            std::fs::File::create(dir.path().join(format!("file-{i}"))).unwrap();
+        }
+
+        let mut file_path = dir.path().join(INDEX_FILE);
+        let res = display_directory_content(&mut file_path, "/dir/", &GlobSet::default())
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        let mut rows = 0;
+        let mut max_frame = 0;
+        while let Some(frame) = body.frame().await {
+            let data = frame.unwrap().into_data().unwrap();
+            max_frame = max_frame.max(data.len());
+            rows += String::from_utf8_lossy(&data).matches("file-").count();
+        }
+        // Each file name appears twice per row (href and text).
+        assert_eq!(rows, entries * 2);
+        assert!(max_frame < 64 * 1024, "Frame too large: {max_frame}");
+    }
+
+    #[tokio::test]
+    async fn directory_listing_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_path = dir.path().join("missing").join(INDEX_FILE);
+        let res = display_directory_content(&mut file_path, "/missing/", &GlobSet::default()).await;
+        assert!(matches!(res, Err(err) if err.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn canonical_index_location_keeps_query() {
+        assert_eq!(
+            canonical_index_location("http://example.com/docs/index.html?a=1&b=2"),
+            Some("http://example.com/docs/?a=1&b=2".to_string())
+        );
+        assert_eq!(
+            canonical_index_location("http://example.com/index.html"),
+            Some("http://example.com/".to_string())
+        );
+    }
+
+    #[test]
+    fn canonical_index_location_ignores_other_files() {
+        assert_eq!(
+            canonical_index_location("http://example.com/docs/myindex.html"),
+            None
+        );
+        assert_eq!(
+            canonical_index_location("http://example.com/docs/page.html?f=index.html"),
+            None
+        );
+    }
 }
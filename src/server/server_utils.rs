@@ -1,35 +1,115 @@
 use std::{
+    collections::HashMap,
     convert::Infallible,
-    net::SocketAddr,
+    future::Future,
     pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use http_body_util::{Full, StreamBody};
 use hyper::{
-    body::{Bytes, Frame, Incoming},
+    body::{Bytes, Frame, Incoming, SizeHint},
     header::{HeaderName, HeaderValue},
     service::service_fn,
     HeaderMap, Request, Response,
 };
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
     rt::{TokioExecutor, TokioIo},
     server::conn::auto::Builder,
 };
 use nix::unistd::getuid;
 use rustls::client::danger::ServerCertVerifier;
 use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_util::sync::CancellationToken;
 
-use crate::config::ConfigHeadersActions;
+use super::{reject_connection, OverMaxConnections, StreamAcceptor};
+use crate::config::{ConfigHeadersActions, Server, ServerParams, TargetType, UpstreamHttp2};
+use crate::middleware::{ObserveFrames, ObservedBody};
+use crate::utils::format_ip;
 
 pub type BoxedFrameStream =
     Pin<Box<dyn futures::Stream<Item = Result<Frame<Bytes>, std::io::Error>> + Send + 'static>>;
 
+pub type ProxyClient = Client<HttpsConnector<HttpConnector>, ProxyHandlerBody>;
+
+// Clients of the backends, one for each upstream_http2 mode and connect
+// timeout of the locations. They share the TLS settings, each one has its
+// own pool, kept across the reloads.
+pub struct ProxyClients {
+    tls_config: rustls::ClientConfig,
+    clients: Mutex<HashMap<(UpstreamHttp2, u64), ProxyClient>>,
+}
+
+impl ProxyClients {
+    pub fn new(tls_config: rustls::ClientConfig) -> ProxyClients {
+        ProxyClients {
+            tls_config,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Client of each location of a server, indexed by its id. Built with
+    // the config of the handler, a request only looks its client up.
+    pub fn targets(&self, params: &ServerParams) -> HashMap<u32, ProxyClient> {
+        params
+            .routes
+            .values()
+            .flatten()
+            .filter_map(|route| match &route.target {
+                TargetType::Location(location) => {
+                    let connect_timeout =
+                        location.connect_timeout.unwrap_or(params.connect_timeout);
+                    Some((
+                        location.id,
+                        self.get(location.upstream_http2, connect_timeout),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // A client shares its pool with its clones.
+    fn get(&self, mode: UpstreamHttp2, connect_timeout: u64) -> ProxyClient {
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .entry((mode, connect_timeout))
+            .or_insert_with(|| self.build(mode, connect_timeout))
+            .clone()
+    }
+
+    fn build(&self, mode: UpstreamHttp2, connect_timeout: u64) -> ProxyClient {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        // Only the TCP connection, the TLS handshake with an https backend
+        // is bounded by the proxy timeout.
+        http.set_connect_timeout(Some(Duration::from_secs(connect_timeout)));
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(self.tls_config.clone())
+            .https_or_http();
+        let mut client = Client::builder(TokioExecutor::new());
+        let connector = match mode {
+            UpstreamHttp2::Never => connector.enable_http1().wrap_connector(http),
+            // The version of a connection is chosen by the backend.
+            UpstreamHttp2::Auto => connector.enable_all_versions().wrap_connector(http),
+            UpstreamHttp2::Always => {
+                client.http2_only(true);
+                connector.enable_http2().wrap_connector(http)
+            }
+        };
+        client.build(connector)
+    }
+}
+
 pub enum ProxyHandlerBody {
     Incoming(Incoming),
+    Upstream(UpstreamBody),
     Full(Full<Bytes>),
     StreamBody(StreamBody<BoxedFrameStream>),
     Empty,
@@ -44,15 +124,8 @@ impl hyper::body::Body for ProxyHandlerBody {
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         match &mut *self.get_mut() {
-            Self::Incoming(incoming) => match Pin::new(incoming).poll_frame(cx) {
-                Poll::Ready(Some(Ok(frame))) => Poll::Ready(Some(Ok(frame))),
-                Poll::Ready(Some(Err(err))) => {
-                    eprintln!("Error: {err}");
-                    Poll::Ready(Some(Err(std::io::Error::other(err))))
-                }
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            },
+            Self::Incoming(incoming) => poll_incoming(incoming, cx),
+            Self::Upstream(upstream) => Pin::new(upstream).poll_frame(cx),
             Self::Full(full) => match Pin::new(full).poll_frame(cx) {
                 Poll::Ready(Some(Ok(frame))) => Poll::Ready(Some(Ok(frame))),
                 Poll::Ready(Some(Err(_err))) => {
@@ -65,6 +138,109 @@ impl hyper::body::Body for ProxyHandlerBody {
             Self::Empty => Poll::Ready(None),
         }
     }
+
+    // The length of a request sent to a backend depends on them.
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Incoming(incoming) => incoming.is_end_stream(),
+            Self::Upstream(upstream) => upstream.is_end_stream(),
+            Self::Full(full) => full.is_end_stream(),
+            Self::StreamBody(stream_body) => stream_body.is_end_stream(),
+            Self::Empty => true,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Incoming(incoming) => incoming.size_hint(),
+            Self::Upstream(upstream) => upstream.size_hint(),
+            Self::Full(full) => full.size_hint(),
+            Self::StreamBody(stream_body) => stream_body.size_hint(),
+            Self::Empty => SizeHint::with_exact(0),
+        }
+    }
+}
+
+fn poll_incoming(
+    incoming: &mut Incoming,
+    cx: &mut Context<'_>,
+) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+    match hyper::body::Body::poll_frame(Pin::new(incoming), cx) {
+        Poll::Ready(Some(Ok(frame))) => Poll::Ready(Some(Ok(frame))),
+        Poll::Ready(Some(Err(err))) => {
+            eprintln!("Error: {err}");
+            Poll::Ready(Some(Err(std::io::Error::other(err))))
+        }
+        Poll::Ready(None) => Poll::Ready(None),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+// Body of a backend response, aborted when no frame arrives for the idle
+// timeout: the proxy timeout ends with the response headers.
+pub type UpstreamBody = ObservedBody<IncomingIo, IdleTimeout>;
+
+// Incoming body failing with the io errors of the other bodies.
+pub struct IncomingIo(pub Incoming);
+
+impl hyper::body::Body for IncomingIo {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+        poll_incoming(&mut self.0, cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
+
+pub struct IdleTimeout {
+    idle_timeout: Duration,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+    dest_url: String, // For the logs.
+}
+
+impl IdleTimeout {
+    pub fn new(idle_timeout: Duration, dest_url: String) -> IdleTimeout {
+        IdleTimeout {
+            idle_timeout,
+            deadline: Box::pin(tokio::time::sleep(idle_timeout)),
+            dest_url,
+        }
+    }
+}
+
+impl ObserveFrames for IdleTimeout {
+    fn frame(&mut self, _data: Option<usize>) {
+        let deadline = tokio::time::Instant::now() + self.idle_timeout;
+        self.deadline.as_mut().reset(deadline);
+    }
+
+    fn poll_wait(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Error> {
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                tracing::error!(
+                    "Backend idle for {}s, response aborted | {}",
+                    self.idle_timeout.as_secs(),
+                    self.dest_url
+                );
+                Poll::Ready(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "upstream idle timeout",
+                ))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 pub trait HasMutableHeaders {
@@ -100,11 +276,45 @@ pub fn custom_headers<T: HasMutableHeaders>(req: &mut T, headers_actions: &Confi
     }
 }
 
-pub async fn welcome_server(http: Arc<Builder<TokioExecutor>>, shutdown_token: CancellationToken) {
-    let port: u16 = if getuid().is_root() { 80 } else { 8080 };
-    let socket_addr: SocketAddr = ([0, 0, 0, 0], port).into();
-    let listener = TcpListener::bind(socket_addr).await.unwrap();
+// Wait at most queue_timeout for a permit when the limit is reached, and
+// return the time waited. None when no permit was freed in time.
+pub async fn acquire_permit(
+    semaphore: &Arc<Semaphore>,
+    queue_timeout: Duration,
+) -> Option<(OwnedSemaphorePermit, Duration)> {
+    if let Ok(permit) = Arc::clone(semaphore).try_acquire_owned() {
+        return Some((permit, Duration::ZERO));
+    }
+    if queue_timeout.is_zero() {
+        return None;
+    }
+    let start = Instant::now();
+    match tokio::time::timeout(queue_timeout, Arc::clone(semaphore).acquire_owned()).await {
+        Ok(Ok(permit)) => Some((permit, start.elapsed())),
+        _ => None,
+    }
+}
 
+// Port of the main server, 8080 instead of the default 80 when the server
+// isn't started as root.
+pub fn welcome_port(main: &Server) -> u16 {
+    if main.port == 80 && !getuid().is_root() {
+        8080
+    } else {
+        main.port
+    }
+}
+
+// The connections over max_connections are rejected like on a configured
+// server.
+pub(super) async fn welcome_server<A: StreamAcceptor>(
+    http: Arc<Builder<TokioExecutor>>,
+    acceptor: Arc<A>,
+    listener: TcpListener,
+    max_conns: Arc<Semaphore>,
+    over_max_conn: OverMaxConnections,
+    shutdown_token: CancellationToken,
+) {
     loop {
         let http = Arc::clone(&http);
         let res = tokio::select! {
@@ -115,15 +325,40 @@ pub async fn welcome_server(http: Arc<Builder<TokioExecutor>>, shutdown_token: C
             incoming = listener.accept() => incoming
         };
 
-        let (stream, _) = match res {
+        let (stream, address) = match res {
             Ok(res) => res,
             Err(err) => {
                 tracing::error!("Welcome server failed to accept connection: {err:#}");
                 continue;
             }
         };
+        let acceptor = Arc::clone(&acceptor);
+        let max_conns = Arc::clone(&max_conns);
+        let over_max_conn = over_max_conn.clone();
 
         tokio::task::spawn(async move {
+            let permit = max_conns.try_acquire_owned().ok();
+            if permit.is_none() {
+                tracing::error!(
+                    "Too many connections on the welcome server from {}.",
+                    format_ip(address.ip())
+                );
+            }
+            let rejection = match permit {
+                Some(_) => None,
+                None => match over_max_conn.rejection() {
+                    Some(rejection) => Some(rejection),
+                    None => return,
+                },
+            };
+            // Failures are logged by the acceptor.
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            if let Some((retry_after, _slot)) = rejection {
+                reject_connection(http, stream, retry_after).await;
+                return;
+            }
             if let Err(err) = http
                 .serve_connection(TokioIo::new(stream), service_fn(welcome_server_msg))
                 .await
@@ -207,3 +442,101 @@ impl ServerCertVerifier for NoCertificateVerification {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queue_for_a_permit() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let (permit, waited) = acquire_permit(&semaphore, Duration::ZERO).await.unwrap();
+        assert_eq!(waited, Duration::ZERO);
+
+        // Rejected at once without a queue, or after the timeout.
+        assert!(acquire_permit(&semaphore, Duration::ZERO).await.is_none());
+        let start = Instant::now();
+        let timeout = Duration::from_millis(50);
+        assert!(acquire_permit(&semaphore, timeout).await.is_none());
+        assert!(start.elapsed() >= timeout);
+
+        // Served as soon as the permit is released.
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(permit);
+        });
+        let (_permit, waited) = acquire_permit(&semaphore, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(waited >= Duration::from_millis(20) && waited < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn proxy_clients_connect_timeout() {
+        let clients = ProxyClients::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(rustls::RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        // A black-holed address (TEST-NET-1) fails with the connect timeout,
+        // or at once without a route.
+        let start = Instant::now();
+        let req = Request::get("http://192.0.2.1:81/")
+            .body(ProxyHandlerBody::Empty)
+            .unwrap();
+        let err = clients
+            .get(UpstreamHttp2::Never, 1)
+            .request(req)
+            .await
+            .unwrap_err();
+        assert!(err.is_connect());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn upstream_idle_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Backend sending the start of its body and then nothing.
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let clients = ProxyClients::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(rustls::RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        let req = Request::get(format!("http://{backend_addr}/"))
+            .body(ProxyHandlerBody::Empty)
+            .unwrap();
+        let res = clients
+            .get(UpstreamHttp2::Never, 5)
+            .request(req)
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let mut body = ProxyHandlerBody::Upstream(UpstreamBody::new(
+            IncomingIo(res.into_body()),
+            IdleTimeout::new(Duration::from_millis(200), "test".to_string()),
+        ));
+        // The length is still known, and the data received is forwarded.
+        assert_eq!(hyper::body::Body::size_hint(&body).exact(), Some(10));
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "abc");
+        let err = body.frame().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
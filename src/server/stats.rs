@@ -0,0 +1,404 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::ipc::IpcHeartbeat;
+use crate::utils;
+
+// Connection counters of a listener, since the server started.
+pub struct ListenerStats {
+    pub port: u16,
+    pub protocol: &'static str,
+    pub accept_loop: Option<usize>, // From 1, when the port has several.
+    accepted_total: AtomicU64,
+    active: AtomicU64,
+    rejected_limit: AtomicU64,    // By max_connections.
+    rejected_ip_limit: AtomicU64, // By max_conn_per_ip.
+    // Closed by the server, idle for idle_timeout or open for
+    // max_connection_lifetime.
+    reaped_idle: AtomicU64,
+    reaped_lifetime: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    handshake_errors: AtomicU64,
+    // Body bytes of the responses, and the responses whose body wasn't sent
+    // completely, e.g. the client went away.
+    bytes_sent: AtomicU64,
+    responses_aborted: AtomicU64,
+    // Completed handshakes, to know when an old version can be dropped.
+    tls_handshakes: DashMap<TlsParams, u64>,
+}
+
+// Negotiated parameters of a TLS connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct TlsParams {
+    pub version: &'static str, // e.g. 1.3
+    pub cipher_suite: &'static str,
+    pub alpn: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TlsHandshakes {
+    #[serde(flatten)]
+    pub params: TlsParams,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ListenerStatsSnapshot {
+    pub port: u16,
+    pub protocol: &'static str,
+    pub accept_loop: Option<usize>,
+    pub accepted_total: u64,
+    pub active: u64,
+    pub rejected_limit: u64,
+    pub rejected_ip_limit: u64,
+    pub reaped_idle: u64,
+    pub reaped_lifetime: u64,
+    pub bytes_sent: u64,
+    pub responses_aborted: u64,
+    // Timeouts included.
+    pub tls_handshake_failed: u64,
+    pub tls_handshake_timeouts: u64,
+    pub tls_handshakes: Vec<TlsHandshakes>,
+}
+
+// Counted as active until the connection is closed.
+pub struct ActiveConnection {
+    stats: Arc<ListenerStats>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ListenerStats {
+    pub fn new(port: u16, protocol: &'static str, accept_loop: Option<usize>) -> ListenerStats {
+        ListenerStats {
+            port,
+            protocol,
+            accept_loop,
+            accepted_total: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            rejected_limit: AtomicU64::new(0),
+            rejected_ip_limit: AtomicU64::new(0),
+            reaped_idle: AtomicU64::new(0),
+            reaped_lifetime: AtomicU64::new(0),
+            handshake_timeouts: AtomicU64::new(0),
+            handshake_errors: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            responses_aborted: AtomicU64::new(0),
+            tls_handshakes: DashMap::new(),
+        }
+    }
+
+    // Separate counters for each accept loop of a port, so an imbalance
+    // between the sockets is visible.
+    pub fn for_accept_loops(
+        port: u16,
+        protocol: &'static str,
+        count: usize,
+    ) -> Vec<Arc<ListenerStats>> {
+        (1..=count)
+            .map(|accept_loop| {
+                let accept_loop = (count > 1).then_some(accept_loop);
+                Arc::new(ListenerStats::new(port, protocol, accept_loop))
+            })
+            .collect()
+    }
+
+    pub fn accepted(&self) {
+        self.accepted_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn active(self: &Arc<Self>) -> ActiveConnection {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            stats: Arc::clone(self),
+        }
+    }
+
+    pub fn rejected_limit(&self) {
+        self.rejected_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected_ip_limit(&self) {
+        self.rejected_ip_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reaped(&self, idle: bool) {
+        let counter = match idle {
+            true => &self.reaped_idle,
+            false => &self.reaped_lifetime,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Return the number of failures of this kind.
+    pub fn handshake_failed(&self, timeout: bool) -> u64 {
+        let counter = match timeout {
+            true => &self.handshake_timeouts,
+            false => &self.handshake_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Once the body of a response is dropped.
+    pub fn response_sent(&self, body_bytes: u64, aborted: bool) {
+        self.bytes_sent.fetch_add(body_bytes, Ordering::Relaxed);
+        if aborted {
+            self.responses_aborted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn handshake_completed(&self, params: TlsParams) {
+        *self.tls_handshakes.entry(params).or_insert(0) += 1;
+    }
+
+    pub fn snapshot(&self) -> ListenerStatsSnapshot {
+        let timeouts = self.handshake_timeouts.load(Ordering::Relaxed);
+        let mut tls_handshakes: Vec<TlsHandshakes> = self
+            .tls_handshakes
+            .iter()
+            .map(|entry| TlsHandshakes {
+                params: *entry.key(),
+                count: *entry.value(),
+            })
+            .collect();
+        tls_handshakes.sort_by_key(|handshakes| handshakes.params);
+        ListenerStatsSnapshot {
+            port: self.port,
+            protocol: self.protocol,
+            accept_loop: self.accept_loop,
+            accepted_total: self.accepted_total.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            rejected_limit: self.rejected_limit.load(Ordering::Relaxed),
+            rejected_ip_limit: self.rejected_ip_limit.load(Ordering::Relaxed),
+            reaped_idle: self.reaped_idle.load(Ordering::Relaxed),
+            reaped_lifetime: self.reaped_lifetime.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            responses_aborted: self.responses_aborted.load(Ordering::Relaxed),
+            tls_handshake_failed: timeouts + self.handshake_errors.load(Ordering::Relaxed),
+            tls_handshake_timeouts: timeouts,
+            tls_handshakes,
+        }
+    }
+}
+
+// Heartbeats of the main process, since the server started.
+#[derive(Default)]
+pub struct HeartbeatStats {
+    received: AtomicU64,
+    // Timeouts without a heartbeat, and without an ack in the main process.
+    missed: AtomicU64,
+    missed_by_main: AtomicU64,
+    // Time from the main process to the IPC loop of the last one.
+    delay_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeartbeatStatsSnapshot {
+    pub received: u64,
+    pub missed: u64,
+    pub missed_by_main: u64,
+    pub delay_ms: u64,
+}
+
+impl HeartbeatStats {
+    pub fn received(&self, heartbeat: &IpcHeartbeat) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.missed_by_main
+            .store(heartbeat.missed, Ordering::Relaxed);
+        self.delay_ms
+            .store(heartbeat.delay().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Return the number of timeouts so far.
+    pub fn record_missed(&self) -> u64 {
+        self.missed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn missed_total(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> HeartbeatStatsSnapshot {
+        HeartbeatStatsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            missed: self.missed.load(Ordering::Relaxed),
+            missed_by_main: self.missed_by_main.load(Ordering::Relaxed),
+            delay_ms: self.delay_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Wait until the connections of the listeners are closed, at most timeout.
+pub async fn wait_closed(listeners: &[Arc<ListenerStats>], timeout: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < timeout
+        && listeners
+            .iter()
+            .any(|stats| stats.active.load(Ordering::Relaxed) > 0)
+    {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Log a summary line per listener every interval seconds, 0 to disable.
+pub fn start_summary(listeners: Vec<Arc<ListenerStats>>, interval: u64) {
+    if interval == 0 || listeners.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut previous = vec![0; listeners.len()];
+        loop {
+            ticker.tick().await;
+            for (stats, previous) in listeners.iter().zip(previous.iter_mut()) {
+                let snapshot = stats.snapshot();
+                tracing::info!("{}", summary_line(&snapshot, *previous, period));
+                *previous = snapshot.accepted_total;
+            }
+        }
+    });
+}
+
+fn summary_line(stats: &ListenerStatsSnapshot, previous_accepted: u64, period: Duration) -> String {
+    let rate = (stats.accepted_total - previous_accepted) as f64 / period.as_secs_f64();
+    let accept_loop = match stats.accept_loop {
+        Some(accept_loop) => format!(", accept loop {accept_loop}"),
+        None => String::new(),
+    };
+    let mut line = format!(
+        "Connections on port {} ({}{accept_loop}): {} active, {} accepted ({rate:.2}/s), {} rejected by max_connections, {} rejected by max_conn_per_ip, {} closed idle, {} closed at max lifetime, {} sent, {} responses aborted",
+        stats.port,
+        stats.protocol,
+        stats.active,
+        stats.accepted_total,
+        stats.rejected_limit,
+        stats.rejected_ip_limit,
+        stats.reaped_idle,
+        stats.reaped_lifetime,
+        utils::format_size(stats.bytes_sent),
+        stats.responses_aborted,
+    );
+    if stats.protocol == "https" {
+        line.push_str(&format!(
+            ", {} TLS handshakes failed",
+            stats.tls_handshake_failed
+        ));
+        let mut versions: Vec<(&str, u64)> = Vec::new();
+        for handshakes in &stats.tls_handshakes {
+            match versions.last_mut() {
+                Some((version, count)) if *version == handshakes.params.version => {
+                    *count += handshakes.count
+                }
+                _ => versions.push((handshakes.params.version, handshakes.count)),
+            }
+        }
+        for (version, count) in versions {
+            line.push_str(&format!(", {count} with TLS {version}"));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_stats() {
+        let stats = Arc::new(ListenerStats::new(443, "https", None));
+        stats.accepted();
+        stats.accepted();
+        stats.accepted();
+        stats.rejected_limit();
+        stats.reaped(true);
+        stats.reaped(false);
+        stats.reaped(false);
+        stats.response_sent(1000, false);
+        stats.response_sent(1048, true);
+        let first = stats.active();
+        let second = stats.active();
+        assert_eq!(stats.handshake_failed(true), 1);
+        assert_eq!(stats.handshake_failed(false), 1);
+        assert_eq!(stats.handshake_failed(true), 2);
+        let tls13 = TlsParams {
+            version: "1.3",
+            cipher_suite: "TLS13_AES_128_GCM_SHA256",
+            alpn: Some("h2"),
+        };
+        let tls12 = TlsParams {
+            version: "1.2",
+            cipher_suite: "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            alpn: None,
+        };
+        stats.handshake_completed(tls13);
+        stats.handshake_completed(tls12);
+        stats.handshake_completed(tls13);
+        stats.handshake_completed(TlsParams {
+            alpn: Some("http/1.1"),
+            ..tls13
+        });
+        drop(first);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted_total, 3);
+        assert_eq!(snapshot.active, 1);
+        assert_eq!(snapshot.rejected_limit, 1);
+        assert_eq!(snapshot.rejected_ip_limit, 0);
+        assert_eq!(snapshot.reaped_idle, 1);
+        assert_eq!(snapshot.reaped_lifetime, 2);
+        assert_eq!(snapshot.bytes_sent, 2048);
+        assert_eq!(snapshot.responses_aborted, 1);
+        assert_eq!(snapshot.tls_handshake_failed, 3);
+        assert_eq!(snapshot.tls_handshake_timeouts, 2);
+        assert_eq!(snapshot.tls_handshakes.len(), 3);
+        assert_eq!(
+            snapshot.tls_handshakes[0],
+            TlsHandshakes {
+                params: tls12,
+                count: 1
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&snapshot.tls_handshakes[1]).unwrap(),
+            serde_json::json!({
+                "version": "1.3",
+                "cipher_suite": "TLS13_AES_128_GCM_SHA256",
+                "alpn": "h2",
+                "count": 2,
+            })
+        );
+        drop(second);
+        assert_eq!(stats.snapshot().active, 0);
+
+        assert_eq!(
+            summary_line(&snapshot, 1, Duration::from_secs(4)),
+            "Connections on port 443 (https): 1 active, 3 accepted (0.50/s), 1 rejected by max_connections, 0 rejected by max_conn_per_ip, 1 closed idle, 2 closed at max lifetime, 2.0 KB sent, 1 responses aborted, 3 TLS handshakes failed, 1 with TLS 1.2, 3 with TLS 1.3"
+        );
+        let plain = ListenerStats::new(80, "http", None).snapshot();
+        assert!(summary_line(&plain, 0, Duration::from_secs(60))
+            .ends_with("0 closed idle, 0 closed at max lifetime, 0 B sent, 0 responses aborted"));
+
+        assert_eq!(
+            ListenerStats::for_accept_loops(80, "http", 1)[0].accept_loop,
+            None
+        );
+        let loops = ListenerStats::for_accept_loops(80, "http", 3);
+        let numbers: Vec<_> = loops.iter().map(|stats| stats.accept_loop).collect();
+        assert_eq!(numbers, [Some(1), Some(2), Some(3)]);
+        loops[1].accepted();
+        assert!(
+            summary_line(&loops[1].snapshot(), 0, Duration::from_secs(1)).starts_with(
+                "Connections on port 80 (http, accept loop 2): 0 active, 1 accepted (1.00/s)"
+            )
+        );
+    }
+}
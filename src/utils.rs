@@ -1,11 +1,24 @@
 use nix::unistd::{getuid, setgid, setgroups, setuid, Group, User};
+use socket2::{Socket, Type};
 use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    os::unix::fs::chown,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        LazyLock, OnceLock,
+    },
 };
 
 pub const QUARK_USER_AND_GROUP: &str = "quark";
+
+// First descriptor passed by systemd, after stdin, stdout and stderr.
+const SD_LISTEN_FDS_START: RawFd = 3;
 pub static CACHED_CURRENT_TIME: AtomicU64 = AtomicU64::new(0);
+// User and group of the server process, set once the config is received.
+static RUN_AS: OnceLock<(String, String)> = OnceLock::new();
 
 pub fn get_current_time() -> u64 {
     CACHED_CURRENT_TIME.load(Ordering::Relaxed)
@@ -19,6 +32,23 @@ pub fn remove_last_slash(path: &str) -> &str {
     }
 }
 
+// Merge the duplicate slashes of a path, "/app//x" becomes "/app/x". The
+// query string is kept as is, like the encoded slashes ("%2F").
+pub fn merge_slashes(path: &str) -> Cow<'_, str> {
+    let (path_only, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+    if !path_only.contains("//") {
+        return Cow::Borrowed(path);
+    }
+    let mut merged = String::with_capacity(path.len());
+    for c in path_only.chars() {
+        if c != '/' || !merged.ends_with('/') {
+            merged.push(c);
+        }
+    }
+    merged.push_str(query);
+    Cow::Owned(merged)
+}
+
 pub fn get_path_and_file(path_str: &str) -> (PathBuf, Option<PathBuf>) {
     let path = Path::new(path_str);
 
@@ -47,23 +77,56 @@ pub fn format_ip(ip: std::net::IpAddr) -> String {
     }
 }
 
-pub fn drop_privileges(name: &str) -> Result<&'static str, Box<dyn std::error::Error>> {
-    // Check if we are already root.
+pub fn set_run_as(user: &str, group: &str) {
+    RUN_AS.set((user.to_string(), group.to_string())).ok();
+}
+
+fn run_as() -> (&'static str, &'static str) {
+    RUN_AS.get().map_or(
+        (QUARK_USER_AND_GROUP, QUARK_USER_AND_GROUP),
+        |(user, group)| (user.as_str(), group.as_str()),
+    )
+}
+
+// Look up the user and group of the server process, [global] user and group.
+pub fn lookup_run_as(user: &str, group: &str) -> Result<(User, Group), String> {
+    let found_user = User::from_name(user)
+        .map_err(|e| format!("Can't look up the user \"{user}\": {e}"))?
+        .ok_or_else(|| {
+            format!("The user \"{user}\" doesn't exist, create it or set another one with [global] user or --user")
+        })?;
+    let found_group = Group::from_name(group)
+        .map_err(|e| format!("Can't look up the group \"{group}\": {e}"))?
+        .ok_or_else(|| {
+            format!("The group \"{group}\" doesn't exist, create it or set another one with [global] group or --group")
+        })?;
+    Ok((found_user, found_group))
+}
+
+// Return false when not root, there is nothing to drop.
+pub fn drop_privileges() -> Result<bool, Box<dyn std::error::Error>> {
     if !getuid().is_root() {
-        return Ok("Privileges already dropped");
+        return Ok(false);
     }
+    let (user, group) = run_as();
+    let (user, group) = lookup_run_as(user, group)?;
+    setgroups(&[group.gid])?;
+    setgid(group.gid)?;
+    setuid(user.uid)?;
+    Ok(true)
+}
 
-    let user = User::from_name(name)?;
-    let group = Group::from_name(name)?;
-
-    if let (Some(user), Some(group)) = (user, group) {
-        setgroups(&[group.gid])?;
-        setgid(group.gid)?;
-        setuid(user.uid)?;
-    } else {
-        return Err("User or group not found".into());
+// Give a path created by the root process to the user of the server, so it
+// can still be written once the privileges are dropped. Does nothing if not
+// root.
+pub fn chown_to_run_as_user(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !getuid().is_root() {
+        return Ok(());
     }
-    Ok("Privileges dropped")
+    let (user, group) = run_as();
+    let (user, group) = lookup_run_as(user, group)?;
+    chown(path, Some(user.uid.as_raw()), Some(group.gid.as_raw()))?;
+    Ok(())
 }
 
 pub fn extract_vars_from_string(text: &str) -> Vec<String> {
@@ -94,12 +157,27 @@ pub fn get_project_version() -> String {
     version
 }
 
+// Number of cores the process can use, 1 if it is unknown.
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
 static COUNTER: AtomicU32 = AtomicU32::new(0);
 
 pub fn generate_u32_id() -> u32 {
     COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+// Random for each process, so the ids don't repeat after a restart.
+static REQUEST_ID_PREFIX: LazyLock<u64> =
+    LazyLock::new(|| std::hash::BuildHasher::hash_one(&RandomState::new(), std::process::id()));
+
+pub fn generate_request_id() -> String {
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{count:016x}", *REQUEST_ID_PREFIX)
+}
+
 const KB: f64 = 1024.0;
 const MB: f64 = KB * 1024.0;
 const GB: f64 = MB * 1024.0;
@@ -121,10 +199,120 @@ pub fn format_size(size: u64) -> String {
     }
 }
 
+// Decode the percent-encoding of a request path, once. The escapes which
+// would change its structure ("%2F", "%00") are refused, like the invalid
+// ones and the bytes which aren't UTF-8, e.g. an overlong "%C0%AF".
+pub fn percent_decode_path(path: &str) -> Result<Cow<'_, str>, &'static str> {
+    if !path.contains('%') {
+        return Ok(Cow::Borrowed(path));
+    }
+    let bytes = path.as_bytes();
+    let hex = |i: usize| bytes.get(i).and_then(|b| (*b as char).to_digit(16));
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let (Some(high), Some(low)) = (hex(i + 1), hex(i + 2)) else {
+            return Err("invalid escape sequence");
+        };
+        match (high * 16 + low) as u8 {
+            b'/' => return Err("encoded slash"),
+            0 => return Err("encoded NUL byte"),
+            byte => decoded.push(byte),
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded)
+        .map(Cow::Owned)
+        .map_err(|_| "invalid UTF-8")
+}
+
+// Encode a file name for a link, the reverse of percent_decode_path.
+pub fn percent_encode_name(name: &str) -> Cow<'_, str> {
+    let keep = |b: u8| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b);
+    if name.bytes().all(keep) {
+        return Cow::Borrowed(name);
+    }
+    let mut encoded = String::with_capacity(name.len() * 3);
+    for b in name.bytes() {
+        if keep(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Listening sockets passed by systemd (socket activation), with their port.
+// Empty when the process wasn't started by a socket unit.
+pub fn systemd_listeners() -> Result<Vec<(u16, OwnedFd)>, String> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    if listen_pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .ok_or("Invalid LISTEN_FDS from systemd")?;
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // The descriptors are owned by this process from now on.
+        let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let port = socket_port(&socket).map_err(|e| format!("Socket {fd} from systemd: {e}"))?;
+        listeners.push((port, socket.into()));
+    }
+    Ok(listeners)
+}
+
+fn socket_port(socket: &Socket) -> Result<u16, String> {
+    if socket.r#type().map_err(|e| e.to_string())? != Type::STREAM {
+        return Err("not a TCP socket".to_string());
+    }
+    socket
+        .local_addr()
+        .map_err(|e| e.to_string())?
+        .as_socket()
+        .map(|address| address.port())
+        .ok_or_else(|| "not a TCP socket".to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn activated_socket_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(
+            socket_port(&Socket::from(OwnedFd::from(listener))),
+            Ok(port)
+        );
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(socket_port(&Socket::from(OwnedFd::from(unix))).is_err());
+    }
+
     #[test]
     fn extract_single_var() {
         let text = "My ${variable}";
@@ -137,4 +325,53 @@ mod tests {
         let var = extract_vars_from_string(text);
         assert_eq!(var, ["var1", "var2", "var3"]);
     }
+
+    #[test]
+    fn merge_duplicate_slashes() {
+        assert!(matches!(merge_slashes("/app/x?a=1"), Cow::Borrowed(_)));
+        assert_eq!(merge_slashes("//app///x//"), "/app/x/");
+        assert_eq!(merge_slashes("/app//x?next=//y"), "/app/x?next=//y");
+        assert_eq!(merge_slashes("/app/%2F%2F/x"), "/app/%2F%2F/x");
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert!(matches!(
+            percent_decode_path("/a+b"),
+            Ok(Cow::Borrowed("/a+b"))
+        ));
+        assert_eq!(
+            percent_decode_path("/my%20file.pdf").unwrap(),
+            "/my file.pdf"
+        );
+        assert_eq!(percent_decode_path("/caf%C3%a9").unwrap(), "/caf\u{e9}");
+        // Decoded once.
+        assert_eq!(percent_decode_path("/a%2520b").unwrap(), "/a%20b");
+        for path in [
+            "/%2e%2e%2fetc",
+            "/a%00",
+            "/a%zz",
+            "/a%2",
+            "/a%",
+            "/a%+1",
+            "/%C0%AF",
+            "/%FF",
+        ] {
+            assert!(percent_decode_path(path).is_err(), "{path}");
+        }
+
+        for name in ["my file.pdf", "100%.txt", "caf\u{e9}?#", "a+b&c"] {
+            let encoded = percent_encode_name(name);
+            assert_eq!(percent_decode_path(&encoded).unwrap(), name);
+        }
+        assert_eq!(percent_encode_name("my file?"), "my%20file%3F");
+    }
+
+    #[test]
+    fn escape_html_special_chars() {
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
 }
@@ -0,0 +1,208 @@
+// Allocations on the hot paths, counted by the global allocator of this test
+// binary only.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use quark::config::{
+    BackendOptions, ConfigHeaders, ForwardedHeaders, InternalConfig, Locations, TargetParams,
+    UpstreamHttp2,
+};
+use quark::load_balancing::LoadBalancerConfig;
+use quark::server::handler::{HandlerParams, ServerHandler};
+use quark::server::server_utils::ProxyClients;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Count the allocations of the current thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Allocations made by f on the current thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn balance_without_allocation() {
+    let location = Locations {
+        id: 3,
+        params: TargetParams {
+            location: vec!["a".to_string(), "b".to_string()],
+            headers: ConfigHeaders::default(),
+        },
+        algo: Some("ip_hash".to_string()),
+        weights: None,
+        ewma_decay: 0.2,
+        outlier_detection: None,
+        proxy_timeout: None,
+        connect_timeout: None,
+        upstream_idle_timeout: None,
+        cache: None,
+        upstream_http2: UpstreamHttp2::Never,
+        request_buffering: None,
+        max_concurrent: None,
+        forwarded_headers: ForwardedHeaders::default(),
+        dns: None,
+        backend_options: vec![
+            BackendOptions {
+                max_conns: Some(10),
+                backup: false,
+            };
+            2
+        ],
+    };
+    let lb = LoadBalancerConfig::new(vec![&location]);
+    let servers = &location.params.location;
+    // The stats of each backend are created by their first request.
+    for ip in ["1.1.1.1", "2.2.2.2", "3.3.3.3"] {
+        lb.balance(&3, servers, &location.algo, ip);
+    }
+
+    let count = allocations(|| {
+        for _ in 0..100 {
+            let backend = lb.balance(&3, servers, &location.algo, "1.1.1.1").unwrap();
+            assert!(!backend.url(servers).is_empty());
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+// A backend on its own thread, its allocations aren't counted. Answers each
+// request of a keep-alive connection with "ok".
+fn spawn_backend() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            std::thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                loop {
+                    while let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        request.drain(..pos + 4);
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                            .unwrap();
+                    }
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn proxied_request_allocations() {
+    let backend = spawn_backend();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+            [services.app]
+            domain = "example.com"
+            locations = [{{ source = "/*", target = "http://{backend}" }}]
+            "#
+        ),
+    )
+    .unwrap();
+    let mut config = InternalConfig::build_from(path.to_string_lossy().to_string()).unwrap();
+    let tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let handler = ServerHandler::builder(
+        config.servers.remove("main").unwrap().params,
+        LoadBalancerConfig::new(Vec::new()),
+        Arc::default(),
+        Arc::new(tokio::sync::Semaphore::new(10)),
+        Duration::ZERO,
+        Arc::new(ProxyClients::new(tls_config)),
+    );
+
+    // The front, the handler and the client run on this thread.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut client = runtime.block_on(async {
+        let front = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let front_addr = front.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = front.accept().await.unwrap();
+            let client_ip: Arc<str> = Arc::from("127.0.0.1");
+            let service = hyper::service::service_fn(move |req| {
+                let handler = Arc::clone(&handler);
+                let hp = HandlerParams {
+                    req,
+                    client_ip: Arc::clone(&client_ip),
+                    scheme: "http",
+                    port: 80,
+                    client_cert_subject: None,
+                };
+                async move { handler.handle(hp).await }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .await;
+        });
+        tokio::net::TcpStream::connect(front_addr).await.unwrap()
+    });
+    let request = |client: &mut tokio::net::TcpStream| {
+        runtime.block_on(async {
+            client
+                .write_all(b"GET /some/path?q=1 HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0; 1024];
+            while !response.ends_with(b"\r\n\r\nok") {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                response.extend_from_slice(&buf[..n]);
+            }
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        })
+    };
+    // The connection to the backend is open and pooled after the first one.
+    for _ in 0..10 {
+        request(&mut client);
+    }
+
+    const REQUESTS: usize = 100;
+    let count = allocations(|| {
+        for _ in 0..REQUESTS {
+            request(&mut client);
+        }
+    });
+    // Of the front, the handler, the client to the backend and tokio: 34
+    // when measured, a few more are tolerated.
+    let per_request = count / REQUESTS;
+    assert!(per_request <= 40, "{per_request} allocations per request");
+}